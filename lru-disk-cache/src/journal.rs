@@ -0,0 +1,316 @@
+//! A log of the changes made to a cache directory, shared between the processes using it.
+//!
+//! The journal starts with a header holding a generation number, followed by one line per
+//! record. Processes append the records of their changes while holding the directory's
+//! `DirLock`, and read the records appended by other processes the next time they take it.
+//! When the journal grows much larger than the cache it describes, it is rewritten as the list of
+//! files in the cache, under a new generation number, which tells other processes to start over
+//! from the beginning. A process starting up loads the cache from the journal the same way.
+
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the journal in a cache directory.
+pub const JOURNAL_FILE: &str = ".lru-disk-cache.journal";
+
+/// The name of the file that a new journal is written to before it replaces the old one.
+const TEMP_JOURNAL_FILE: &str = ".lru-disk-cache.journal.tmp";
+
+/// The start of the first line of the journal, which is followed by its generation number.
+const HEADER: &str = "lru-disk-cache journal v2 ";
+
+/// The number of records a journal may have beyond twice the number of files in the cache before
+/// it is rewritten, so that small caches aren't rewritten all the time.
+const MIN_COMPACTION_RECORDS: usize = 1000;
+
+/// A change to the cache, relative to its root.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    /// A file of the given size, used the given number of times, was added, or replaced, as
+    /// the most-recently-used file.
+    Insert(OsString, u64, u64),
+    /// A file was used, making it the most-recently-used file.
+    Access(OsString),
+    /// A file was removed.
+    Remove(OsString),
+}
+
+/// The changes read from the journal by `Journal::read`.
+#[derive(Debug, PartialEq)]
+pub enum Changes {
+    /// These records were appended since the journal was last read.
+    Appended(Vec<Record>),
+    /// The journal was rewritten, and the cache consists of exactly these records.
+    Rewritten(Vec<Record>),
+    /// The journal is missing or corrupt, so it has to be rewritten.
+    Missing,
+}
+
+/// The journal of a cache directory, and how much of it has been read.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    temp_path: PathBuf,
+    /// The generation of the journal that was read, or 0 if it has to be rewritten.
+    generation: u64,
+    /// The offset of the end of the last record that was read or written.
+    offset: u64,
+    /// The number of records in the journal up to `offset`.
+    records: usize,
+}
+
+impl Journal {
+    /// The journal of the cache directory `root`, which has yet to be read.
+    pub fn new(root: &Path) -> Journal {
+        Journal {
+            path: root.join(JOURNAL_FILE),
+            temp_path: root.join(TEMP_JOURNAL_FILE),
+            generation: 0,
+            offset: 0,
+            records: 0,
+        }
+    }
+
+    /// Read the records written since the journal was last read or written.
+    ///
+    /// This must only be called while holding the directory's lock, because an incomplete
+    /// record at the end of the journal, left by a process that died while writing it, is
+    /// removed.
+    pub fn read(&mut self) -> io::Result<Changes> {
+        let file = match OpenOptions::new().read(true).write(true).open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(self.detach()),
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::new(&file);
+        let mut header = vec![];
+        reader.read_until(b'\n', &mut header)?;
+        let generation = match parse_header(&header) {
+            Some(generation) => generation,
+            None => {
+                warn!("Ignoring journal {:?} with an invalid header", self.path);
+                return Ok(self.detach());
+            }
+        };
+        let rewritten = generation != self.generation;
+        let start = if rewritten { header.len() as u64 } else { self.offset };
+        if file.metadata()?.len() < start {
+            warn!("Ignoring journal {:?} which was truncated", self.path);
+            return Ok(self.detach());
+        }
+        reader.seek(SeekFrom::Start(start))?;
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        let complete = data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        if complete < data.len() {
+            warn!("Removing an incomplete record from journal {:?}", self.path);
+            file.set_len(start + complete as u64)?;
+        }
+        let records = match parse_records(&data[..complete]) {
+            Some(records) => records,
+            None => {
+                warn!("Ignoring journal {:?} with invalid records", self.path);
+                return Ok(self.detach());
+            }
+        };
+        if rewritten {
+            self.records = 0;
+        }
+        self.records += records.len();
+        self.generation = generation;
+        self.offset = start + complete as u64;
+        Ok(if rewritten {
+            Changes::Rewritten(records)
+        } else {
+            Changes::Appended(records)
+        })
+    }
+
+    /// Append `records` to the journal.
+    pub fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        let data = format_records(records);
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        file.write_all(&data)?;
+        self.offset += data.len() as u64;
+        self.records += records.len();
+        Ok(())
+    }
+
+    /// Whether the journal of a cache holding `files` files should be rewritten rather than
+    /// appended to.
+    pub fn needs_compaction(&self, files: usize) -> bool {
+        self.generation == 0 || self.records > 2 * files + MIN_COMPACTION_RECORDS
+    }
+
+    /// Replace the journal with one holding only `records`, under a new generation.
+    pub fn rewrite(&mut self, records: &[Record]) -> io::Result<()> {
+        let generation = new_generation(self.generation);
+        let mut data = format!("{}{}\n", HEADER, generation).into_bytes();
+        data.extend(format_records(records));
+        fs::write(&self.temp_path, &data)?;
+        fs::rename(&self.temp_path, &self.path)?;
+        self.generation = generation;
+        self.offset = data.len() as u64;
+        self.records = records.len();
+        Ok(())
+    }
+
+    /// Forget the journal that was read, so that it is rewritten next.
+    fn detach(&mut self) -> Changes {
+        self.generation = 0;
+        self.offset = 0;
+        self.records = 0;
+        Changes::Missing
+    }
+}
+
+/// Parse the generation out of the first line of a journal.
+fn parse_header(line: &[u8]) -> Option<u64> {
+    let line = std::str::from_utf8(line).ok()?;
+    if !line.starts_with(HEADER) || !line.ends_with('\n') {
+        return None;
+    }
+    line[HEADER.len()..line.len() - 1].parse().ok().filter(|&g| g != 0)
+}
+
+/// Pick a generation for a rewritten journal that differs from `old`.
+fn new_generation(old: u64) -> u64 {
+    let d = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let generation = d.as_secs().wrapping_mul(1_000_000_000).wrapping_add(d.subsec_nanos() as u64)
+        ^ ((process::id() as u64) << 48);
+    if generation == 0 || generation == old {
+        old.wrapping_add(1).max(1)
+    } else {
+        generation
+    }
+}
+
+fn format_records(records: &[Record]) -> Vec<u8> {
+    let mut data = vec![];
+    for record in records {
+        let (path, line) = match *record {
+            Record::Insert(ref path, size, uses) => (path, format!("I {} {} ", size, uses)),
+            Record::Access(ref path) => (path, "A ".to_owned()),
+            Record::Remove(ref path) => (path, "R ".to_owned()),
+        };
+        match path.to_str() {
+            Some(p) if !p.contains('\n') => {
+                data.extend(line.as_bytes());
+                data.extend(p.as_bytes());
+                data.push(b'\n');
+            }
+            _ => warn!("Not journaling a change to {:?}, which is not a valid UTF-8 file name", path),
+        }
+    }
+    data
+}
+
+/// Parse the records in `data`, or return `None` if any of them is invalid.
+fn parse_records(data: &[u8]) -> Option<Vec<Record>> {
+    std::str::from_utf8(data).ok()?
+        .lines()
+        .map(|line| {
+            let record = parse_record(line);
+            if record.is_none() {
+                warn!("Invalid journal record: {:?}", line);
+            }
+            record
+        })
+        .collect()
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let mut parts = line.splitn(2, ' ');
+    let kind = parts.next()?;
+    let rest = parts.next()?;
+    match kind {
+        "I" => {
+            let mut parts = rest.splitn(3, ' ');
+            let size = parts.next()?.parse().ok()?;
+            let uses = parts.next()?.parse().ok()?;
+            Some(Record::Insert(parts.next()?.into(), size, uses))
+        }
+        "A" => Some(Record::Access(rest.into())),
+        "R" => Some(Record::Remove(rest.into())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn insert(path: &str, size: u64) -> Record {
+        Record::Insert(path.into(), size, 0)
+    }
+
+    #[test]
+    fn test_journal() {
+        let dir = TempDir::new("lru-disk-cache-test").unwrap();
+        let mut a = Journal::new(dir.path());
+        let mut b = Journal::new(dir.path());
+        assert_eq!(a.read().unwrap(), Changes::Missing);
+        assert!(a.needs_compaction(0));
+        a.rewrite(&[insert("a/b/c", 10)]).unwrap();
+        assert!(!a.needs_compaction(1));
+        assert_eq!(b.read().unwrap(), Changes::Rewritten(vec![insert("a/b/c", 10)]));
+
+        let records = vec![
+            Record::Insert("a/b/d".into(), 5, 3),
+            Record::Access("a/b/c".into()),
+            Record::Remove("a/b/d".into()),
+        ];
+        b.append(&records).unwrap();
+        assert_eq!(a.read().unwrap(), Changes::Appended(records));
+        assert_eq!(a.read().unwrap(), Changes::Appended(vec![]));
+
+        // A record that was only partly written is dropped, and not mixed up with the next one.
+        OpenOptions::new().append(true).open(dir.path().join(JOURNAL_FILE)).unwrap()
+            .write_all(b"I 20 0 a/b/").unwrap();
+        assert_eq!(b.read().unwrap(), Changes::Appended(vec![]));
+        b.append(&[insert("a/b/e", 20)]).unwrap();
+        assert_eq!(a.read().unwrap(), Changes::Appended(vec![insert("a/b/e", 20)]));
+
+        // Readers start over when the journal is rewritten.
+        a.rewrite(&[insert("a/b/e", 20)]).unwrap();
+        assert_eq!(b.read().unwrap(), Changes::Rewritten(vec![insert("a/b/e", 20)]));
+
+        // Journals that are corrupt are rewritten.
+        a.append(&[Record::Access("a/b/e".into())]).unwrap();
+        OpenOptions::new().append(true).open(dir.path().join(JOURNAL_FILE)).unwrap()
+            .write_all(b"X a/b/e\n").unwrap();
+        assert_eq!(b.read().unwrap(), Changes::Missing);
+        assert!(b.needs_compaction(1));
+        fs::write(dir.path().join(JOURNAL_FILE), "garbage\n").unwrap();
+        assert_eq!(a.read().unwrap(), Changes::Missing);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dir = TempDir::new("lru-disk-cache-test").unwrap();
+        let mut a = Journal::new(dir.path());
+        let mut b = Journal::new(dir.path());
+        a.rewrite(&[insert("a/b/c", 10), insert("a/b/d", 10)]).unwrap();
+        let accesses = vec![Record::Access("a/b/c".into()); MIN_COMPACTION_RECORDS];
+        a.append(&accesses).unwrap();
+        assert!(!a.needs_compaction(2));
+        a.append(&accesses[..3]).unwrap();
+        assert!(a.needs_compaction(2));
+        // Other processes count the records they read the same way.
+        b.read().unwrap();
+        assert!(b.needs_compaction(2));
+        b.rewrite(&[insert("a/b/d", 10), insert("a/b/c", 10)]).unwrap();
+        assert!(!b.needs_compaction(2));
+        assert_eq!(a.read().unwrap(),
+                   Changes::Rewritten(vec![insert("a/b/d", 10), insert("a/b/c", 10)]));
+        assert!(!a.needs_compaction(2));
+    }
+}
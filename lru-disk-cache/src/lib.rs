@@ -3,42 +3,174 @@
 #[macro_use]
 extern crate log;
 //extern crate lru_cache;
+mod journal;
+mod lock;
 pub mod lru_cache;
 
 use std::io::prelude::*;
 use std::borrow::Borrow;
 use std::boxed::Box;
+use std::collections::BTreeMap;
 use std::collections::hash_map::RandomState;
 use std::error::Error as StdError;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::fs::{self,File};
+use std::fs::{self,File,OpenOptions};
 use std::io;
 use std::hash::BuildHasher;
+use std::mem;
 use std::path::{Path,PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use filetime::{FileTime, set_file_times};
+use crate::journal::{Changes, Journal, Record};
+use crate::lock::{DirLock, FileLock};
 pub use crate::lru_cache::{LruCache,Meter};
-use walkdir::WalkDir;
+use walkdir::{WalkDir, WalkDirIterator};
+
+/// The start of the names of the files in the cache directory that are used to share it between
+/// processes, rather than being stored in the cache.
+const METADATA_PREFIX: &str = ".lru-disk-cache";
+
+/// The directory in the cache directory that files are written to before they are renamed into
+/// place, so that the cache never holds an incomplete file.
+const TEMP_DIR: &str = ".lru-disk-cache.tmp";
+
+/// The extension of the lock files of the directories in `TEMP_DIR`.
+const WRITE_DIR_LOCK_EXTENSION: &str = "lock";
+
+/// A counter to give the `WriteDir` of every cache in this process a unique name.
+static NEXT_WRITE_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of uses of files in the cache that are kept in memory before they are recorded in
+/// the journal, if no other change records them first.
+const MAX_PENDING_ACCESSES: usize = 100;
+
+/// What the cache knows about a file in it.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    /// The size of the file.
+    size: u64,
+    /// The number of times the file was used since it was added.
+    uses: u64,
+    /// Where the file is in the eviction order of the `Lfu` and `Size` policies, before `seq`.
+    rank: u64,
+    /// When the file was added to the cache or last used, which orders files of the same rank.
+    seq: u64,
+}
+
+impl Entry {
+    fn new(size: u64) -> Entry {
+        Entry { size, uses: 0, rank: 0, seq: 0 }
+    }
+}
 
 struct FileSize;
 
-/// Given a tuple of (path, filesize), use the filesize for measurement.
-impl<K> Meter<K, u64> for FileSize {
+/// Given a tuple of (path, entry), use the file size for measurement.
+impl<K> Meter<K, Entry> for FileSize {
     type Measure = usize;
-    fn measure<Q: ?Sized>(&self, _: &Q, v: &u64) -> usize
+    fn measure<Q: ?Sized>(&self, _: &Q, v: &Entry) -> usize
         where K: Borrow<Q>
     {
-        *v as usize
+        v.size as usize
+    }
+}
+
+/// How an `LruDiskCache` chooses the files to evict when it is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the least-recently-used files first.
+    Lru,
+    /// Evict the least-frequently-used files first, and the least-recently-used of those first.
+    ///
+    /// Use counts are aged, so that files which were used a lot in the past but no longer are
+    /// evicted eventually: each time a file is evicted, its count becomes the count that files
+    /// start from when they are added or used. The age isn't shared with other processes, or
+    /// kept when the cache is reopened, but the use counts are.
+    Lfu,
+    /// Evict the largest files first, and the least-recently-used of those first, so that as
+    /// few files as possible are evicted.
+    Size,
+}
+
+/// When and how an `LruDiskCache` evicts files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EvictionConfig {
+    /// How to choose the files to evict.
+    pub policy: EvictionPolicy,
+    /// Start evicting files when adding one would take the cache above this percentage of its
+    /// size, or of `max_entries`.
+    pub high_watermark: u8,
+    /// Once eviction starts, evict files until the one being added fits within this percentage
+    /// of the cache's size, and of `max_entries`.
+    pub low_watermark: u8,
+    /// The maximum number of files in the cache, if it is limited.
+    pub max_entries: Option<u64>,
+}
+
+impl Default for EvictionConfig {
+    /// Evict as few least-recently-used files as possible, only once the cache is full.
+    fn default() -> EvictionConfig {
+        EvictionConfig {
+            policy: EvictionPolicy::Lru,
+            high_watermark: 100,
+            low_watermark: 100,
+            max_entries: None,
+        }
+    }
+}
+
+/// Counts of the files an `LruDiskCache` evicted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvictionStats {
+    /// The number of files evicted.
+    pub files: u64,
+    /// The total size of the files evicted.
+    pub bytes: u64,
+}
+
+/// A handle to the counts of the files an `LruDiskCache` evicted, which can be read and reset
+/// without access to the cache.
+#[derive(Clone, Debug, Default)]
+pub struct EvictionCounter {
+    stats: Arc<Mutex<EvictionStats>>,
+}
+
+impl EvictionCounter {
+    fn add(&self, size: u64) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.files += 1;
+        stats.bytes += size;
+    }
+
+    /// Return the counts of the files evicted since the cache was created, or since they were
+    /// last taken.
+    pub fn get(&self) -> EvictionStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Return the counts like `get`, and reset them.
+    pub fn take(&self) -> EvictionStats {
+        mem::replace(&mut *self.stats.lock().unwrap(), EvictionStats::default())
     }
 }
 
+/// Return `percent` percent of `n`, rounded down.
+fn percent_of(n: u64, percent: u8) -> u64 {
+    n / 100 * percent as u64 + n % 100 * percent as u64 / 100
+}
+
 /// Return an iterator of `(path, size)` of files under `path` sorted by ascending last-modified
 /// time, such that the oldest modified file is returned first.
 fn get_all_files<P: AsRef<Path>>(path: P) -> Box<dyn Iterator<Item=(PathBuf, u64)>> {
     let mut files: Vec<_> = WalkDir::new(path.as_ref())
         .into_iter()
+        // Skip the cache's own metadata and temporary files.
+        .filter_entry(|e| !e.file_name().to_string_lossy().starts_with(METADATA_PREFIX))
         .filter_map(|e| e.ok()
                     .and_then(|f| {
                         // Only look at files
@@ -55,10 +187,141 @@ fn get_all_files<P: AsRef<Path>>(path: P) -> Box<dyn Iterator<Item=(PathBuf, u64
     Box::new(files.into_iter().map(|(_mtime, path, size)| (path, size)))
 }
 
+/// The directory in `TEMP_DIR` that one cache writes files to before they are renamed into place.
+///
+/// The cache holds the lock on a file next to the directory for as long as it exists, so that
+/// the directories of caches in processes that died can be told apart from the ones in use.
+struct WriteDir {
+    path: PathBuf,
+    next_file: usize,
+    _lock: FileLock,
+}
+
+impl WriteDir {
+    /// Create a new `WriteDir` in the cache directory `root`.
+    fn create(root: &Path) -> io::Result<WriteDir> {
+        let temp_dir = root.join(TEMP_DIR);
+        fs::create_dir_all(&temp_dir)?;
+        loop {
+            let name = format!("{}-{}", process::id(), NEXT_WRITE_DIR.fetch_add(1, Ordering::Relaxed));
+            let path = temp_dir.join(name);
+            let lock_path = path.with_extension(WRITE_DIR_LOCK_EXTENSION);
+            let lock = FileLock::acquire(&lock_path)?;
+            // Another process may have removed the lock file in between creating and locking
+            // it, taking it for one left behind by a process with the same id that died.
+            if !lock_path.exists() {
+                continue;
+            }
+            // The directory may have been left behind by such a process too.
+            if let Err(e) = fs::remove_dir_all(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+            fs::create_dir(&path)?;
+            return Ok(WriteDir { path, next_file: 0, _lock: lock });
+        }
+    }
+
+    /// Return a new path for a file in the directory.
+    fn next_path(&mut self) -> PathBuf {
+        self.next_file += 1;
+        self.path.join(self.next_file.to_string())
+    }
+}
+
+impl Drop for WriteDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+        let _ = fs::remove_file(self.path.with_extension(WRITE_DIR_LOCK_EXTENSION));
+    }
+}
+
+/// Remove the files left in `TEMP_DIR` in the cache directory `root` by processes that died
+/// while writing them, returning how many were removed.
+///
+/// Those are the files in directories whose lock isn't held, and any files directly in
+/// `TEMP_DIR`, which older versions of this crate wrote.
+fn remove_temp_files(root: &Path) -> usize {
+    let temp_dir = root.join(TEMP_DIR);
+    let entries = match fs::read_dir(&temp_dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return 0,
+        Err(e) => {
+            error!("Error listing temporary files in {:?}: {}", root, e);
+            return 0;
+        }
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            // Directories are removed along with their lock files.
+            continue;
+        }
+        if path.extension() != Some(OsStr::new(WRITE_DIR_LOCK_EXTENSION)) {
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => error!("Error removing temporary file `{:?}`: {}", path, e),
+            }
+            continue;
+        }
+        let lock = match FileLock::try_acquire(&path) {
+            Ok(Some(lock)) => lock,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Error locking `{:?}`: {}", path, e);
+                continue;
+            }
+        };
+        let dir = path.with_extension("");
+        let files = WalkDir::new(&dir).into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .count();
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => removed += files,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                error!("Error removing temporary files in `{:?}`: {}", dir, e);
+                continue;
+            }
+        }
+        // Remove the lock file while holding the lock, so that no other process removes the
+        // file of a directory that was created since.
+        fs::remove_file(&path).unwrap_or_else(|e| error!("Error removing `{:?}`: {}", path, e));
+        drop(lock);
+    }
+    removed
+}
+
 /// An LRU cache of files on disk.
+///
+/// Several processes can share a cache directory: every change to the cache is made while
+/// holding an advisory lock on a file in the directory, and is recorded in a journal that the
+/// other processes replay the next time they take the lock. This keeps their view of which files
+/// are in the cache, in what order they were used and what their total size is the same. Uses of
+/// files are recorded along with the next change, or once enough of them pile up, so that reading
+/// files doesn't need the lock.
+///
+/// Files are written under a temporary name without holding the lock, and renamed into place
+/// once they are complete, so a process that dies while writing one never leaves an incomplete
+/// file in the cache.
 pub struct LruDiskCache<S: BuildHasher = RandomState> {
-    lru: LruCache<OsString, u64, S, FileSize>,
+    lru: LruCache<OsString, Entry, S, FileSize>,
+    /// The files in the cache by `(rank, seq)`, for the policies other than `Lru`.
+    eviction_order: BTreeMap<(u64, u64), OsString>,
+    /// The `seq` of the file that was last added or used.
+    seq: u64,
+    /// The use count that files start from under the `Lfu` policy.
+    lfu_age: u64,
     root: PathBuf,
+    journal: Journal,
+    write_dir: WriteDir,
+    /// The files used since the journal was last written to.
+    accesses: Vec<OsString>,
+    eviction: EvictionConfig,
+    evictions: EvictionCounter,
 }
 
 /// Errors returned by this crate.
@@ -122,73 +385,344 @@ enum AddFile<'a> {
 impl LruDiskCache {
     /// Create an `LruDiskCache` that stores files in `path`, limited to `size` bytes.
     ///
-    /// Existing files in `path` will be stored with their last-modified time from the filesystem
-    /// used as the order for the recency of their use. Any files that are individually larger
-    /// than `size` bytes will be removed.
+    /// The files already in the cache, and the order in which they were used, are loaded from
+    /// the journal in `path`. Existing files in `path` that aren't in the journal, which is all
+    /// of them if there is no journal or it is corrupt, are stored as the least-recently-used
+    /// files, with their last-modified time from the filesystem used as the order for the
+    /// recency of their use, and are added to the journal. Any files that are individually
+    /// larger than `size` bytes will be removed.
     ///
-    /// The cache is not observant of changes to files under `path` from external sources, it
-    /// expects to have sole maintence of the contents.
+    /// Other `LruDiskCache`s, in this or other processes, may use the same `path`, and should be
+    /// given the same `size`: a cache that finds the files stored by the others don't fit in its
+    /// own `size` removes some of them. Otherwise the cache is not observant of changes to files
+    /// under `path` from external sources, it expects to have sole maintenance of the contents.
     pub fn new<T>(path: T, size: u64) -> Result<Self>
         where PathBuf: From<T>
     {
+        LruDiskCache::with_eviction(path, size, EvictionConfig::default())
+    }
+
+    /// Create an `LruDiskCache` like `new`, which evicts files as described by `eviction`.
+    ///
+    /// Watermarks are capped at 100, and the low watermark at the high one.
+    pub fn with_eviction<T>(path: T, size: u64, mut eviction: EvictionConfig) -> Result<Self>
+        where PathBuf: From<T>
+    {
+        eviction.high_watermark = eviction.high_watermark.min(100).max(1);
+        eviction.low_watermark = eviction.low_watermark.min(eviction.high_watermark).max(1);
+        let root = PathBuf::from(path);
+        fs::create_dir_all(&root)?;
+        let orphans = remove_temp_files(&root);
         LruDiskCache {
             lru: LruCache::with_meter(size, FileSize),
-            root: PathBuf::from(path),
-        }.init()
+            eviction_order: BTreeMap::new(),
+            seq: 0,
+            lfu_age: 0,
+            journal: Journal::new(&root),
+            write_dir: WriteDir::create(&root)?,
+            root,
+            accesses: vec![],
+            eviction,
+            evictions: EvictionCounter::default(),
+        }.init(orphans)
     }
 
     /// Return the current size of all the files in the cache.
+    ///
+    /// This includes the changes made by other processes sharing the cache as of the last time
+    /// the cache was modified or refreshed.
     pub fn size(&self) -> u64 { self.lru.size() }
 
     /// Return the maximum size of the cache.
     pub fn capacity(&self) -> u64 { self.lru.capacity() }
 
+    /// Return the number of files in the cache.
+    pub fn len(&self) -> usize { self.lru.len() }
+
+    /// Return `true` if there are no files in the cache.
+    pub fn is_empty(&self) -> bool { self.lru.is_empty() }
+
+    /// Return the counts of the files this cache evicted since it was created, or since
+    /// `reset_eviction_stats` was called.
+    ///
+    /// Files evicted by other processes sharing the cache directory aren't counted.
+    pub fn eviction_stats(&self) -> EvictionStats { self.evictions.get() }
+
+    /// Reset the counts returned by `eviction_stats`.
+    pub fn reset_eviction_stats(&mut self) { self.evictions.take(); }
+
+    /// Return a handle to the counts returned by `eviction_stats`, which other threads can read
+    /// and reset while the cache is in use.
+    pub fn eviction_counter(&self) -> EvictionCounter { self.evictions.clone() }
+
     /// Return the path in which the cache is stored.
     pub fn path(&self) -> &Path { self.root.as_path() }
 
     /// Return the path that `key` would be stored at.
     fn rel_to_abs_path<K: AsRef<Path>>(&self, rel_path: K) -> PathBuf { self.root.join(rel_path) }
 
-    /// Scan `self.root` for existing files and store them.
-    fn init(mut self) -> Result<Self> {
-        fs::create_dir_all(&self.root)?;
-        for (file, size) in get_all_files(&self.root) {
-            if !self.can_store(size) {
-                fs::remove_file(file).unwrap_or_else(|e| error!("Error removing file `{}` which is too large for the cache ({} bytes)", e, size));
-            } else {
-                self.add_file(AddFile::AbsPath(file), size)
-                    .unwrap_or_else(|e| error!("Error adding file: {}", e));
+    /// Load the files in the cache from the journal in `self.root`, or from a scan of
+    /// `self.root` if the journal is missing or corrupt.
+    ///
+    /// Neither the scan nor a check of the files in the journal is done otherwise, since that
+    /// means reading the metadata of every file in the cache: `get_file` removes the files that
+    /// are missing or don't have the size recorded for them when they are used. `orphans` is
+    /// the number of files left incomplete by processes that died while writing them, which
+    /// were removed already.
+    fn init(mut self, orphans: usize) -> Result<Self> {
+        let mut lock = DirLock::acquire(&self.root)?;
+        let mut changes = vec![];
+        let mut scanned = None;
+        match self.journal.read()? {
+            Changes::Appended(records) | Changes::Rewritten(records) => {
+                self.replay(records, &mut changes)
+            }
+            Changes::Missing => {
+                // Scan without holding the lock, so that other processes aren't kept waiting
+                // for it.
+                drop(lock);
+                let found: Vec<_> = get_all_files(&self.root).collect();
+                lock = DirLock::acquire(&self.root)?;
+                match self.journal.read()? {
+                    // Another process rebuilt the journal in the meantime.
+                    Changes::Appended(records) | Changes::Rewritten(records) => {
+                        self.replay(records, &mut changes)
+                    }
+                    Changes::Missing => scanned = Some(self.add_found(found, &mut changes)),
+                }
+            }
+        }
+        match scanned {
+            Some(found) => {
+                info!("Loaded {} files ({} bytes) in {:?} from a scan, finding {} files; \
+                       removed {} incomplete files",
+                      self.lru.len(), self.lru.size(), self.root, found, orphans);
+                // Write a new journal, listing the files in the same order as this cache.
+                self.compact()?;
+                drop(lock);
+            }
+            None => {
+                info!("Loaded {} files ({} bytes) in {:?} from the journal; removed {} \
+                       incomplete files",
+                      self.lru.len(), self.lru.size(), self.root, orphans);
+                self.commit(lock, changes)?;
             }
         }
         Ok(self)
     }
 
+    /// Add the files in `found`, from a scan of `self.root` in order of their last-modified
+    /// time, to the cache, adding the files removed to make room for them to `changes` and
+    /// returning how many were found.
+    ///
+    /// This must be called while holding the lock, so that the files that were removed since
+    /// the scan are skipped.
+    fn add_found(&mut self, found: Vec<(PathBuf, u64)>, changes: &mut Vec<Record>) -> usize {
+        let found: Vec<_> = found.into_iter()
+            .filter_map(|(path, _)| fs::metadata(&path).ok().map(|m| (path, m.len())))
+            .collect();
+        let count = found.len();
+        for (path, size) in found {
+            if !self.can_store(size) {
+                fs::remove_file(&path).unwrap_or_else(|e| error!("Error removing file `{:?}` which is too large for the cache ({} bytes): {}", path, size, e));
+                continue;
+            }
+            self.add_file(AddFile::AbsPath(path.clone()), Entry::new(size), changes)
+                .unwrap_or_else(|e| error!("Error adding file `{:?}`: {}", path, e));
+        }
+        count
+    }
+
     /// Returns `true` if the disk cache can store a file of `size` bytes.
     pub fn can_store(&self, size: u64) -> bool {
-        size <= self.lru.capacity() as u64
+        size <= self.limits(self.eviction.high_watermark).0
+    }
+
+    /// Return the size and number of files that are `percent` percent of the cache's limits.
+    fn limits(&self, percent: u8) -> (u64, usize) {
+        let files = match self.eviction.max_entries {
+            Some(max_entries) => percent_of(max_entries, percent).max(1) as usize,
+            None => usize::max_value(),
+        };
+        (percent_of(self.lru.capacity(), percent), files)
+    }
+
+    /// Return `true` if adding a file of `size` bytes would take the cache over `limits`.
+    fn over(&self, size: u64, limits: (u64, usize)) -> bool {
+        self.lru.size() + size > limits.0 || self.lru.len() >= limits.1
+    }
+
+    /// If adding a file of `size` bytes would take the cache above its high watermark, evict
+    /// files until it fits within the low watermark, adding the files removed to `changes`.
+    fn make_room(&mut self, size: u64, changes: &mut Vec<Record>) {
+        if !self.over(size, self.limits(self.eviction.high_watermark)) {
+            return;
+        }
+        let low = self.limits(self.eviction.low_watermark);
+        //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
+        // A file that is larger than the low watermark only needs to fit within the high one,
+        // which `can_store` checks, so stop once the cache is empty.
+        while !self.lru.is_empty() && self.over(size, low) {
+            let (rel_path, entry) = if self.eviction.policy == EvictionPolicy::Lru {
+                self.lru.remove_lru().expect("Unexpectedly empty cache!")
+            } else {
+                let key = *self.eviction_order.keys().next().expect("Unexpectedly empty cache!");
+                let rel_path = self.eviction_order.remove(&key).unwrap();
+                match self.lru.remove(&rel_path) {
+                    Some(entry) => (rel_path, entry),
+                    // `LruCache::insert` evicted the file itself.
+                    None => continue,
+                }
+            };
+            if self.eviction.policy == EvictionPolicy::Lfu {
+                self.lfu_age = entry.rank;
+            }
+            self.evict(rel_path, entry.size, changes);
+        }
+    }
+
+    /// Insert `entry` for the file at `rel_path` into the cache as its most-recently-used file.
+    fn insert_entry(&mut self, rel_path: OsString, mut entry: Entry) {
+        self.seq += 1;
+        entry.seq = self.seq;
+        entry.rank = match self.eviction.policy {
+            EvictionPolicy::Lru => 0,
+            EvictionPolicy::Lfu => self.lfu_age.saturating_add(entry.uses),
+            EvictionPolicy::Size => u64::max_value() - entry.size,
+        };
+        if self.eviction.policy != EvictionPolicy::Lru {
+            self.eviction_order.insert((entry.rank, entry.seq), rel_path.clone());
+        }
+        self.lru.insert(rel_path, entry);
+    }
+
+    /// Remove the file at `rel_path` from the cache, without deleting it, returning its entry.
+    fn remove_entry(&mut self, rel_path: &OsStr) -> Option<Entry> {
+        let entry = self.lru.remove(rel_path)?;
+        self.eviction_order.remove(&(entry.rank, entry.seq));
+        Some(entry)
+    }
+
+    /// Remove all the files from the cache, without deleting them.
+    fn clear_entries(&mut self) {
+        self.lru.clear();
+        self.eviction_order.clear();
+    }
+
+    /// Delete the file at `rel_path` of `size` bytes, which was evicted from the cache, adding
+    /// its removal to `changes`.
+    fn evict(&mut self, rel_path: OsString, size: u64, changes: &mut Vec<Record>) {
+        let remove_path = self.rel_to_abs_path(&rel_path);
+        // Another process may have removed the file without recording it in the journal
+        // before it died, so this isn't fatal.
+        fs::remove_file(&remove_path).unwrap_or_else(|e| error!("Error removing file from cache: `{:?}`: {}", remove_path, e));
+        self.evictions.add(size);
+        changes.push(Record::Remove(rel_path));
     }
 
-    /// Add the file at `path` of size `size` to the cache.
-    fn add_file(&mut self, addfile_path: AddFile<'_>, size: u64) -> Result<()> {
-        if !self.can_store(size) {
+    /// Mark the file at `rel_path` as used, returning its size, or `None` if it isn't in the
+    /// cache.
+    fn touch(&mut self, rel_path: &OsStr) -> Option<u64> {
+        let mut entry = self.remove_entry(rel_path)?;
+        entry.uses += 1;
+        self.insert_entry(rel_path.to_owned(), entry);
+        Some(entry.size)
+    }
+
+    /// Add the file at `path` to the cache, adding the files removed to make room for it to
+    /// `changes`.
+    fn add_file(&mut self, addfile_path: AddFile<'_>, entry: Entry, changes: &mut Vec<Record>) -> Result<()> {
+        if !self.can_store(entry.size) {
             return Err(Error::FileTooLarge);
         }
         let rel_path = match addfile_path {
-            AddFile::AbsPath(ref p) => p.strip_prefix(&self.root).expect("Bad path?").as_os_str(),
-            AddFile::RelPath(p) => p,
+            AddFile::AbsPath(ref p) => p.strip_prefix(&self.root).expect("Bad path?").as_os_str().to_owned(),
+            AddFile::RelPath(p) => p.to_owned(),
         };
-        //TODO: ideally LRUCache::insert would give us back the entries it had to remove.
-        while self.lru.size() as u64 + size > self.lru.capacity() as u64 {
-            let (rel_path,  _) = self.lru.remove_lru().expect("Unexpectedly empty cache!");
-            let remove_path = self.rel_to_abs_path(rel_path);
-            //TODO: check that files are removable during `init`, so that this is only
-            // due to outside interference.
-            fs::remove_file(&remove_path).unwrap_or_else(|e| panic!("Error removing file from cache: `{:?}`: {}", remove_path, e));
-        }
-        self.lru.insert(rel_path.to_owned(), size);
+        // Don't count the file being replaced, or evict it to make room for itself.
+        self.remove_entry(&rel_path);
+        self.make_room(entry.size, changes);
+        self.insert_entry(rel_path, entry);
         Ok(())
     }
 
+    /// Take the lock of the cache directory, and apply the changes that other processes made to
+    /// the cache since it was last held.
+    ///
+    /// Returns the lock, which must be passed to `commit` along with the changes to record,
+    /// starting with the ones returned here.
+    fn lock(&mut self) -> Result<(DirLock, Vec<Record>)> {
+        let lock = DirLock::acquire(&self.root)?;
+        let mut changes = vec![];
+        match self.journal.read()? {
+            Changes::Appended(records) => self.replay(records, &mut changes),
+            Changes::Rewritten(records) => {
+                self.clear_entries();
+                self.replay(records, &mut changes);
+            }
+            // Keep what this cache knows, which will be written to a new journal by `commit`.
+            Changes::Missing => {}
+        }
+        Ok((lock, changes))
+    }
+
+    /// Apply `records` read from the journal, adding the files removed because they don't fit
+    /// in this cache to `changes`.
+    fn replay(&mut self, records: Vec<Record>, changes: &mut Vec<Record>) {
+        for record in records {
+            match record {
+                Record::Insert(rel_path, size, uses) => {
+                    let entry = Entry { uses, ..Entry::new(size) };
+                    if let Err(e) = self.add_file(AddFile::RelPath(&rel_path), entry, changes) {
+                        warn!("Removing file `{}` added by another process: {}", rel_path.to_string_lossy(), e);
+                        self.evict(rel_path, size, changes);
+                    }
+                }
+                Record::Access(rel_path) => { self.touch(&rel_path); }
+                Record::Remove(rel_path) => { self.remove_entry(&rel_path); }
+            }
+        }
+    }
+
+    /// Record the uses of files since the journal was last written to and `changes` in the
+    /// journal, and release the lock.
+    fn commit(&mut self, _lock: DirLock, changes: Vec<Record>) -> Result<()> {
+        self.record(changes)
+    }
+
+    /// Record the uses of files since the journal was last written to and `changes` in the
+    /// journal. This must be called while holding the lock.
+    fn record(&mut self, changes: Vec<Record>) -> Result<()> {
+        let mut records: Vec<_> = mem::replace(&mut self.accesses, vec![])
+            .into_iter()
+            .map(Record::Access)
+            .collect();
+        records.extend(changes);
+        if self.journal.needs_compaction(self.lru.len()) {
+            return self.compact();
+        }
+        if let Err(e) = self.journal.append(&records) {
+            warn!("Failed to append to the cache journal, rewriting it: {}", e);
+            return self.compact();
+        }
+        Ok(())
+    }
+
+    /// Replace the journal with a list of the files in the cache.
+    fn compact(&mut self) -> Result<()> {
+        let records: Vec<_> = self.lru.iter()
+            .map(|(rel_path, e)| Record::Insert(rel_path.clone(), e.size, e.uses))
+            .collect();
+        self.journal.rewrite(&records).map_err(Into::into)
+    }
+
+    /// Apply the changes made by other processes sharing the cache directory since the cache
+    /// was last modified or refreshed.
+    pub fn refresh(&mut self) -> Result<()> {
+        let (lock, changes) = self.lock()?;
+        self.commit(lock, changes)
+    }
+
     fn insert_by<K: AsRef<OsStr>, F: FnOnce(&Path) -> io::Result<()>>(&mut self, key: K, size: Option<u64>, by: F) -> Result<()> {
         if let Some(size) = size {
             if !self.can_store(size) {
@@ -197,18 +731,63 @@ impl LruDiskCache {
         }
         let rel_path = key.as_ref();
         let path = self.rel_to_abs_path(rel_path);
-        fs::create_dir_all(path.parent().expect("Bad path?"))?;
-        by(&path)?;
-        let size = match size {
-            Some(size) => size,
-            None => fs::metadata(path)?.len(),
+        // Write the file without holding the lock, which is only needed to move the complete
+        // file into place and record it in the journal, so that no other process evicts it in
+        // between.
+        let temp_path = self.write_dir.next_path();
+        // Flush the file to disk before it's renamed, so that the rename can't outlive its
+        // contents after a power loss. Windows only allows that through a writable handle.
+        let written = by(&temp_path)
+            .and_then(|_| OpenOptions::new().write(true).open(&temp_path))
+            .and_then(|file| file.sync_all().and_then(|_| file.metadata()))
+            .and_then(|m| fs::create_dir_all(path.parent().expect("Bad path?")).map(|_| m.len()));
+        let size = match written {
+            Ok(size) if self.can_store(size) => size,
+            Ok(_) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(Error::FileTooLarge);
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e.into());
+            }
         };
-        self.add_file(AddFile::RelPath(rel_path), size)
-            .or_else(|e| {
-                error!("Failed to insert file `{}`: {}", rel_path.to_string_lossy(), e);
-                fs::remove_file(&self.rel_to_abs_path(rel_path)).expect("Failed to remove file we just created!");
-                Err(e)
-            })
+        let (lock, mut changes) = match self.lock() {
+            Ok(locked) => locked,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        if let Err(e) = self.add_file(AddFile::RelPath(rel_path), Entry::new(size), &mut changes) {
+            error!("Failed to insert file `{}`: {}", rel_path.to_string_lossy(), e);
+            let _ = fs::remove_file(&temp_path);
+            self.commit(lock, changes)?;
+            return Err(e);
+        }
+        // Record the file in the journal before it's renamed into place, so that every file in
+        // the cache directory is in the journal. If this process dies in between, the file is
+        // removed from the cache when it's next used.
+        changes.push(Record::Insert(rel_path.to_owned(), size, 0));
+        if let Err(e) = self.record(changes) {
+            let _ = fs::remove_file(&temp_path);
+            self.remove_entry(rel_path);
+            return Err(e);
+        }
+        if let Err(e) = fs::rename(&temp_path, &path) {
+            let _ = fs::remove_file(&temp_path);
+            // Don't keep a file this one was meant to replace either, as the journal now has
+            // the wrong size for it.
+            self.remove_entry(rel_path);
+            if let Err(e) = fs::remove_file(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("Error removing file from cache: `{:?}`: {}", path, e);
+                }
+            }
+            self.commit(lock, vec![Record::Remove(rel_path.to_owned())])?;
+            return Err(e.into());
+        }
+        Ok(())
     }
 
     /// Add a file by calling `with` with the open `File` corresponding to the cache at path `key`.
@@ -240,23 +819,86 @@ impl LruDiskCache {
         })
     }
 
-    /// Return `true` if a file with path `key` is in the cache.
+    /// Return `true` if a file with path `key` is in the cache, as of the last time the cache
+    /// was modified or refreshed.
     pub fn contains_key<K: AsRef<OsStr>>(&self, key: K) -> bool {
         self.lru.contains_key(key.as_ref())
     }
 
     /// Get an opened `File` for `key`, if one exists and can be opened. Updates the LRU state
     /// of the file if present. Avoid using this method if at all possible, prefer `.get`.
+    ///
+    /// The cache is refreshed first if it doesn't know of the file, in case another process
+    /// added it. Otherwise the file is opened without taking the lock, and its use is recorded
+    /// in the journal later. A file that is missing or doesn't have the size it was added with
+    /// is removed from the cache.
     pub fn get_file<K: AsRef<OsStr>>(&mut self, key: K) -> Result<File> {
         let rel_path = key.as_ref();
         let path = self.rel_to_abs_path(rel_path);
-        self.lru.get(rel_path)
-            .ok_or(Error::FileNotInCache)
-            .and_then(|_| {
-                let t = filetime_now();
-                set_file_times(&path, t, t)?;
-                File::open(path).map_err(Into::into)
-            })
+        if !self.lru.contains_key(rel_path) {
+            self.refresh()?;
+        }
+        let size = match self.touch(rel_path) {
+            Some(size) => size,
+            None => return Err(Error::FileNotInCache),
+        };
+        let t = filetime_now();
+        let opened = set_file_times(&path, t, t)
+            .and_then(|_| File::open(&path))
+            .and_then(|file| file.metadata().map(|m| (file, m.len())));
+        match opened {
+            Ok((file, len)) if len == size => {
+                self.accesses.push(rel_path.to_owned());
+                if self.accesses.len() >= MAX_PENDING_ACCESSES {
+                    if let Err(e) = self.refresh() {
+                        warn!("Failed to record file uses in the cache journal: {}", e);
+                    }
+                }
+                Ok(file)
+            }
+            // Another process evicted or replaced the file since the cache was last refreshed,
+            // or the file was damaged.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.remove_if_invalid(rel_path)?;
+                Err(Error::FileNotInCache)
+            }
+            Ok(_) => {
+                if self.remove_if_invalid(rel_path)? {
+                    Err(Error::FileNotInCache)
+                } else {
+                    File::open(&path).map_err(Into::into)
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove the file at `rel_path` from the cache if it is missing or doesn't have the size
+    /// recorded for it, returning `true` if it isn't in the cache anymore.
+    fn remove_if_invalid(&mut self, rel_path: &OsStr) -> Result<bool> {
+        let (lock, mut changes) = self.lock()?;
+        let path = self.rel_to_abs_path(rel_path);
+        let removed = match self.remove_entry(rel_path) {
+            Some(entry) => match fs::metadata(&path) {
+                Ok(ref m) if m.len() == entry.size => {
+                    self.insert_entry(rel_path.to_owned(), entry);
+                    false
+                }
+                _ => {
+                    warn!("Removing `{}` from the cache, because it is missing or incomplete", rel_path.to_string_lossy());
+                    match fs::remove_file(&path) {
+                        Ok(()) => {}
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => error!("Error removing file from cache: `{:?}`: {}", path, e),
+                    }
+                    changes.push(Record::Remove(rel_path.to_owned()));
+                    true
+                }
+            },
+            None => true,
+        };
+        self.commit(lock, changes)?;
+        Ok(removed)
     }
 
     /// Get an opened readable and seekable handle to the file at `key`, if one exists and can
@@ -264,11 +906,64 @@ impl LruDiskCache {
     pub fn get<K: AsRef<OsStr>>(&mut self, key: K) -> Result<Box<dyn ReadSeek>> {
         self.get_file(key).map(|f| Box::new(f) as Box<dyn ReadSeek>)
     }
+
+    /// Get an opened readable and seekable handle to the file at `key`, if one exists and can
+    /// be opened, without updating the LRU state of the file.
+    pub fn peek<K: AsRef<OsStr>>(&self, key: K) -> Result<Box<dyn ReadSeek>> {
+        let rel_path = key.as_ref();
+        if !self.lru.contains_key(rel_path) {
+            return Err(Error::FileNotInCache);
+        }
+        let f = File::open(self.rel_to_abs_path(rel_path))?;
+        Ok(Box::new(f) as Box<dyn ReadSeek>)
+    }
+
+    /// Remove the file at `key` from the cache, deleting it from disk.
+    pub fn remove<K: AsRef<OsStr>>(&mut self, key: K) -> Result<()> {
+        let rel_path = key.as_ref();
+        let (lock, mut changes) = self.lock()?;
+        let result = match self.remove_entry(rel_path) {
+            Some(_) => {
+                changes.push(Record::Remove(rel_path.to_owned()));
+                fs::remove_file(self.rel_to_abs_path(rel_path)).map_err(Into::into)
+            }
+            None => Err(Error::FileNotInCache),
+        };
+        self.commit(lock, changes)?;
+        result
+    }
+
+    /// Return an iterator over the relative paths and sizes of the files in the cache, in
+    /// least- to most-recently-used order. Does not update the LRU state of any file.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, u64)> {
+        self.lru.iter().map(|(k, v)| (Path::new(k), v.size))
+    }
+}
+
+impl<S: BuildHasher> Drop for LruDiskCache<S> {
+    /// Record the uses of files that are still only kept in memory.
+    fn drop(&mut self) {
+        if self.accesses.is_empty() {
+            return;
+        }
+        let records: Vec<_> = self.accesses.drain(..).map(Record::Access).collect();
+        let result = DirLock::acquire(&self.root).and_then(|_lock| {
+            // Reading the journal removes any incomplete record at its end, which would
+            // otherwise swallow the first of these.
+            match self.journal.read()? {
+                Changes::Missing => Ok(()),
+                Changes::Appended(_) | Changes::Rewritten(_) => self.journal.append(&records),
+            }
+        });
+        if let Err(e) = result {
+            warn!("Failed to record file uses in the cache journal: {}", e);
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{LruDiskCache, Error};
+    use super::{EvictionConfig, EvictionPolicy, EvictionStats, LruDiskCache, Error};
 
     use filetime::{FileTime, set_file_times};
     use std::fs::{self,File};
@@ -315,6 +1010,14 @@ mod tests {
         pub fn create_file<T: AsRef<Path>>(&self, path: T, size: usize) -> PathBuf {
             create_file(self.tempdir.path(), path, |mut f| f.write_all(&vec![0; size])).unwrap()
         }
+
+        /// Return the number of temporary files in the cache, which doesn't count lock files.
+        pub fn temp_files(&self) -> usize {
+            walkdir::WalkDir::new(self.tmp().join(".lru-disk-cache.tmp")).into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file() && e.path().extension() != Some("lock".as_ref()))
+                .count()
+        }
     }
 
     #[test]
@@ -444,6 +1147,330 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_remove() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        assert_eq!(c.iter().map(|(p, s)| (p.to_owned(), s)).collect::<Vec<_>>(),
+                   vec![(PathBuf::from("file1"), 10), (PathBuf::from("file2"), 10)]);
+        c.remove("file1").unwrap();
+        assert_eq!(c.size(), 10);
+        assert!(!c.contains_key("file1"));
+        assert!(!f.tmp().join("file1").exists());
+        match c.remove("file1") {
+            Err(Error::FileNotInCache) => assert!(true),
+            x @ _ => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_peek() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.insert_bytes("file2", &vec![2; 10]).unwrap();
+        assert_eq!(read_all(&mut c.peek("file1").unwrap()).unwrap(), vec![1; 10]);
+        // Peeking doesn't update the LRU state, so file1 is still evicted first.
+        c.insert_bytes("file3", &vec![3; 10]).unwrap();
+        assert!(!c.contains_key("file1"));
+        assert!(c.contains_key("file2"));
+        match c.peek("file1") {
+            Err(Error::FileNotInCache) => assert!(true),
+            x @ _ => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_shared_directory() {
+        let f = TestFixture::new();
+        let mut c1 = LruDiskCache::new(f.tmp(), 25).unwrap();
+        let mut c2 = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c1.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c2.insert_bytes("file2", &vec![2; 10]).unwrap();
+        // Each cache sees the files added by the other once it refreshes.
+        assert_eq!(c2.size(), 20);
+        assert_eq!(c1.size(), 10);
+        c1.refresh().unwrap();
+        assert_eq!(c1.size(), 20);
+        assert_eq!(read_all(&mut c1.get("file2").unwrap()).unwrap(), vec![2u8; 10]);
+        // file1 is now the least-recently-used file for both caches, so it is evicted.
+        c2.insert_bytes("file3", &vec![3; 10]).unwrap();
+        assert!(!c2.contains_key("file1"));
+        assert!(!f.tmp().join("file1").exists());
+        c1.refresh().unwrap();
+        assert_eq!(c1.size(), 20);
+        assert!(!c1.contains_key("file1"));
+        assert!(c1.contains_key("file3"));
+        c1.remove("file2").unwrap();
+        c2.refresh().unwrap();
+        assert_eq!(c2.size(), 10);
+        assert_eq!(c2.iter().map(|(p, s)| (p.to_owned(), s)).collect::<Vec<_>>(),
+                   vec![(PathBuf::from("file3"), 10)]);
+        // A new cache sees the same files, and not the files used to share the directory.
+        let c3 = LruDiskCache::new(f.tmp(), 25).unwrap();
+        assert_eq!(c3.size(), 10);
+        c2.refresh().unwrap();
+        assert_eq!(c2.size(), 10);
+    }
+
+    #[test]
+    fn test_shared_directory_get() {
+        let f = TestFixture::new();
+        let mut c1 = LruDiskCache::new(f.tmp(), 25).unwrap();
+        let mut c2 = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c1.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c1.insert_bytes("file2", &vec![2; 10]).unwrap();
+        // A cache refreshes to look for files it doesn't know of.
+        assert_eq!(read_all(&mut c2.get("file1").unwrap()).unwrap(), vec![1u8; 10]);
+        assert_eq!(c2.size(), 20);
+        // Uses of files are only recorded in the journal along with the next change.
+        c1.refresh().unwrap();
+        c2.insert_bytes("file3", &vec![3; 10]).unwrap();
+        assert!(c2.contains_key("file1"));
+        assert!(!c2.contains_key("file2"));
+        c1.refresh().unwrap();
+        assert_eq!(c1.iter().map(|(p, _)| p.to_owned()).collect::<Vec<_>>(),
+                   vec![PathBuf::from("file1"), PathBuf::from("file3")]);
+        // A file that another process evicted is a miss.
+        c2.remove("file1").unwrap();
+        match c1.get("file1") {
+            Err(Error::FileNotInCache) => {}
+            x => panic!("Unexpected result: {:?}", x.map(|_| ())),
+        }
+        assert!(!c1.contains_key("file1"));
+    }
+
+    #[test]
+    fn test_journal_startup() {
+        let f = TestFixture::new();
+        {
+            let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+            c.insert_bytes("file1", &vec![1; 10]).unwrap();
+            c.insert_bytes("file2", &vec![2; 10]).unwrap();
+            c.get("file1").unwrap();
+        }
+        // The order of use comes from the journal rather than from mtimes.
+        set_mtime_back(f.tmp().join("file1"), 10);
+        {
+            let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+            assert_eq!(c.size(), 20);
+            c.insert_bytes("file3", &vec![3; 10]).unwrap();
+            assert!(c.contains_key("file1"));
+            assert!(!c.contains_key("file2"));
+        }
+        // Files that aren't in the journal are ignored while it is intact, so that opening the
+        // cache doesn't need to look at every file in it.
+        f.create_file("file4", 1);
+        set_mtime_back(f.tmp().join("file4"), 20);
+        {
+            let c = LruDiskCache::new(f.tmp(), 25).unwrap();
+            assert_eq!(c.size(), 20);
+            assert!(!c.contains_key("file4"));
+        }
+        // A corrupt journal is replaced by a scan of all the files, ordered by their mtimes.
+        fs::write(f.tmp().join(".lru-disk-cache.journal"), "garbage\n").unwrap();
+        {
+            let c = LruDiskCache::new(f.tmp(), 25).unwrap();
+            assert_eq!(c.size(), 21);
+            assert_eq!(c.iter().map(|(p, _)| p.to_owned()).collect::<Vec<_>>(),
+                       vec![PathBuf::from("file4"), PathBuf::from("file1"), PathBuf::from("file3")]);
+        }
+        // They were added to the new journal.
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        assert_eq!(c.size(), 21);
+        c.insert_bytes("file5", &vec![5; 5]).unwrap();
+        assert!(!c.contains_key("file4"));
+        assert!(c.contains_key("file1"));
+        c.insert_bytes("file6", &vec![6; 1]).unwrap();
+        assert!(!c.contains_key("file1"));
+        assert_eq!(c.size(), 16);
+        drop(c);
+        assert_eq!(LruDiskCache::new(f.tmp(), 25).unwrap().size(), 16);
+    }
+
+    fn eviction(policy: EvictionPolicy) -> EvictionConfig {
+        EvictionConfig { policy, ..EvictionConfig::default() }
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let f = TestFixture::new();
+        let config = EvictionConfig {
+            high_watermark: 80,
+            low_watermark: 50,
+            ..EvictionConfig::default()
+        };
+        let mut c = LruDiskCache::with_eviction(f.tmp(), 100, config).unwrap();
+        for i in 0..8 {
+            c.insert_bytes(format!("file{}", i), &vec![0; 10]).unwrap();
+        }
+        assert_eq!(c.size(), 80);
+        assert_eq!(c.eviction_stats(), EvictionStats::default());
+        // Going over the high watermark evicts files down to the low watermark.
+        c.insert_bytes("file8", &vec![0; 10]).unwrap();
+        assert_eq!(c.size(), 50);
+        assert_eq!(c.eviction_stats(), EvictionStats { files: 4, bytes: 40 });
+        assert!(!c.contains_key("file3"));
+        assert!(c.contains_key("file4"));
+        c.reset_eviction_stats();
+        assert_eq!(c.eviction_stats(), EvictionStats::default());
+        assert!(!c.can_store(81));
+        // A file larger than the low watermark evicts every other file.
+        c.insert_bytes("file9", &vec![0; 70]).unwrap();
+        assert_eq!(c.size(), 70);
+        assert_eq!(c.len(), 1);
+        assert_eq!(c.eviction_stats(), EvictionStats { files: 5, bytes: 50 });
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let f = TestFixture::new();
+        let config = EvictionConfig { max_entries: Some(2), ..EvictionConfig::default() };
+        let mut c = LruDiskCache::with_eviction(f.tmp(), 100, config).unwrap();
+        c.insert_bytes("file1", &vec![0; 1]).unwrap();
+        c.insert_bytes("file2", &vec![0; 1]).unwrap();
+        c.insert_bytes("file3", &vec![0; 1]).unwrap();
+        assert_eq!(c.len(), 2);
+        assert!(!c.contains_key("file1"));
+        // Replacing a file doesn't need room for another one.
+        c.insert_bytes("file2", &vec![0; 2]).unwrap();
+        assert_eq!(c.len(), 2);
+        assert_eq!(c.size(), 3);
+    }
+
+    #[test]
+    fn test_lfu() {
+        let f = TestFixture::new();
+        {
+            let mut c = LruDiskCache::with_eviction(f.tmp(), 30, eviction(EvictionPolicy::Lfu)).unwrap();
+            c.insert_bytes("file1", &vec![1; 10]).unwrap();
+            c.insert_bytes("file2", &vec![2; 10]).unwrap();
+            c.insert_bytes("file3", &vec![3; 10]).unwrap();
+            c.get("file1").unwrap();
+            c.get("file1").unwrap();
+            c.get("file2").unwrap();
+            c.get("file3").unwrap();
+            // file2 and file3 were used as often, and file2 less recently.
+            c.insert_bytes("file4", &vec![4; 10]).unwrap();
+            assert!(!c.contains_key("file2"));
+        }
+        // Use counts are kept in the journal.
+        let mut c = LruDiskCache::with_eviction(f.tmp(), 30, eviction(EvictionPolicy::Lfu)).unwrap();
+        c.get("file4").unwrap();
+        c.insert_bytes("file5", &vec![5; 10]).unwrap();
+        assert!(!c.contains_key("file3"));
+        assert!(c.contains_key("file1"));
+        assert!(c.contains_key("file4"));
+    }
+
+    #[test]
+    fn test_lfu_aging() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::with_eviction(f.tmp(), 30, eviction(EvictionPolicy::Lfu)).unwrap();
+        c.insert_bytes("file1", &vec![1; 10]).unwrap();
+        c.get("file1").unwrap();
+        c.get("file1").unwrap();
+        for i in 2..6 {
+            let name = format!("file{}", i);
+            c.insert_bytes(&name, &vec![i; 10]).unwrap();
+            c.get(&name).unwrap();
+        }
+        // file1 was used more often than any other file, but not since they were added, so the
+        // counts they started from caught up with it.
+        c.insert_bytes("file6", &vec![6; 10]).unwrap();
+        assert!(!c.contains_key("file1"));
+        assert!(c.contains_key("file4"));
+        assert!(c.contains_key("file5"));
+    }
+
+    #[test]
+    fn test_size_policy() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::with_eviction(f.tmp(), 30, eviction(EvictionPolicy::Size)).unwrap();
+        c.insert_bytes("file1", &vec![1; 5]).unwrap();
+        c.insert_bytes("file2", &vec![2; 15]).unwrap();
+        c.insert_bytes("file3", &vec![3; 5]).unwrap();
+        c.insert_bytes("file4", &vec![4; 5]).unwrap();
+        // Evicting the largest file makes enough room, even though it isn't the oldest.
+        c.insert_bytes("file5", &vec![5; 10]).unwrap();
+        assert_eq!(c.eviction_stats(), EvictionStats { files: 1, bytes: 15 });
+        assert!(!c.contains_key("file2"));
+        assert!(c.contains_key("file1"));
+    }
+
+    #[test]
+    fn test_failed_insert() {
+        let f = TestFixture::new();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        let result = c.insert_with("file1", |mut file| {
+            file.write_all(&[1; 5])?;
+            Err(io::Error::new(io::ErrorKind::Other, "interrupted"))
+        });
+        assert!(result.is_err());
+        assert!(!c.contains_key("file1"));
+        assert!(!f.tmp().join("file1").exists());
+        assert_eq!(f.temp_files(), 0);
+    }
+
+    #[test]
+    fn test_recovery() {
+        let f = TestFixture::new();
+        {
+            let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+            c.insert_bytes("file1", &vec![1; 5]).unwrap();
+            c.insert_bytes("file2", &vec![2; 5]).unwrap();
+            c.insert_bytes("file3", &vec![3; 5]).unwrap();
+        }
+        // Simulate processes that died while writing files, one of them running an older
+        // version of this crate, a file that was recorded in the journal by a process that died
+        // before renaming it into place, and a file that was damaged.
+        f.create_file(".lru-disk-cache.tmp/1234-0/1", 3);
+        f.create_file(".lru-disk-cache.tmp/1234-0.lock", 0);
+        f.create_file(".lru-disk-cache.tmp/1234.0", 3);
+        fs::remove_file(f.tmp().join("file1")).unwrap();
+        fs::write(f.tmp().join("file2"), &[2; 2]).unwrap();
+        let mut c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        // The damaged files are only found once they are used.
+        assert_eq!(c.len(), 3);
+        match c.get("file1") {
+            Err(Error::FileNotInCache) => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        match c.get("file2") {
+            Err(Error::FileNotInCache) => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert_eq!(c.iter().map(|(p, s)| (p.to_owned(), s)).collect::<Vec<_>>(),
+                   vec![(PathBuf::from("file3"), 5)]);
+        assert!(!f.tmp().join("file2").exists());
+        // Their removal is recorded in the journal.
+        drop(c);
+        assert_eq!(LruDiskCache::new(f.tmp(), 25).unwrap().size(), 5);
+        assert!(!f.tmp().join(".lru-disk-cache.tmp/1234-0").exists());
+        assert!(!f.tmp().join(".lru-disk-cache.tmp/1234-0.lock").exists());
+        assert!(!f.tmp().join(".lru-disk-cache.tmp/1234.0").exists());
+        // The files being written by caches that are still open are left alone.
+        let mut c2 = LruDiskCache::new(f.tmp(), 25).unwrap();
+        c2.insert_with("file4", |mut file| {
+            file.write_all(&[4; 5])?;
+            LruDiskCache::new(f.tmp(), 25).unwrap();
+            assert_eq!(f.temp_files(), 1);
+            Ok(())
+        }).unwrap();
+        assert_eq!(read_all(&mut c2.get("file4").unwrap()).unwrap(), vec![4; 5]);
+        assert_eq!(f.temp_files(), 0);
+        drop(c2);
+        // Temporary files are not mistaken for cache entries by a scan either.
+        f.create_file(".lru-disk-cache.tmp/1234-1/1", 3);
+        f.create_file(".lru-disk-cache.tmp/1234-1.lock", 0);
+        fs::remove_file(f.tmp().join(".lru-disk-cache.journal")).unwrap();
+        let c = LruDiskCache::new(f.tmp(), 25).unwrap();
+        assert_eq!(c.size(), 10);
+        assert!(!f.tmp().join(".lru-disk-cache.tmp/1234-1").exists());
+    }
+
     #[test]
     fn test_insert_file() {
         let f = TestFixture::new();
@@ -0,0 +1,170 @@
+//! Exclusive locks on the files of a cache directory, shared between processes.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// The name of the lock file in a cache directory.
+pub const LOCK_FILE: &str = ".lru-disk-cache.lock";
+
+/// An exclusive advisory lock on a file, which is released when it is dropped.
+///
+/// The operating system releases the lock when the process holding it exits, so a process that
+/// dies never leaves the lock held. The file itself is never removed, so every process locks the
+/// same file.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Take the lock on the file at `path`, creating it if needed, and waiting for the lock if
+    /// another process holds it.
+    pub fn acquire(path: &Path) -> io::Result<FileLock> {
+        let file = open(path)?;
+        sys::lock(&file, true)?;
+        Ok(FileLock { file })
+    }
+
+    /// Take the lock on the file at `path` if no other process holds it, without waiting.
+    pub fn try_acquire(path: &Path) -> io::Result<Option<FileLock>> {
+        let file = open(path)?;
+        match sys::lock(&file, false) {
+            Ok(()) => Ok(Some(FileLock { file })),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Closing the file would release the lock anyway.
+        let _ = sys::unlock(&self.file);
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).create(true).open(path)
+}
+
+/// The lock of a cache directory, which processes hold while they change the files in the
+/// cache and the journal.
+#[derive(Debug)]
+pub struct DirLock {
+    _lock: FileLock,
+}
+
+impl DirLock {
+    /// Take the lock of the cache directory `root`, waiting for it if another process holds it.
+    pub fn acquire(root: &Path) -> io::Result<DirLock> {
+        FileLock::acquire(&root.join(LOCK_FILE)).map(|lock| DirLock { _lock: lock })
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    /// Lock `file`, failing with `WouldBlock` rather than waiting if `wait` isn't set.
+    pub fn lock(file: &File, wait: bool) -> io::Result<()> {
+        let operation = if wait { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+        flock(file, operation)
+    }
+
+    pub fn unlock(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_UN)
+    }
+
+    fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::{LockFileEx, UnlockFile};
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY};
+
+    /// Lock `file`, failing with `WouldBlock` rather than waiting if `wait` isn't set.
+    pub fn lock(file: &File, wait: bool) -> io::Result<()> {
+        let mut flags = LOCKFILE_EXCLUSIVE_LOCK;
+        if !wait {
+            flags |= LOCKFILE_FAIL_IMMEDIATELY;
+        }
+        let ok = unsafe {
+            let mut overlapped = mem::zeroed();
+            LockFileEx(file.as_raw_handle() as _, flags, 0, !0, !0, &mut overlapped)
+        };
+        if ok != 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+            Err(io::ErrorKind::WouldBlock.into())
+        } else {
+            Err(e)
+        }
+    }
+
+    pub fn unlock(file: &File) -> io::Result<()> {
+        if unsafe { UnlockFile(file.as_raw_handle() as _, 0, 0, !0, !0) } != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_lock() {
+        let dir = TempDir::new("lru-disk-cache-test").unwrap();
+        let path = dir.path().join(LOCK_FILE);
+        let lock = DirLock::acquire(dir.path()).unwrap();
+        assert!(FileLock::try_acquire(&path).unwrap().is_none());
+
+        // Other holders wait until the lock is released.
+        let acquired = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let root = dir.path().to_owned();
+            let acquired = acquired.clone();
+            thread::spawn(move || {
+                let _lock = DirLock::acquire(&root).unwrap();
+                acquired.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!acquired.load(Ordering::SeqCst));
+        drop(lock);
+        waiter.join().unwrap();
+        assert!(acquired.load(Ordering::SeqCst));
+
+        // The lock file stays, so that every process locks the same file.
+        assert!(path.exists());
+        assert!(FileLock::try_acquire(&path).unwrap().is_some());
+    }
+}
@@ -14,22 +14,25 @@
 // limitations under the License.
 
 use crate::azure::credentials::*;
+use chrono::DateTime;
 use crypto::digest::Digest;
 use crypto::hmac::Hmac;
 use crypto::mac::Mac;
 use crypto::md5::Md5;
 use crypto::sha2::Sha256;
 use futures::{Future, Stream};
-use hyper::Method;
+use hyper::{Method, StatusCode};
 use hyper::header::HeaderValue;
 use hyperx::header;
+use regex::Regex;
 use url::Url;
 use reqwest::r#async::{Request, Client};
 use std::fmt;
 use std::str::FromStr;
+use std::time::SystemTime;
 
 use crate::errors::*;
-use crate::util::HeadersExt;
+use crate::util::{xml_unescape, HeadersExt};
 
 const BLOB_API_VERSION: &str = "2017-04-17";
 
@@ -97,10 +100,12 @@ impl BlobContainer {
         let uri_copy = uri.clone();
         let uri_second_copy = uri.clone();
 
-        let mut request = Request::new(Method::GET, uri);
+        let mut request = Request::new(Method::GET, with_sas(uri, creds));
         request.headers_mut().insert("x-ms-date", HeaderValue::from_str(&date).expect("Date is an invalid header value"));
         request.headers_mut().insert("x-ms-version", HeaderValue::from_static(BLOB_API_VERSION));
-        request.headers_mut().insert("Authorization", HeaderValue::from_str(&auth).expect("Authorization is an invalid header value"));
+        if let Some(auth) = auth {
+            request.headers_mut().insert("Authorization", HeaderValue::from_str(&auth).expect("Authorization is an invalid header value"));
+        }
 
         Box::new(self.client.execute(request).chain_err(move || {
             format!("failed GET: {}", uri_copy)
@@ -155,13 +160,15 @@ impl BlobContainer {
             &uri,
             creds);
 
-        let mut request = Request::new(Method::PUT, uri);
+        let mut request = Request::new(Method::PUT, with_sas(uri, creds));
         request.headers_mut().set(header::ContentType(content_type.parse().unwrap()));
         request.headers_mut().set(header::ContentLength(content.len() as u64));
         request.headers_mut().insert("x-ms-blob-type", HeaderValue::from_static("BlockBlob"));
         request.headers_mut().insert("x-ms-date", HeaderValue::from_str(&date).expect("Invalid x-ms-date header"));
         request.headers_mut().insert("x-ms-version", HeaderValue::from_static(BLOB_API_VERSION));
-        request.headers_mut().insert("Authorization", HeaderValue::from_str(&auth).expect("Invalid Authorization header"));
+        if let Some(auth) = auth {
+            request.headers_mut().insert("Authorization", HeaderValue::from_str(&auth).expect("Invalid Authorization header"));
+        }
         request.headers_mut().insert("Content-MD5", HeaderValue::from_str(&content_md5).expect("Invalid Content-MD5 header"));
 
         *request.body_mut() = Some(content.into());
@@ -184,11 +191,174 @@ impl BlobContainer {
             }
         }))
     }
+
+    pub fn exists(&self, key: &str, creds: &AzureCredentials) -> SFuture<bool> {
+        let url_string = format!("{}{}", self.url, key);
+        let uri = Url::from_str(&url_string).unwrap();
+        let request = self.signed_request(Method::HEAD, uri.clone(), creds);
+
+        Box::new(self.client.execute(request).chain_err(move || {
+            format!("failed HEAD: {}", uri)
+        }).and_then(|res| {
+            if res.status().is_success() {
+                Ok(true)
+            } else if res.status() == StatusCode::NOT_FOUND {
+                Ok(false)
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }))
+    }
+
+    pub fn delete(&self, key: &str, creds: &AzureCredentials) -> SFuture<()> {
+        let url_string = format!("{}{}", self.url, key);
+        let uri = Url::from_str(&url_string).unwrap();
+        let request = self.signed_request(Method::DELETE, uri.clone(), creds);
+
+        Box::new(self.client.execute(request).chain_err(move || {
+            format!("failed DELETE: {}", uri)
+        }).and_then(|res| {
+            if res.status().is_success() || res.status() == StatusCode::NOT_FOUND {
+                Ok(())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }))
+    }
+
+    /// List up to `max_results` blobs in the container whose names start with
+    /// `prefix`, starting at `marker`.
+    pub fn list(&self, prefix: &str, marker: Option<&str>, max_results: usize, creds: &AzureCredentials) -> SFuture<BlobListing> {
+        let mut uri = Url::from_str(self.url.trim_end_matches('/')).unwrap();
+        {
+            let mut query = uri.query_pairs_mut();
+            query.append_pair("restype", "container");
+            query.append_pair("comp", "list");
+            query.append_pair("maxresults", &max_results.to_string());
+            if !prefix.is_empty() {
+                query.append_pair("prefix", prefix);
+            }
+            if let Some(marker) = marker {
+                query.append_pair("marker", marker);
+            }
+        }
+        let request = self.signed_request(Method::GET, uri.clone(), creds);
+
+        Box::new(self.client.execute(request).chain_err(move || {
+            format!("failed GET: {}", uri)
+        }).and_then(|res| {
+            if res.status().is_success() {
+                Ok(res.into_body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(|body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, reqwest::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            })
+        }).and_then(|body| {
+            parse_blob_listing(&String::from_utf8_lossy(&body))
+        }))
+    }
+
+    /// Build a request with no body for `uri`, signed for `method`.
+    fn signed_request(&self, method: Method, uri: Url, creds: &AzureCredentials) -> Request {
+        let date = time::now_utc().rfc822().to_string();
+        let canonical_headers = format!("x-ms-date:{}\nx-ms-version:{}\n", date, BLOB_API_VERSION);
+
+        let auth = compute_auth_header(
+            method.as_str(),
+            "",    // content_length
+            "",    // content_md5
+            "",    // content_type
+            &canonical_headers,
+            &uri,
+            creds);
+
+        let mut request = Request::new(method, with_sas(uri, creds));
+        request.headers_mut().insert("x-ms-date", HeaderValue::from_str(&date).expect("Date is an invalid header value"));
+        request.headers_mut().insert("x-ms-version", HeaderValue::from_static(BLOB_API_VERSION));
+        if let Some(auth) = auth {
+            request.headers_mut().insert("Authorization", HeaderValue::from_str(&auth).expect("Authorization is an invalid header value"));
+        }
+        request
+    }
+}
+
+/// Add the shared access signature of `creds` to the query of `uri`, if they have one.
+///
+/// This is done only to the URL that is requested, so that the signature doesn't end up in
+/// error messages.
+fn with_sas(mut uri: Url, creds: &AzureCredentials) -> Url {
+    if let AzureAuth::SasToken(ref token) = *creds.azure_auth() {
+        let query = match uri.query() {
+            Some(query) if !query.is_empty() => format!("{}&{}", query, token),
+            _ => token.clone(),
+        };
+        uri.set_query(Some(&query));
+    }
+    uri
+}
+
+/// A blob in a container, as returned by `BlobContainer::list`.
+#[derive(Debug, PartialEq)]
+pub struct Blob {
+    pub name: String,
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// A page of blobs returned by `BlobContainer::list`.
+#[derive(Debug)]
+pub struct BlobListing {
+    pub blobs: Vec<Blob>,
+    /// The marker to pass to `BlobContainer::list` for the next page, if there are more blobs.
+    pub next_marker: Option<String>,
 }
 
+// https://docs.microsoft.com/en-us/rest/api/storageservices/list-blobs
+fn parse_blob_listing(body: &str) -> Result<BlobListing> {
+    lazy_static! {
+        static ref BLOB_REGEX: Regex = Regex::new(r"(?s)<Blob>(.*?)</Blob>").unwrap();
+        static ref NAME_REGEX: Regex = Regex::new(r"(?s)<Name>(.*?)</Name>").unwrap();
+        static ref LENGTH_REGEX: Regex = Regex::new(r"<Content-Length>(\d+)</Content-Length>").unwrap();
+        static ref LAST_MODIFIED_REGEX: Regex = Regex::new(r"<Last-Modified>(.*?)</Last-Modified>").unwrap();
+        static ref MARKER_REGEX: Regex = Regex::new(r"(?s)<NextMarker>(.+?)</NextMarker>").unwrap();
+    }
+
+    let mut blobs = vec![];
+    for blob in BLOB_REGEX.captures_iter(body) {
+        let blob = &blob[1];
+        let name = match NAME_REGEX.captures(blob) {
+            Some(caps) => xml_unescape(&caps[1]),
+            None => bail!("List Blobs response is missing a blob name"),
+        };
+        let size = match LENGTH_REGEX.captures(blob) {
+            Some(caps) => caps[1].parse().chain_err(|| "Invalid blob size in List Blobs response")?,
+            None => bail!("List Blobs response is missing a size for {}", name),
+        };
+        let last_modified = LAST_MODIFIED_REGEX.captures(blob)
+            .and_then(|caps| DateTime::parse_from_rfc2822(&caps[1]).ok())
+            .map(SystemTime::from);
+        blobs.push(Blob { name, size, last_modified });
+    }
+    let next_marker = MARKER_REGEX.captures(body).map(|caps| xml_unescape(&caps[1]));
+    Ok(BlobListing { blobs, next_marker })
+}
+
+/// Compute the `Authorization` header of a request, or `None` if it is authorized by a shared
+/// access signature instead.
 fn compute_auth_header(verb: &str, content_length: &str, md5: &str,
                        content_type: &str, canonical_headers: &str,
-                       uri: &Url, creds: &AzureCredentials) -> String {
+                       uri: &Url, creds: &AzureCredentials) -> Option<String> {
+    let account_key = match *creds.azure_auth() {
+        AzureAuth::SharedKey(ref key) => key,
+        AzureAuth::SasToken(_) => return None,
+    };
+
     /*
     Signature format taken from MSDN docs:
     https://docs.microsoft.com/en-us/azure/storage/common/storage-rest-api-auth
@@ -220,7 +390,7 @@ fn compute_auth_header(verb: &str, content_length: &str, md5: &str,
                 headers = canonical_headers,
                 resource = canonical_resource);
 
-    format!("SharedKey {}:{}", creds.azure_account_name(), signature(&string_to_sign, creds.azure_account_key()))
+    Some(format!("SharedKey {}:{}", creds.azure_account_name(), signature(&string_to_sign, account_key)))
 }
 
 fn canonicalize_resource(uri: &Url, account_name: &str) -> String {
@@ -229,7 +399,14 @@ fn canonicalize_resource(uri: &Url, account_name: &str) -> String {
     canonical_resource.push_str(account_name);
     canonical_resource.push_str(uri.path());
 
-    // Deliberately ignoring query params, because we aren't using them.
+    // Query parameters are appended as `name:value` lines, sorted by name.
+    let mut params = uri.query_pairs()
+        .map(|(name, value)| (name.to_lowercase(), value.into_owned()))
+        .collect::<Vec<_>>();
+    params.sort();
+    for (name, value) in params {
+        canonical_resource.push_str(&format!("\n{}:{}", name, value));
+    }
 
     canonical_resource
 }
@@ -259,6 +436,74 @@ mod test {
         let canon = canonicalize_resource(&url, "testaccount");
 
         assert_eq!("/testaccount/container/key", &canon);
+
+        let url = Url::from_str("https://testaccount.blob.core.windows.net/container?restype=container&comp=list").unwrap();
+        let canon = canonicalize_resource(&url, "testaccount");
+
+        assert_eq!("/testaccount/container\ncomp:list\nrestype:container", &canon);
+    }
+
+    #[test]
+    fn test_with_sas() {
+        let url = Url::from_str("https://testaccount.blob.core.windows.net/container/key").unwrap();
+        let creds = AzureCredentials::new("https://testaccount.blob.core.windows.net/", "",
+                                          AzureAuth::SasToken("?sv=2018-03-28&sig=abc%3D".to_owned()), None);
+        assert_eq!(with_sas(url.clone(), &creds).as_str(),
+                   "https://testaccount.blob.core.windows.net/container/key?sv=2018-03-28&sig=abc%3D");
+        assert_eq!(compute_auth_header("GET", "", "", "", "", &url, &creds), None);
+
+        let url = Url::from_str("https://testaccount.blob.core.windows.net/container?restype=container&comp=list").unwrap();
+        assert_eq!(with_sas(url, &creds).as_str(),
+                   "https://testaccount.blob.core.windows.net/container?restype=container&comp=list&sv=2018-03-28&sig=abc%3D");
+
+        let creds = AzureCredentials::new("https://testaccount.blob.core.windows.net/", "testaccount",
+                                          AzureAuth::SharedKey("a2V5".to_owned()), None);
+        let url = Url::from_str("https://testaccount.blob.core.windows.net/container/key").unwrap();
+        assert_eq!(with_sas(url.clone(), &creds), url);
+    }
+
+    #[test]
+    fn test_parse_blob_listing() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults ServiceEndpoint="https://testaccount.blob.core.windows.net/" ContainerName="container">
+  <MaxResults>2</MaxResults>
+  <Blobs>
+    <Blob>
+      <Name>abcdef</Name>
+      <Properties>
+        <Last-Modified>Fri, 01 Mar 2019 12:00:00 GMT</Last-Modified>
+        <Content-Length>1234</Content-Length>
+        <BlobType>BlockBlob</BlobType>
+      </Properties>
+    </Blob>
+    <Blob>
+      <Name>x&amp;y</Name>
+      <Properties>
+        <Content-Length>5</Content-Length>
+      </Properties>
+    </Blob>
+  </Blobs>
+  <NextMarker>2!76!MDAwMDE4IXNjY2FjaGU-</NextMarker>
+</EnumerationResults>"#;
+        let listing = parse_blob_listing(body).unwrap();
+        assert_eq!(listing.blobs, vec![
+            Blob {
+                name: "abcdef".to_owned(),
+                size: 1234,
+                last_modified: Some(SystemTime::from(
+                    DateTime::parse_from_rfc3339("2019-03-01T12:00:00Z").unwrap())),
+            },
+            Blob {
+                name: "x&y".to_owned(),
+                size: 5,
+                last_modified: None,
+            },
+        ]);
+        assert_eq!(listing.next_marker.as_ref().map(|s| s.as_str()), Some("2!76!MDAwMDE4IXNjY2FjaGU-"));
+
+        let listing = parse_blob_listing("<EnumerationResults><Blobs /><NextMarker /></EnumerationResults>").unwrap();
+        assert!(listing.blobs.is_empty());
+        assert_eq!(listing.next_marker, None);
     }
 
     #[test]
@@ -283,7 +528,7 @@ mod test {
         let client_key = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
 
         let container_name = Some("sccache".to_owned());
-        let creds = AzureCredentials::new(&blob_endpoint, &client_name, &client_key, container_name.clone());
+        let creds = AzureCredentials::new(&blob_endpoint, &client_name, AzureAuth::SharedKey(client_key.to_owned()), container_name.clone());
 
         let mut runtime = Runtime::new().unwrap();
 
@@ -13,20 +13,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::env::*;
+use crate::config::AzureCacheConfig;
 
 use crate::errors::*;
 
+/// How requests to the blob service are authorized.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AzureAuth {
+    /// Sign requests with the storage account's key.
+    SharedKey(String),
+    /// Add a shared access signature to the query of every request.
+    SasToken(String),
+}
+
 #[derive(Clone, Debug)]
 pub struct AzureCredentials {
     blob_endpoint: String,
     account_name: String,
-    account_key: String,
+    auth: AzureAuth,
     container_name: Option<String>,
 }
 
 impl AzureCredentials {
-    pub fn new(blob_endpoint: &str, account_name: &str, account_key: &str, container_name: Option<String>) -> AzureCredentials {
+    pub fn new(blob_endpoint: &str, account_name: &str, auth: AzureAuth, container_name: Option<String>) -> AzureCredentials {
 
         let endpoint = if blob_endpoint.ends_with("/") {
             blob_endpoint.to_owned()
@@ -34,10 +43,16 @@ impl AzureCredentials {
             blob_endpoint.to_owned() + "/"
         };
 
+        // Tokens are often copied from the Azure portal with a leading `?`.
+        let auth = match auth {
+            AzureAuth::SasToken(token) => AzureAuth::SasToken(token.trim_start_matches('?').to_owned()),
+            auth => auth,
+        };
+
         AzureCredentials {
             blob_endpoint: endpoint,
             account_name: account_name.to_owned(),
-            account_key: account_key.to_owned(),
+            auth: auth,
             container_name: container_name,
         }
     }
@@ -50,8 +65,8 @@ impl AzureCredentials {
         &self.account_name
     }
 
-    pub fn azure_account_key(&self) -> &str {
-        &self.account_key
+    pub fn azure_auth(&self) -> &AzureAuth {
+        &self.auth
     }
 
     pub fn blob_container_name(&self) -> &Option<String> {
@@ -63,39 +78,45 @@ pub trait AzureCredentialsProvider {
     fn provide_credentials(&self) -> Result<AzureCredentials>;
 }
 
-pub struct EnvironmentProvider;
+/// Provides the credentials described by an `AzureCacheConfig`, which is read from the config
+/// file or from the `SCCACHE_AZURE_*` environment variables.
+pub struct ConfigProvider<'a>(pub &'a AzureCacheConfig);
 
-impl AzureCredentialsProvider for EnvironmentProvider {
+impl<'a> AzureCredentialsProvider for ConfigProvider<'a> {
     fn provide_credentials(&self) -> Result<AzureCredentials> {
-        credentials_from_environment()
+        credentials_from_config(self.0)
     }
 }
 
-fn credentials_from_environment() -> Result<AzureCredentials> {
-    let env_conn_str = var("SCCACHE_AZURE_CONNECTION_STRING").chain_err(|| {
-        "No SCCACHE_AZURE_CONNECTION_STRING in environment"
-    })?;
-
-    let container_name = match var("SCCACHE_AZURE_BLOB_CONTAINER") {
-        Ok(text) => {
-            if text.is_empty() {
-                None
-            } else {
-                Some(text)
-            }
-        }
+fn credentials_from_config(config: &AzureCacheConfig) -> Result<AzureCredentials> {
+    let container_name = config.container.clone();
+    if let Some(ref conn) = config.connection_string {
+        return parse_connection_string(conn, container_name, config.sas_token.clone());
+    }
 
-        Err(_) => None
+    let account_name = match config.account {
+        Some(ref account) => account,
+        None => bail!("Azure cache needs either a connection string or an account"),
     };
-
-    parse_connection_string(&env_conn_str, container_name)
+    let token = match config.sas_token {
+        Some(ref token) => token,
+        None => bail!("Azure cache needs either a connection string or a SAS token for account {}", account_name),
+    };
+    let blob_endpoint = match config.endpoint {
+        Some(ref endpoint) => endpoint.clone(),
+        None => format!("https://{}.blob.core.windows.net/", account_name),
+    };
+    Ok(AzureCredentials::new(&blob_endpoint, account_name, AzureAuth::SasToken(token.clone()), container_name))
 }
 
-fn parse_connection_string(conn: &str, container_name: Option<String>) -> Result<AzureCredentials> {
+/// Parse an Azure Storage connection string. Requests are authorized with `sas_token` if it is
+/// set, and otherwise with the connection string's `AccountKey` or `SharedAccessSignature`.
+fn parse_connection_string(conn: &str, container_name: Option<String>, sas_token: Option<String>) -> Result<AzureCredentials> {
     let mut blob_endpoint = String::default();
     let mut default_endpoint_protocol: String = "https".to_owned();
     let mut account_name = String::default();
     let mut account_key = String::default();
+    let mut shared_access_signature = String::default();
     let mut endpoint_suffix = String::default();
 
     let split = conn.split(";");
@@ -120,6 +141,11 @@ fn parse_connection_string(conn: &str, container_name: Option<String>) -> Result
             continue;
         }
 
+        if part.starts_with("SharedAccessSignature=") {
+            shared_access_signature = substr(part, "SharedAccessSignature=".len()).to_owned();
+            continue;
+        }
+
         if part.starts_with("EndpointSuffix=") {
             endpoint_suffix = substr(part, "EndpointSuffix=".len()).to_owned();
         }
@@ -139,15 +165,22 @@ fn parse_connection_string(conn: &str, container_name: Option<String>) -> Result
         }
     }
 
-    if blob_endpoint.is_empty() || account_name.is_empty() || account_key.is_empty() {
-        bail!("Azure connection string missing at least one of BlobEndpoint (or DefaultEndpointProtocol and EndpointSuffix), AccountName, or AccountKey.");
-    }
+    // Requests authorized with a shared access signature don't need the account name.
+    let auth = if let Some(token) = sas_token {
+        AzureAuth::SasToken(token)
+    } else if !account_key.is_empty() && !account_name.is_empty() {
+        AzureAuth::SharedKey(account_key)
+    } else if !shared_access_signature.is_empty() {
+        AzureAuth::SasToken(shared_access_signature)
+    } else {
+        bail!("Azure connection string missing at least one of BlobEndpoint (or DefaultEndpointProtocol and EndpointSuffix), AccountName and AccountKey, or SharedAccessSignature.");
+    };
 
     if !blob_endpoint.starts_with("http") {
         blob_endpoint = format!("{}://{}", default_endpoint_protocol, blob_endpoint);
     }
 
-    Ok(AzureCredentials::new(&blob_endpoint, &account_name, &account_key, container_name))
+    Ok(AzureCredentials::new(&blob_endpoint, &account_name, auth, container_name))
 }
 
 fn substr(text: &str, to_skip: usize) -> &str {
@@ -165,20 +198,72 @@ mod test {
     fn test_parse_connection_string() {
         let conn = "DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==;BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1;";
 
-        let creds = parse_connection_string(&conn, None).unwrap();
+        let creds = parse_connection_string(&conn, None, None).unwrap();
         assert_eq!("http://127.0.0.1:10000/devstoreaccount1/", creds.azure_blob_endpoint());
         assert_eq!("devstoreaccount1", creds.azure_account_name());
-        assert_eq!("Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==", creds.azure_account_key());
+        assert_eq!(&AzureAuth::SharedKey("Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==".to_owned()), creds.azure_auth());
         assert_eq!(&None, creds.blob_container_name());
     }
 
     #[test]
     fn test_conn_str_with_endpoint_suffix_only() {
         let conn = "DefaultEndpointsProtocol=https;AccountName=foo;EndpointSuffix=core.windows.net;AccountKey=bar;";
-        let creds = parse_connection_string(&conn, None).unwrap();
+        let creds = parse_connection_string(&conn, None, None).unwrap();
 
         assert_eq!("https://foo.blob.core.windows.net/", creds.azure_blob_endpoint());
         assert_eq!("foo", creds.azure_account_name());
-        assert_eq!("bar", creds.azure_account_key());
+        assert_eq!(&AzureAuth::SharedKey("bar".to_owned()), creds.azure_auth());
+    }
+
+    #[test]
+    fn test_conn_str_with_sas() {
+        let conn = "BlobEndpoint=https://foo.blob.core.windows.net;SharedAccessSignature=sv=2018-03-28&sig=abc%3D";
+        let creds = parse_connection_string(&conn, None, None).unwrap();
+
+        assert_eq!("https://foo.blob.core.windows.net/", creds.azure_blob_endpoint());
+        assert_eq!(&AzureAuth::SasToken("sv=2018-03-28&sig=abc%3D".to_owned()), creds.azure_auth());
+
+        let conn = "BlobEndpoint=https://foo.blob.core.windows.net;";
+        assert!(parse_connection_string(&conn, None, None).is_err());
+    }
+
+    fn config() -> AzureCacheConfig {
+        AzureCacheConfig {
+            connection_string: None,
+            account: None,
+            endpoint: None,
+            container: None,
+            sas_token: None,
+            key_prefix: None,
+            read_only: false,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_credentials_from_config() {
+        let mut config = config();
+        assert!(credentials_from_config(&config).is_err());
+
+        config.account = Some("foo".to_owned());
+        config.container = Some("sccache".to_owned());
+        assert!(credentials_from_config(&config).is_err());
+
+        config.sas_token = Some("?sv=2018-03-28&sig=abc".to_owned());
+        let creds = credentials_from_config(&config).unwrap();
+        assert_eq!("https://foo.blob.core.windows.net/", creds.azure_blob_endpoint());
+        assert_eq!(&AzureAuth::SasToken("sv=2018-03-28&sig=abc".to_owned()), creds.azure_auth());
+        assert_eq!(&Some("sccache".to_owned()), creds.blob_container_name());
+
+        config.endpoint = Some("http://127.0.0.1:10000/devstoreaccount1".to_owned());
+        let creds = credentials_from_config(&config).unwrap();
+        assert_eq!("http://127.0.0.1:10000/devstoreaccount1/", creds.azure_blob_endpoint());
+
+        // A SAS token takes precedence over the connection string's account key.
+        config.connection_string = Some("AccountName=bar;AccountKey=key;EndpointSuffix=core.windows.net".to_owned());
+        let creds = credentials_from_config(&config).unwrap();
+        assert_eq!("https://bar.blob.core.windows.net/", creds.azure_blob_endpoint());
+        assert_eq!(&AzureAuth::SasToken("sv=2018-03-28&sig=abc".to_owned()), creds.azure_auth());
     }
 }
@@ -15,7 +15,8 @@
 
 use crate::azure::BlobContainer;
 use crate::azure::*;
-use crate::cache::{Cache, CacheRead, CacheWrite, Storage};
+use crate::cache::{Cache, CacheRead, CacheWrite, Storage, StorageEntry, StorageListing};
+use crate::config::AzureCacheConfig;
 use futures::future::Future;
 use std::io;
 use std::rc::Rc;
@@ -25,15 +26,17 @@ use crate::errors::*;
 
 pub struct AzureBlobCache {
     container: Rc<BlobContainer>,
-    credentials: AzureCredentials
+    credentials: AzureCredentials,
+    /// The prefix of the name of every blob stored by this cache.
+    key_prefix: String,
 }
 
 impl AzureBlobCache {
-    pub fn new() -> Result<AzureBlobCache> {
-        let credentials = match EnvironmentProvider.provide_credentials() {
-            Ok(creds) => creds,
-            Err(_) => bail!("Could not find Azure credentials in the environment")
-        };
+    /// Create a new `AzureBlobCache` for the account and container described by `config`,
+    /// storing entries under names starting with `key_prefix`.
+    pub fn new(config: &AzureCacheConfig, key_prefix: &str) -> Result<AzureBlobCache> {
+        let credentials = ConfigProvider(config).provide_credentials()
+            .chain_err(|| "Could not find Azure credentials")?;
 
         let container = match BlobContainer::new(credentials.azure_blob_endpoint(), credentials.blob_container_name()) {
             Ok(container) => container,
@@ -42,14 +45,20 @@ impl AzureBlobCache {
 
         Ok(AzureBlobCache {
             container: Rc::new(container),
-            credentials: credentials
+            credentials: credentials,
+            key_prefix: key_prefix.to_owned(),
         })
     }
+
+    /// The name of the blob that the entry for `key` is stored in.
+    fn blob_name(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
 }
 
 impl Storage for AzureBlobCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
-        Box::new(self.container.get(key, &self.credentials).then(|result| {
+        Box::new(self.container.get(&self.blob_name(key), &self.credentials).then(|result| {
             match result {
                 Ok(data) => {
                     let hit = CacheRead::from(io::Cursor::new(data))?;
@@ -70,7 +79,7 @@ impl Storage for AzureBlobCache {
             Err(e) => return f_err(e),
         };
 
-        let response = self.container.put(key, data, &self.credentials).chain_err(|| {
+        let response = self.container.put(&self.blob_name(key), data, &self.credentials).chain_err(|| {
             "Failed to put cache entry in Azure"
         });
 
@@ -83,4 +92,27 @@ impl Storage for AzureBlobCache {
 
     fn current_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
     fn max_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        self.container.delete(&self.blob_name(key), &self.credentials).chain_err(|| {
+            "Failed to delete cache entry from Azure"
+        })
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        self.container.exists(&self.blob_name(key), &self.credentials)
+    }
+
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        let listing = self.container.list(&self.key_prefix, continuation.as_ref().map(|s| s.as_str()), max_keys, &self.credentials);
+        let prefix_len = self.key_prefix.len();
+        Box::new(listing.map(move |listing| {
+            let entries = listing.blobs.into_iter().map(|blob| StorageEntry {
+                key: blob.name[prefix_len..].to_owned(),
+                size: blob.size,
+                last_modified: blob.last_modified,
+            }).collect();
+            StorageListing { entries, next: listing.next_marker }
+        }))
+    }
 }
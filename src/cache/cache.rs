@@ -14,25 +14,38 @@
 
 #[cfg(feature = "azure")]
 use crate::cache::azure::AzureBlobCache;
+use crate::cache::cas::CasStorage;
 use crate::cache::disk::DiskCache;
+use crate::cache::failover::FailoverStorage;
 #[cfg(feature = "gcs")]
-use crate::cache::gcs::{self, GCSCache, GCSCredentialProvider, RWMode, ServiceAccountInfo};
+use crate::cache::gcs::{GCSCache, GCSCredentialProvider, RWMode, ServiceAccountInfo};
 #[cfg(feature = "memcached")]
 use crate::cache::memcached::MemcachedCache;
+use crate::cache::mirror::MirrorStorage;
+use crate::cache::readonly::ReadOnlyStorage;
 #[cfg(feature = "redis")]
 use crate::cache::redis::RedisCache;
 #[cfg(feature = "s3")]
 use crate::cache::s3::S3Cache;
-use crate::config::{self, CacheType, Config};
+use crate::cache::tiered::TieredCache;
+use crate::cache::timeout::TimeoutStorage;
+#[cfg(feature = "webdav")]
+use crate::cache::webdav::WebdavCache;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::config::{self, CacheType, Config, DiskCacheConfig, DiskEvictionPolicy, FailoverConfig};
+use crate::util::Digest;
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
 use futures_cpupool::CpuPool;
+use lru_disk_cache::{EvictionConfig, EvictionPolicy};
+use std::collections::BTreeMap;
 use std::fmt;
-#[cfg(feature = "gcs")]
-use std::fs::File;
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::sync::Arc;
-use std::time::Duration;
-use zip::write::FileOptions;
-use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::ops::Add;
+use std::time::{Duration, SystemTime};
+use zip::ZipArchive;
 
 use crate::errors::*;
 
@@ -61,47 +74,383 @@ pub trait ReadSeek: Read + Seek + Send {}
 
 impl<T: Read + Seek + Send> ReadSeek for T {}
 
+/// The magic bytes at the start of a cache entry in the zstd format.
+const ENTRY_MAGIC: &[u8; 8] = b"SCCACHE\0";
+
+/// The version of the zstd cache entry format written by this version of sccache.
+const ENTRY_VERSION: u32 = 1;
+
+/// The zstd compression level used for new cache entries, which
+/// `storage_from_config` sets from the configuration.
+static ZSTD_LEVEL: AtomicUsize = AtomicUsize::new(config::DEFAULT_ZSTD_LEVEL as usize);
+
+/// The table of contents of a cache entry in the zstd format.
+///
+/// An entry starts with `ENTRY_MAGIC`, followed by `ENTRY_VERSION` and the
+/// length of the JSON-serialized table of contents as little-endian `u32`s,
+/// and then the table of contents itself. The objects come after that, each
+/// compressed as a separate zstd frame.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct EntryToc {
+    objects: Vec<EntryObject>,
+    /// Information about how the entry was created, if recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<CacheEntryMetadata>,
+}
+
+/// Information about how a cache entry was created.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheEntryMetadata {
+    /// When the entry was created, in seconds since the unix epoch.
+    pub created: u64,
+    /// The version of sccache that created the entry.
+    pub sccache_version: String,
+    /// The kind of compiler that produced the entry.
+    pub compiler_kind: String,
+    /// The path to the compiler executable.
+    pub compiler_path: String,
+    /// The input file that was compiled.
+    pub input: String,
+    /// The paths of the compiler outputs, by object name.
+    pub outputs: BTreeMap<String, String>,
+    /// How long the compile that produced the entry took.
+    pub compile_duration: Duration,
+    /// The host the entry was created on.
+    pub host: String,
+}
+
+/// An object in a cache entry, as described by `CacheRead::objects`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheObjectInfo {
+    /// The name of the object.
+    pub name: String,
+    /// The file mode the object was stored with, if any.
+    pub mode: Option<u32>,
+    /// The uncompressed size of the object.
+    pub size: u64,
+}
+
+/// A description of a cache entry, for `sccache --inspect-entry`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntryInfo {
+    /// Information about how the entry was created, if recorded.
+    pub metadata: Option<CacheEntryMetadata>,
+    /// The objects in the entry.
+    pub objects: Vec<CacheObjectInfo>,
+}
+
+/// An object in a cache entry in the zstd format.
+#[derive(Debug, Serialize, Deserialize)]
+struct EntryObject {
+    /// The name the object was stored under.
+    name: String,
+    /// The file mode the object was stored with, if any.
+    mode: Option<u32>,
+    /// The offset of the compressed object from the end of the table of contents.
+    offset: u64,
+    /// The compressed size of the object.
+    compressed_size: u64,
+    /// The uncompressed size of the object.
+    size: u64,
+    /// The SHA-512 digest of the uncompressed object, in hex.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+}
+
+/// A compressed object copied out of a cache entry in the zstd format, which
+/// can be added to another entry without decompressing it.
+pub struct RawObject {
+    /// The compressed object.
+    data: Vec<u8>,
+    /// The uncompressed size of the object.
+    size: u64,
+    /// The SHA-512 digest of the uncompressed object, in hex, if recorded.
+    digest: Option<String>,
+}
+
+impl RawObject {
+    /// The recorded digest of the uncompressed object, if any.
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_ref().map(|d| &d[..])
+    }
+}
+
+/// A reader that computes the digest of everything read through it.
+struct DigestReader<R> {
+    inner: R,
+    digest: Digest,
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A writer that computes the digest of everything written through it.
+struct DigestWriter<W> {
+    inner: W,
+    digest: Digest,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copy the object `name` from `from` to `to`, returning its size. Errors
+/// reading `from` mean the object is corrupt, unless the entry changed while
+/// it was being read, while errors writing to `to` are returned as they are.
+fn copy_object<R: Read, W: Write>(name: &str, from: &mut R, to: &mut W) -> Result<u64> {
+    let mut buf = [0; 8 * 1024];
+    let mut size = 0;
+    loop {
+        let n = from.read(&mut buf).map_err(|e| {
+            match e.get_ref().and_then(|inner| inner.downcast_ref::<EntryChanged>()) {
+                Some(&EntryChanged(ref key)) => ErrorKind::CacheEntryChanged(key.clone()).into(),
+                None => corrupt_entry(format!("failed to read `{}`: {}", name, e)),
+            }
+        })?;
+        if n == 0 {
+            return Ok(size);
+        }
+        to.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+}
+
+/// Make an error for a cache entry that fails integrity checks.
+fn corrupt_entry<S: Into<String>>(why: S) -> Error {
+    ErrorKind::CorruptCacheEntry(why.into()).into()
+}
+
+/// The format of a cache entry being read.
+enum CacheReadFormat {
+    /// An entry in the original zip format.
+    Zip(ZipArchive<Box<dyn ReadSeek>>),
+    /// An entry in the zstd format.
+    Zstd {
+        reader: Box<dyn ReadSeek>,
+        toc: EntryToc,
+        /// The offset in `reader` of the first object.
+        data_start: u64,
+    },
+}
+
 /// Data stored in the compiler cache.
 pub struct CacheRead {
-    zip: ZipArchive<Box<dyn ReadSeek>>,
+    format: CacheReadFormat,
 }
 
 impl CacheRead {
-    /// Create a cache entry from `reader`.
+    /// Create a cache entry from `reader`, which may hold an entry in either
+    /// the zstd or the zip format.
+    ///
+    /// Entries that can't be parsed produce `ErrorKind::CorruptCacheEntry`.
     pub fn from<R>(reader: R) -> Result<CacheRead>
     where
         R: ReadSeek + 'static,
     {
-        let z = ZipArchive::new(Box::new(reader) as Box<dyn ReadSeek>)
-            .chain_err(|| "Failed to parse cache entry")?;
-        Ok(CacheRead { zip: z })
+        let mut reader = Box::new(reader) as Box<dyn ReadSeek>;
+        let mut magic = [0; 8];
+        let is_zstd = match reader.read_exact(&mut magic) {
+            Ok(()) => magic == *ENTRY_MAGIC,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e.into()),
+        };
+        let format = if is_zstd {
+            let version = reader.read_u32::<LittleEndian>()
+                .map_err(|e| corrupt_entry(format!("failed to read version: {}", e)))?;
+            if version != ENTRY_VERSION {
+                bail!("Unsupported cache entry version {}", version);
+            }
+            let toc_len = reader.read_u32::<LittleEndian>()
+                .map_err(|e| corrupt_entry(format!("failed to read table of contents: {}", e)))?;
+            let toc = serde_json::from_reader(reader.by_ref().take(toc_len as u64))
+                .map_err(|e| corrupt_entry(format!("failed to parse table of contents: {}", e)))?;
+            let data_start = reader.seek(SeekFrom::Current(0))?;
+            CacheReadFormat::Zstd { reader, toc, data_start }
+        } else {
+            reader.seek(SeekFrom::Start(0))?;
+            let zip = ZipArchive::new(reader)
+                .map_err(|e| corrupt_entry(format!("failed to parse zip: {}", e)))?;
+            CacheReadFormat::Zip(zip)
+        };
+        Ok(CacheRead { format })
+    }
+
+    /// Consume this cache entry and return its raw serialized data.
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut reader = match self.format {
+            CacheReadFormat::Zip(zip) => zip.into_inner(),
+            CacheReadFormat::Zstd { reader, .. } => reader,
+        };
+        let mut data = vec![];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Return the metadata recorded in this cache entry, if any.
+    ///
+    /// Entries in the zip format never have metadata.
+    pub fn metadata(&self) -> Option<&CacheEntryMetadata> {
+        match self.format {
+            CacheReadFormat::Zip(_) => None,
+            CacheReadFormat::Zstd { ref toc, .. } => toc.metadata.as_ref(),
+        }
+    }
+
+    /// Describe the objects in this cache entry.
+    pub fn objects(&mut self) -> Result<Vec<CacheObjectInfo>> {
+        match self.format {
+            CacheReadFormat::Zip(ref mut zip) => {
+                let mut objects = vec![];
+                for i in 0..zip.len() {
+                    let file = zip.by_index(i).chain_err(|| "Failed to read object from cache entry")?;
+                    objects.push(CacheObjectInfo {
+                        name: file.name().to_owned(),
+                        mode: file.unix_mode(),
+                        size: file.size(),
+                    });
+                }
+                Ok(objects)
+            }
+            CacheReadFormat::Zstd { ref toc, .. } => {
+                Ok(toc.objects.iter().map(|o| CacheObjectInfo {
+                    name: o.name.clone(),
+                    mode: o.mode,
+                    size: o.size,
+                }).collect())
+            }
+        }
+    }
+
+    /// Return the names of the objects in this cache entry.
+    pub fn object_names(&mut self) -> Result<Vec<String>> {
+        Ok(self.objects()?.into_iter().map(|o| o.name).collect())
+    }
+
+    /// Describe this cache entry.
+    pub fn info(&mut self) -> Result<CacheEntryInfo> {
+        Ok(CacheEntryInfo {
+            metadata: self.metadata().cloned(),
+            objects: self.objects()?,
+        })
     }
 
     /// Get an object from this cache entry at `name` and write it to `to`.
     /// If the file has stored permissions, return them.
+    ///
+    /// The object is checked against its recorded size and digest (or the
+    /// zip CRC) as it is written to `to`, and `ErrorKind::CorruptCacheEntry`
+    /// is returned if it doesn't match. Whatever was written to `to` by then
+    /// must be discarded.
     pub fn get_object<T>(&mut self, name: &str, to: &mut T) -> Result<Option<u32>>
     where
         T: Write,
     {
-        let mut file = self
-            .zip
-            .by_name(name)
-            .chain_err(|| "Failed to read object from cache entry")?;
-        io::copy(&mut file, to)?;
-        Ok(file.unix_mode())
+        match self.format {
+            CacheReadFormat::Zip(ref mut zip) => {
+                let mut file = zip
+                    .by_name(name)
+                    .chain_err(|| "Failed to read object from cache entry")?;
+                // Reading the whole file checks its CRC.
+                copy_object(name, &mut file, to)?;
+                Ok(file.unix_mode())
+            }
+            CacheReadFormat::Zstd { ref mut reader, ref toc, data_start } => {
+                let object = toc.objects.iter()
+                    .find(|o| o.name == name)
+                    .ok_or_else(|| format!("Object `{}` not found in cache entry", name))?;
+                reader.seek(SeekFrom::Start(data_start + object.offset))?;
+                let mut decoder = zstd::stream::Decoder::new(reader.by_ref().take(object.compressed_size))
+                    .map_err(|e| corrupt_entry(format!("failed to decompress `{}`: {}", name, e)))?;
+                let mut to = DigestWriter { inner: to, digest: Digest::new() };
+                let size = copy_object(name, &mut decoder, &mut to)?;
+                if size != object.size {
+                    return Err(corrupt_entry(format!("`{}` has size {}, expected {}",
+                                                     name, size, object.size)));
+                }
+                if let Some(ref expected) = object.digest {
+                    if to.digest.finish() != *expected {
+                        return Err(corrupt_entry(format!("`{}` does not match its digest", name)));
+                    }
+                }
+                Ok(object.mode)
+            }
+        }
+    }
+
+    /// Copy the compressed object at `name` out of this cache entry, to be
+    /// added to another entry with `CacheWrite::put_raw_object`.
+    ///
+    /// The object is not decompressed, so it is only checked against its
+    /// digest when it is read from the entry it is added to. Entries in the
+    /// zip format don't support this.
+    pub fn get_raw_object(&mut self, name: &str) -> Result<RawObject> {
+        match self.format {
+            CacheReadFormat::Zip(_) => {
+                bail!("Cannot copy `{}` out of a cache entry in the zip format", name)
+            }
+            CacheReadFormat::Zstd { ref mut reader, ref toc, data_start } => {
+                let object = toc.objects.iter()
+                    .find(|o| o.name == name)
+                    .ok_or_else(|| format!("Object `{}` not found in cache entry", name))?;
+                reader.seek(SeekFrom::Start(data_start + object.offset))?;
+                let mut data = vec![];
+                reader.by_ref().take(object.compressed_size).read_to_end(&mut data)?;
+                if data.len() as u64 != object.compressed_size {
+                    return Err(corrupt_entry(format!("`{}` is truncated", name)));
+                }
+                Ok(RawObject { data, size: object.size, digest: object.digest.clone() })
+            }
+        }
     }
 }
 
+/// The state of a `CacheWrite`.
+enum CacheWriteData {
+    /// An entry that objects are still being added to.
+    Objects {
+        objects: Vec<EntryObject>,
+        /// The compressed objects.
+        data: Vec<u8>,
+        metadata: Option<CacheEntryMetadata>,
+    },
+    /// An entry that has already been serialized, e.g. one read from another cache.
+    Finished(Vec<u8>),
+}
+
 /// Data to be stored in the compiler cache.
 pub struct CacheWrite {
-    zip: ZipWriter<io::Cursor<Vec<u8>>>,
+    data: CacheWriteData,
 }
 
 impl CacheWrite {
     /// Create a new, empty cache entry.
     pub fn new() -> CacheWrite {
         CacheWrite {
-            zip: ZipWriter::new(io::Cursor::new(vec![])),
+            data: CacheWriteData::Objects { objects: vec![], data: vec![], metadata: None },
+        }
+    }
+
+    /// Create a cache entry from the already-serialized `data` of another entry.
+    ///
+    /// No further objects can be added to the returned entry.
+    pub fn from_bytes(data: Vec<u8>) -> CacheWrite {
+        CacheWrite {
+            data: CacheWriteData::Finished(data),
         }
     }
 
@@ -111,29 +460,104 @@ impl CacheWrite {
     where
         T: Read,
     {
-        let opts = FileOptions::default().compression_method(CompressionMethod::Deflated);
-        let opts = if let Some(mode) = mode {
-            opts.unix_permissions(mode)
-        } else {
-            opts
+        let (objects, data) = match self.data {
+            CacheWriteData::Objects { ref mut objects, ref mut data, .. } => (objects, data),
+            CacheWriteData::Finished(_) => bail!("Cannot add objects to a finished cache entry"),
         };
-        self.zip
-            .start_file(name, opts)
+        let offset = data.len() as u64;
+        let level = ZSTD_LEVEL.load(Ordering::Relaxed) as i32;
+        let mut encoder = zstd::stream::Encoder::new(&mut *data, level)
             .chain_err(|| "Failed to start cache entry object")?;
-        io::copy(from, &mut self.zip)?;
+        let mut from = DigestReader { inner: from, digest: Digest::new() };
+        let size = io::copy(&mut from, &mut encoder)?;
+        encoder.finish()?;
+        objects.push(EntryObject {
+            name: name.to_owned(),
+            mode,
+            offset,
+            compressed_size: data.len() as u64 - offset,
+            size,
+            digest: Some(from.digest.finish()),
+        });
+        Ok(())
+    }
+
+    /// Add `object`, copied out of another cache entry with
+    /// `CacheRead::get_raw_object`, to this cache entry at `name`.
+    /// If `mode` is `Some`, store the file entry with that mode.
+    pub fn put_raw_object(&mut self, name: &str, object: &RawObject, mode: Option<u32>) -> Result<()> {
+        let (objects, data) = match self.data {
+            CacheWriteData::Objects { ref mut objects, ref mut data, .. } => (objects, data),
+            CacheWriteData::Finished(_) => bail!("Cannot add objects to a finished cache entry"),
+        };
+        objects.push(EntryObject {
+            name: name.to_owned(),
+            mode,
+            offset: data.len() as u64,
+            compressed_size: object.data.len() as u64,
+            size: object.size,
+            digest: object.digest.clone(),
+        });
+        data.extend_from_slice(&object.data);
+        Ok(())
+    }
+
+    /// Record `metadata` describing how this cache entry was created.
+    pub fn set_metadata(&mut self, metadata: CacheEntryMetadata) -> Result<()> {
+        match self.data {
+            CacheWriteData::Objects { metadata: ref mut m, .. } => *m = Some(metadata),
+            CacheWriteData::Finished(_) => bail!("Cannot add metadata to a finished cache entry"),
+        }
         Ok(())
     }
 
     /// Finish writing data to the cache entry writer, and return the data.
     pub fn finish(self) -> Result<Vec<u8>> {
-        let CacheWrite { mut zip } = self;
-        let cur = zip
-            .finish()
-            .chain_err(|| "Failed to finish cache entry zip")?;
-        Ok(cur.into_inner())
+        match self.data {
+            CacheWriteData::Objects { objects, data, metadata } => {
+                let mut entry = entry_header(objects, metadata, data.len())?;
+                entry.extend_from_slice(&data);
+                Ok(entry)
+            }
+            CacheWriteData::Finished(data) => Ok(data),
+        }
+    }
+
+    /// Finish writing data to the cache entry writer, and return its size
+    /// and a reader for the data, which unlike `finish` doesn't copy the
+    /// objects into a new buffer.
+    pub fn into_reader(self) -> Result<(u64, Box<dyn Read + Send>)> {
+        match self.data {
+            CacheWriteData::Objects { objects, data, metadata } => {
+                let header = entry_header(objects, metadata, 0)?;
+                let size = (header.len() + data.len()) as u64;
+                let reader: Box<dyn Read + Send> =
+                    Box::new(io::Cursor::new(header).chain(io::Cursor::new(data)));
+                Ok((size, reader))
+            }
+            CacheWriteData::Finished(data) => {
+                let size = data.len() as u64;
+                let reader: Box<dyn Read + Send> = Box::new(io::Cursor::new(data));
+                Ok((size, reader))
+            }
+        }
     }
 }
 
+/// Serialize the start of a cache entry in the zstd format, up to its
+/// objects, leaving room for `capacity` more bytes.
+fn entry_header(objects: Vec<EntryObject>,
+                metadata: Option<CacheEntryMetadata>,
+                capacity: usize) -> Result<Vec<u8>> {
+    let toc = serde_json::to_vec(&EntryToc { objects, metadata })?;
+    let mut header = Vec::with_capacity(ENTRY_MAGIC.len() + 8 + toc.len() + capacity);
+    header.extend_from_slice(ENTRY_MAGIC);
+    header.write_u32::<LittleEndian>(ENTRY_VERSION)?;
+    header.write_u32::<LittleEndian>(toc.len() as u32)?;
+    header.extend_from_slice(&toc);
+    Ok(header)
+}
+
 /// An interface to cache storage.
 pub trait Storage {
     /// Get a cache entry by `key`.
@@ -145,6 +569,12 @@ pub trait Storage {
     /// return a `Cache::Hit`.
     fn get(&self, key: &str) -> SFuture<Cache>;
 
+    /// Get a cache entry by `key` like `get`, but without updating any usage
+    /// information the storage uses to decide what to evict.
+    fn peek(&self, key: &str) -> SFuture<Cache> {
+        self.get(key)
+    }
+
     /// Put `entry` in the cache under `key`.
     ///
     /// Returns a `Future` that will provide the result or error when the put is
@@ -159,124 +589,530 @@ pub trait Storage {
 
     /// Get the maximum storage size, if applicable.
     fn max_size(&self) -> SFuture<Option<u64>>;
+
+    /// Get statistics specific to this storage, as (name, value) pairs.
+    fn stats(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    /// Get the counts of entries this storage evicted to stay within its
+    /// limits since this was last called, and reset them.
+    ///
+    /// Storage that doesn't evict entries itself, or can't tell, has nothing
+    /// to report.
+    fn take_eviction_stats(&self) -> EvictionStats {
+        EvictionStats::default()
+    }
+
+    /// Remove the cache entry for `key`, if present.
+    ///
+    /// Removing an entry that does not exist is not an error. Storage that
+    /// cannot remove entries should return
+    /// `ErrorKind::UnsupportedStorageOperation`.
+    fn delete(&self, key: &str) -> SFuture<()>;
+
+    /// Check whether an entry for `key` exists, without fetching it.
+    fn exists(&self, key: &str) -> SFuture<bool>;
+
+    /// List up to `max_keys` entries in the cache.
+    ///
+    /// Pass `None` as `continuation` to start from the beginning, then the
+    /// `next` token of each returned `StorageListing` to fetch the following
+    /// page. Entries are not guaranteed to come back in any particular order.
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing>;
+
+    /// Remove data that no cache entry refers to any more, returning the
+    /// number of stored items removed.
+    ///
+    /// Storage that never keeps such data has nothing to do here.
+    fn collect_garbage(&self) -> SFuture<u64> {
+        f_ok(0)
+    }
 }
 
-/// Get a suitable `Storage` implementation from configuration.
-pub fn storage_from_config(config: &Config, pool: &CpuPool) -> Arc<dyn Storage> {
-    for cache_type in config.caches.iter() {
-        match *cache_type {
-            CacheType::Azure(config::AzureCacheConfig) => {
-                debug!("Trying Azure Blob Store account");
-                #[cfg(feature = "azure")]
-                match AzureBlobCache::new() {
-                    Ok(storage) => {
-                        trace!("Using AzureBlobCache");
-                        return Arc::new(storage);
-                    }
-                    Err(e) => warn!("Failed to create Azure cache: {:?}", e),
+/// Counts of the entries a `Storage` evicted, as returned by
+/// `Storage::take_eviction_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvictionStats {
+    /// The number of entries evicted.
+    pub entries: u64,
+    /// The total size of the entries evicted, in bytes.
+    pub bytes: u64,
+}
+
+impl Add for EvictionStats {
+    type Output = EvictionStats;
+
+    fn add(self, other: EvictionStats) -> EvictionStats {
+        EvictionStats {
+            entries: self.entries + other.entries,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+/// An entry in a `Storage`, as returned by `Storage::list`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StorageEntry {
+    /// The key the entry is stored under.
+    pub key: String,
+    /// The stored size of the entry, in bytes.
+    pub size: u64,
+    /// When the entry was last written, if the storage knows.
+    pub last_modified: Option<SystemTime>,
+}
+
+/// A page of entries returned by `Storage::list`.
+#[derive(Debug, Default)]
+pub struct StorageListing {
+    pub entries: Vec<StorageEntry>,
+    /// A token to pass to `Storage::list` to fetch the next page, or `None`
+    /// if this was the last page.
+    pub next: Option<String>,
+}
+
+/// The number of entries to list at a time when clearing a cache.
+const CLEAR_PAGE_SIZE: usize = 1000;
+
+/// The number of deletes to have in flight at once when clearing a cache.
+const CLEAR_CONCURRENCY: usize = 16;
+
+/// Remove every entry from `storage`, returning the number of entries removed.
+pub fn clear_storage(storage: Arc<dyn Storage>) -> SFuture<u64> {
+    Box::new(future::loop_fn((None, 0), move |(continuation, removed): (Option<String>, u64)| {
+        let storage = storage.clone();
+        storage.list(continuation, CLEAR_PAGE_SIZE).and_then(move |listing| {
+            let StorageListing { entries, next } = listing;
+            let count = entries.len() as u64;
+            stream::iter_ok::<_, Error>(entries)
+                .map(move |entry| storage.delete(&entry.key))
+                .buffer_unordered(CLEAR_CONCURRENCY)
+                .for_each(|()| Ok(()))
+                .map(move |()| match next {
+                    Some(next) => Loop::Continue((Some(next), removed + count)),
+                    None => Loop::Break(removed + count),
+                })
+        })
+    }))
+}
+
+/// Try to create the remote `Storage` implementation described by `cache_type`.
+#[cfg_attr(not(feature = "memcached"), allow(unused_variables))]
+fn remote_storage_from_cache_type(cache_type: &CacheType, pool: &CpuPool) -> Option<Arc<dyn Storage>> {
+    let key_prefix = cache_type.key_prefix();
+    match *cache_type {
+        CacheType::Azure(ref config) => {
+            debug!("Trying Azure Blob Store account({:?}, {:?})", config.account, config.container);
+            #[cfg(feature = "azure")]
+            match AzureBlobCache::new(config, key_prefix) {
+                Ok(storage) => {
+                    trace!("Using AzureBlobCache");
+                    return Some(Arc::new(storage));
                 }
+                Err(e) => warn!("Failed to create Azure cache: {:?}", e),
             }
-            CacheType::GCS(config::GCSCacheConfig {
-                ref bucket,
-                ref cred_path,
-                ref url,
-                rw_mode,
-            }) => {
-                debug!(
-                    "Trying GCS bucket({}, {:?}, {:?}, {:?})",
-                    bucket, cred_path, url, rw_mode
-                );
-                #[cfg(feature = "gcs")]
-                {
-                    let service_account_info_opt: Option<gcs::ServiceAccountInfo> =
-                        if let Some(ref cred_path) = *cred_path {
-                            // Attempt to read the service account key from file
-                            let service_account_key_res: Result<
-                                gcs::ServiceAccountKey,
-                            > = (|| {
-                                let mut file = File::open(&cred_path)?;
-                                let mut service_account_json = String::new();
-                                file.read_to_string(&mut service_account_json)?;
-                                Ok(serde_json::from_str(&service_account_json)?)
-                            })();
-
-                            // warn! if an error was encountered reading the key from the file
-                            if let Err(ref e) = service_account_key_res {
-                                warn!(
-                                    "Failed to parse service account credentials from file: {:?}. \
-                                     Continuing without authentication.",
-                                    e
-                                );
-                            }
-
-                            service_account_key_res.ok().map(|account_key| ServiceAccountInfo::AccountKey(account_key))
-                        } else if let Some(ref url) = *url {
-                            Some(ServiceAccountInfo::URL(url.clone()))
-                        } else {
-                            warn!(
-                            "No SCCACHE_GCS_KEY_PATH specified-- no authentication will be used."
-                        );
-                            None
-                        };
-
-                    let gcs_read_write_mode = match rw_mode {
-                        config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
-                        config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
-                    };
-
-                    let gcs_cred_provider = service_account_info_opt
-                        .map(|info| GCSCredentialProvider::new(gcs_read_write_mode, info));
-
-                    match GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode) {
-                        Ok(s) => {
-                            trace!("Using GCSCache");
-                            return Arc::new(s);
-                        }
-                        Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
+        }
+        CacheType::GCS(config::GCSCacheConfig {
+            ref bucket,
+            ref cred_path,
+            ref url,
+            rw_mode,
+            ..
+        }) => {
+            debug!(
+                "Trying GCS bucket({}, {:?}, {:?}, {:?})",
+                bucket, cred_path, url, rw_mode
+            );
+            #[cfg(feature = "gcs")]
+            {
+                let service_account_info_res = if let Some(ref cred_path) = *cred_path {
+                    ServiceAccountInfo::from_file(cred_path).map(Some)
+                } else if let Some(ref url) = *url {
+                    Ok(Some(ServiceAccountInfo::URL(url.clone())))
+                } else {
+                    ServiceAccountInfo::application_default()
+                };
+                let service_account_info_opt = match service_account_info_res {
+                    Ok(Some(info)) => Some(info),
+                    Ok(None) => {
+                        warn!("No GCS credentials found-- no authentication will be used.");
+                        None
                     }
-                }
-            }
-            CacheType::Memcached(config::MemcachedCacheConfig { ref url }) => {
-                debug!("Trying Memcached({})", url);
-                #[cfg(feature = "memcached")]
-                match MemcachedCache::new(&url, pool) {
+                    Err(e) => {
+                        warn!("Failed to read GCS credentials: {:?}. Continuing without authentication.", e);
+                        None
+                    }
+                };
+
+                let gcs_read_write_mode = match rw_mode {
+                    config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
+                    config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
+                };
+
+                let gcs_cred_provider = service_account_info_opt
+                    .map(|info| GCSCredentialProvider::new(gcs_read_write_mode, info));
+
+                match GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode, key_prefix) {
                     Ok(s) => {
-                        trace!("Using Memcached: {}", url);
-                        return Arc::new(s);
+                        trace!("Using GCSCache");
+                        return Some(Arc::new(s));
                     }
-                    Err(e) => warn!("Failed to create MemcachedCache: {:?}", e),
+                    Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
                 }
             }
-            CacheType::Redis(config::RedisCacheConfig { ref url }) => {
-                debug!("Trying Redis({})", url);
-                #[cfg(feature = "redis")]
-                match RedisCache::new(&url) {
-                    Ok(s) => {
-                        trace!("Using Redis: {}", url);
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create RedisCache: {:?}", e),
+        }
+        CacheType::Memcached(ref config) => {
+            debug!("Trying Memcached({})", config.url);
+            #[cfg(feature = "memcached")]
+            match MemcachedCache::new(&config.url, pool, key_prefix, config.ttl.unwrap_or(0)) {
+                Ok(s) => {
+                    trace!("Using Memcached: {}", config.url);
+                    return Some(Arc::new(s));
                 }
+                Err(e) => warn!("Failed to create MemcachedCache: {:?}", e),
             }
-            CacheType::S3(config::S3CacheConfig {
-                ref bucket,
-                ref endpoint,
-            }) => {
-                debug!("Trying S3Cache({}, {})", bucket, endpoint);
-                #[cfg(feature = "s3")]
-                match S3Cache::new(&bucket, &endpoint) {
-                    Ok(s) => {
-                        trace!("Using S3Cache");
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create S3Cache: {:?}", e),
+        }
+        CacheType::Redis(ref config) => {
+            debug!("Trying Redis({})", config.url);
+            #[cfg(feature = "redis")]
+            match RedisCache::new(config, key_prefix) {
+                Ok(s) => {
+                    trace!("Using Redis: {}", config.url);
+                    return Some(Arc::new(s));
+                }
+                Err(e) => warn!("Failed to create RedisCache: {:?}", e),
+            }
+        }
+        CacheType::S3(ref config) => {
+            debug!("Trying S3Cache({})", config.bucket);
+            #[cfg(feature = "s3")]
+            match S3Cache::new(config, pool) {
+                Ok(s) => {
+                    trace!("Using S3Cache");
+                    return Some(Arc::new(s));
+                }
+                Err(e) => warn!("Failed to create S3Cache: {:?}", e),
+            }
+        }
+        CacheType::Webdav(ref config) => {
+            debug!("Trying WebdavCache({})", config.endpoint);
+            #[cfg(feature = "webdav")]
+            match WebdavCache::new(config) {
+                Ok(s) => {
+                    trace!("Using WebdavCache");
+                    return Some(Arc::new(s));
                 }
+                Err(e) => warn!("Failed to create WebdavCache: {:?}", e),
+            }
+        }
+    }
+    None
+}
+
+/// Get a suitable `Storage` implementation from configuration.
+pub fn storage_from_config(config: &Config, pool: &CpuPool) -> Arc<dyn Storage> {
+    ZSTD_LEVEL.store(config.zstd_level() as usize, Ordering::Relaxed);
+    let storage = storage_from_cache_types(config, pool);
+    if config.cas {
+        trace!("Using CasStorage");
+        let gc_grace_period = Duration::from_secs(config.cas_gc_grace_period);
+        return Arc::new(CasStorage::new(storage, pool, gc_grace_period));
+    }
+    storage
+}
+
+/// Get the `Storage` for the usable caches in `config`, falling back to the
+/// local disk cache.
+///
+/// In mirror mode, every usable cache is written to, and lookups try them in
+/// the order of `config.caches`. Otherwise, without failover, only the first
+/// usable cache is used. With failover, each usable cache falls back to the
+/// next one, and the last one to the local disk cache, unless that is already
+/// in front of them all.
+fn storage_from_cache_types(config: &Config, pool: &CpuPool) -> Arc<dyn Storage> {
+    let mut remotes = config.caches.iter().filter_map(|cache_type| {
+        remote_storage_from_cache_type(cache_type, pool).map(|storage| {
+            let (read_timeout, write_timeout) = cache_type.timeouts();
+            let storage = with_timeouts(read_timeout, write_timeout, storage);
+            (cache_type.name(), read_only_if(cache_type.read_only(), storage))
+        })
+    });
+    if config.mirror {
+        // Each cache is skipped while it keeps failing, leaving the others.
+        let remotes: Vec<_> = remotes.map(|(name, storage)| {
+            let storage = if config.failover.failure_threshold == 0 {
+                storage
+            } else {
+                with_failover(&config.failover, vec![storage], None)
+            };
+            (name.as_str().to_owned(), storage)
+        }).collect();
+        if !remotes.is_empty() {
+            trace!("Using MirrorStorage");
+            let storage = Arc::new(MirrorStorage::new(remotes, pool));
+            return tiered_if(&config.fallback_cache, pool, storage);
+        }
+    } else if config.failover.failure_threshold == 0 {
+        if let Some((_, storage)) = remotes.next() {
+            return tiered_if(&config.fallback_cache, pool, storage);
+        }
+    } else {
+        let remotes: Vec<_> = remotes.map(|(_, storage)| storage).collect();
+        if !remotes.is_empty() {
+            if config.fallback_cache.tiered {
+                let storage = with_failover(&config.failover, remotes, None);
+                return tiered_if(&config.fallback_cache, pool, storage);
             }
+            let local = disk_storage(&config.fallback_cache, pool);
+            return with_failover(&config.failover, remotes, Some(local));
         }
     }
 
     info!("No configured caches successful, falling back to default");
-    let (dir, size) = (&config.fallback_cache.dir, config.fallback_cache.size);
-    trace!("Using DiskCache({:?}, {})", dir, size);
-    Arc::new(DiskCache::new(&dir, size, pool))
+    disk_storage(&config.fallback_cache, pool)
+}
+
+/// Wrap each of `remotes` in a `FailoverStorage` that falls back to the next
+/// one, and the last one to `fallback`.
+fn with_failover(config: &FailoverConfig,
+                 remotes: Vec<Arc<dyn Storage>>,
+                 fallback: Option<Arc<dyn Storage>>) -> Arc<dyn Storage> {
+    remotes.into_iter().rev().fold(fallback, |fallback, storage| {
+        trace!("Using FailoverStorage({})", storage.location());
+        let storage: Arc<dyn Storage> = Arc::new(FailoverStorage::new(
+            storage,
+            fallback,
+            config.failure_threshold,
+            Duration::from_secs(config.retry_interval),
+            Duration::from_secs(config.max_retry_interval)));
+        Some(storage)
+    }).expect("No caches to fail over between")
+}
+
+/// Put the local disk cache described by `config` in front of `remote` if
+/// it is tiered.
+fn tiered_if(config: &DiskCacheConfig, pool: &CpuPool, remote: Arc<dyn Storage>) -> Arc<dyn Storage> {
+    if config.tiered {
+        let local = disk_storage(config, pool);
+        trace!("Using TieredCache");
+        return Arc::new(TieredCache::new(local, remote));
+    }
+    remote
+}
+
+/// Get the local disk cache `Storage` described by `config`.
+fn disk_storage(config: &DiskCacheConfig, pool: &CpuPool) -> Arc<dyn Storage> {
+    trace!("Using DiskCache({:?}, {})", config.dir, config.size);
+    let eviction = EvictionConfig {
+        policy: match config.eviction_policy {
+            DiskEvictionPolicy::Lru => EvictionPolicy::Lru,
+            DiskEvictionPolicy::Lfu => EvictionPolicy::Lfu,
+            DiskEvictionPolicy::Size => EvictionPolicy::Size,
+        },
+        high_watermark: config.high_watermark,
+        low_watermark: config.low_watermark,
+        max_entries: config.max_entries,
+    };
+    let storage = Arc::new(DiskCache::with_eviction(&config.dir, config.size, eviction, pool));
+    let (read_timeout, write_timeout) = config.timeouts();
+    let storage = with_timeouts(read_timeout, write_timeout, storage);
+    read_only_if(config.read_only, storage)
+}
+
+/// Wrap `storage` in a `TimeoutStorage` if either timeout is set.
+fn with_timeouts(read_timeout: Option<Duration>,
+                 write_timeout: Option<Duration>,
+                 storage: Arc<dyn Storage>) -> Arc<dyn Storage> {
+    if read_timeout.is_none() && write_timeout.is_none() {
+        return storage;
+    }
+    trace!("Using TimeoutStorage({:?}, {:?})", read_timeout, write_timeout);
+    Arc::new(TimeoutStorage::new(storage, read_timeout, write_timeout))
+}
+
+/// Wrap `storage` in a `ReadOnlyStorage` if `read_only` is set.
+fn read_only_if(read_only: bool, storage: Arc<dyn Storage>) -> Arc<dyn Storage> {
+    if read_only {
+        trace!("Using ReadOnlyStorage");
+        return Arc::new(ReadOnlyStorage::new(storage));
+    }
+    storage
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    fn read_object(entry: &mut CacheRead, name: &str) -> (Vec<u8>, Option<u32>) {
+        let mut v = vec![];
+        let mode = entry.get_object(name, &mut v).unwrap();
+        (v, mode)
+    }
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object data"[..], Some(0o755)).unwrap();
+        entry.put_object("stdout", &mut &b""[..], None).unwrap();
+        let data = entry.finish().unwrap();
+        assert!(data.starts_with(ENTRY_MAGIC));
+
+        let mut entry = CacheRead::from(io::Cursor::new(data.clone())).unwrap();
+        assert_eq!(read_object(&mut entry, "obj"), (b"object data".to_vec(), Some(0o755)));
+        assert_eq!(read_object(&mut entry, "stdout"), (vec![], None));
+        assert!(entry.get_object("missing", &mut vec![]).is_err());
+        assert_eq!(entry.into_bytes().unwrap(), data);
+    }
+
+    fn is_corrupt<T>(result: Result<T>) -> bool {
+        match result {
+            Err(Error(ErrorKind::CorruptCacheEntry(_), _)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_corrupt_entry() {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object data"[..], None).unwrap();
+        let data = entry.finish().unwrap();
+
+        // Flip a bit in the compressed object.
+        let mut flipped = data.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 0x01;
+        let mut entry = CacheRead::from(io::Cursor::new(flipped)).unwrap();
+        assert!(is_corrupt(entry.get_object("obj", &mut vec![])));
+
+        // Change the recorded digest, so that the object decompresses fine
+        // but doesn't match it.
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object data"[..], None).unwrap();
+        if let CacheWriteData::Objects { ref mut objects, .. } = entry.data {
+            objects[0].digest = Some("0".repeat(128));
+        }
+        let mut entry = CacheRead::from(io::Cursor::new(entry.finish().unwrap())).unwrap();
+        assert!(is_corrupt(entry.get_object("obj", &mut vec![])));
+
+        // Truncate the entry.
+        let truncated = data[..data.len() - 4].to_vec();
+        let mut entry = CacheRead::from(io::Cursor::new(truncated)).unwrap();
+        assert!(is_corrupt(entry.get_object("obj", &mut vec![])));
+
+        // Truncate the table of contents.
+        assert!(is_corrupt(CacheRead::from(io::Cursor::new(data[..20].to_vec()))));
+
+        // Garbage is neither format.
+        assert!(is_corrupt(CacheRead::from(io::Cursor::new(b"not an entry".to_vec()))));
+    }
+
+    #[test]
+    fn test_entry_metadata() {
+        let metadata = CacheEntryMetadata {
+            created: 1234,
+            sccache_version: "0.0.0".to_owned(),
+            compiler_kind: "GCC".to_owned(),
+            compiler_path: "/usr/bin/gcc".to_owned(),
+            input: "foo.c".to_owned(),
+            outputs: vec![("obj".to_owned(), "foo.o".to_owned())].into_iter().collect(),
+            compile_duration: Duration::from_millis(1500),
+            host: "builder".to_owned(),
+        };
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object data"[..], Some(0o644)).unwrap();
+        entry.set_metadata(metadata.clone()).unwrap();
+        let mut entry = CacheRead::from(io::Cursor::new(entry.finish().unwrap())).unwrap();
+        let info = entry.info().unwrap();
+        assert_eq!(info.metadata, Some(metadata));
+        assert_eq!(info.objects, vec![CacheObjectInfo {
+            name: "obj".to_owned(),
+            mode: Some(0o644),
+            size: 11,
+        }]);
+    }
+
+    #[test]
+    fn test_read_zip_entry() {
+        let mut zip = ZipWriter::new(io::Cursor::new(vec![]));
+        let opts = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("obj", opts.unix_permissions(0o644)).unwrap();
+        zip.write_all(b"object data").unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        let mut entry = CacheRead::from(io::Cursor::new(data)).unwrap();
+        let (contents, mode) = read_object(&mut entry, "obj");
+        assert_eq!(contents, b"object data");
+        assert_eq!(mode.map(|m| m & 0o777), Some(0o644));
+        assert!(entry.metadata().is_none());
+        assert_eq!(entry.object_names().unwrap(), vec!["obj"]);
+    }
+
+    #[test]
+    fn test_unsupported_entry_version() {
+        let mut data = ENTRY_MAGIC.to_vec();
+        data.write_u32::<LittleEndian>(ENTRY_VERSION + 1).unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        assert!(CacheRead::from(io::Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn test_finished_entry() {
+        let mut entry = CacheWrite::from_bytes(b"data".to_vec());
+        assert!(entry.put_object("obj", &mut &b""[..], None).is_err());
+        assert_eq!(entry.finish().unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_entry_into_reader() {
+        let make_entry = || {
+            let mut entry = CacheWrite::new();
+            entry.put_object("obj", &mut &b"object data"[..], None).unwrap();
+            entry
+        };
+        let data = make_entry().finish().unwrap();
+        let (size, mut reader) = make_entry().into_reader().unwrap();
+        let mut v = vec![];
+        reader.read_to_end(&mut v).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(v, data);
+    }
+
+    /// A reader for an entry that is replaced after the first `limit` bytes
+    /// have been read.
+    struct ChangingReader {
+        inner: io::Cursor<Vec<u8>>,
+        limit: u64,
+    }
+
+    impl Read for ChangingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let pos = self.inner.position();
+            if pos >= self.limit {
+                return Err(io::Error::new(io::ErrorKind::Other, EntryChanged("key".to_owned())));
+            }
+            let max = std::cmp::min(buf.len() as u64, self.limit - pos) as usize;
+            self.inner.read(&mut buf[..max])
+        }
+    }
+
+    impl Seek for ChangingReader {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_entry_changed() {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"object data"[..], None).unwrap();
+        let data = entry.finish().unwrap();
+        let limit = data.len() as u64 - 4;
+        let mut entry = CacheRead::from(ChangingReader { inner: io::Cursor::new(data), limit }).unwrap();
+        match entry.get_object("obj", &mut vec![]) {
+            Err(Error(ErrorKind::CacheEntryChanged(ref key), _)) if key == "key" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
 }
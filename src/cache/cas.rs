@@ -0,0 +1,547 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    EvictionStats,
+    RawObject,
+    Storage,
+    StorageEntry,
+    StorageListing,
+};
+use crate::util::Digest;
+use futures::future::{self, Future, Loop};
+use futures::{stream, Stream};
+use futures_cpupool::CpuPool;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::errors::*;
+
+/// The name of the object holding the manifest of a content-addressed entry.
+const MANIFEST_OBJECT: &str = "sccache-cas-manifest";
+
+/// The name of the object holding the contents of a blob.
+const BLOB_OBJECT: &str = "blob";
+
+/// The suffix of the keys blobs are stored under.
+const BLOB_KEY_SUFFIX: &str = ".blob";
+
+/// The number of keys to request per page when collecting garbage.
+const GC_PAGE_SIZE: usize = 1000;
+
+/// The number of requests to have in flight at once when collecting garbage.
+const GC_CONCURRENCY: usize = 16;
+
+/// The contents of a content-addressed cache entry.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    objects: Vec<ManifestObject>,
+}
+
+/// An object in a content-addressed cache entry.
+#[derive(Serialize, Deserialize)]
+struct ManifestObject {
+    /// The name of the object in the cache entry.
+    name: String,
+    /// The unix mode of the object, if any.
+    mode: Option<u32>,
+    /// The digest of the object's contents, which names its blob.
+    digest: String,
+}
+
+/// Counters kept by a `CasStorage`.
+#[derive(Default)]
+struct CasStats {
+    /// Blobs written to storage.
+    blobs_stored: u64,
+    /// Blobs that were already in storage when writing an entry.
+    blobs_deduplicated: u64,
+    /// Lookups that found a manifest but not all of its blobs.
+    missing_blobs: u64,
+}
+
+/// A `Storage` that stores each distinct object only once.
+///
+/// Entries are written to the inner storage as a manifest listing the name,
+/// mode and content digest of each object, and the object contents are
+/// written alongside them as blobs keyed by digest. Entries without a
+/// manifest are returned as they are, so existing caches keep working.
+pub struct CasStorage {
+    /// The storage holding manifests and blobs.
+    inner: Arc<dyn Storage>,
+    /// Thread pool to split entries on.
+    pool: CpuPool,
+    /// Deduplication statistics.
+    stats: Rc<RefCell<CasStats>>,
+    /// How old an unreferenced blob must be for garbage collection to remove
+    /// it, since the manifest referring to it may not have been written yet.
+    gc_grace_period: Duration,
+    /// When garbage collection first found each unreferenced blob whose age
+    /// the storage can't report.
+    unreferenced_since: Rc<RefCell<HashMap<String, Instant>>>,
+}
+
+impl CasStorage {
+    /// Create a new `CasStorage` storing manifests and blobs in `inner`,
+    /// whose garbage collection keeps unreferenced blobs younger than
+    /// `gc_grace_period`.
+    pub fn new(inner: Arc<dyn Storage>, pool: &CpuPool, gc_grace_period: Duration) -> CasStorage {
+        CasStorage {
+            inner: inner,
+            pool: pool.clone(),
+            stats: Rc::new(RefCell::new(CasStats::default())),
+            gc_grace_period,
+            unreferenced_since: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+}
+
+/// Make the storage key for the blob with `digest`.
+fn blob_key(digest: &str) -> String {
+    format!("{}{}", digest, BLOB_KEY_SUFFIX)
+}
+
+/// Compute the digest of `data`.
+fn digest(data: &[u8]) -> String {
+    let mut digest = Digest::new();
+    digest.update(data);
+    digest.finish()
+}
+
+/// Read the manifest from `entry`, if it is a content-addressed entry.
+fn read_manifest(entry: &mut CacheRead) -> Result<Option<Manifest>> {
+    if !entry.object_names()?.iter().any(|name| name == MANIFEST_OBJECT) {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    entry.get_object(MANIFEST_OBJECT, &mut data)?;
+    let manifest = serde_json::from_slice(&data)
+        .chain_err(|| "Failed to parse content-addressed cache entry manifest")?;
+    Ok(Some(manifest))
+}
+
+impl CasStorage {
+    /// Look up `key`, reassembling content-addressed entries from their
+    /// blobs. Reads use `Storage::peek` rather than `Storage::get` unless
+    /// `touch` is set.
+    ///
+    /// Blobs are copied into the entry still compressed, and are checked
+    /// against their digests when the entry is read.
+    fn lookup(&self, key: &str, touch: bool) -> SFuture<Cache> {
+        let inner = self.inner.clone();
+        let stats = self.stats.clone();
+        let key = key.to_owned();
+        let read = move |inner: &Arc<dyn Storage>, key: &str| {
+            if touch { inner.get(key) } else { inner.peek(key) }
+        };
+        Box::new(read(&self.inner, &key).and_then(move |result| -> SFuture<Cache> {
+            let mut entry = match result {
+                Cache::Hit(entry) => entry,
+                other => return f_ok(other),
+            };
+            let manifest = match ftry!(read_manifest(&mut entry)) {
+                Some(manifest) => manifest,
+                None => return f_ok(Cache::Hit(entry)),
+            };
+            let metadata = entry.metadata().cloned();
+            let digests = manifest.objects.iter()
+                .map(|object| object.digest.clone())
+                .collect::<HashSet<_>>();
+            let fetches = digests.into_iter().map(|digest| {
+                read(&inner, &blob_key(&digest)).and_then(move |result| -> Result<Option<(String, RawObject)>> {
+                    let mut blob = match result {
+                        Cache::Hit(blob) => blob,
+                        _ => return Ok(None),
+                    };
+                    let object = blob.get_raw_object(BLOB_OBJECT)?;
+                    if object.digest() != Some(&digest[..]) {
+                        warn!("CasStorage: blob {} does not match its digest", digest);
+                        return Ok(None);
+                    }
+                    Ok(Some((digest, object)))
+                })
+            }).collect::<Vec<_>>();
+            Box::new(future::join_all(fetches).and_then(move |blobs| -> Result<Cache> {
+                let mut contents = HashMap::new();
+                for blob in blobs {
+                    match blob {
+                        Some((digest, object)) => {
+                            contents.insert(digest, object);
+                        }
+                        None => {
+                            debug!("CasStorage: blobs missing for {}", key);
+                            stats.borrow_mut().missing_blobs += 1;
+                            return Ok(Cache::Miss);
+                        }
+                    }
+                }
+                let mut entry = CacheWrite::new();
+                for object in manifest.objects {
+                    entry.put_raw_object(&object.name, &contents[&object.digest], object.mode)?;
+                }
+                if let Some(metadata) = metadata {
+                    entry.set_metadata(metadata)?;
+                }
+                Ok(Cache::Hit(CacheRead::from(Cursor::new(entry.finish()?))?))
+            }))
+        }))
+    }
+}
+
+impl Storage for CasStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.lookup(key, true)
+    }
+
+    fn peek(&self, key: &str) -> SFuture<Cache> {
+        self.lookup(key, false)
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let inner = self.inner.clone();
+        let pool = self.pool.clone();
+        let stats = self.stats.clone();
+        let key = key.to_owned();
+        // Split the entry into its objects off the event loop.
+        let split = self.pool.spawn_fn(move || -> Result<_> {
+            let mut entry = CacheRead::from(Cursor::new(entry.finish()?))?;
+            let metadata = entry.metadata().cloned();
+            let mut objects = vec![];
+            for name in entry.object_names()? {
+                let mut data = vec![];
+                let mode = entry.get_object(&name, &mut data)?;
+                let digest = digest(&data);
+                objects.push((ManifestObject { name, mode, digest }, data));
+            }
+            Ok((objects, metadata))
+        });
+        Box::new(split.and_then(move |(objects, metadata)| {
+            let mut blobs = HashMap::new();
+            let objects = objects.into_iter()
+                .map(|(object, data)| {
+                    blobs.insert(object.digest.clone(), data);
+                    object
+                })
+                .collect();
+            let manifest = Manifest { objects };
+            // Store any blobs that aren't already present before the
+            // manifest, so that a manifest never refers to missing blobs.
+            let uploads = blobs.into_iter().map(|(digest, data)| {
+                let inner = inner.clone();
+                let pool = pool.clone();
+                let stats = stats.clone();
+                let key = blob_key(&digest);
+                inner.exists(&key).or_else(|_| Ok::<_, Error>(false)).and_then(move |exists| -> SFuture<()> {
+                    if exists {
+                        stats.borrow_mut().blobs_deduplicated += 1;
+                        return f_ok(());
+                    }
+                    let blob = pool.spawn_fn(move || -> Result<_> {
+                        let mut blob = CacheWrite::new();
+                        blob.put_object(BLOB_OBJECT, &mut &data[..], None)?;
+                        Ok(blob)
+                    });
+                    Box::new(blob.and_then(move |blob| inner.put(&key, blob)).map(move |_| {
+                        stats.borrow_mut().blobs_stored += 1;
+                    }))
+                })
+            }).collect::<Vec<_>>();
+            future::join_all(uploads).and_then(move |_| -> SFuture<Duration> {
+                let data = ftry!(serde_json::to_vec(&manifest));
+                let mut entry = CacheWrite::new();
+                ftry!(entry.put_object(MANIFEST_OBJECT, &mut &data[..], None));
+                if let Some(metadata) = metadata {
+                    ftry!(entry.set_metadata(metadata));
+                }
+                Box::new(inner.put(&key, entry).map(move |_| start.elapsed()))
+            })
+        }))
+    }
+
+    fn location(&self) -> String {
+        format!("Content-addressed: {}", self.inner.location())
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+
+    fn stats(&self) -> Vec<(String, String)> {
+        let stats = self.stats.borrow();
+        let mut v = vec![
+            ("CAS blobs stored".to_owned(), stats.blobs_stored.to_string()),
+            ("CAS blobs deduplicated".to_owned(), stats.blobs_deduplicated.to_string()),
+            ("CAS lookups with missing blobs".to_owned(), stats.missing_blobs.to_string()),
+        ];
+        v.extend(self.inner.stats());
+        v
+    }
+
+    fn take_eviction_stats(&self) -> EvictionStats {
+        self.inner.take_eviction_stats()
+    }
+
+    /// Remove the manifest for `key`. Its blobs may be shared with other
+    /// entries, and are left for `collect_garbage` to remove.
+    fn delete(&self, key: &str) -> SFuture<()> {
+        self.inner.delete(key)
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        self.inner.exists(key)
+    }
+
+    /// List both manifests and blobs, so that clearing the cache removes
+    /// everything.
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        self.inner.list(continuation, max_keys)
+    }
+
+    /// Remove the blobs that no manifest refers to.
+    fn collect_garbage(&self) -> SFuture<u64> {
+        let inner = self.inner.clone();
+        let all = future::loop_fn((None, vec![]), move |(continuation, mut all): (Option<String>, Vec<StorageEntry>)| {
+            inner.list(continuation, GC_PAGE_SIZE).map(move |listing| {
+                all.extend(listing.entries);
+                match listing.next {
+                    Some(next) => Loop::Continue((Some(next), all)),
+                    None => Loop::Break(all),
+                }
+            })
+        });
+        let inner = self.inner.clone();
+        let gc_grace_period = self.gc_grace_period;
+        let unreferenced_since = self.unreferenced_since.clone();
+        Box::new(all.and_then(move |all| {
+            let (blobs, entries): (Vec<_>, Vec<_>) = all.into_iter()
+                .partition(|entry| entry.key.ends_with(BLOB_KEY_SUFFIX));
+            // Peek at entries so that reading them doesn't make them look
+            // recently used to the storage.
+            let peek_inner = inner.clone();
+            stream::iter_ok::<_, Error>(entries)
+                .map(move |entry| {
+                    let key = entry.key;
+                    peek_inner.peek(&key).map(move |result| (key, result))
+                })
+                .buffer_unordered(GC_CONCURRENCY)
+                .fold(HashSet::new(), |mut referenced, (key, result)| -> Result<_> {
+                    if let Cache::Hit(mut entry) = result {
+                        // A manifest that can't be read can't be used to
+                        // reassemble its entry either, so its blobs aren't
+                        // needed for it.
+                        match read_manifest(&mut entry) {
+                            Ok(Some(manifest)) => {
+                                referenced.extend(manifest.objects.into_iter().map(|object| object.digest));
+                            }
+                            Ok(None) => {}
+                            Err(e) => warn!("CasStorage: skipping unreadable manifest {}: {}", key, e),
+                        }
+                    }
+                    Ok(referenced)
+                })
+                .and_then(move |referenced| {
+                    let now = SystemTime::now();
+                    let started = Instant::now();
+                    let mut unreferenced_since = unreferenced_since.borrow_mut();
+                    let mut still_unreferenced = HashMap::new();
+                    let garbage = blobs.into_iter()
+                        .filter(|blob| {
+                            let digest = &blob.key[..blob.key.len() - BLOB_KEY_SUFFIX.len()];
+                            !referenced.contains(digest)
+                        })
+                        .filter(|blob| match blob.last_modified.map(|t| now.duration_since(t)) {
+                            Some(Ok(age)) => age >= gc_grace_period,
+                            Some(Err(_)) => false,
+                            // For storage that can't say how old a blob is,
+                            // count from when it was first found unreferenced.
+                            None => {
+                                let since = unreferenced_since.get(&blob.key).cloned().unwrap_or(started);
+                                if started.duration_since(since) >= gc_grace_period {
+                                    true
+                                } else {
+                                    still_unreferenced.insert(blob.key.clone(), since);
+                                    false
+                                }
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    *unreferenced_since = still_unreferenced;
+                    let count = garbage.len() as u64;
+                    debug!("CasStorage: removing {} unreferenced blobs", count);
+                    stream::iter_ok::<_, Error>(garbage)
+                        .map(move |blob| inner.delete(&blob.key))
+                        .buffer_unordered(GC_CONCURRENCY)
+                        .for_each(|()| Ok(()))
+                        .map(move |()| count)
+                })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use filetime::{set_file_times, FileTime};
+    use std::thread;
+    use std::u64;
+    use tempdir::TempDir;
+
+    fn entry(objects: &[(&str, &[u8])]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        for &(name, contents) in objects {
+            entry.put_object(name, &mut &contents[..], Some(0o644)).unwrap();
+        }
+        entry
+    }
+
+    fn read_obj(cache: Cache, name: &str) -> Vec<u8> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut v = vec![];
+                assert_eq!(entry.get_object(name, &mut v).unwrap(), Some(0o644));
+                v
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+    }
+
+    fn keys(storage: &dyn Storage) -> Vec<String> {
+        storage.list(None, 1000).wait().unwrap().entries.into_iter().map(|e| e.key).collect()
+    }
+
+    #[test]
+    fn test_cas_deduplicates_objects() {
+        let tempdir = TempDir::new("sccache_test_cas").unwrap();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&tempdir.path(), u64::MAX, &pool));
+        let cas = CasStorage::new(disk.clone(), &pool, Duration::from_secs(60 * 60));
+        cas.put("aaaa", entry(&[("obj", b"same"), ("stdout", b"one")])).wait().unwrap();
+        cas.put("bbbb", entry(&[("obj", b"same"), ("stdout", b"two")])).wait().unwrap();
+        // Two manifests and three distinct blobs.
+        assert_eq!(keys(&*disk).len(), 5);
+        {
+            let stats = cas.stats.borrow();
+            assert_eq!(stats.blobs_stored, 3);
+            assert_eq!(stats.blobs_deduplicated, 1);
+        }
+        assert_eq!(read_obj(cas.get("aaaa").wait().unwrap(), "obj"), b"same");
+        assert_eq!(read_obj(cas.get("aaaa").wait().unwrap(), "stdout"), b"one");
+        assert_eq!(read_obj(cas.get("bbbb").wait().unwrap(), "stdout"), b"two");
+        // Entries written without CAS are still readable.
+        disk.put("cccc", entry(&[("obj", b"plain")])).wait().unwrap();
+        assert_eq!(read_obj(cas.get("cccc").wait().unwrap(), "obj"), b"plain");
+        // A manifest whose blobs are gone is a miss.
+        disk.delete(&blob_key(&digest(b"one"))).wait().unwrap();
+        match cas.get("aaaa").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_cas_collect_garbage() {
+        let tempdir = TempDir::new("sccache_test_cas").unwrap();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&tempdir.path(), u64::MAX, &pool));
+        let cas = CasStorage::new(disk.clone(), &pool, Duration::from_secs(60 * 60));
+        cas.put("aaaa", entry(&[("obj", b"same"), ("stdout", b"one")])).wait().unwrap();
+        cas.put("bbbb", entry(&[("obj", b"same"), ("stdout", b"two")])).wait().unwrap();
+        cas.delete("aaaa").wait().unwrap();
+        // The unreferenced blob is too new to be removed.
+        assert_eq!(cas.collect_garbage().wait().unwrap(), 0);
+        // Age every blob past the grace period.
+        let old = FileTime::from_unix_time(0, 0);
+        for entry in walkdir::WalkDir::new(tempdir.path()) {
+            let entry = entry.unwrap();
+            if entry.file_type().is_file() {
+                set_file_times(entry.path(), old, old).unwrap();
+            }
+        }
+        assert_eq!(cas.collect_garbage().wait().unwrap(), 1);
+        assert!(!disk.exists(&blob_key(&digest(b"one"))).wait().unwrap());
+        assert_eq!(keys(&*disk).len(), 3);
+        assert_eq!(read_obj(cas.get("bbbb").wait().unwrap(), "obj"), b"same");
+        assert_eq!(read_obj(cas.get("bbbb").wait().unwrap(), "stdout"), b"two");
+    }
+
+    #[test]
+    fn test_cas_collect_garbage_corrupt_manifest() {
+        let tempdir = TempDir::new("sccache_test_cas").unwrap();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&tempdir.path(), u64::MAX, &pool));
+        let cas = CasStorage::new(disk.clone(), &pool, Duration::from_secs(0));
+        cas.put("aaaa", entry(&[("obj", b"same"), ("stdout", b"one")])).wait().unwrap();
+        cas.put("bbbb", entry(&[("obj", b"same"), ("stdout", b"two")])).wait().unwrap();
+        disk.put("cccc", entry(&[(MANIFEST_OBJECT, b"not a manifest")])).wait().unwrap();
+        cas.delete("aaaa").wait().unwrap();
+        // The corrupt manifest is skipped, and the rest are still collected.
+        assert_eq!(cas.collect_garbage().wait().unwrap(), 1);
+        assert!(!disk.exists(&blob_key(&digest(b"one"))).wait().unwrap());
+        assert_eq!(read_obj(cas.get("bbbb").wait().unwrap(), "stdout"), b"two");
+    }
+
+    /// A storage that doesn't report when its entries were written.
+    struct AgelessStorage(Arc<DiskCache>);
+
+    impl Storage for AgelessStorage {
+        fn get(&self, key: &str) -> SFuture<Cache> { self.0.get(key) }
+        fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> { self.0.put(key, entry) }
+        fn location(&self) -> String { self.0.location() }
+        fn current_size(&self) -> SFuture<Option<u64>> { self.0.current_size() }
+        fn max_size(&self) -> SFuture<Option<u64>> { self.0.max_size() }
+        fn delete(&self, key: &str) -> SFuture<()> { self.0.delete(key) }
+        fn exists(&self, key: &str) -> SFuture<bool> { self.0.exists(key) }
+        fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+            Box::new(self.0.list(continuation, max_keys).map(|mut listing| {
+                for entry in &mut listing.entries {
+                    entry.last_modified = None;
+                }
+                listing
+            }))
+        }
+    }
+
+    #[test]
+    fn test_cas_collect_garbage_without_ages() {
+        let tempdir = TempDir::new("sccache_test_cas").unwrap();
+        let pool = CpuPool::new(1);
+        let disk = Arc::new(DiskCache::new(&tempdir.path(), u64::MAX, &pool));
+        let storage = Arc::new(AgelessStorage(disk.clone()));
+        let cas = CasStorage::new(storage, &pool, Duration::from_millis(100));
+        cas.put("aaaa", entry(&[("obj", b"same"), ("stdout", b"one")])).wait().unwrap();
+        cas.put("bbbb", entry(&[("obj", b"same"), ("stdout", b"two")])).wait().unwrap();
+        cas.delete("aaaa").wait().unwrap();
+        // The unreferenced blob is only removed once it has been found
+        // unreferenced for the whole grace period.
+        assert_eq!(cas.collect_garbage().wait().unwrap(), 0);
+        assert!(disk.exists(&blob_key(&digest(b"one"))).wait().unwrap());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(cas.collect_garbage().wait().unwrap(), 1);
+        assert!(!disk.exists(&blob_key(&digest(b"one"))).wait().unwrap());
+        assert_eq!(read_obj(cas.get("bbbb").wait().unwrap(), "stdout"), b"two");
+    }
+}
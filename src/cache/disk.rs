@@ -16,12 +16,18 @@ use crate::cache::{
     Cache,
     CacheRead,
     CacheWrite,
+    EvictionStats,
     Storage,
+    StorageEntry,
+    StorageListing,
 };
 use futures_cpupool::CpuPool;
-use lru_disk_cache::LruDiskCache;
+use lru_disk_cache::{EvictionConfig, EvictionCounter, LruDiskCache};
 use lru_disk_cache::Error as LruError;
+use std::cmp;
+use std::collections::BinaryHeap;
 use std::ffi::OsStr;
+use std::fs;
 use std::path::{Path,PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Instant, Duration};
@@ -29,10 +35,16 @@ use std::time::{Instant, Duration};
 use crate::errors::*;
 
 /// A cache that stores entries at local disk paths.
+///
+/// Several sccache servers can share the same directory: `LruDiskCache` coordinates their
+/// changes, and the cache is refreshed with the changes made by the others before it is
+/// inspected.
 #[derive(Clone)]
 pub struct DiskCache {
     /// `LruDiskCache` does all the real work here.
     lru: Arc<Mutex<LruDiskCache>>,
+    /// The counts of the entries `lru` evicted, which are read without locking it.
+    evictions: EvictionCounter,
     /// Thread pool to execute disk I/O
     pool: CpuPool,
 }
@@ -42,28 +54,37 @@ impl DiskCache {
     pub fn new<T: AsRef<OsStr>>(root: &T,
                                 max_size: u64,
                                 pool: &CpuPool) -> DiskCache {
+        DiskCache::with_eviction(root, max_size, EvictionConfig::default(), pool)
+    }
+
+    /// Create a new `DiskCache` like `new`, which evicts entries as described by `eviction`.
+    pub fn with_eviction<T: AsRef<OsStr>>(root: &T,
+                                          max_size: u64,
+                                          eviction: EvictionConfig,
+                                          pool: &CpuPool) -> DiskCache {
+        //TODO: change this function to return a Result
+        let lru = LruDiskCache::with_eviction(root, max_size, eviction)
+            .expect("Couldn't instantiate disk cache!");
         DiskCache {
-            //TODO: change this function to return a Result
-            lru: Arc::new(Mutex::new(LruDiskCache::new(root, max_size).expect("Couldn't instantiate disk cache!"))),
+            evictions: lru.eviction_counter(),
+            lru: Arc::new(Mutex::new(lru)),
             pool: pool.clone(),
         }
     }
-}
 
-/// Make a path to the cache entry with key `key`.
-fn make_key_path(key: &str) -> PathBuf {
-    Path::new(&key[0..1]).join(&key[1..2]).join(key)
-}
-
-impl Storage for DiskCache {
-    fn get(&self, key: &str) -> SFuture<Cache> {
-        trace!("DiskCache::get({})", key);
+    /// Read the entry at `key`, updating its LRU state if `touch` is set.
+    fn read_entry(&self, key: &str, touch: bool) -> SFuture<Cache> {
         let path = make_key_path(key);
         let lru = self.lru.clone();
         let key = key.to_owned();
         Box::new(self.pool.spawn_fn(move || {
             let mut lru = lru.lock().unwrap();
-            let f = match lru.get(&path) {
+            let f = if touch {
+                lru.get(&path)
+            } else {
+                lru.refresh().and_then(|_| lru.peek(&path))
+            };
+            let f = match f {
                 Ok(f) => f,
                 Err(LruError::FileNotInCache) => {
                     trace!("DiskCache::get({}): FileNotInCache", key);
@@ -73,12 +94,29 @@ impl Storage for DiskCache {
                     trace!("DiskCache::get({}): IoError: {:?}", key, e);
                     return Err(e.into());
                 }
-                Err(_) => panic!("Unexpected error!"),
+                Err(e) => return Err(e.into()),
             };
             let hit = CacheRead::from(f)?;
             Ok(Cache::Hit(hit))
         }))
     }
+}
+
+/// Make a path to the cache entry with key `key`.
+fn make_key_path(key: &str) -> PathBuf {
+    Path::new(&key[0..1]).join(&key[1..2]).join(key)
+}
+
+impl Storage for DiskCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        trace!("DiskCache::get({})", key);
+        self.read_entry(key, true)
+    }
+
+    fn peek(&self, key: &str) -> SFuture<Cache> {
+        trace!("DiskCache::peek({})", key);
+        self.read_entry(key, false)
+    }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
         // We should probably do this on a background thread if we're going to buffer
@@ -99,9 +137,163 @@ impl Storage for DiskCache {
     }
 
     fn current_size(&self) -> SFuture<Option<u64>> {
-        f_ok(Some(self.lru.lock().unwrap().size()))
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let mut lru = lru.lock().unwrap();
+            lru.refresh()?;
+            Ok(Some(lru.size()))
+        }))
     }
     fn max_size(&self) -> SFuture<Option<u64>> {
         f_ok(Some(self.lru.lock().unwrap().capacity()))
     }
+
+    fn take_eviction_stats(&self) -> EvictionStats {
+        let stats = self.evictions.take();
+        EvictionStats { entries: stats.files, bytes: stats.bytes }
+    }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        trace!("DiskCache::delete({})", key);
+        let path = make_key_path(key);
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            match lru.lock().unwrap().remove(&path) {
+                Ok(()) | Err(LruError::FileNotInCache) => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        }))
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        let path = make_key_path(key);
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            let mut lru = lru.lock().unwrap();
+            lru.refresh()?;
+            Ok(lru.contains_key(path))
+        }))
+    }
+
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        // An empty page would look like the end of the listing.
+        let max_keys = cmp::max(max_keys, 1);
+        let lru = self.lru.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            // The continuation token is the last key of the previous page, so
+            // pages are taken from the keys in sorted order. Only the first
+            // `max_keys + 1` keys after it are kept, in a max-heap, rather
+            // than sorting every key in the cache for each page.
+            let (root, mut keys) = {
+                let mut lru = lru.lock().unwrap();
+                lru.refresh()?;
+                let mut heap = BinaryHeap::with_capacity(max_keys + 2);
+                for (path, size) in lru.iter() {
+                    let key = match path.file_name().and_then(|name| name.to_str()) {
+                        Some(key) => key,
+                        None => continue,
+                    };
+                    match continuation {
+                        Some(ref after) if key <= after.as_str() => continue,
+                        _ => {}
+                    }
+                    heap.push((key, size));
+                    if heap.len() > max_keys + 1 {
+                        heap.pop();
+                    }
+                }
+                let keys = heap.into_sorted_vec().into_iter()
+                    .map(|(key, size)| (key.to_owned(), size))
+                    .collect::<Vec<_>>();
+                (lru.path().to_owned(), keys)
+            };
+            let next = if keys.len() > max_keys {
+                keys.truncate(max_keys);
+                keys.last().map(|&(ref key, _)| key.clone())
+            } else {
+                None
+            };
+            let entries = keys.into_iter().map(|(key, size)| {
+                let last_modified = fs::metadata(root.join(make_key_path(&key)))
+                    .and_then(|m| m.modified())
+                    .ok();
+                StorageEntry { key, size, last_modified }
+            }).collect();
+            Ok(StorageListing { entries, next })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_delete_exists_list() {
+        let dir = TempDir::new("sccache_test_disk_cache").unwrap();
+        let pool = CpuPool::new(1);
+        let cache = DiskCache::new(&dir.path(), 1024 * 1024, &pool);
+        let keys = ["aaaa", "bbbb", "cccc"];
+        for key in keys.iter() {
+            cache.put(key, CacheWrite::new()).wait().unwrap();
+        }
+        assert!(cache.exists("bbbb").wait().unwrap());
+
+        let first = cache.list(None, 2).wait().unwrap();
+        assert_eq!(first.entries.iter().map(|e| e.key.as_str()).collect::<Vec<_>>(),
+                   vec!["aaaa", "bbbb"]);
+        assert!(first.entries.iter().all(|e| e.size > 0 && e.last_modified.is_some()));
+        let second = cache.list(first.next, 2).wait().unwrap();
+        assert_eq!(second.entries.iter().map(|e| e.key.as_str()).collect::<Vec<_>>(),
+                   vec!["cccc"]);
+        assert_eq!(second.next, None);
+
+        cache.delete("bbbb").wait().unwrap();
+        assert!(!cache.exists("bbbb").wait().unwrap());
+        // Deleting a missing entry is not an error.
+        cache.delete("bbbb").wait().unwrap();
+        assert_eq!(cache.list(None, 10).wait().unwrap().entries.len(), 2);
+        // Asking for no keys still makes progress.
+        let page = cache.list(None, 0).wait().unwrap();
+        assert_eq!(page.entries.len(), 1);
+        assert!(page.next.is_some());
+    }
+
+    #[test]
+    fn test_shared_directory() {
+        let dir = TempDir::new("sccache_test_disk_cache").unwrap();
+        let pool = CpuPool::new(1);
+        let a = DiskCache::new(&dir.path(), 1024 * 1024, &pool);
+        let b = DiskCache::new(&dir.path(), 1024 * 1024, &pool);
+        a.put("aaaa", CacheWrite::new()).wait().unwrap();
+        assert!(b.exists("aaaa").wait().unwrap());
+        match b.get("aaaa").wait().unwrap() {
+            Cache::Hit(_) => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        let size = a.current_size().wait().unwrap();
+        assert!(size.unwrap() > 0);
+        assert_eq!(b.current_size().wait().unwrap(), size);
+        b.delete("aaaa").wait().unwrap();
+        assert!(!a.exists("aaaa").wait().unwrap());
+        assert_eq!(a.current_size().wait().unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_eviction_stats() {
+        let dir = TempDir::new("sccache_test_disk_cache").unwrap();
+        let pool = CpuPool::new(1);
+        let eviction = EvictionConfig { max_entries: Some(2), ..EvictionConfig::default() };
+        let cache = DiskCache::with_eviction(&dir.path(), 1024 * 1024, eviction, &pool);
+        for key in ["aaaa", "bbbb", "cccc"].iter() {
+            cache.put(key, CacheWrite::new()).wait().unwrap();
+        }
+        assert!(!cache.exists("aaaa").wait().unwrap());
+        let stats = cache.take_eviction_stats();
+        assert_eq!(stats.entries, 1);
+        assert!(stats.bytes > 0);
+        assert_eq!(cache.take_eviction_stats(), EvictionStats::default());
+    }
 }
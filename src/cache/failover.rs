@@ -0,0 +1,536 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    Cache,
+    CacheWrite,
+    EvictionStats,
+    Storage,
+    StorageListing,
+};
+use futures::future::Future;
+use std::cell::RefCell;
+use std::cmp;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::util::fmt_duration_as_secs;
+
+use crate::errors::*;
+
+/// Whether a `FailoverStorage` is using the storage it wraps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// The storage is in use, and this many operations on it failed in a row.
+    Available(u32),
+    /// The storage failed too often, and is skipped until `until`, having
+    /// been skipped for `interval`.
+    Unavailable { until: Instant, interval: Duration },
+    /// An operation is trying the storage again, after it was skipped for
+    /// the given interval.
+    Retrying(Duration),
+}
+
+/// What the result of an operation tells about whether the storage works.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Worked,
+    Failed,
+    /// The result doesn't tell, such as a lookup that missed, which
+    /// several storages also report when they fail.
+    Unknown,
+}
+
+/// The health of the storage wrapped by a `FailoverStorage`.
+struct Breaker {
+    /// Skip the storage after this many operations fail in a row.
+    failure_threshold: u32,
+    /// How long to skip the storage for at first.
+    retry_interval: Duration,
+    /// The longest time to skip the storage for.
+    max_retry_interval: Duration,
+    state: State,
+    /// The number of times the storage started being skipped.
+    failovers: u64,
+    /// Operations that skipped the storage.
+    skipped: u64,
+}
+
+impl Breaker {
+    /// Whether the next operation should use the storage, counting it as
+    /// skipped if not.
+    ///
+    /// Once the storage has been skipped for long enough, one operation is
+    /// let through to see whether it works again, while the others keep
+    /// skipping it until that operation finishes.
+    fn try_storage(&mut self) -> bool {
+        match self.state {
+            State::Available(_) => true,
+            State::Unavailable { until, interval } if Instant::now() >= until => {
+                self.state = State::Retrying(interval);
+                true
+            }
+            _ => {
+                self.skipped += 1;
+                false
+            }
+        }
+    }
+
+    /// Update the state with the outcome of an operation on the storage at
+    /// `location`.
+    fn record(&mut self, outcome: Outcome, location: &str) {
+        let failed = match outcome {
+            Outcome::Worked => false,
+            Outcome::Failed => true,
+            Outcome::Unknown => {
+                // Let the next operation try the storage instead.
+                if let State::Retrying(interval) = self.state {
+                    self.state = State::Unavailable { until: Instant::now(), interval };
+                }
+                return;
+            }
+        };
+        self.state = match self.state {
+            State::Available(failures) if failed => {
+                let failures = failures + 1;
+                if failures < self.failure_threshold {
+                    State::Available(failures)
+                } else {
+                    warn!("Skipping cache {} for {:?} after {} failures in a row",
+                          location, self.retry_interval, failures);
+                    self.failovers += 1;
+                    self.unavailable_for(self.retry_interval)
+                }
+            }
+            State::Available(_) => State::Available(0),
+            State::Retrying(interval) if failed => {
+                let interval = cmp::min(interval * 2, self.max_retry_interval);
+                warn!("Cache {} is still failing, skipping it for {:?}", location, interval);
+                self.unavailable_for(interval)
+            }
+            State::Retrying(_) => {
+                info!("Cache {} is working again", location);
+                State::Available(0)
+            }
+            // Operations that started before the storage was skipped don't
+            // tell whether it works now.
+            state @ State::Unavailable { .. } => state,
+        };
+    }
+
+    fn unavailable_for(&self, interval: Duration) -> State {
+        State::Unavailable { until: Instant::now() + interval, interval }
+    }
+
+    fn is_available(&self) -> bool {
+        match self.state {
+            State::Available(_) => true,
+            _ => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.state {
+            State::Available(_) => "available".to_owned(),
+            State::Unavailable { until, .. } => {
+                let now = Instant::now();
+                if until > now {
+                    format!("unavailable, retrying in {}", fmt_duration_as_secs(&(until - now)))
+                } else {
+                    "unavailable, retrying on next use".to_owned()
+                }
+            }
+            State::Retrying(_) => "unavailable, retrying now".to_owned(),
+        }
+    }
+}
+
+/// Whether `err` means that the storage isn't working, rather than that it
+/// refused the operation or that the entry itself is bad.
+fn is_failure(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::CacheWriteSkipped(_)
+        | ErrorKind::CorruptCacheEntry(_)
+        | ErrorKind::CacheEntryChanged(_)
+        | ErrorKind::UnsupportedStorageOperation(..) => false,
+        _ => true,
+    }
+}
+
+/// A `Storage` that stops using another `Storage` when it keeps failing.
+///
+/// After `failure_threshold` lookups or writes fail or time out in a row,
+/// they go to the `fallback` storage instead, or are skipped if there is
+/// none: lookups miss and writes fail with `ErrorKind::CacheUnavailable`.
+/// Once `retry_interval` has passed, the next operation tries the storage
+/// again, and if it still fails the interval is doubled, up to
+/// `max_retry_interval`. Lookups that miss count neither as failures nor as
+/// successes.
+///
+/// Deleting, listing and clearing entries always use the wrapped storage.
+pub struct FailoverStorage {
+    /// The storage to use while it works.
+    inner: Arc<dyn Storage>,
+    /// The storage to use instead while it doesn't.
+    fallback: Option<Arc<dyn Storage>>,
+    /// Whether `inner` is working.
+    breaker: Rc<RefCell<Breaker>>,
+}
+
+impl FailoverStorage {
+    /// Create a new `FailoverStorage` that uses `fallback` when `inner`
+    /// keeps failing.
+    pub fn new(inner: Arc<dyn Storage>,
+               fallback: Option<Arc<dyn Storage>>,
+               failure_threshold: u32,
+               retry_interval: Duration,
+               max_retry_interval: Duration) -> FailoverStorage {
+        FailoverStorage {
+            inner: inner,
+            fallback: fallback,
+            breaker: Rc::new(RefCell::new(Breaker {
+                failure_threshold: failure_threshold,
+                retry_interval: retry_interval,
+                max_retry_interval: cmp::max(retry_interval, max_retry_interval),
+                state: State::Available(0),
+                failovers: 0,
+                skipped: 0,
+            })),
+        }
+    }
+
+    /// Whether the next operation should use `inner`.
+    fn try_inner(&self) -> bool {
+        self.breaker.borrow_mut().try_storage()
+    }
+
+    /// Record the outcome of `future`, an operation on `inner`.
+    fn track<T: 'static>(&self, future: SFuture<T>) -> SFuture<T> {
+        self.track_by(future, |_| Outcome::Worked)
+    }
+
+    /// Record the outcome of `future`, a lookup on `inner`.
+    ///
+    /// Several storages report errors as misses, so a miss doesn't count
+    /// either way.
+    fn track_lookup(&self, future: SFuture<Cache>) -> SFuture<Cache> {
+        self.track_by(future, |cache| match *cache {
+            Cache::Miss => Outcome::Unknown,
+            _ => Outcome::Worked,
+        })
+    }
+
+    /// Record the outcome of `future`, an operation on `inner`, using
+    /// `outcome` to tell what a successful result means.
+    fn track_by<T: 'static, F>(&self, future: SFuture<T>, outcome: F) -> SFuture<T>
+        where F: FnOnce(&T) -> Outcome + 'static
+    {
+        let breaker = self.breaker.clone();
+        let location = self.inner.location();
+        Box::new(future.then(move |result| {
+            let outcome = match result {
+                Ok(ref value) => outcome(value),
+                Err(ref e) if is_failure(e) => Outcome::Failed,
+                Err(_) => Outcome::Worked,
+            };
+            breaker.borrow_mut().record(outcome, &location);
+            result
+        }))
+    }
+
+    /// The fallback storage, if `inner` isn't in use.
+    fn current_fallback(&self) -> Option<&Arc<dyn Storage>> {
+        if self.breaker.borrow().is_available() {
+            None
+        } else {
+            self.fallback.as_ref()
+        }
+    }
+}
+
+impl Storage for FailoverStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        if self.try_inner() {
+            return self.track_lookup(self.inner.get(key));
+        }
+        match self.fallback {
+            Some(ref fallback) => fallback.get(key),
+            None => f_ok(Cache::Miss),
+        }
+    }
+
+    fn peek(&self, key: &str) -> SFuture<Cache> {
+        if self.try_inner() {
+            return self.track_lookup(self.inner.peek(key));
+        }
+        match self.fallback {
+            Some(ref fallback) => fallback.peek(key),
+            None => f_ok(Cache::Miss),
+        }
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        if self.try_inner() {
+            return self.track(self.inner.put(key, entry));
+        }
+        match self.fallback {
+            Some(ref fallback) => fallback.put(key, entry),
+            None => f_err(ErrorKind::CacheUnavailable("put", self.inner.location())),
+        }
+    }
+
+    /// The location of the wrapped storage, whether or not it is in use,
+    /// which `stats` reports.
+    fn location(&self) -> String {
+        self.inner.location()
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        match self.current_fallback() {
+            Some(fallback) => fallback.current_size(),
+            None => self.inner.current_size(),
+        }
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        match self.current_fallback() {
+            Some(fallback) => fallback.max_size(),
+            None => self.inner.max_size(),
+        }
+    }
+
+    fn stats(&self) -> Vec<(String, String)> {
+        let breaker = self.breaker.borrow();
+        let location = self.inner.location();
+        let mut state = breaker.describe();
+        if let Some(fallback) = self.current_fallback() {
+            state = format!("{}, using {}", state, fallback.location());
+        }
+        let mut v = vec![
+            (format!("Cache state ({})", location), state),
+            (format!("Cache failovers ({})", location), breaker.failovers.to_string()),
+            (format!("Cache operations skipped ({})", location), breaker.skipped.to_string()),
+        ];
+        v.extend(self.inner.stats());
+        if let Some(ref fallback) = self.fallback {
+            v.extend(fallback.stats());
+        }
+        v
+    }
+
+    fn take_eviction_stats(&self) -> EvictionStats {
+        let stats = self.inner.take_eviction_stats();
+        match self.fallback {
+            Some(ref fallback) => stats + fallback.take_eviction_stats(),
+            None => stats,
+        }
+    }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        self.inner.delete(key)
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        self.inner.exists(key)
+    }
+
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        self.inner.list(continuation, max_keys)
+    }
+
+    fn collect_garbage(&self) -> SFuture<u64> {
+        self.inner.collect_garbage()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::cell::Cell;
+    use std::thread;
+    use std::u64;
+    use tempdir::TempDir;
+
+    /// A `Storage` that can be made to fail, and counts the lookups and
+    /// writes it is asked to do.
+    #[derive(Default)]
+    struct FlakyStorage {
+        failing: Cell<bool>,
+        /// Report failed lookups as misses, as several storages do.
+        miss_on_failure: Cell<bool>,
+        calls: Cell<u32>,
+    }
+
+    impl FlakyStorage {
+        fn call<T: 'static>(&self, value: T) -> SFuture<T> {
+            self.calls.set(self.calls.get() + 1);
+            if self.failing.get() {
+                f_err("flaky storage is down")
+            } else {
+                f_ok(value)
+            }
+        }
+    }
+
+    impl Storage for FlakyStorage {
+        fn get(&self, _key: &str) -> SFuture<Cache> {
+            if self.miss_on_failure.get() {
+                self.calls.set(self.calls.get() + 1);
+                return f_ok(Cache::Miss);
+            }
+            self.call(Cache::Miss)
+        }
+        fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
+            self.call(Duration::from_secs(0))
+        }
+        fn location(&self) -> String { "flaky".to_owned() }
+        fn current_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+        fn max_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+        fn delete(&self, _key: &str) -> SFuture<()> { f_ok(()) }
+        fn exists(&self, _key: &str) -> SFuture<bool> { f_ok(false) }
+        fn list(&self, _continuation: Option<String>, _max_keys: usize) -> SFuture<StorageListing> {
+            f_ok(StorageListing { entries: vec![], next: None })
+        }
+    }
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    fn state(storage: &FailoverStorage) -> State {
+        storage.breaker.borrow().state
+    }
+
+    #[test]
+    fn test_failover_to_fallback() {
+        let tempdir = TempDir::new("sccache_test_failover").unwrap();
+        let pool = CpuPool::new(1);
+        let flaky = Arc::new(FlakyStorage::default());
+        let fallback = Arc::new(DiskCache::new(tempdir.path(), u64::MAX, &pool));
+        let storage = FailoverStorage::new(flaky.clone(), Some(fallback.clone()), 2,
+                                           Duration::from_secs(3600),
+                                           Duration::from_secs(3600));
+        flaky.failing.set(true);
+        assert!(storage.get("abcdef").wait().is_err());
+        assert_eq!(state(&storage), State::Available(1));
+        assert!(storage.put("abcdef", entry(b"hello")).wait().is_err());
+        assert!(!storage.breaker.borrow().is_available());
+        assert_eq!(flaky.calls.get(), 2);
+
+        // Lookups and writes now go to the fallback storage.
+        storage.put("abcdef", entry(b"hello")).wait().unwrap();
+        match storage.get("abcdef").wait().unwrap() {
+            Cache::Hit(_) => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        assert!(fallback.exists("abcdef").wait().unwrap());
+        assert_eq!(flaky.calls.get(), 2);
+        // The state is reported on its own, under the location of the storage.
+        assert_eq!(storage.location(), "flaky");
+        let stats = storage.stats();
+        assert_eq!(stats[0].0, "Cache state (flaky)");
+        assert!(stats[0].1.starts_with("unavailable, retrying in"));
+        assert!(stats[0].1.ends_with(&format!("using {}", fallback.location())));
+        assert_eq!(stats[1].1, "1");
+        assert_eq!(stats[2].1, "2");
+    }
+
+    #[test]
+    fn test_retry() {
+        let flaky = Arc::new(FlakyStorage::default());
+        let storage = FailoverStorage::new(flaky.clone(), None, 1,
+                                           Duration::from_millis(10),
+                                           Duration::from_millis(15));
+        // A miss isn't a failure.
+        match storage.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        flaky.failing.set(true);
+        assert!(storage.get("abcdef").wait().is_err());
+        // Without a fallback, operations are skipped while the storage is.
+        match storage.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        match storage.put("abcdef", entry(b"hello")).wait() {
+            Err(Error(ErrorKind::CacheUnavailable("put", _), _)) => {}
+            r => panic!("Unexpected put result: {:?}", r),
+        }
+        assert_eq!(flaky.calls.get(), 2);
+
+        // Retrying a storage that still fails skips it for longer, up to the
+        // maximum interval.
+        thread::sleep(Duration::from_millis(10));
+        assert!(storage.get("abcdef").wait().is_err());
+        assert_eq!(flaky.calls.get(), 3);
+        match state(&storage) {
+            State::Unavailable { interval, .. } => assert_eq!(interval, Duration::from_millis(15)),
+            s => panic!("Unexpected state: {:?}", s),
+        }
+
+        // Once it works again, it is used again.
+        flaky.failing.set(false);
+        thread::sleep(Duration::from_millis(15));
+        storage.put("abcdef", entry(b"hello")).wait().unwrap();
+        assert_eq!(state(&storage), State::Available(0));
+        assert_eq!(flaky.calls.get(), 4);
+        assert_eq!(storage.breaker.borrow().failovers, 1);
+    }
+
+    #[test]
+    fn test_alternating_failures() {
+        let flaky = Arc::new(FlakyStorage::default());
+        let storage = FailoverStorage::new(flaky.clone(), None, 4,
+                                           Duration::from_millis(10),
+                                           Duration::from_millis(10));
+        flaky.failing.set(true);
+        for _ in 0..2 {
+            assert!(storage.get("abcdef").wait().is_err());
+            assert!(storage.put("abcdef", entry(b"hello")).wait().is_err());
+        }
+        assert!(!storage.breaker.borrow().is_available());
+
+        // Lookups that report failures as misses don't hide the failed
+        // writes in between.
+        let flaky = Arc::new(FlakyStorage::default());
+        let storage = FailoverStorage::new(flaky.clone(), None, 2,
+                                           Duration::from_millis(10),
+                                           Duration::from_millis(10));
+        flaky.failing.set(true);
+        flaky.miss_on_failure.set(true);
+        for _ in 0..2 {
+            match storage.get("abcdef").wait().unwrap() {
+                Cache::Miss => {}
+                c => panic!("Unexpected cache result: {:?}", c),
+            }
+            assert!(storage.put("abcdef", entry(b"hello")).wait().is_err());
+        }
+        assert!(!storage.breaker.borrow().is_available());
+
+        // Nor does a miss tell whether the storage works again, so the next
+        // operation tries it too.
+        thread::sleep(Duration::from_millis(10));
+        storage.get("abcdef").wait().unwrap();
+        assert_eq!(flaky.calls.get(), 5);
+        assert!(storage.put("abcdef", entry(b"hello")).wait().is_err());
+        assert_eq!(flaky.calls.get(), 6);
+        assert!(!storage.breaker.borrow().is_available());
+    }
+}
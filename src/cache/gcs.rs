@@ -14,8 +14,11 @@
 // limitations under the License.
 
 use std::cell::RefCell;
+use std::env;
 use std::fmt;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time;
 
@@ -24,13 +27,16 @@ use crate::cache::{
     CacheRead,
     CacheWrite,
     Storage,
+    StorageEntry,
+    StorageListing,
 };
 use futures::future::Shared;
 use futures::{future, Async, Future, Stream};
 use hyperx::header::{Authorization, Bearer, ContentType, ContentLength};
-use hyper::Method;
-use reqwest::r#async::{Request, Client};
+use hyper::{Method, StatusCode};
+use reqwest::r#async::{Request, Response, Client};
 use crate::jwt;
+use directories::{BaseDirs, UserDirs};
 use url::form_urlencoded;
 use url::percent_encoding::{percent_encode, PATH_SEGMENT_ENCODE_SET, QUERY_ENCODE_SET};
 
@@ -138,23 +144,211 @@ impl Bucket {
             })
         }))
     }
+
+    fn exists(&self, key: &str, cred_provider: &Option<GCSCredentialProvider>) -> SFuture<bool> {
+        let url = format!("https://www.googleapis.com/storage/v1/b/{}/o/{}",
+                    percent_encode(self.name.as_bytes(), PATH_SEGMENT_ENCODE_SET),
+                    percent_encode(key.as_bytes(), PATH_SEGMENT_ENCODE_SET));
+
+        Box::new(self.execute(Method::GET, url, cred_provider).and_then(|res| {
+            if res.status().is_success() {
+                Ok(true)
+            } else if res.status() == StatusCode::NOT_FOUND {
+                Ok(false)
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }))
+    }
+
+    fn delete(&self, key: &str, cred_provider: &Option<GCSCredentialProvider>) -> SFuture<()> {
+        let url = format!("https://www.googleapis.com/storage/v1/b/{}/o/{}",
+                    percent_encode(self.name.as_bytes(), PATH_SEGMENT_ENCODE_SET),
+                    percent_encode(key.as_bytes(), PATH_SEGMENT_ENCODE_SET));
+
+        Box::new(self.execute(Method::DELETE, url, cred_provider).and_then(|res| {
+            if res.status().is_success() || res.status() == StatusCode::NOT_FOUND {
+                Ok(())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }))
+    }
+
+    fn list(&self, prefix: &str, page_token: Option<&str>, max_results: usize, cred_provider: &Option<GCSCredentialProvider>) -> SFuture<ObjectList> {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("maxResults", &max_results.to_string());
+        if !prefix.is_empty() {
+            query.append_pair("prefix", prefix);
+        }
+        query.append_pair("fields", "items(name,size,updated),nextPageToken");
+        if let Some(page_token) = page_token {
+            query.append_pair("pageToken", page_token);
+        }
+        let url = format!("https://www.googleapis.com/storage/v1/b/{}/o?{}",
+                    percent_encode(self.name.as_bytes(), PATH_SEGMENT_ENCODE_SET),
+                    query.finish());
+
+        Box::new(self.execute(Method::GET, url, cred_provider).and_then(|res| {
+            if res.status().is_success() {
+                Ok(res.into_body())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }).and_then(|body| {
+            body.fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, reqwest::Error>(body)
+            }).chain_err(|| {
+                "failed to read HTTP body"
+            })
+        }).and_then(|body| -> Result<ObjectList> {
+            Ok(serde_json::from_slice(&body)?)
+        }))
+    }
+
+    /// Send a `method` request with no body to `url`, authorized with credentials from
+    /// `cred_provider` if there is one.
+    fn execute(&self, method: Method, url: String, cred_provider: &Option<GCSCredentialProvider>) -> SFuture<Response> {
+        let client = self.client.clone();
+
+        let creds_opt_future = if let &Some(ref cred_provider) = cred_provider {
+            future::Either::A(cred_provider.credentials(&self.client).map(Some))
+        } else {
+            future::Either::B(future::ok(None))
+        };
+
+        Box::new(creds_opt_future.and_then(move |creds_opt| {
+            let mut request = Request::new(method.clone(), url.parse().unwrap());
+            if let Some(creds) = creds_opt {
+                request.headers_mut()
+                    .set(Authorization(Bearer { token: creds.token }));
+            }
+            client.execute(request).chain_err(move || {
+                format!("failed {}: {}", method, url)
+            })
+        }))
+    }
+}
+
+/// ObjectList is the subset of a GCS objects.list response that we request.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectList {
+    #[serde(default)]
+    items: Vec<ObjectResource>,
+    next_page_token: Option<String>,
+}
+
+/// ObjectResource is the subset of a GCS object's metadata that we request.
+#[derive(Deserialize)]
+struct ObjectResource {
+    name: String,
+    /// GCS reports sizes as strings, since they may not fit in a JSON number.
+    size: String,
+    updated: Option<String>,
 }
 
 /// GCSCredentialProvider provides GCS OAUTH tokens.
 ///
-/// It uses service account credentials to request tokens, and caches the result so that successive
-/// calls to GCS APIs don't need to request new tokens.
+/// It requests tokens from the source described by its `ServiceAccountInfo`, and caches the result
+/// so that successive calls to GCS APIs don't need to request new tokens.
 pub struct GCSCredentialProvider {
     rw_mode: RWMode,
     sa_info: ServiceAccountInfo,
     cached_credentials: RefCell<Option<Shared<SFuture<GCSCredential>>>>,
 }
 
-/// ServiceAccountInfo either contains a URL to fetch the oauth token
-/// or the service account key
+/// The metadata server of GCE instances and GKE pods.
+const DEFAULT_METADATA_URL: &str = "http://metadata.google.internal";
+
+/// The endpoint that refresh tokens are exchanged for access tokens at.
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+/// ServiceAccountInfo describes where OAUTH tokens are obtained from.
 pub enum ServiceAccountInfo {
+    /// A URL that returns a token in the format of taskcluster-auth's gcsCredentials endpoint.
     URL(String),
+    /// A service account key, used to sign token requests.
     AccountKey(ServiceAccountKey),
+    /// A user's refresh token, as written by `gcloud auth application-default login`.
+    AuthorizedUser(AuthorizedUserKey),
+    /// The base URL of a GCE metadata server, which provides tokens for the instance's
+    /// service account.
+    Metadata(String),
+}
+
+impl ServiceAccountInfo {
+    /// Read a service account key or an authorized user's refresh token from the JSON
+    /// credentials file at `path`, depending on its `type`.
+    pub fn from_file(path: &Path) -> Result<ServiceAccountInfo> {
+        let json = fs::read_to_string(path)
+            .chain_err(|| format!("failed to read GCS credentials from {}", path.display()))?;
+        let file: CredentialsFile = serde_json::from_str(&json)?;
+        match file.kind.as_ref().map(|s| s.as_str()) {
+            Some("service_account") | None => {
+                Ok(ServiceAccountInfo::AccountKey(serde_json::from_str(&json)?))
+            }
+            Some("authorized_user") => {
+                Ok(ServiceAccountInfo::AuthorizedUser(serde_json::from_str(&json)?))
+            }
+            Some(kind) => bail!("unsupported GCS credentials type `{}` in {}", kind, path.display()),
+        }
+    }
+
+    /// Find Application Default Credentials, in the same order as Google's client libraries:
+    ///
+    /// 1. the credentials file named by `GOOGLE_APPLICATION_CREDENTIALS`
+    /// 2. the file written by `gcloud auth application-default login`
+    /// 3. the metadata server, at `GCE_METADATA_HOST` if it is set, or if running on GCE
+    ///
+    /// Returns `None` if none of them are available.
+    pub fn application_default() -> Result<Option<ServiceAccountInfo>> {
+        if let Some(path) = env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            return ServiceAccountInfo::from_file(Path::new(&path)).map(Some);
+        }
+        if let Some(path) = gcloud_credentials_path() {
+            if path.is_file() {
+                return ServiceAccountInfo::from_file(&path).map(Some);
+            }
+        }
+        if let Ok(host) = env::var("GCE_METADATA_HOST") {
+            return Ok(Some(ServiceAccountInfo::Metadata(format!("http://{}", host))));
+        }
+        if on_gce() {
+            return Ok(Some(ServiceAccountInfo::Metadata(DEFAULT_METADATA_URL.to_owned())));
+        }
+        Ok(None)
+    }
+}
+
+/// The path of the credentials file written by `gcloud auth application-default login`.
+fn gcloud_credentials_path() -> Option<PathBuf> {
+    let config_dir = if let Some(dir) = env::var_os("CLOUDSDK_CONFIG") {
+        PathBuf::from(dir)
+    } else if cfg!(windows) {
+        BaseDirs::new()?.config_dir().join("gcloud")
+    } else {
+        // gcloud uses ~/.config on every platform but Windows, including macOS.
+        UserDirs::new()?.home_dir().join(".config").join("gcloud")
+    };
+    Some(config_dir.join("application_default_credentials.json"))
+}
+
+/// Whether this is a GCE instance, going by the DMI product name, as Google's client
+/// libraries do. This avoids waiting on requests to a metadata server that doesn't exist.
+fn on_gce() -> bool {
+    fs::read_to_string("/sys/class/dmi/id/product_name")
+        .map(|name| name.trim().starts_with("Google"))
+        .unwrap_or(false)
+}
+
+/// CredentialsFile is the field of a JSON credentials file that says what kind of
+/// credentials it contains.
+#[derive(Deserialize)]
+struct CredentialsFile {
+    #[serde(rename = "type")]
+    kind: Option<String>,
 }
 
 /// ServiceAccountKey is a subset of the information in the JSON service account credentials.
@@ -167,6 +361,21 @@ pub struct ServiceAccountKey {
     client_email: String,
 }
 
+/// AuthorizedUserKey is the subset of an authorized user's JSON credentials that is needed to
+/// exchange their refresh token for access tokens.
+#[derive(Debug, Deserialize)]
+pub struct AuthorizedUserKey {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    DEFAULT_TOKEN_URI.to_owned()
+}
+
 /// JwtClaims are the required claims that must be present in the OAUTH token request JWT.
 #[derive(Serialize)]
 struct JwtClaims {
@@ -188,6 +397,8 @@ struct JwtClaims {
 #[derive(Deserialize)]
 struct TokenMsg {
     access_token: String,
+    /// The lifetime of the token in seconds.
+    expires_in: Option<i64>,
 }
 
 /// AuthResponse represents the json response body from taskcluster-auth.gcsCredentials endpoint
@@ -328,6 +539,33 @@ impl GCSCredentialProvider {
         )
     }
 
+    fn request_new_token_from_refresh_token(&self, key: &AuthorizedUserKey, client: &Client) -> SFuture<GCSCredential> {
+        let params = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "refresh_token")
+            .append_pair("client_id", &key.client_id)
+            .append_pair("client_secret", &key.client_secret)
+            .append_pair("refresh_token", &key.refresh_token)
+            .finish();
+        let url = ftry!(key.token_uri.parse::<reqwest::Url>()
+            .chain_err(|| format!("invalid token URI: {}", key.token_uri)));
+
+        let mut request = Request::new(Method::POST, url);
+        {
+            let headers = request.headers_mut();
+            headers.set(ContentType::form_url_encoded());
+            headers.set(ContentLength(params.len() as u64));
+        }
+        *request.body_mut() = Some(params.into());
+
+        read_token(client.execute(request))
+    }
+
+    fn request_new_token_from_metadata(&self, base_url: &str, client: &Client) -> SFuture<GCSCredential> {
+        let url = format!("{}/computeMetadata/v1/instance/service-accounts/default/token",
+                          base_url.trim_end_matches('/'));
+        read_token(client.get(&url).header("Metadata-Flavor", "Google").send())
+    }
+
     pub fn credentials(&self, client: &Client) -> SFuture<GCSCredential> {
         let mut future_opt = self.cached_credentials.borrow_mut();
 
@@ -341,6 +579,8 @@ impl GCSCredentialProvider {
             let credentials = match self.sa_info {
                 ServiceAccountInfo::AccountKey(ref sa_key) => self.request_new_token(sa_key, client),
                 ServiceAccountInfo::URL(ref url) => self.request_new_token_from_tcauth(url, client),
+                ServiceAccountInfo::AuthorizedUser(ref key) => self.request_new_token_from_refresh_token(key, client),
+                ServiceAccountInfo::Metadata(ref url) => self.request_new_token_from_metadata(url, client),
             };
             *future_opt = Some(credentials.shared());
         };
@@ -354,6 +594,35 @@ impl GCSCredentialProvider {
     }
 }
 
+/// Parse the `TokenMsg` in the response to a token request, which is considered to
+/// expire a minute before the end of its lifetime.
+fn read_token<F>(response: F) -> SFuture<GCSCredential>
+    where F: Future<Item=Response, Error=reqwest::Error> + 'static
+{
+    let requested_at = chrono::offset::Utc::now();
+    Box::new(response.map_err(Into::into).and_then(|res| {
+        if res.status().is_success() {
+            Ok(res.into_body())
+        } else {
+            Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+        }
+    }).and_then(|body| {
+        body.fold(Vec::new(), |mut body, chunk| {
+            body.extend_from_slice(&chunk);
+            Ok::<_, reqwest::Error>(body)
+        }).chain_err(|| {
+            "failed to read HTTP body"
+        })
+    }).and_then(move |body| {
+        let token_msg: TokenMsg = serde_json::from_slice(&body)?;
+        let lifetime = token_msg.expires_in.unwrap_or(3600) - 60;
+        Ok(GCSCredential {
+            token: token_msg.access_token,
+            expiration_time: requested_at + chrono::Duration::seconds(lifetime),
+        })
+    }))
+}
+
 /// A cache that stores entries in Google Cloud Storage
 pub struct GCSCache {
     /// The GCS bucket
@@ -362,25 +631,35 @@ pub struct GCSCache {
     credential_provider: Option<GCSCredentialProvider>,
     /// Read-only or not
     rw_mode: RWMode,
+    /// The prefix of the name of every object stored by this cache
+    key_prefix: String,
 }
 
 impl GCSCache {
-    /// Create a new `GCSCache` storing data in `bucket`
+    /// Create a new `GCSCache` storing data in `bucket`, under names starting
+    /// with `key_prefix`
     pub fn new(bucket: String,
                credential_provider: Option<GCSCredentialProvider>,
-               rw_mode: RWMode) -> Result<GCSCache>
+               rw_mode: RWMode,
+               key_prefix: &str) -> Result<GCSCache>
     {
         Ok(GCSCache {
             bucket: Rc::new(Bucket::new(bucket)?),
             rw_mode: rw_mode,
             credential_provider: credential_provider,
+            key_prefix: key_prefix.to_owned(),
         })
     }
+
+    /// The name of the object that the entry for `key` is stored in
+    fn object_name(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
 }
 
 impl Storage for GCSCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
-        Box::new(self.bucket.get(&key, &self.credential_provider).then(|result| {
+        Box::new(self.bucket.get(&self.object_name(key), &self.credential_provider).then(|result| {
             match result {
                 Ok(data) => {
                     let hit = CacheRead::from(io::Cursor::new(data))?;
@@ -405,7 +684,7 @@ impl Storage for GCSCache {
             Err(e) => return Box::new(future::err(e.into())),
         };
         let bucket = self.bucket.clone();
-        let response = bucket.put(&key, data, &self.credential_provider).chain_err(|| {
+        let response = bucket.put(&self.object_name(key), data, &self.credential_provider).chain_err(|| {
             "failed to put cache entry in GCS"
         });
 
@@ -418,6 +697,38 @@ impl Storage for GCSCache {
 
     fn current_size(&self) -> SFuture<Option<u64>> { Box::new(future::ok(None)) }
     fn max_size(&self) -> SFuture<Option<u64>> { Box::new(future::ok(None)) }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        if let RWMode::ReadOnly = self.rw_mode {
+            return f_err("cannot delete from a read-only GCS cache");
+        }
+        self.bucket.delete(&self.object_name(key), &self.credential_provider).chain_err(|| {
+            "failed to delete cache entry from GCS"
+        })
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        self.bucket.exists(&self.object_name(key), &self.credential_provider)
+    }
+
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        let list = self.bucket.list(&self.key_prefix, continuation.as_ref().map(|s| s.as_str()), max_keys, &self.credential_provider);
+        let prefix_len = self.key_prefix.len();
+        Box::new(list.and_then(move |list| -> Result<StorageListing> {
+            let entries = list.items.into_iter().map(|item| {
+                let last_modified = match item.updated {
+                    Some(updated) => Some(updated.parse::<chrono::DateTime<chrono::offset::Utc>>()?.into()),
+                    None => None,
+                };
+                Ok(StorageEntry {
+                    key: item.name[prefix_len..].to_owned(),
+                    size: item.size.parse().chain_err(|| "invalid object size from GCS")?,
+                    last_modified,
+                })
+            }).collect::<Result<_>>()?;
+            Ok(StorageListing { entries, next: list.next_page_token })
+        }))
+    }
 }
 
 #[test]
@@ -454,3 +765,77 @@ fn test_gcs_credential_provider() {
 
     server.with_graceful_shutdown(cred_fut);
 }
+
+#[test]
+fn test_gcs_metadata_credentials() {
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let url = crate::test::utils::serve_http(&mut runtime, |parts, _| {
+        let mut res = hyper::Response::new(hyper::Body::empty());
+        if parts.uri.path() != "/computeMetadata/v1/instance/service-accounts/default/token" {
+            *res.status_mut() = StatusCode::NOT_FOUND;
+        } else if parts.headers.get("Metadata-Flavor").map(|v| v.as_bytes()) != Some(b"Google") {
+            *res.status_mut() = StatusCode::FORBIDDEN;
+        } else {
+            let token = serde_json::json!({
+                "access_token": "metadata-token",
+                "expires_in": 3599,
+                "token_type": "Bearer",
+            });
+            *res.body_mut() = hyper::Body::from(token.to_string());
+        }
+        res
+    });
+
+    let credential_provider = GCSCredentialProvider::new(
+        RWMode::ReadWrite,
+        ServiceAccountInfo::Metadata(url),
+    );
+    let before = chrono::offset::Utc::now();
+    let credential = runtime.block_on(credential_provider.credentials(&Client::new())).unwrap();
+    assert_eq!(credential.token, "metadata-token");
+    assert!(credential.expiration_time > before + chrono::Duration::minutes(55));
+    assert!(credential.expiration_time < before + chrono::Duration::minutes(60));
+}
+
+#[test]
+fn test_gcs_authorized_user_credentials() {
+    let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let url = crate::test::utils::serve_http(&mut runtime, |_, body| {
+        let params: std::collections::HashMap<_, _> = form_urlencoded::parse(&body).into_owned().collect();
+        let mut res = hyper::Response::new(hyper::Body::empty());
+        if params.get("grant_type").map(|s| s.as_str()) != Some("refresh_token") ||
+           params.get("refresh_token").map(|s| s.as_str()) != Some("refresh-me") {
+            *res.status_mut() = StatusCode::BAD_REQUEST;
+        } else {
+            let token = serde_json::json!({
+                "access_token": "user-token",
+                "expires_in": 3599,
+            });
+            *res.body_mut() = hyper::Body::from(token.to_string());
+        }
+        res
+    });
+
+    let dir = tempdir::TempDir::new("sccache_gcs").unwrap();
+    let path = dir.path().join("application_default_credentials.json");
+    let key = serde_json::json!({
+        "type": "authorized_user",
+        "client_id": "client",
+        "client_secret": "secret",
+        "refresh_token": "refresh-me",
+        "token_uri": format!("{}/token", url),
+    });
+    fs::write(&path, key.to_string()).unwrap();
+    let info = ServiceAccountInfo::from_file(&path).unwrap();
+    match info {
+        ServiceAccountInfo::AuthorizedUser(_) => {}
+        _ => panic!("Unexpected credentials type"),
+    }
+
+    let credential_provider = GCSCredentialProvider::new(RWMode::ReadOnly, info);
+    let credential = runtime.block_on(credential_provider.credentials(&Client::new())).unwrap();
+    assert_eq!(credential.token, "user-token");
+
+    fs::write(&path, r#"{"type": "external_account"}"#).unwrap();
+    assert!(ServiceAccountInfo::from_file(&path).is_err());
+}
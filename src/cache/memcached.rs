@@ -18,38 +18,95 @@ use crate::cache::{
     CacheRead,
     CacheWrite,
     Storage,
+    StorageListing,
 };
 use crate::errors::*;
 use futures_cpupool::CpuPool;
 use memcached::client::Client;
 use memcached::proto::Operation;
-use memcached::proto::NoReplyOperation;
 use memcached::proto::ProtoType::Binary;
 use std::cell::RefCell;
 use std::io::Cursor;
 use std::time::{
     Duration,
     Instant,
+    SystemTime,
+    UNIX_EPOCH,
 };
+use uuid::Uuid;
+
+/// The largest value stored under a single Memcached key. Memcached's default
+/// item size limit is 1MB, which also has to fit the key and item header.
+const MAX_CHUNK_SIZE: usize = 1000 * 1000;
+
+/// The start of every value that is the index of a chunked entry.
+const CHUNK_INDEX_MAGIC: &[u8] = b"SCCACHE-MEMCACHED-CHUNKS\0";
+
+/// Memcached treats expiration times of more than this many seconds as unix
+/// timestamps rather than as relative times.
+const MAX_RELATIVE_EXPIRATION: u64 = 60 * 60 * 24 * 30;
 
 thread_local! {
     static CLIENT: RefCell<Option<Client>> = RefCell::default();
 }
 
+/// The value stored under an entry's key when the entry is too large for a
+/// single Memcached item. The entry itself is stored in `chunks` items under
+/// the keys returned by `chunk_key`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ChunkIndex {
+    /// A unique id for the write that stored the chunks, so that chunks from
+    /// different writes of the same entry are never mixed up.
+    id: String,
+    /// The number of chunks.
+    chunks: usize,
+    /// The total size of the entry.
+    size: u64,
+}
+
+/// A cache that stores entries in Memcached.
+///
+/// Entries larger than `MAX_CHUNK_SIZE` are split into chunks, which are
+/// written before a `ChunkIndex` under the entry's key. An entry whose index
+/// or chunks are missing, for instance because Memcached evicted some of
+/// them, is treated as a cache miss.
 #[derive(Clone)]
 pub struct MemcachedCache {
     url: String,
     pool: CpuPool,
+    /// The prefix of every key stored by this cache.
+    key_prefix: String,
+    /// How long Memcached should keep entries, in seconds, or 0 to keep them
+    /// until they are evicted.
+    ttl: u64,
 }
 
 impl MemcachedCache {
-    pub fn new(url: &str, pool: &CpuPool) -> Result<MemcachedCache> {
+    pub fn new(url: &str, pool: &CpuPool, key_prefix: &str, ttl: u64) -> Result<MemcachedCache> {
         Ok(MemcachedCache {
             url: url.to_owned(),
             pool: pool.clone(),
+            key_prefix: key_prefix.to_owned(),
+            ttl: ttl,
         })
     }
 
+    /// The expiration time to store new items with.
+    fn expiration(&self) -> u32 {
+        if self.ttl <= MAX_RELATIVE_EXPIRATION {
+            return self.ttl as u32;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        (now + self.ttl) as u32
+    }
+
+    /// The Memcached key that the entry for `key` is stored under.
+    fn memcached_key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
+
     fn parse(&self) -> Vec<(&str, usize)> {
         self.url.split_whitespace().map(|w| (w, 1usize)).collect()
     }
@@ -67,24 +124,106 @@ impl MemcachedCache {
     }
 }
 
+/// The key that chunk `n` of the write `id` of the entry at `key` is stored
+/// under.
+fn chunk_key(key: &str, id: &str, n: usize) -> String {
+    format!("{}.{}.{}", key, id, n)
+}
+
+/// Parse `value` as a `ChunkIndex`, or return `None` if it is an entry that
+/// was stored whole.
+fn parse_index(value: &[u8]) -> Result<Option<ChunkIndex>> {
+    if !value.starts_with(CHUNK_INDEX_MAGIC) {
+        return Ok(None);
+    }
+    Ok(Some(bincode::deserialize(&value[CHUNK_INDEX_MAGIC.len()..])?))
+}
+
+/// Store `data` as the entry at `key` by calling `set` for every item it
+/// needs, splitting it into chunks of at most `chunk_size` bytes if it does
+/// not fit in one item.
+///
+/// The index of a chunked entry is written last, so that readers never see
+/// an index whose chunks were not all written.
+fn write_value<F>(key: &str, data: &[u8], chunk_size: usize, mut set: F) -> Result<()>
+    where F: FnMut(&str, &[u8]) -> Result<()>
+{
+    if data.len() <= chunk_size && !data.starts_with(CHUNK_INDEX_MAGIC) {
+        return set(key, data);
+    }
+    let id = Uuid::new_v4().to_simple_ref().to_string();
+    let mut chunks = 0;
+    for (n, chunk) in data.chunks(chunk_size).enumerate() {
+        set(&chunk_key(key, &id, n), chunk)?;
+        chunks += 1;
+    }
+    let index = ChunkIndex {
+        id: id,
+        chunks: chunks,
+        size: data.len() as u64,
+    };
+    let mut value = CHUNK_INDEX_MAGIC.to_vec();
+    value.extend(bincode::serialize(&index)?);
+    set(key, &value)
+}
+
+/// Read the entry at `key` by calling `get` for every item it is stored in,
+/// returning `None` if any of them is missing.
+fn read_value<F>(key: &str, mut get: F) -> Result<Option<Vec<u8>>>
+    where F: FnMut(&str) -> Option<Vec<u8>>
+{
+    let value = match get(key) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let index = match parse_index(&value)? {
+        Some(index) => index,
+        None => return Ok(Some(value)),
+    };
+    let mut data = Vec::with_capacity(index.size as usize);
+    for n in 0..index.chunks {
+        match get(&chunk_key(key, &index.id, n)) {
+            Some(chunk) => data.extend_from_slice(&chunk),
+            None => {
+                debug!("Chunk {} of {} is missing from memcached", n, key);
+                return Ok(None);
+            }
+        }
+    }
+    if data.len() as u64 != index.size {
+        debug!("Chunks of {} have size {}, expected {}", key, data.len(), index.size);
+        return Ok(None);
+    }
+    Ok(Some(data))
+}
+
 impl Storage for MemcachedCache {
     fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = key.to_owned();
+        let key = self.memcached_key(key);
         let me = self.clone();
         Box::new(self.pool.spawn_fn(move || {
-            me.exec(|c| c.get(&key.as_bytes()))
-            .map(|(d, _)| CacheRead::from(Cursor::new(d)).map(Cache::Hit))
-            .unwrap_or(Ok(Cache::Miss))
+            let value = me.exec(|c| {
+                read_value(&key, |k| c.get(&k.as_bytes()).ok().map(|(d, _)| d))
+            })?;
+            match value {
+                Some(d) => CacheRead::from(Cursor::new(d)).map(Cache::Hit),
+                None => Ok(Cache::Miss),
+            }
         }))
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = key.to_owned();
+        let key = self.memcached_key(key);
         let me = self.clone();
         Box::new(self.pool.spawn_fn(move || {
             let start = Instant::now();
             let d = entry.finish()?;
-            me.exec(|c| c.set_noreply(&key.as_bytes(), &d, 0, 0))?;
+            let expiration = me.expiration();
+            // Wait for Memcached to store each item, so that the index is
+            // not written if any of the chunks could not be.
+            me.exec(|c| write_value(&key, &d, MAX_CHUNK_SIZE, |k, v| {
+                c.set(&k.as_bytes(), v, 0, expiration).map_err(Into::into)
+            }))?;
             Ok(start.elapsed())
         }))
     }
@@ -95,4 +234,76 @@ impl Storage for MemcachedCache {
 
     fn current_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
     fn max_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        let key = self.memcached_key(key);
+        let me = self.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            // Memcached reports deleting a missing key as an error, so check
+            // for the key first.
+            me.exec(|c| -> Result<()> {
+                let value = match c.get(&key.as_bytes()) {
+                    Ok((value, _)) => value,
+                    Err(_) => return Ok(()),
+                };
+                c.delete(&key.as_bytes())?;
+                // Chunks that are already gone will be missing anyway.
+                if let Some(index) = parse_index(&value)? {
+                    for n in 0..index.chunks {
+                        let _ = c.delete(&chunk_key(&key, &index.id, n).as_bytes());
+                    }
+                }
+                Ok(())
+            })
+        }))
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        let key = self.memcached_key(key);
+        let me = self.clone();
+        Box::new(self.pool.spawn_fn(move || {
+            Ok::<_, Error>(me.exec(|c| c.get(&key.as_bytes())).is_ok())
+        }))
+    }
+
+    /// Memcached has no way to enumerate stored keys.
+    fn list(&self, _continuation: Option<String>, _max_keys: usize) -> SFuture<StorageListing> {
+        f_err(ErrorKind::UnsupportedStorageOperation("list", self.location()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn write(items: &mut HashMap<String, Vec<u8>>, key: &str, data: &[u8]) {
+        write_value(key, data, 4, |k, v| {
+            items.insert(k.to_owned(), v.to_vec());
+            Ok(())
+        }).unwrap();
+    }
+
+    fn read(items: &HashMap<String, Vec<u8>>, key: &str) -> Option<Vec<u8>> {
+        read_value(key, |k| items.get(k).cloned()).unwrap()
+    }
+
+    #[test]
+    fn test_chunked_values() {
+        let mut items = HashMap::new();
+        write(&mut items, "small", b"abc");
+        assert_eq!(items.len(), 1);
+        assert_eq!(read(&items, "small"), Some(b"abc".to_vec()));
+
+        write(&mut items, "large", b"0123456789");
+        assert_eq!(items.len(), 5);
+        assert_eq!(read(&items, "large"), Some(b"0123456789".to_vec()));
+        assert_eq!(read(&items, "missing"), None);
+
+        // An entry with an evicted chunk should read as missing.
+        let index = parse_index(&items["large"]).unwrap().unwrap();
+        assert_eq!(index.chunks, 3);
+        items.remove(&chunk_key("large", &index.id, 1));
+        assert_eq!(read(&items, "large"), None);
+    }
 }
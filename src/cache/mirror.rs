@@ -0,0 +1,411 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    Cache,
+    CacheWrite,
+    EvictionStats,
+    Storage,
+    StorageListing,
+};
+use futures::future::{self, Future, Loop};
+use futures_cpupool::CpuPool;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+/// Counters kept by a `MirrorStorage` for each of its storages.
+#[derive(Default)]
+struct MirrorStats {
+    /// Entries written to the storage.
+    writes: u64,
+    /// Errors writing to the storage.
+    write_errors: u64,
+    /// Writes the storage skipped, because it is read-only or unavailable.
+    writes_skipped: u64,
+}
+
+/// A cache that writes entries to several `Storage`s.
+///
+/// Writes go to every storage, and succeed if any of them stored the entry.
+/// Lookups try the storages in order until one finds the entry, so the first
+/// storage serves every lookup it can.
+pub struct MirrorStorage {
+    /// The storages to write to, in the order to look entries up in.
+    storages: Vec<Arc<dyn Storage>>,
+    /// The names of the storages, which label their statistics.
+    names: Vec<String>,
+    /// The pool that entries are compressed on before they are written.
+    pool: CpuPool,
+    /// Write statistics, one for each storage.
+    stats: Rc<RefCell<Vec<MirrorStats>>>,
+}
+
+impl MirrorStorage {
+    /// Create a new `MirrorStorage` that writes to all of `storages`, which
+    /// must not be empty, given with their names.
+    pub fn new(storages: Vec<(String, Arc<dyn Storage>)>, pool: &CpuPool) -> MirrorStorage {
+        assert!(!storages.is_empty(), "MirrorStorage needs at least one storage");
+        let stats = storages.iter().map(|_| MirrorStats::default()).collect();
+        let (names, storages) = storages.into_iter().unzip();
+        MirrorStorage {
+            storages: storages,
+            names: names,
+            pool: pool.clone(),
+            stats: Rc::new(RefCell::new(stats)),
+        }
+    }
+
+    /// Look `key` up in each storage in turn with `lookup`, until one finds
+    /// it.
+    ///
+    /// Storages that fail are skipped, and the lookup only fails if they
+    /// all do.
+    fn lookup<F>(&self, key: &str, lookup: F) -> SFuture<Cache>
+        where F: Fn(&dyn Storage, &str) -> SFuture<Cache> + 'static,
+    {
+        let storages = self.storages.clone();
+        let key = key.to_owned();
+        let start = (0, None, false);
+        Box::new(future::loop_fn(start, move |(i, first_error, missed): (usize, Option<Error>, bool)| {
+            let last = i + 1 == storages.len();
+            let location = storages[i].location();
+            let key = key.clone();
+            lookup(&*storages[i], &key).then(move |result| {
+                let (first_error, missed) = match result {
+                    Ok(Cache::Miss) => (first_error, true),
+                    Ok(cache) => return Ok(Loop::Break(cache)),
+                    Err(e) => {
+                        warn!("MirrorStorage: lookup of {} in {} failed: {}", key, location, e);
+                        (first_error.or(Some(e)), missed)
+                    }
+                };
+                if !last {
+                    return Ok(Loop::Continue((i + 1, first_error, missed)));
+                }
+                match (first_error, missed) {
+                    (Some(e), false) => Err(e),
+                    _ => Ok(Loop::Break(Cache::Miss)),
+                }
+            })
+        }))
+    }
+
+    fn locations(&self) -> Vec<String> {
+        self.storages.iter().map(|storage| storage.location()).collect()
+    }
+}
+
+/// Whether `err` means that a storage deliberately didn't write an entry.
+fn is_skipped(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::CacheWriteSkipped(_) | ErrorKind::CacheUnavailable(..) => true,
+        _ => false,
+    }
+}
+
+impl Storage for MirrorStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.lookup(key, |storage, key| storage.get(key))
+    }
+
+    fn peek(&self, key: &str) -> SFuture<Cache> {
+        self.lookup(key, |storage, key| storage.peek(key))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let storages = self.storages.clone();
+        let stats = self.stats.clone();
+        let locations = self.locations();
+        let key = key.to_owned();
+        // Compress the entry once, off the event loop, for all the storages.
+        let data = self.pool.spawn_fn(move || entry.finish());
+        Box::new(data.and_then(move |data| {
+            let writes: Vec<_> = storages.iter()
+                .map(|storage| storage.put(&key, CacheWrite::from_bytes(data.clone())).then(Ok))
+                .collect();
+            future::join_all(writes).map(move |results| (key, results))
+        }).and_then(move |(key, results): (String, Vec<Result<_>>)| {
+            let mut stats = stats.borrow_mut();
+            let mut first_error = None;
+            let mut stored = false;
+            for ((result, counters), location) in results.into_iter().zip(stats.iter_mut()).zip(locations) {
+                match result {
+                    Ok(_) => {
+                        counters.writes += 1;
+                        stored = true;
+                    }
+                    Err(e) => {
+                        if is_skipped(&e) {
+                            counters.writes_skipped += 1;
+                        } else {
+                            warn!("MirrorStorage: failed to write {} to {}: {}", key, location, e);
+                            counters.write_errors += 1;
+                        }
+                        first_error = first_error.or(Some(e));
+                    }
+                }
+            }
+            match (first_error, stored) {
+                (Some(e), false) => Err(e),
+                _ => Ok(start.elapsed()),
+            }
+        }))
+    }
+
+    fn location(&self) -> String {
+        format!("Mirror: {}", self.locations().join(", "))
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.storages[0].current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.storages[0].max_size()
+    }
+
+    fn stats(&self) -> Vec<(String, String)> {
+        let mut v = vec![];
+        let counters = self.stats.borrow();
+        for ((storage, name), stats) in self.storages.iter().zip(&self.names).zip(counters.iter()) {
+            v.push((format!("Mirror writes ({})", name), stats.writes.to_string()));
+            v.push((format!("Mirror write errors ({})", name), stats.write_errors.to_string()));
+            v.push((format!("Mirror writes skipped ({})", name), stats.writes_skipped.to_string()));
+            v.extend(storage.stats());
+        }
+        v
+    }
+
+    fn take_eviction_stats(&self) -> EvictionStats {
+        self.storages.iter()
+            .map(|storage| storage.take_eviction_stats())
+            .fold(EvictionStats::default(), |a, b| a + b)
+    }
+
+    /// Remove the entry for `key` from every storage, succeeding if any of
+    /// them did.
+    fn delete(&self, key: &str) -> SFuture<()> {
+        let deletes: Vec<_> = self.storages.iter().map(|storage| storage.delete(key).then(Ok)).collect();
+        let locations = self.locations();
+        let key = key.to_owned();
+        Box::new(future::join_all(deletes).and_then(move |results: Vec<Result<()>>| {
+            let mut first_error = None;
+            let mut deleted = false;
+            for (result, location) in results.into_iter().zip(locations) {
+                match result {
+                    Ok(()) => deleted = true,
+                    Err(e) => {
+                        warn!("MirrorStorage: failed to delete {} from {}: {}", key, location, e);
+                        first_error = first_error.or(Some(e));
+                    }
+                }
+            }
+            match (first_error, deleted) {
+                (Some(e), false) => Err(e),
+                _ => Ok(()),
+            }
+        }))
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        let checks: Vec<_> = self.storages.iter().map(|storage| storage.exists(key).then(Ok)).collect();
+        Box::new(future::join_all(checks).and_then(|results: Vec<Result<bool>>| {
+            let mut first_error = None;
+            let mut checked = false;
+            for result in results {
+                match result {
+                    Ok(true) => return Ok(true),
+                    Ok(false) => checked = true,
+                    Err(e) => first_error = first_error.or(Some(e)),
+                }
+            }
+            match (first_error, checked) {
+                (Some(e), false) => Err(e),
+                _ => Ok(false),
+            }
+        }))
+    }
+
+    /// List the entries of each storage in turn. Entries stored in several
+    /// storages are listed once for each.
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        // Continuation tokens are those of the underlying storage, prefixed
+        // with its index.
+        let (index, token) = match continuation {
+            None => (0, None),
+            Some(c) => {
+                let mut parts = c.splitn(2, ':');
+                let index = parts.next().and_then(|i| i.parse::<usize>().ok());
+                match (index, parts.next()) {
+                    (Some(index), Some(token)) if index < self.storages.len() => {
+                        (index, Some(token.to_owned()).filter(|t| !t.is_empty()))
+                    }
+                    _ => return f_err(format!("Invalid continuation token: {}", c)),
+                }
+            }
+        };
+        let count = self.storages.len();
+        Box::new(self.storages[index].list(token, max_keys).map(move |mut listing| {
+            listing.next = match listing.next {
+                Some(next) => Some(format!("{}:{}", index, next)),
+                None if index + 1 < count => Some(format!("{}:", index + 1)),
+                None => None,
+            };
+            listing
+        }))
+    }
+
+    fn collect_garbage(&self) -> SFuture<u64> {
+        let collections: Vec<_> = self.storages.iter().map(|storage| storage.collect_garbage()).collect();
+        Box::new(future::join_all(collections).map(|removed| removed.into_iter().sum()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use crate::cache::readonly::ReadOnlyStorage;
+    use std::u64;
+    use tempdir::TempDir;
+
+    /// A `Storage` whose lookups and writes always fail.
+    struct FailingStorage;
+
+    impl Storage for FailingStorage {
+        fn get(&self, _key: &str) -> SFuture<Cache> { f_err("failing storage") }
+        fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
+            f_err("failing storage")
+        }
+        fn location(&self) -> String { "failing".to_owned() }
+        fn current_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+        fn max_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+        fn delete(&self, _key: &str) -> SFuture<()> { f_err("failing storage") }
+        fn exists(&self, _key: &str) -> SFuture<bool> { f_err("failing storage") }
+        fn list(&self, _continuation: Option<String>, _max_keys: usize) -> SFuture<StorageListing> {
+            f_err("failing storage")
+        }
+    }
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    fn read_obj(cache: Cache) -> Vec<u8> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut v = vec![];
+                entry.get_object("obj", &mut v).unwrap();
+                v
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+    }
+
+    /// Name each of `storages` after its index.
+    fn named(storages: Vec<Arc<dyn Storage>>) -> Vec<(String, Arc<dyn Storage>)> {
+        storages.into_iter().enumerate().map(|(i, storage)| (i.to_string(), storage)).collect()
+    }
+
+    fn disk_caches(tempdir: &TempDir, pool: &CpuPool) -> (Arc<DiskCache>, Arc<DiskCache>) {
+        (Arc::new(DiskCache::new(&tempdir.path().join("a"), u64::MAX, pool)),
+         Arc::new(DiskCache::new(&tempdir.path().join("b"), u64::MAX, pool)))
+    }
+
+    #[test]
+    fn test_mirror_put_writes_all() {
+        let tempdir = TempDir::new("sccache_test_mirror").unwrap();
+        let pool = CpuPool::new(1);
+        let (a, b) = disk_caches(&tempdir, &pool);
+        let mirror = MirrorStorage::new(named(vec![
+            a.clone(),
+            Arc::new(FailingStorage),
+            Arc::new(ReadOnlyStorage::new(b.clone())),
+            b.clone(),
+        ]), &pool);
+        mirror.put("abcdef", entry(b"hello")).wait().unwrap();
+        assert_eq!(read_obj(a.get("abcdef").wait().unwrap()), b"hello");
+        assert_eq!(read_obj(b.get("abcdef").wait().unwrap()), b"hello");
+        let stats = mirror.stats.borrow();
+        let counts: Vec<_> = stats.iter().map(|s| (s.writes, s.write_errors, s.writes_skipped)).collect();
+        assert_eq!(counts, vec![(1, 0, 0), (0, 1, 0), (0, 0, 1), (1, 0, 0)]);
+        // The statistics are labelled with the names of the storages.
+        let labels: Vec<_> = mirror.stats().into_iter()
+            .map(|(label, _)| label)
+            .filter(|label| label.starts_with("Mirror writes ("))
+            .collect();
+        assert_eq!(labels, vec!["Mirror writes (0)", "Mirror writes (1)",
+                                "Mirror writes (2)", "Mirror writes (3)"]);
+
+        // Writes only fail if no storage stored the entry.
+        let mirror = MirrorStorage::new(named(vec![Arc::new(FailingStorage), Arc::new(FailingStorage)]),
+                                        &pool);
+        assert!(mirror.put("abcdef", entry(b"hello")).wait().is_err());
+    }
+
+    #[test]
+    fn test_mirror_get_in_order() {
+        let tempdir = TempDir::new("sccache_test_mirror").unwrap();
+        let pool = CpuPool::new(1);
+        let (a, b) = disk_caches(&tempdir, &pool);
+        let mirror = MirrorStorage::new(named(vec![Arc::new(FailingStorage), a.clone(), b.clone()]), &pool);
+        b.put("abcdef", entry(b"from b")).wait().unwrap();
+        assert_eq!(read_obj(mirror.get("abcdef").wait().unwrap()), b"from b");
+        a.put("abcdef", entry(b"from a")).wait().unwrap();
+        assert_eq!(read_obj(mirror.get("abcdef").wait().unwrap()), b"from a");
+        match mirror.get("missing").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        // Lookups only fail if every storage failed.
+        let mirror = MirrorStorage::new(named(vec![Arc::new(FailingStorage), Arc::new(FailingStorage)]),
+                                        &pool);
+        assert!(mirror.get("abcdef").wait().is_err());
+    }
+
+    #[test]
+    fn test_mirror_list_all() {
+        let tempdir = TempDir::new("sccache_test_mirror").unwrap();
+        let pool = CpuPool::new(1);
+        let (a, b) = disk_caches(&tempdir, &pool);
+        a.put("aaaa", entry(b"a")).wait().unwrap();
+        b.put("bbbb", entry(b"b")).wait().unwrap();
+        b.put("cccc", entry(b"c")).wait().unwrap();
+        let mirror = Arc::new(MirrorStorage::new(named(vec![a.clone(), b.clone()]), &pool));
+        let mut keys = vec![];
+        let mut continuation = None;
+        loop {
+            let listing = mirror.list(continuation, 1).wait().unwrap();
+            keys.extend(listing.entries.into_iter().map(|e| e.key));
+            match listing.next {
+                Some(next) => continuation = Some(next),
+                None => break,
+            }
+        }
+        keys[1..].sort();
+        assert_eq!(keys, vec!["aaaa", "bbbb", "cccc"]);
+        assert!(mirror.exists("cccc").wait().unwrap());
+        assert_eq!(crate::cache::clear_storage(mirror.clone()).wait().unwrap(), 3);
+        assert!(!a.exists("aaaa").wait().unwrap());
+        assert!(!b.exists("bbbb").wait().unwrap());
+        assert!(!mirror.exists("cccc").wait().unwrap());
+    }
+}
@@ -15,14 +15,22 @@
 #[cfg(feature = "azure")]
 pub mod azure;
 pub mod cache;
+pub mod cas;
 pub mod disk;
+pub mod failover;
 #[cfg(feature = "memcached")]
 pub mod memcached;
+pub mod mirror;
+pub mod readonly;
 #[cfg(feature = "redis")]
 pub mod redis;
 #[cfg(feature = "s3")]
 pub mod s3;
 #[cfg(feature = "gcs")]
 pub mod gcs;
+pub mod tiered;
+pub mod timeout;
+#[cfg(feature = "webdav")]
+pub mod webdav;
 
 pub use crate::cache::cache::*;
@@ -0,0 +1,128 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    Cache,
+    CacheWrite,
+    EvictionStats,
+    Storage,
+    StorageListing,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::errors::*;
+
+/// A `Storage` that serves lookups from another `Storage` but never writes
+/// to it.
+///
+/// Puts fail with `ErrorKind::CacheWriteSkipped`, so that callers can tell
+/// them apart from real write errors, and deletes are not supported.
+pub struct ReadOnlyStorage {
+    /// The storage to read from.
+    inner: Arc<dyn Storage>,
+}
+
+impl ReadOnlyStorage {
+    /// Create a new `ReadOnlyStorage` that reads from `inner`.
+    pub fn new(inner: Arc<dyn Storage>) -> ReadOnlyStorage {
+        ReadOnlyStorage { inner: inner }
+    }
+}
+
+impl Storage for ReadOnlyStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.inner.get(key)
+    }
+
+    fn peek(&self, key: &str) -> SFuture<Cache> {
+        self.inner.peek(key)
+    }
+
+    fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
+        f_err(ErrorKind::CacheWriteSkipped(self.inner.location()))
+    }
+
+    fn location(&self) -> String {
+        format!("{} (read-only)", self.inner.location())
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+
+    fn stats(&self) -> Vec<(String, String)> {
+        self.inner.stats()
+    }
+
+    fn take_eviction_stats(&self) -> EvictionStats {
+        self.inner.take_eviction_stats()
+    }
+
+    fn delete(&self, _key: &str) -> SFuture<()> {
+        f_err(ErrorKind::UnsupportedStorageOperation("delete", self.location()))
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        self.inner.exists(key)
+    }
+
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        self.inner.list(continuation, max_keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use futures::Future;
+    use futures_cpupool::CpuPool;
+    use std::u64;
+    use tempdir::TempDir;
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    #[test]
+    fn test_read_only_skips_writes() {
+        let tempdir = TempDir::new("sccache_test_readonly").unwrap();
+        let pool = CpuPool::new(1);
+        let inner = Arc::new(DiskCache::new(&tempdir.path(), u64::MAX, &pool));
+        inner.put("abcdef", entry(b"hello")).wait().unwrap();
+        let storage = ReadOnlyStorage::new(inner.clone());
+        match storage.get("abcdef").wait().unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut v = vec![];
+                entry.get_object("obj", &mut v).unwrap();
+                assert_eq!(v, b"hello");
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        match storage.put("012345", entry(b"world")).wait() {
+            Err(Error(ErrorKind::CacheWriteSkipped(_), _)) => {}
+            r => panic!("Unexpected put result: {:?}", r),
+        }
+        assert!(!inner.exists("012345").wait().unwrap());
+        assert!(storage.delete("abcdef").wait().is_err());
+        assert!(inner.exists("abcdef").wait().unwrap());
+    }
+}
@@ -18,79 +18,527 @@ use crate::cache::{
     CacheRead,
     CacheWrite,
     Storage,
+    StorageEntry,
+    StorageListing,
 };
+use crate::config::RedisCacheConfig;
 use crate::errors::*;
-use futures::{future, Future};
+use futures::future::{self, Loop};
+use futures::Future;
 use redis::{
     cmd,
+    from_redis_value,
+    pipe,
     Client,
     InfoDict,
+    RedisError,
+    Value,
 };
 use redis::r#async::Connection;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io::Cursor;
+use std::rc::Rc;
 use std::time::{
     Duration,
     Instant,
 };
 
+/// The scheme of URLs of the sentinels monitoring a master.
+const SENTINEL_SCHEME: &str = "redis+sentinel://";
+
+/// The scheme of URLs of the nodes of a Redis Cluster.
+const CLUSTER_SCHEME: &str = "redis+cluster://";
+
+/// The number of idle connections to keep open to each server.
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// The number of hash slots that a Redis Cluster divides keys into.
+const CLUSTER_SLOTS: u16 = 16384;
+
 /// A cache that stores entries in a Redis.
+///
+/// Connections are kept open and reused by later requests. The cache may be a
+/// single server, a master whose address is looked up from sentinels, or a
+/// Redis Cluster. Whenever a request fails, the master's address or the
+/// cluster's slots are looked up again, in case of a failover or resharding.
 #[derive(Clone)]
 pub struct RedisCache {
     url: String,
-    client: Client,
+    /// Where the servers are.
+    topology: Topology,
+    /// Idle connections to the servers.
+    pool: ConnectionPool,
+    /// The prefix of every key stored by this cache.
+    key_prefix: String,
+    /// How long to keep entries after they were last written or read, in
+    /// seconds, if at all.
+    ttl: Option<u64>,
 }
 
+/// Where the servers that store the cache are.
+#[derive(Clone)]
+enum Topology {
+    /// A single server, at the given URL.
+    Server(String),
+    /// A master monitored by sentinels.
+    Sentinel(Rc<Sentinel>),
+    /// A Redis Cluster.
+    Cluster(Rc<Cluster>),
+}
+
+/// The sentinels monitoring a master.
+struct Sentinel {
+    /// The URLs of the sentinels.
+    sentinels: Vec<String>,
+    /// The name the sentinels know the master by.
+    master: String,
+    /// The `[:<passwd>]` part of the master's URL.
+    auth: Option<String>,
+    /// The database to use on the master.
+    db: Option<String>,
+    /// The URL of the master, as last reported by a sentinel.
+    current: RefCell<Option<String>>,
+}
+
+/// A Redis Cluster.
+struct Cluster {
+    /// The URLs of the nodes to discover the cluster from.
+    nodes: Vec<String>,
+    /// The `[:<passwd>]` part of the URLs of the cluster's nodes.
+    auth: Option<String>,
+    /// The slots served by each master, as last reported by the cluster.
+    slots: RefCell<Vec<SlotRange>>,
+}
+
+/// A range of hash slots served by one master of a Redis Cluster.
+#[derive(Debug, PartialEq)]
+struct SlotRange {
+    start: u16,
+    end: u16,
+    /// The `<host>:<port>` of the master.
+    node: String,
+}
+
+/// Idle connections to each server, by URL.
+#[derive(Clone, Default)]
+struct ConnectionPool(Rc<RefCell<HashMap<String, Vec<Connection>>>>);
+
 impl RedisCache {
-    /// Create a new `RedisCache`.
-    pub fn new(url: &str) -> Result<RedisCache> {
+    /// Create a new `RedisCache` for the servers described by `config`,
+    /// storing entries under keys starting with `key_prefix`.
+    pub fn new(config: &RedisCacheConfig, key_prefix: &str) -> Result<RedisCache> {
         Ok(RedisCache {
-            url: url.to_owned(),
-            client: Client::open(url)?,
+            url: config.url.clone(),
+            topology: parse_topology(&config.url)?,
+            pool: ConnectionPool::default(),
+            key_prefix: key_prefix.to_owned(),
+            // `SET EX` and `EXPIRE` don't accept a TTL of 0.
+            ttl: config.ttl.and_then(|ttl| if ttl == 0 { None } else { Some(ttl) }),
         })
     }
 
-    /// Returns a connection with configured read and write timeouts.
-    fn connect(&self) -> impl Future<Item = Connection, Error = Error> {
-        self.client.get_async_connection()
-            .map_err(|e| e.into())
+    /// The Redis key that the entry for `key` is stored under.
+    fn redis_key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
+
+    /// The URLs of all of the masters that store entries.
+    fn servers(&self) -> SFuture<Vec<String>> {
+        match self.topology {
+            Topology::Server(ref url) => f_ok(vec![url.clone()]),
+            Topology::Sentinel(ref sentinel) => {
+                Box::new(Sentinel::master_url(sentinel).map(|url| vec![url]))
+            }
+            Topology::Cluster(ref cluster) => {
+                let c = cluster.clone();
+                Cluster::with_slots(cluster, move |slots| {
+                    let mut urls = slots.iter().map(|r| c.node_url(&r.node)).collect::<Vec<_>>();
+                    urls.sort();
+                    urls.dedup();
+                    Ok(urls)
+                })
+            }
+        }
+    }
+
+    /// The URL of the master that stores `key`.
+    fn server_for(&self, key: &str) -> SFuture<String> {
+        match self.topology {
+            Topology::Cluster(ref cluster) => {
+                let slot = key_slot(key);
+                let c = cluster.clone();
+                Cluster::with_slots(cluster, move |slots| {
+                    slots.iter()
+                        .find(|r| r.start <= slot && slot <= r.end)
+                        .map(|r| c.node_url(&r.node))
+                        .ok_or_else(|| format!("No Redis Cluster node serves slot {}", slot).into())
+                })
+            }
+            _ => Box::new(self.servers().map(|mut urls| urls.remove(0))),
+        }
+    }
+
+    /// Run `query` on a pooled connection to the master that stores `key`.
+    fn query<T, F, R>(&self, key: &str, query: F) -> SFuture<T>
+        where T: 'static,
+              F: FnOnce(Connection) -> R + 'static,
+              R: Future<Item = (Connection, T), Error = RedisError> + 'static,
+    {
+        let me = self.clone();
+        Box::new(self.server_for(key).and_then(move |url| me.query_at(url, query)))
+    }
+
+    /// Run `query` on a pooled connection to the server at `url`.
+    ///
+    /// If it fails, the server's idle connections are closed and the servers
+    /// are looked up again by the next query.
+    fn query_at<T, F, R>(&self, url: String, query: F) -> SFuture<T>
+        where T: 'static,
+              F: FnOnce(Connection) -> R + 'static,
+              R: Future<Item = (Connection, T), Error = RedisError> + 'static,
+    {
+        let pool = self.pool.clone();
+        let topology = self.topology.clone();
+        let connection = pool.get(&url);
+        Box::new(connection.and_then(move |c| query(c).from_err()).then(move |result| {
+            match result {
+                Ok((c, v)) => {
+                    pool.put(url, c);
+                    Ok(v)
+                }
+                Err(e) => {
+                    pool.clear(&url);
+                    topology.forget();
+                    Err(e)
+                }
+            }
+        }))
+    }
+}
+
+impl Topology {
+    /// Forget where the servers are, so that they are looked up again.
+    fn forget(&self) {
+        match *self {
+            Topology::Server(_) => {}
+            Topology::Sentinel(ref sentinel) => *sentinel.current.borrow_mut() = None,
+            Topology::Cluster(ref cluster) => cluster.slots.borrow_mut().clear(),
+        }
+    }
+}
+
+impl Sentinel {
+    /// The URL of the master, asking the sentinels for it if it isn't known.
+    fn master_url(me: &Rc<Sentinel>) -> SFuture<String> {
+        if let Some(ref url) = *me.current.borrow() {
+            return f_ok(url.clone());
+        }
+        let me = me.clone();
+        let master = me.master.clone();
+        Box::new(query_any(me.sentinels.clone(), move |c| {
+            cmd("SENTINEL").arg("get-master-addr-by-name").arg(&master).query_async(c)
+        }).and_then(move |addr: Option<(String, u16)>| {
+            let (host, port) = match addr {
+                Some(addr) => addr,
+                None => bail!("The Redis sentinels don't know master {}", me.master),
+            };
+            let url = server_url(&me.auth, &format!("{}:{}", host, port), &me.db);
+            debug!("Redis master {} is at {}:{}", me.master, host, port);
+            *me.current.borrow_mut() = Some(url.clone());
+            Ok(url)
+        }))
+    }
+}
+
+impl Cluster {
+    /// The URL of the cluster node at `<host>:<port>`.
+    fn node_url(&self, node: &str) -> String {
+        server_url(&self.auth, node, &None)
+    }
+
+    /// Call `f` with the slots served by each master, asking the cluster for
+    /// them if they aren't known.
+    fn with_slots<T, F>(me: &Rc<Cluster>, f: F) -> SFuture<T>
+        where T: 'static,
+              F: FnOnce(&[SlotRange]) -> Result<T> + 'static,
+    {
+        if !me.slots.borrow().is_empty() {
+            return Box::new(future::result(f(&me.slots.borrow())));
+        }
+        let me = me.clone();
+        Box::new(query_any(me.nodes.clone(), |c| {
+            cmd("CLUSTER").arg("SLOTS").query_async(c)
+        }).and_then(move |value: Value| {
+            let slots = parse_cluster_slots(&value)?;
+            let result = f(&slots);
+            *me.slots.borrow_mut() = slots;
+            result
+        }))
+    }
+}
+
+impl ConnectionPool {
+    /// Take an idle connection to the server at `url`, or open a new one.
+    fn get(&self, url: &str) -> SFuture<Connection> {
+        if let Some(c) = self.0.borrow_mut().get_mut(url).and_then(|idle| idle.pop()) {
+            return f_ok(c);
+        }
+        connect(url)
+    }
+
+    /// Return a connection to the server at `url` that was used successfully.
+    fn put(&self, url: String, c: Connection) {
+        let mut pool = self.0.borrow_mut();
+        let idle = pool.entry(url).or_insert_with(Vec::new);
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(c);
+        }
+    }
+
+    /// Close the idle connections to the server at `url`.
+    fn clear(&self, url: &str) {
+        self.0.borrow_mut().remove(url);
+    }
+}
+
+/// Open a new connection to the server at `url`.
+fn connect(url: &str) -> SFuture<Connection> {
+    let client = ftry!(Client::open(url));
+    Box::new(client.get_async_connection().from_err())
+}
+
+/// Send `query` to each of the servers at `urls` in turn, until one of them
+/// answers.
+fn query_any<T, F, R>(urls: Vec<String>, query: F) -> SFuture<T>
+    where T: 'static,
+          F: Fn(Connection) -> R + 'static,
+          R: Future<Item = (Connection, T), Error = RedisError> + 'static,
+{
+    let query = Rc::new(query);
+    Box::new(future::loop_fn(0, move |i| -> SFuture<Loop<T, usize>> {
+        let url = match urls.get(i) {
+            Some(url) => url.clone(),
+            None => return f_err("None of the Redis servers answered"),
+        };
+        let query = query.clone();
+        Box::new(connect(&url).and_then(move |c| query(c).from_err()).then(move |result| {
+            match result {
+                Ok((_, v)) => Ok(Loop::Break(v)),
+                Err(e) => {
+                    warn!("Failed to query Redis server {}: {}", url, e);
+                    Ok(Loop::Continue(i + 1))
+                }
+            }
+        }))
+    }))
+}
+
+/// The URL of the server at `<host>:<port>`.
+fn server_url(auth: &Option<String>, host: &str, db: &Option<String>) -> String {
+    let mut url = "redis://".to_owned();
+    if let Some(ref auth) = *auth {
+        url.push_str(auth);
+        url.push('@');
+    }
+    url.push_str(host);
+    if let Some(ref db) = *db {
+        url.push('/');
+        url.push_str(db);
+    }
+    url
+}
+
+/// The parts of a URL with several hosts, after its scheme:
+/// `[<auth>@]<host>[,<host>...][/<path>]`.
+#[derive(Debug, PartialEq)]
+struct MultiHostUrl {
+    auth: Option<String>,
+    /// The `<host>:<port>` of each host.
+    hosts: Vec<String>,
+    /// The non-empty segments of the path.
+    path: Vec<String>,
+}
+
+/// Parse a URL with several hosts, using `default_port` for hosts without a
+/// port.
+fn parse_multi_host_url(url: &str, default_port: u16) -> Result<MultiHostUrl> {
+    let (auth, rest) = match url.rfind('@') {
+        Some(i) => (Some(url[..i].to_owned()), &url[i + 1..]),
+        None => (None, url),
+    };
+    let (hosts, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, ""),
+    };
+    let hosts = hosts.split(',').filter(|h| !h.is_empty()).map(|h| {
+        if h.rsplit(']').next().map_or(false, |h| h.contains(':')) {
+            h.to_owned()
+        } else {
+            format!("{}:{}", h, default_port)
+        }
+    }).collect::<Vec<_>>();
+    if hosts.is_empty() {
+        bail!("Redis URL has no hosts: {}", url);
+    }
+    let path = path.split('/').filter(|s| !s.is_empty()).map(|s| s.to_owned()).collect();
+    Ok(MultiHostUrl { auth, hosts, path })
+}
+
+/// Parse the URL of the servers that store a cache.
+fn parse_topology(url: &str) -> Result<Topology> {
+    if url.starts_with(SENTINEL_SCHEME) {
+        let parsed = parse_multi_host_url(&url[SENTINEL_SCHEME.len()..], 26379)?;
+        let mut path = parsed.path.into_iter();
+        let master = path.next().ok_or("Redis sentinel URL has no master name")?;
+        let db = path.next();
+        return Ok(Topology::Sentinel(Rc::new(Sentinel {
+            sentinels: parsed.hosts.iter().map(|h| server_url(&None, h, &None)).collect(),
+            master,
+            auth: parsed.auth,
+            db,
+            current: RefCell::new(None),
+        })));
+    }
+    if url.starts_with(CLUSTER_SCHEME) {
+        let parsed = parse_multi_host_url(&url[CLUSTER_SCHEME.len()..], 6379)?;
+        if !parsed.path.is_empty() {
+            bail!("Redis Cluster URLs can't select a database");
+        }
+        return Ok(Topology::Cluster(Rc::new(Cluster {
+            nodes: parsed.hosts.iter().map(|h| server_url(&parsed.auth, h, &None)).collect(),
+            auth: parsed.auth,
+            slots: RefCell::new(vec![]),
+        })));
+    }
+    Client::open(url)?;
+    Ok(Topology::Server(url.to_owned()))
+}
+
+/// Parse the reply to `CLUSTER SLOTS`.
+fn parse_cluster_slots(value: &Value) -> Result<Vec<SlotRange>> {
+    let ranges = match *value {
+        Value::Bulk(ref ranges) => ranges,
+        _ => bail!("Invalid CLUSTER SLOTS reply: {:?}", value),
+    };
+    let mut slots = vec![];
+    for range in ranges {
+        match *range {
+            Value::Bulk(ref items) if items.len() >= 3 => {
+                let start: u16 = from_redis_value(&items[0])?;
+                let end: u16 = from_redis_value(&items[1])?;
+                // The master comes first, followed by its replicas.
+                let (host, port): (String, u16) = match items[2] {
+                    Value::Bulk(ref node) if node.len() >= 2 => {
+                        (from_redis_value(&node[0])?, from_redis_value(&node[1])?)
+                    }
+                    _ => bail!("Invalid CLUSTER SLOTS reply: {:?}", value),
+                };
+                slots.push(SlotRange { start, end, node: format!("{}:{}", host, port) });
+            }
+            _ => bail!("Invalid CLUSTER SLOTS reply: {:?}", value),
+        }
+    }
+    Ok(slots)
+}
+
+/// The Redis Cluster hash slot of `key`. If the key contains a non-empty
+/// `{...}` hash tag, only the tag is hashed.
+fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    let hashed = match key.iter().position(|&b| b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|&b| b == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(hashed) % CLUSTER_SLOTS
+}
+
+/// The CRC16 (XMODEM) checksum that Redis Cluster hashes keys with.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= u16::from(b) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Parse a `list` continuation token into the index of the server to scan
+/// and the SCAN cursor to continue from.
+fn parse_continuation(continuation: Option<&str>) -> Result<(usize, String)> {
+    let token = match continuation {
+        Some(token) => token,
+        None => return Ok((0, "0".to_owned())),
+    };
+    match token.find(':') {
+        Some(i) => {
+            let index = token[..i].parse().chain_err(|| "Invalid Redis listing continuation")?;
+            Ok((index, token[i + 1..].to_owned()))
+        }
+        None => Ok((0, token.to_owned())),
+    }
+}
+
+/// The continuation token for the page after one whose SCAN of server
+/// `index` of `count` returned `cursor`.
+fn next_continuation(index: usize, cursor: &str, count: usize) -> Option<String> {
+    if cursor != "0" {
+        if count == 1 {
+            Some(cursor.to_owned())
+        } else {
+            Some(format!("{}:{}", index, cursor))
+        }
+    } else if index + 1 < count {
+        Some(format!("{}:0", index + 1))
+    } else {
+        None
     }
 }
 
 impl Storage for RedisCache {
-    /// Open a connection and query for a key.
+    /// Query for a key, refreshing its TTL if there is one.
     fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = key.to_owned();
-        let me = self.clone();
+        let key = self.redis_key(key);
+        let ttl = self.ttl;
         Box::new(
-            me.connect()
-                .and_then(|c| {
-                    cmd("GET").arg(key).query_async(c).from_err()
-                })
-                .and_then(|(_, d): (_, Vec<u8>)| {
-                    if d.is_empty() {
-                        Ok(Cache::Miss)
-                    } else {
-                        CacheRead::from(Cursor::new(d))
-                            .map(Cache::Hit)
-                    }
-                })
+            self.query(&key.clone(), move |c| {
+                let mut p = pipe();
+                p.cmd("GET").arg(&key);
+                if let Some(ttl) = ttl {
+                    p.cmd("EXPIRE").arg(&key).arg(ttl).ignore();
+                }
+                p.query_async(c)
+            }).and_then(|(d,): (Vec<u8>,)| {
+                if d.is_empty() {
+                    Ok(Cache::Miss)
+                } else {
+                    CacheRead::from(Cursor::new(d))
+                        .map(Cache::Hit)
+                }
+            })
         )
     }
 
-    /// Open a connection and store a object in the cache.
+    /// Store an object in the cache, with the TTL if there is one.
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = key.to_owned();
-        let me = self.clone();
+        let key = self.redis_key(key);
+        let ttl = self.ttl;
         let start = Instant::now();
+        let d = ftry!(entry.finish());
         Box::new(
-            me.connect()
-                .and_then(move |c| {
-                    future::result(entry.finish()).and_then(|d| {
-                        cmd("SET").arg(key).arg(d).query_async(c).from_err()
-                    })
-                })
-                .map(move |(_, ())| start.elapsed())
+            self.query(&key.clone(), move |c| {
+                let mut set = cmd("SET");
+                set.arg(key).arg(d);
+                if let Some(ttl) = ttl {
+                    set.arg("EX").arg(ttl);
+                }
+                set.query_async(c)
+            }).map(move |()| start.elapsed())
         )
     }
 
@@ -100,33 +548,180 @@ impl Storage for RedisCache {
     }
 
     /// Returns the current cache size. This value is aquired via
-    /// the Redis INFO command (used_memory).
+    /// the Redis INFO command (used_memory), summed over the masters
+    /// of a cluster.
     fn current_size(&self) -> SFuture<Option<u64>> {
-        Box::new(
-            self.connect()
-                .and_then(|c| cmd("INFO").query_async(c).from_err())
-                .map(|(_, v)| v)
-                .map(|i: InfoDict| i.get("used_memory"))
-        )
+        let me = self.clone();
+        Box::new(self.servers().and_then(move |urls| {
+            future::join_all(urls.into_iter().map(move |url| {
+                me.query_at(url, |c| cmd("INFO").query_async(c))
+                    .map(|i: InfoDict| i.get::<u64>("used_memory"))
+            }).collect::<Vec<_>>())
+        }).map(|sizes| sizes.into_iter().sum()))
     }
 
     /// Returns the maximum cache size. This value is read via
-    /// the Redis CONFIG command (maxmemory). If the server has no
-    /// configured limit, the result is None.
+    /// the Redis CONFIG command (maxmemory), and summed over the masters
+    /// of a cluster. If any server has no configured limit, the result
+    /// is None.
     fn max_size(&self) -> SFuture<Option<u64>> {
-        Box::new(
-            self.connect()
-                .and_then(|c| cmd("CONFIG").arg("GET").arg("maxmemory").query_async(c).from_err())
-                .map(|(_, v)| v)
-                .map(|h: HashMap<String, usize>| {
-                    h.get("maxmemory").and_then(|&s| {
-                        if s != 0 {
-                            Some(s as u64)
-                        } else {
-                            None
-                        }
+        let me = self.clone();
+        Box::new(self.servers().and_then(move |urls| {
+            future::join_all(urls.into_iter().map(move |url| {
+                me.query_at(url, |c| cmd("CONFIG").arg("GET").arg("maxmemory").query_async(c))
+                    .map(|h: HashMap<String, usize>| {
+                        h.get("maxmemory").and_then(|&s| {
+                            if s != 0 {
+                                Some(s as u64)
+                            } else {
+                                None
+                            }
+                        })
                     })
-                })
+            }).collect::<Vec<_>>())
+        }).map(|sizes| sizes.into_iter().sum()))
+    }
+
+    /// Remove a key.
+    fn delete(&self, key: &str) -> SFuture<()> {
+        let key = self.redis_key(key);
+        Box::new(
+            self.query(&key.clone(), |c| cmd("DEL").arg(key).query_async(c))
+                .map(|_: u64| ())
         )
     }
+
+    /// Check whether a key exists.
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        let key = self.redis_key(key);
+        self.query(&key.clone(), |c| cmd("EXISTS").arg(key).query_async(c))
+    }
+
+    /// List keys with the Redis SCAN command, using its cursor as the
+    /// continuation token. `max_keys` is passed as the SCAN count, which
+    /// Redis treats as a hint, so pages may be somewhat larger or smaller.
+    /// The masters of a cluster are scanned one after the other, and the
+    /// continuation token also says which of them is being scanned.
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        let pattern = format!("{}*", escape_glob(&self.key_prefix));
+        let prefix_len = self.key_prefix.len();
+        let me = self.clone();
+        Box::new(self.servers().and_then(move |urls| -> SFuture<StorageListing> {
+            let (index, cursor) = ftry!(parse_continuation(continuation.as_ref().map(|s| s.as_str())));
+            let url = match urls.get(index) {
+                Some(url) => url.clone(),
+                None => return f_err("Invalid Redis listing continuation"),
+            };
+            let count = urls.len();
+            let scan = me.query_at(url.clone(), move |c| {
+                cmd("SCAN").arg(cursor).arg("MATCH").arg(pattern).arg("COUNT").arg(max_keys)
+                    .query_async(c)
+            });
+            Box::new(scan.and_then(move |(cursor, keys): (String, Vec<String>)| {
+                let next = next_continuation(index, &cursor, count);
+                if keys.is_empty() {
+                    return future::Either::A(future::ok(StorageListing { entries: vec![], next }));
+                }
+                let mut sizes = pipe();
+                for key in &keys {
+                    sizes.cmd("STRLEN").arg(key);
+                }
+                future::Either::B(me.query_at(url, move |c| sizes.query_async(c)).map(move |sizes: Vec<u64>| {
+                    let entries = keys.into_iter().zip(sizes).map(|(key, size)| {
+                        StorageEntry { key: key[prefix_len..].to_owned(), size, last_modified: None }
+                    }).collect();
+                    StorageListing { entries, next }
+                }))
+            }))
+        }))
+    }
+}
+
+/// Escape the characters that are special in Redis glob-style patterns.
+fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '*' | '?' | '[' | ']' | '\\' => escaped.push('\\'),
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[test]
+fn test_escape_glob() {
+    assert_eq!(escape_glob(""), "");
+    assert_eq!(escape_glob("team-a/"), "team-a/");
+    assert_eq!(escape_glob("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\");
+}
+
+#[test]
+fn test_key_slot() {
+    // Examples from the Redis Cluster specification.
+    assert_eq!(crc16(b"123456789"), 0x31c3);
+    assert_eq!(key_slot("foo"), 12182);
+    assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+    assert_ne!(key_slot("foo{}{bar}"), key_slot("bar"));
+    assert_eq!(key_slot("foo{{bar}}zap"), key_slot("{bar"));
+}
+
+#[test]
+fn test_parse_topology() {
+    match parse_topology("redis://:secret@localhost:6380/2").unwrap() {
+        Topology::Server(url) => assert_eq!(url, "redis://:secret@localhost:6380/2"),
+        _ => panic!("Expected a single server"),
+    }
+    match parse_topology("redis+sentinel://:secret@s1,s2:26380/mymaster/3").unwrap() {
+        Topology::Sentinel(sentinel) => {
+            assert_eq!(sentinel.sentinels, vec!["redis://s1:26379", "redis://s2:26380"]);
+            assert_eq!(sentinel.master, "mymaster");
+            assert_eq!(server_url(&sentinel.auth, "10.0.0.1:6379", &sentinel.db),
+                       "redis://:secret@10.0.0.1:6379/3");
+        }
+        _ => panic!("Expected sentinels"),
+    }
+    match parse_topology("redis+cluster://n1:7000,n2").unwrap() {
+        Topology::Cluster(cluster) => {
+            assert_eq!(cluster.nodes, vec!["redis://n1:7000", "redis://n2:6379"]);
+        }
+        _ => panic!("Expected a cluster"),
+    }
+    assert!(parse_topology("redis+sentinel://s1,s2").is_err());
+    assert!(parse_topology("redis+cluster://n1/1").is_err());
+    assert!(parse_topology("redis+cluster:///").is_err());
+}
+
+#[test]
+fn test_parse_cluster_slots() {
+    fn node(host: &str, port: i64) -> Value {
+        Value::Bulk(vec![Value::Data(host.as_bytes().to_vec()), Value::Int(port),
+                         Value::Data(b"07c37dfeb235213a872192d90877d0cd55635b91".to_vec())])
+    }
+    let reply = Value::Bulk(vec![
+        Value::Bulk(vec![Value::Int(0), Value::Int(5460), node("127.0.0.1", 30001), node("127.0.0.1", 30004)]),
+        Value::Bulk(vec![Value::Int(5461), Value::Int(16383), node("127.0.0.1", 30002)]),
+    ]);
+    assert_eq!(parse_cluster_slots(&reply).unwrap(), vec![
+        SlotRange { start: 0, end: 5460, node: "127.0.0.1:30001".to_owned() },
+        SlotRange { start: 5461, end: 16383, node: "127.0.0.1:30002".to_owned() },
+    ]);
+    assert!(parse_cluster_slots(&Value::Bulk(vec![Value::Int(0)])).is_err());
+}
+
+#[test]
+fn test_continuation() {
+    assert_eq!(parse_continuation(None).unwrap(), (0, "0".to_owned()));
+    assert_eq!(parse_continuation(Some("17")).unwrap(), (0, "17".to_owned()));
+    assert_eq!(parse_continuation(Some("2:17")).unwrap(), (2, "17".to_owned()));
+    assert!(parse_continuation(Some("x:17")).is_err());
+
+    // A single server's cursor is used as is.
+    assert_eq!(next_continuation(0, "17", 1), Some("17".to_owned()));
+    assert_eq!(next_continuation(0, "0", 1), None);
+    // Each master of a cluster is scanned in turn.
+    assert_eq!(next_continuation(0, "17", 2), Some("0:17".to_owned()));
+    assert_eq!(next_continuation(0, "0", 2), Some("1:0".to_owned()));
+    assert_eq!(next_continuation(1, "0", 2), None);
 }
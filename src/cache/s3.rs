@@ -17,92 +17,216 @@ use crate::cache::{
     CacheRead,
     CacheWrite,
     Storage,
+    StorageEntry,
+    StorageListing,
 };
+use bytes::Bytes;
 use directories::UserDirs;
 use futures::future;
 use futures::future::Future;
+use futures::stream::{self, Stream};
+use futures_cpupool::CpuPool;
+use crate::config::{S3Addressing, S3CacheConfig};
 use crate::simples3::{
     AutoRefreshingProvider,
     Bucket,
     ChainProvider,
+    AwsCredentials,
     ProfileProvider,
     ProvideAwsCredentials,
     Ssl,
+    WriteOptions,
 };
-use std::io;
-use std::rc::Rc;
+use std::cmp;
+use std::io::Read;
+use std::sync::Arc;
 use std::time::{Instant, Duration};
 
 use crate::errors::*;
 
+/// Entries larger than this are uploaded with a multipart upload, in parts of
+/// this size.
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// How many parts of a multipart upload to upload at once.
+const MULTIPART_CONCURRENCY: usize = 4;
+
+/// The size of the blocks that entries are read from S3 in.
+const READ_BLOCK_SIZE: u64 = 8 * 1024 * 1024;
+
 /// A cache that stores entries in Amazon S3.
 pub struct S3Cache {
     /// The S3 bucket.
-    bucket: Rc<Bucket>,
+    bucket: Arc<Bucket>,
     /// Credentials provider.
     provider: AutoRefreshingProvider<ChainProvider>,
+    /// The prefix of the name of every object stored by this cache.
+    key_prefix: String,
+    /// Thread pool to read entries on.
+    pool: CpuPool,
 }
 
 impl S3Cache {
-    /// Create a new `S3Cache` storing data in `bucket`.
-    pub fn new(bucket: &str, endpoint: &str) -> Result<S3Cache> {
+    /// Create a new `S3Cache` as described by `config`.
+    pub fn new(config: &S3CacheConfig, pool: &CpuPool) -> Result<S3Cache> {
         let user_dirs = UserDirs::new().ok_or("Couldn't get user directories")?;
         let home = user_dirs.home_dir();
 
         let profile_providers = vec![
             ProfileProvider::with_configuration(home.join(".aws").join("credentials"), "default"),
+            ProfileProvider::with_configuration(home.join(".aws").join("config"), "default"),
             //TODO: this is hacky, this is where our mac builders store their
             // credentials. We should either match what boto does more directly
             // or make those builders put their credentials in ~/.aws/credentials
             ProfileProvider::with_configuration(home.join(".boto"), "Credentials"),
         ];
-        let provider = AutoRefreshingProvider::new(ChainProvider::with_profile_providers(profile_providers));
-        //TODO: configurable SSL
-        let bucket = Rc::new(Bucket::new(bucket, endpoint, Ssl::No)?);
+        let provider = AutoRefreshingProvider::new(ChainProvider::with_profile_providers(profile_providers, pool));
+        let addressing = config.addressing();
+        let ssl = if addressing.use_ssl { Ssl::Yes } else { Ssl::No };
+        let mut bucket = Bucket::new(&config.bucket, &addressing.endpoint, &addressing.region,
+                                     addressing.use_path_style, ssl)?;
+        bucket.set_write_options(write_options(config, &addressing)?);
         Ok(S3Cache {
-            bucket: bucket,
+            bucket: Arc::new(bucket),
             provider: provider,
+            key_prefix: config.key_prefix.clone().unwrap_or_default(),
+            pool: pool.clone(),
+        })
+    }
+
+    fn credentials(&self) -> SFuture<AwsCredentials> {
+        self.provider.credentials().chain_err(|| {
+            "failed to get AWS credentials"
         })
     }
+
+    fn normalize_key(&self, key: &str) -> String {
+        format!("{}{}/{}/{}/{}", self.key_prefix, &key[0..1], &key[1..2], &key[2..3], &key)
+    }
 }
 
-fn normalize_key(key: &str) -> String {
-    format!("{}/{}/{}/{}", &key[0..1], &key[1..2], &key[2..3], &key)
+/// Check the settings in `config` for the objects the cache writes to the
+/// bucket at `addressing`.
+fn write_options(config: &S3CacheConfig, addressing: &S3Addressing) -> Result<WriteOptions> {
+    match config.server_side_encryption.as_ref().map(|s| s.as_str()) {
+        None | Some("AES256") => {
+            if config.sse_kms_key_id.is_some() {
+                bail!("An S3 KMS key id can only be used with `aws:kms` server-side encryption");
+            }
+        }
+        Some("aws:kms") => {
+            // S3 rejects requests for KMS-encrypted objects that aren't
+            // made over HTTPS.
+            if !addressing.use_ssl {
+                bail!("S3 `aws:kms` server-side encryption requires HTTPS, but the endpoint uses HTTP");
+            }
+        }
+        Some(sse) => bail!("Unknown S3 server-side encryption `{}`, expected `AES256` or `aws:kms`", sse),
+    }
+    if let Some(ref class) = config.storage_class {
+        if class == "GLACIER" || class == "DEEP_ARCHIVE" {
+            bail!("Objects in the S3 storage class `{}` can't be read back directly", class);
+        }
+    }
+    Ok(WriteOptions {
+        server_side_encryption: config.server_side_encryption.clone(),
+        sse_kms_key_id: config.sse_kms_key_id.clone(),
+        storage_class: config.storage_class.clone(),
+        acl: config.acl.clone(),
+    })
 }
 
-impl Storage for S3Cache {
-    fn get(&self, key: &str) -> SFuture<Cache> {
-        let key = normalize_key(key);
-        Box::new(self.bucket.get(&key).then(|result| {
+/// Upload the `size` bytes read from `reader` to `key` in `bucket` with a
+/// multipart upload, which is abandoned if any of the parts fail to upload.
+///
+/// Parts are read as they are needed, so only the parts being uploaded are
+/// held in memory in addition to whatever `reader` holds.
+fn put_multipart(bucket: Arc<Bucket>,
+                 key: String,
+                 reader: Box<dyn Read + Send>,
+                 size: u64,
+                 creds: AwsCredentials) -> SFuture<()> {
+    Box::new(bucket.initiate_multipart_upload(&key, &creds).and_then(move |upload_id| {
+        // Read the parts in order, numbering them from 1.
+        let parts = stream::unfold((reader, 0, 1), move |(mut reader, offset, number)| {
+            if offset >= size {
+                return None;
+            }
+            let len = cmp::min(MULTIPART_PART_SIZE as u64, size - offset);
+            let mut part = vec![0; len as usize];
+            let read = reader.read_exact(&mut part)
+                .map(|()| ((number, Bytes::from(part)), (reader, offset + len, number + 1)))
+                .chain_err(|| "failed to read cache entry");
+            Some(future::result(read))
+        });
+        let uploads = {
+            let bucket = bucket.clone();
+            let key = key.clone();
+            let upload_id = upload_id.clone();
+            let creds = creds.clone();
+            parts.map(move |(number, part)| {
+                bucket.upload_part(&key, &upload_id, number, part, &creds)
+            }).buffered(MULTIPART_CONCURRENCY).collect()
+        };
+        uploads.then(move |result| -> SFuture<()> {
             match result {
-                Ok(data) => {
-                    let hit = CacheRead::from(io::Cursor::new(data))?;
-                    Ok(Cache::Hit(hit))
-                }
+                Ok(etags) => bucket.complete_multipart_upload(&key, &upload_id, &etags, &creds),
                 Err(e) => {
-                    warn!("Got AWS error: {:?}", e);
-                    Ok(Cache::Miss)
+                    Box::new(bucket.abort_multipart_upload(&key, &upload_id, &creds).then(|abort| {
+                        if let Err(abort) = abort {
+                            warn!("Failed to abort multipart upload: {}", abort);
+                        }
+                        Err(e)
+                    }))
                 }
             }
+        })
+    }))
+}
+
+/// Recover the cache key from an object name produced by `normalize_key`.
+fn denormalize_key(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+impl Storage for S3Cache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let key = self.normalize_key(key);
+        let bucket = self.bucket.clone();
+        let pool = self.pool.clone();
+        // Public buckets can be read without credentials.
+        Box::new(self.credentials().then(move |credentials| {
+            // Entries are read from S3 a block at a time as they are used,
+            // which blocks, so do it off the event loop.
+            pool.spawn_fn(move || {
+                match bucket.open(&key, READ_BLOCK_SIZE, credentials.ok()) {
+                    Ok(Some(reader)) => Ok(Cache::Hit(CacheRead::from(reader)?)),
+                    Ok(None) => Ok(Cache::Miss),
+                    Err(e) => {
+                        warn!("Got AWS error: {:?}", e);
+                        Ok(Cache::Miss)
+                    }
+                }
+            })
         }))
     }
 
     fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
-        let key = normalize_key(&key);
+        let key = self.normalize_key(&key);
         let start = Instant::now();
-        let data = match entry.finish() {
-            Ok(data) => data,
-            Err(e) => return f_err(e),
-        };
-        let credentials = self.provider.credentials().chain_err(|| {
-            "failed to get AWS credentials"
-        });
+        let (size, mut reader) = ftry!(entry.into_reader());
+        let credentials = self.credentials();
 
         let bucket = self.bucket.clone();
-        let response = credentials.and_then(move |credentials| {
-            bucket.put(&key, data, &credentials).chain_err(|| {
-                "failed to put cache entry in s3"
-            })
+        let response = credentials.and_then(move |credentials| -> SFuture<()> {
+            let put = if size > MULTIPART_PART_SIZE as u64 {
+                put_multipart(bucket, key, reader, size, credentials)
+            } else {
+                let mut data = Vec::with_capacity(size as usize);
+                ftry!(reader.read_to_end(&mut data));
+                bucket.put(&key, data, &credentials)
+            };
+            put.chain_err(|| "failed to put cache entry in s3")
         });
 
         Box::new(response.map(move |_| start.elapsed()))
@@ -114,4 +238,69 @@ impl Storage for S3Cache {
 
     fn current_size(&self) -> SFuture<Option<u64>> { Box::new(future::ok(None)) }
     fn max_size(&self) -> SFuture<Option<u64>> { Box::new(future::ok(None)) }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        let key = self.normalize_key(key);
+        let bucket = self.bucket.clone();
+        Box::new(self.credentials().and_then(move |credentials| {
+            bucket.delete(&key, &credentials).chain_err(|| {
+                "failed to delete cache entry from s3"
+            })
+        }))
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        let key = self.normalize_key(key);
+        let bucket = self.bucket.clone();
+        Box::new(self.credentials().and_then(move |credentials| bucket.exists(&key, &credentials)))
+    }
+
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        let bucket = self.bucket.clone();
+        let key_prefix = self.key_prefix.clone();
+        Box::new(self.credentials().and_then(move |credentials| {
+            bucket.list(&key_prefix, continuation.as_ref().map(|s| s.as_str()), max_keys, &credentials)
+        }).map(|listing| {
+            let entries = listing.objects.into_iter().map(|object| StorageEntry {
+                key: denormalize_key(&object.key).to_owned(),
+                size: object.size,
+                last_modified: object.last_modified,
+            }).collect();
+            StorageListing { entries, next: listing.next_continuation_token }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kms_requires_https() {
+        let mut config = S3CacheConfig {
+            bucket: "bucket".to_owned(),
+            endpoint: Some("minio:9000".to_owned()),
+            region: None,
+            use_path_style: None,
+            use_ssl: None,
+            server_side_encryption: Some("aws:kms".to_owned()),
+            sse_kms_key_id: Some("key".to_owned()),
+            storage_class: None,
+            acl: None,
+            key_prefix: None,
+            read_only: false,
+            read_timeout: None,
+            write_timeout: None,
+        };
+        assert!(write_options(&config, &config.addressing()).is_err());
+        config.endpoint = Some("https://minio:9000".to_owned());
+        assert!(write_options(&config, &config.addressing()).is_ok());
+        config.endpoint = None;
+        assert!(write_options(&config, &config.addressing()).is_ok());
+        config.use_ssl = Some(false);
+        assert!(write_options(&config, &config.addressing()).is_err());
+        config.server_side_encryption = Some("AES256".to_owned());
+        config.sse_kms_key_id = None;
+        assert!(write_options(&config, &config.addressing()).is_ok());
+    }
 }
@@ -0,0 +1,327 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    EvictionStats,
+    Storage,
+    StorageListing,
+};
+use futures::future::Future;
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+
+use crate::errors::*;
+
+/// Prefix of `TieredCache::list` continuation tokens for the local cache.
+const LOCAL_TOKEN_PREFIX: &str = "local:";
+/// Prefix of `TieredCache::list` continuation tokens for the remote cache.
+const REMOTE_TOKEN_PREFIX: &str = "remote:";
+
+/// Counters kept by a `TieredCache`.
+#[derive(Default)]
+struct TieredStats {
+    /// Lookups served from the local cache.
+    local_hits: u64,
+    /// Lookups served from the remote cache.
+    remote_hits: u64,
+    /// Lookups found in neither cache.
+    misses: u64,
+    /// Remote hits that were copied into the local cache.
+    local_fills: u64,
+    /// Errors writing to the local cache.
+    local_write_errors: u64,
+    /// Errors writing to the remote cache.
+    remote_write_errors: u64,
+}
+
+/// A cache that keeps a local disk cache in front of a remote `Storage`.
+///
+/// Lookups try the local cache first and then the remote cache, and remote
+/// hits are copied into the local cache. Writes go to both caches.
+pub struct TieredCache {
+    /// The local disk cache.
+    local: Arc<dyn Storage>,
+    /// The remote cache.
+    remote: Arc<dyn Storage>,
+    /// Hit and write statistics.
+    stats: Rc<RefCell<TieredStats>>,
+}
+
+impl TieredCache {
+    /// Create a new `TieredCache` that puts `local` in front of `remote`.
+    pub fn new(local: Arc<dyn Storage>, remote: Arc<dyn Storage>) -> TieredCache {
+        TieredCache {
+            local: local,
+            remote: remote,
+            stats: Rc::new(RefCell::new(TieredStats::default())),
+        }
+    }
+}
+
+impl Storage for TieredCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let local = self.local.clone();
+        let remote = self.remote.clone();
+        let stats = self.stats.clone();
+        let key = key.to_owned();
+        Box::new(self.local.get(&key).then(move |result| -> SFuture<Cache> {
+            match result {
+                Ok(Cache::Hit(entry)) => {
+                    stats.borrow_mut().local_hits += 1;
+                    return f_ok(Cache::Hit(entry));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("TieredCache: local lookup of {} failed: {}", key, e),
+            }
+            Box::new(remote.get(&key).and_then(move |result| -> SFuture<Cache> {
+                let entry = match result {
+                    Cache::Hit(entry) => entry,
+                    other => {
+                        stats.borrow_mut().misses += 1;
+                        return f_ok(other);
+                    }
+                };
+                stats.borrow_mut().remote_hits += 1;
+                let data = ftry!(entry.into_bytes());
+                let fill = local.put(&key, CacheWrite::from_bytes(data.clone()));
+                Box::new(fill.then(move |res| {
+                    match res {
+                        Ok(_) => stats.borrow_mut().local_fills += 1,
+                        Err(Error(ErrorKind::CacheWriteSkipped(_), _)) => {}
+                        Err(e) => {
+                            warn!("TieredCache: failed to fill local cache with {}: {}", key, e);
+                            stats.borrow_mut().local_write_errors += 1;
+                        }
+                    }
+                    CacheRead::from(Cursor::new(data)).map(Cache::Hit)
+                }))
+            }))
+        }))
+    }
+
+    /// Look up `key` in the local cache and then the remote cache, without
+    /// copying remote hits into the local cache.
+    fn peek(&self, key: &str) -> SFuture<Cache> {
+        let remote = self.remote.clone();
+        let key = key.to_owned();
+        Box::new(self.local.peek(&key).then(move |result| -> SFuture<Cache> {
+            match result {
+                Ok(Cache::Hit(entry)) => f_ok(Cache::Hit(entry)),
+                _ => remote.peek(&key),
+            }
+        }))
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let data = ftry!(entry.finish());
+        let local = self.local.put(key, CacheWrite::from_bytes(data.clone())).then(Ok);
+        let remote = self.remote.put(key, CacheWrite::from_bytes(data)).then(Ok);
+        let stats = self.stats.clone();
+        let key = key.to_owned();
+        Box::new(local.join(remote).and_then(move |(local, remote): (Result<_>, Result<_>)| {
+            match local {
+                Ok(_) | Err(Error(ErrorKind::CacheWriteSkipped(_), _)) => {}
+                Err(e) => {
+                    warn!("TieredCache: failed to write {} to local cache: {}", key, e);
+                    stats.borrow_mut().local_write_errors += 1;
+                }
+            }
+            match remote {
+                Ok(_) => Ok(start.elapsed()),
+                Err(e @ Error(ErrorKind::CacheWriteSkipped(_), _)) => Err(e),
+                Err(e) => {
+                    stats.borrow_mut().remote_write_errors += 1;
+                    Err(e)
+                }
+            }
+        }))
+    }
+
+    fn location(&self) -> String {
+        format!("Tiered: {} in front of {}", self.local.location(), self.remote.location())
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.local.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.local.max_size()
+    }
+
+    fn stats(&self) -> Vec<(String, String)> {
+        let stats = self.stats.borrow();
+        let mut v = vec![
+            ("Tiered cache local hits".to_owned(), stats.local_hits.to_string()),
+            ("Tiered cache remote hits".to_owned(), stats.remote_hits.to_string()),
+            ("Tiered cache misses".to_owned(), stats.misses.to_string()),
+            ("Tiered cache local fills".to_owned(), stats.local_fills.to_string()),
+            ("Tiered cache local write errors".to_owned(), stats.local_write_errors.to_string()),
+            ("Tiered cache remote write errors".to_owned(), stats.remote_write_errors.to_string()),
+        ];
+        v.extend(self.local.stats());
+        v.extend(self.remote.stats());
+        v
+    }
+
+    fn take_eviction_stats(&self) -> EvictionStats {
+        self.local.take_eviction_stats() + self.remote.take_eviction_stats()
+    }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        let local = self.local.delete(key).then(Ok);
+        let remote = self.remote.delete(key);
+        let key = key.to_owned();
+        Box::new(local.join(remote).map(move |(local, ()): (Result<_>, _)| {
+            if let Err(e) = local {
+                warn!("TieredCache: failed to delete {} from local cache: {}", key, e);
+            }
+        }))
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        let remote = self.remote.clone();
+        let key = key.to_owned();
+        Box::new(self.local.exists(&key).then(move |result| -> SFuture<bool> {
+            match result {
+                Ok(true) => f_ok(true),
+                _ => remote.exists(&key),
+            }
+        }))
+    }
+
+    /// List the entries of the remote cache, followed by those of the local
+    /// cache. Entries stored in both caches are listed twice.
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        // Continuation tokens are those of the underlying cache, prefixed to
+        // say which cache they belong to.
+        let (in_local, token) = match continuation {
+            None => (false, None),
+            Some(ref c) if c.starts_with(LOCAL_TOKEN_PREFIX) => {
+                (true, Some(c[LOCAL_TOKEN_PREFIX.len()..].to_owned()))
+            }
+            Some(ref c) if c.starts_with(REMOTE_TOKEN_PREFIX) => {
+                (false, Some(c[REMOTE_TOKEN_PREFIX.len()..].to_owned()))
+            }
+            Some(c) => return f_err(format!("Invalid continuation token: {}", c)),
+        };
+        if in_local {
+            let token = token.filter(|t| !t.is_empty());
+            Box::new(self.local.list(token, max_keys).map(|mut listing| {
+                listing.next = listing.next.map(|next| format!("{}{}", LOCAL_TOKEN_PREFIX, next));
+                listing
+            }))
+        } else {
+            Box::new(self.remote.list(token, max_keys).map(|mut listing| {
+                listing.next = Some(match listing.next {
+                    Some(next) => format!("{}{}", REMOTE_TOKEN_PREFIX, next),
+                    None => LOCAL_TOKEN_PREFIX.to_owned(),
+                });
+                listing
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::disk::DiskCache;
+    use futures_cpupool::CpuPool;
+    use std::u64;
+    use tempdir::TempDir;
+
+    fn entry(contents: &[u8]) -> CacheWrite {
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &contents[..], None).unwrap();
+        entry
+    }
+
+    fn read_obj(cache: Cache) -> Vec<u8> {
+        match cache {
+            Cache::Hit(mut entry) => {
+                let mut v = vec![];
+                entry.get_object("obj", &mut v).unwrap();
+                v
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_tiered_put_writes_both() {
+        let tempdir = TempDir::new("sccache_test_tiered").unwrap();
+        let pool = CpuPool::new(1);
+        let local = DiskCache::new(&tempdir.path().join("local"), u64::MAX, &pool);
+        let remote = Arc::new(DiskCache::new(&tempdir.path().join("remote"), u64::MAX, &pool));
+        let tiered = TieredCache::new(Arc::new(local.clone()), remote.clone());
+        tiered.put("abcdef", entry(b"hello")).wait().unwrap();
+        assert_eq!(read_obj(local.get("abcdef").wait().unwrap()), b"hello");
+        assert_eq!(read_obj(remote.get("abcdef").wait().unwrap()), b"hello");
+    }
+
+    #[test]
+    fn test_tiered_remote_hit_fills_local() {
+        let tempdir = TempDir::new("sccache_test_tiered").unwrap();
+        let pool = CpuPool::new(1);
+        let local = DiskCache::new(&tempdir.path().join("local"), u64::MAX, &pool);
+        let remote = Arc::new(DiskCache::new(&tempdir.path().join("remote"), u64::MAX, &pool));
+        remote.put("abcdef", entry(b"hello")).wait().unwrap();
+        let tiered = TieredCache::new(Arc::new(local.clone()), remote.clone());
+        match local.get("abcdef").wait().unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        assert_eq!(read_obj(tiered.get("abcdef").wait().unwrap()), b"hello");
+        assert_eq!(read_obj(local.get("abcdef").wait().unwrap()), b"hello");
+        // The second lookup should be served locally.
+        assert_eq!(read_obj(tiered.get("abcdef").wait().unwrap()), b"hello");
+        let stats = tiered.stats.borrow();
+        assert_eq!(stats.remote_hits, 1);
+        assert_eq!(stats.local_hits, 1);
+        assert_eq!(stats.local_fills, 1);
+    }
+
+    #[test]
+    fn test_tiered_list_both() {
+        let tempdir = TempDir::new("sccache_test_tiered").unwrap();
+        let pool = CpuPool::new(1);
+        let local = DiskCache::new(&tempdir.path().join("local"), u64::MAX, &pool);
+        let remote = Arc::new(DiskCache::new(&tempdir.path().join("remote"), u64::MAX, &pool));
+        local.put("aaaa", entry(b"local")).wait().unwrap();
+        remote.put("bbbb", entry(b"remote")).wait().unwrap();
+        let tiered = Arc::new(TieredCache::new(Arc::new(local.clone()), remote.clone()));
+        let mut keys = vec![];
+        let mut continuation = None;
+        loop {
+            let listing = tiered.list(continuation, 1).wait().unwrap();
+            keys.extend(listing.entries.into_iter().map(|e| e.key));
+            match listing.next {
+                Some(next) => continuation = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(keys, vec!["bbbb", "aaaa"]);
+        assert_eq!(crate::cache::clear_storage(tiered).wait().unwrap(), 2);
+        assert!(!local.exists("aaaa").wait().unwrap());
+        assert!(!remote.exists("bbbb").wait().unwrap());
+    }
+}
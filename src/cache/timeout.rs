@@ -0,0 +1,206 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    Cache,
+    CacheWrite,
+    EvictionStats,
+    Storage,
+    StorageListing,
+};
+use futures::future::Future;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_timer::Timeout;
+
+use crate::errors::*;
+
+/// Counters kept by a `TimeoutStorage`.
+#[derive(Default)]
+struct TimeoutStats {
+    /// Lookups that took longer than the read timeout.
+    read_timeouts: u64,
+    /// Writes that took longer than the write timeout.
+    write_timeouts: u64,
+}
+
+/// A `Storage` that gives up on lookups and writes to another `Storage` if
+/// they take too long.
+///
+/// Operations that time out fail with `ErrorKind::CacheTimedOut`.
+pub struct TimeoutStorage {
+    /// The storage to time operations on.
+    inner: Arc<dyn Storage>,
+    /// How long to wait for `get` and `peek`, if at all.
+    read_timeout: Option<Duration>,
+    /// How long to wait for `put`, if at all.
+    write_timeout: Option<Duration>,
+    /// Timeout statistics.
+    stats: Rc<RefCell<TimeoutStats>>,
+}
+
+impl TimeoutStorage {
+    /// Create a new `TimeoutStorage` that applies `read_timeout` and
+    /// `write_timeout` to `inner`.
+    pub fn new(inner: Arc<dyn Storage>,
+               read_timeout: Option<Duration>,
+               write_timeout: Option<Duration>) -> TimeoutStorage {
+        TimeoutStorage {
+            inner: inner,
+            read_timeout: read_timeout,
+            write_timeout: write_timeout,
+            stats: Rc::new(RefCell::new(TimeoutStats::default())),
+        }
+    }
+
+    fn read<T: 'static>(&self, future: SFuture<T>, operation: &'static str) -> SFuture<T> {
+        let stats = self.stats.clone();
+        with_timeout(future, self.read_timeout, operation, self.inner.location(), move || {
+            stats.borrow_mut().read_timeouts += 1;
+        })
+    }
+}
+
+/// Fail `future` with `ErrorKind::CacheTimedOut` if it does not finish within
+/// `timeout`, calling `on_timeout` when it doesn't.
+fn with_timeout<T, F>(future: SFuture<T>,
+                      timeout: Option<Duration>,
+                      operation: &'static str,
+                      location: String,
+                      on_timeout: F) -> SFuture<T>
+    where T: 'static,
+          F: FnOnce() + 'static,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return future,
+    };
+    Box::new(Timeout::new(future, timeout).map_err(move |err| {
+        if err.is_elapsed() {
+            on_timeout();
+            ErrorKind::CacheTimedOut(operation, location).into()
+        } else if err.is_inner() {
+            err.into_inner().unwrap()
+        } else {
+            err.into_timer().unwrap().into()
+        }
+    }))
+}
+
+impl Storage for TimeoutStorage {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        self.read(self.inner.get(key), "get")
+    }
+
+    fn peek(&self, key: &str) -> SFuture<Cache> {
+        self.read(self.inner.peek(key), "peek")
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let stats = self.stats.clone();
+        let location = self.inner.location();
+        with_timeout(self.inner.put(key, entry), self.write_timeout, "put", location, move || {
+            stats.borrow_mut().write_timeouts += 1;
+        })
+    }
+
+    fn location(&self) -> String {
+        self.inner.location()
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> {
+        self.inner.current_size()
+    }
+
+    fn max_size(&self) -> SFuture<Option<u64>> {
+        self.inner.max_size()
+    }
+
+    fn stats(&self) -> Vec<(String, String)> {
+        let stats = self.stats.borrow();
+        let location = self.inner.location();
+        let mut v = vec![
+            (format!("Cache read timeouts ({})", location), stats.read_timeouts.to_string()),
+            (format!("Cache write timeouts ({})", location), stats.write_timeouts.to_string()),
+        ];
+        v.extend(self.inner.stats());
+        v
+    }
+
+    fn take_eviction_stats(&self) -> EvictionStats {
+        self.inner.take_eviction_stats()
+    }
+
+    fn delete(&self, key: &str) -> SFuture<()> {
+        self.inner.delete(key)
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        self.inner.exists(key)
+    }
+
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        self.inner.list(continuation, max_keys)
+    }
+
+    fn collect_garbage(&self) -> SFuture<u64> {
+        self.inner.collect_garbage()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// A `Storage` whose lookups and writes never finish.
+    struct HangingStorage;
+
+    impl Storage for HangingStorage {
+        fn get(&self, _key: &str) -> SFuture<Cache> { Box::new(future::empty()) }
+        fn put(&self, _key: &str, _entry: CacheWrite) -> SFuture<Duration> {
+            Box::new(future::empty())
+        }
+        fn location(&self) -> String { "hanging".to_owned() }
+        fn current_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+        fn max_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+        fn delete(&self, _key: &str) -> SFuture<()> { f_ok(()) }
+        fn exists(&self, _key: &str) -> SFuture<bool> { f_ok(false) }
+        fn list(&self, _continuation: Option<String>, _max_keys: usize) -> SFuture<StorageListing> {
+            f_ok(StorageListing { entries: vec![], next: None })
+        }
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let mut runtime = Runtime::new().unwrap();
+        let storage = TimeoutStorage::new(Arc::new(HangingStorage),
+                                          Some(Duration::from_millis(10)),
+                                          Some(Duration::from_millis(10)));
+        match runtime.block_on(storage.get("abcdef")) {
+            Err(Error(ErrorKind::CacheTimedOut("get", _), _)) => {}
+            r => panic!("Unexpected get result: {:?}", r),
+        }
+        match runtime.block_on(storage.put("abcdef", CacheWrite::new())) {
+            Err(Error(ErrorKind::CacheTimedOut("put", _), _)) => {}
+            r => panic!("Unexpected put result: {:?}", r),
+        }
+        let stats = storage.stats.borrow();
+        assert_eq!(stats.read_timeouts, 1);
+        assert_eq!(stats.write_timeouts, 1);
+    }
+}
@@ -0,0 +1,651 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{
+    Cache,
+    CacheRead,
+    CacheWrite,
+    Storage,
+    StorageEntry,
+    StorageListing,
+};
+use crate::config::{WebdavCacheConfig, WebdavLayout};
+use futures::{Future, Stream};
+use hyperx::header::{Authorization, Basic, Bearer, ContentLength, ContentType, HttpDate};
+use hyper::{Method, StatusCode};
+use regex::Regex;
+use reqwest::header::HeaderValue;
+use reqwest::r#async::{Client, Request, Response};
+use reqwest::{Certificate, Url};
+use std::fs::File;
+use std::io::{self, Read};
+use std::time::{Duration, Instant, SystemTime};
+use url::percent_encoding::percent_decode;
+
+use crate::errors::*;
+use crate::util::{sha256_hex, xml_unescape, HeadersExt};
+
+/// The path of the output file that the action results stored by a
+/// `WebdavCache` in the Bazel layout use to refer to their entry.
+const ENTRY_OUTPUT_PATH: &str = "sccache-entry";
+
+/// The body of the `PROPFIND` request used to list a `WebdavCache`.
+const PROPFIND_BODY: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+<D:propfind xmlns:D=\"DAV:\"><D:prop>\
+<D:resourcetype/><D:getcontentlength/><D:getlastmodified/>\
+</D:prop></D:propfind>";
+
+/// How requests to a `WebdavCache` are authenticated.
+#[derive(Clone)]
+enum Auth {
+    None,
+    Basic { username: String, password: Option<String> },
+    Bearer(String),
+}
+
+/// A cache that stores entries on an HTTP server.
+///
+/// In the flat layout, each entry is stored at `<endpoint>/<key prefix><key>`,
+/// which works with WebDAV servers such as nginx's `ngx_http_dav_module`.
+/// In the Bazel layout, entries are stored as described by Bazel's HTTP
+/// remote cache protocol, for servers such as bazel-remote.
+#[derive(Clone)]
+pub struct WebdavCache {
+    /// The URL entries are stored under, without a trailing slash.
+    endpoint: String,
+    /// How entries are laid out under `endpoint`.
+    layout: WebdavLayout,
+    /// The prefix of the name of every entry stored by this cache.
+    key_prefix: String,
+    /// How to authenticate requests.
+    auth: Auth,
+    client: Client,
+}
+
+impl WebdavCache {
+    /// Create a new `WebdavCache` as described by `config`.
+    pub fn new(config: &WebdavCacheConfig) -> Result<WebdavCache> {
+        let endpoint = config.endpoint.trim_end_matches('/').to_owned();
+        Url::parse(&endpoint).chain_err(|| format!("invalid WebDAV endpoint: {}", endpoint))?;
+        let auth = match (&config.username, &config.token) {
+            (Some(_), Some(_)) => bail!("Only one of a WebDAV username and token may be set"),
+            (Some(username), None) => Auth::Basic {
+                username: username.clone(),
+                password: config.password.clone(),
+            },
+            (None, Some(token)) => Auth::Bearer(token.clone()),
+            (None, None) => Auth::None,
+        };
+        let mut builder = Client::builder();
+        if let Some(ref path) = config.ca_cert {
+            let mut pem = vec![];
+            File::open(path)
+                .and_then(|mut f| f.read_to_end(&mut pem))
+                .chain_err(|| format!("failed to read CA certificate {}", path.display()))?;
+            let cert = Certificate::from_pem(&pem)
+                .chain_err(|| format!("invalid CA certificate {}", path.display()))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        Ok(WebdavCache {
+            endpoint,
+            layout: config.layout,
+            key_prefix: config.key_prefix.clone().unwrap_or_default(),
+            auth,
+            client: builder.build()?,
+        })
+    }
+
+    /// The path under `endpoint` that the entry for `key` is looked up at.
+    fn entry_path(&self, key: &str) -> String {
+        match self.layout {
+            WebdavLayout::Flat => format!("{}{}", self.key_prefix, key),
+            WebdavLayout::Bazel => {
+                format!("ac/{}", sha256_hex(format!("{}{}", self.key_prefix, key).as_bytes()))
+            }
+        }
+    }
+
+    /// Make a `method` request for `path` under `endpoint`, with `body` if
+    /// there is one.
+    fn request(&self, method: Method, path: &str, body: Option<Vec<u8>>) -> Result<Request> {
+        let url = format!("{}/{}", self.endpoint, path);
+        let parsed = Url::parse(&url).chain_err(|| format!("invalid URL: {}", url))?;
+        let mut request = Request::new(method, parsed);
+        {
+            let headers = request.headers_mut();
+            match self.auth {
+                Auth::None => {}
+                Auth::Basic { ref username, ref password } => {
+                    headers.set(Authorization(Basic {
+                        username: username.clone(),
+                        password: password.clone(),
+                    }));
+                }
+                Auth::Bearer(ref token) => {
+                    headers.set(Authorization(Bearer { token: token.clone() }));
+                }
+            }
+            if let Some(ref body) = body {
+                headers.set(ContentType::octet_stream());
+                headers.set(ContentLength(body.len() as u64));
+            }
+        }
+        *request.body_mut() = body.map(Into::into);
+        Ok(request)
+    }
+
+    /// Send `request`.
+    fn send(&self, request: Request) -> SFuture<Response> {
+        let description = format!("{} {}", request.method(), request.url());
+        Box::new(self.client.execute(request).chain_err(move || {
+            format!("failed {}", description)
+        }))
+    }
+
+    /// Send a `method` request for `path` under `endpoint`, with `body` if
+    /// there is one.
+    fn execute(&self, method: Method, path: &str, body: Option<Vec<u8>>) -> SFuture<Response> {
+        let request = ftry!(self.request(method, path, body));
+        self.send(request)
+    }
+
+    /// Fetch the cache entry at `path` under `endpoint`.
+    fn fetch(&self, path: &str) -> SFuture<Cache> {
+        Box::new(self.execute(Method::GET, path, None).and_then(|res| -> SFuture<Cache> {
+            if res.status() == StatusCode::NOT_FOUND {
+                return f_ok(Cache::Miss);
+            }
+            Box::new(read_body(res).and_then(|body| {
+                Ok(Cache::Hit(CacheRead::from(io::Cursor::new(body))?))
+            }))
+        }))
+    }
+
+    /// Store `data` at `path` under `endpoint`.
+    fn store(&self, path: &str, data: Vec<u8>) -> SFuture<()> {
+        Box::new(self.execute(Method::PUT, path, Some(data)).and_then(check_status))
+    }
+}
+
+/// Fail unless `res` was successful.
+fn check_status(res: Response) -> Result<()> {
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+    }
+}
+
+/// Read the whole body of `res`, failing if it was not successful.
+fn read_body(res: Response) -> SFuture<Vec<u8>> {
+    if !res.status().is_success() {
+        return f_err(ErrorKind::BadHTTPStatus(res.status().clone()));
+    }
+    Box::new(res.into_body().fold(Vec::new(), |mut body, chunk| {
+        body.extend_from_slice(&chunk);
+        Ok::<_, reqwest::Error>(body)
+    }).chain_err(|| "failed to read HTTP body"))
+}
+
+/// Append `value` to `buf` as a protobuf varint.
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Append the length-delimited protobuf field `field` holding `data` to `buf`.
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// Read a protobuf varint from the start of `data`, and advance `data` past it.
+fn get_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    while shift < 64 {
+        let byte = match data.first() {
+            Some(&byte) => byte,
+            None => bail!("Truncated varint in action result"),
+        };
+        *data = &data[1..];
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+    bail!("Invalid varint in action result")
+}
+
+/// Get the length-delimited fields of the protobuf message `data`, as
+/// (field number, contents) pairs, skipping any other fields.
+fn bytes_fields(mut data: &[u8]) -> Result<Vec<(u64, &[u8])>> {
+    let mut fields = vec![];
+    while !data.is_empty() {
+        let key = get_varint(&mut data)?;
+        let len = match key & 7 {
+            0 => {
+                get_varint(&mut data)?;
+                continue;
+            }
+            1 => 8,
+            2 => get_varint(&mut data)? as usize,
+            5 => 4,
+            wire_type => bail!("Unsupported protobuf wire type {} in action result", wire_type),
+        };
+        if data.len() < len {
+            bail!("Truncated field in action result");
+        }
+        if key & 7 == 2 {
+            fields.push((key >> 3, &data[..len]));
+        }
+        data = &data[len..];
+    }
+    Ok(fields)
+}
+
+/// Make an action result with a single output file, `ENTRY_OUTPUT_PATH`,
+/// whose contents are the cache entry stored in the CAS with the SHA-256
+/// digest `hash` and `size`.
+// https://github.com/bazelbuild/remote-apis/blob/master/build/bazel/remote/execution/v2/remote_execution.proto
+fn action_result(hash: &str, size: u64) -> Vec<u8> {
+    // Digest { hash = 1, size_bytes = 2 }
+    let mut digest = vec![];
+    put_bytes_field(&mut digest, 1, hash.as_bytes());
+    put_varint(&mut digest, 2 << 3);
+    put_varint(&mut digest, size);
+    // OutputFile { path = 1, digest = 2 }
+    let mut output_file = vec![];
+    put_bytes_field(&mut output_file, 1, ENTRY_OUTPUT_PATH.as_bytes());
+    put_bytes_field(&mut output_file, 2, &digest);
+    // ActionResult { output_files = 2 }
+    let mut result = vec![];
+    put_bytes_field(&mut result, 2, &output_file);
+    result
+}
+
+/// Get the SHA-256 digest of the cache entry that the action result `data`
+/// refers to, if it refers to one.
+fn parse_action_result(data: &[u8]) -> Result<Option<String>> {
+    for (field, output_file) in bytes_fields(data)? {
+        if field != 2 {
+            continue;
+        }
+        let output_file = bytes_fields(output_file)?;
+        if !output_file.iter().any(|&(field, path)| field == 1 && path == ENTRY_OUTPUT_PATH.as_bytes()) {
+            continue;
+        }
+        for (field, digest) in output_file {
+            if field != 2 {
+                continue;
+            }
+            for (field, hash) in bytes_fields(digest)? {
+                if field == 1 {
+                    if hash.len() != 64 || !hash.iter().all(|b| b.is_ascii_hexdigit()) {
+                        bail!("Invalid digest in action result");
+                    }
+                    return Ok(Some(String::from_utf8_lossy(hash).into_owned()));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Get the name, size and modification time of each file, but not
+/// collection, in the response to a `PROPFIND` request.
+// http://www.webdav.org/specs/rfc4918.html#METHOD_PROPFIND
+fn parse_propfind(body: &str) -> Vec<StorageEntry> {
+    let response_regex = Regex::new(r"(?s)<(?:\w+:)?response\b[^>]*>(.*?)</(?:\w+:)?response>").unwrap();
+    let href_regex = Regex::new(r"(?s)<(?:\w+:)?href\b[^>]*>(.*?)</(?:\w+:)?href>").unwrap();
+    let collection_regex = Regex::new(r"<(?:\w+:)?collection\b").unwrap();
+    let length_regex = Regex::new(r"<(?:\w+:)?getcontentlength\b[^>]*>\s*(\d+)\s*<").unwrap();
+    let modified_regex = Regex::new(r"<(?:\w+:)?getlastmodified\b[^>/]*>([^<]*)<").unwrap();
+
+    let mut entries = vec![];
+    for response in response_regex.captures_iter(body) {
+        let response = &response[1];
+        let href = match href_regex.captures(response) {
+            Some(caps) => xml_unescape(caps[1].trim()),
+            None => continue,
+        };
+        if href.ends_with('/') || collection_regex.is_match(response) {
+            continue;
+        }
+        let name = href.rsplit('/').next().unwrap_or(&href);
+        let key = percent_decode(name.as_bytes()).decode_utf8_lossy().into_owned();
+        let size = length_regex.captures(response)
+            .and_then(|caps| caps[1].parse().ok())
+            .unwrap_or(0);
+        let last_modified = modified_regex.captures(response)
+            .and_then(|caps| caps[1].trim().parse::<HttpDate>().ok())
+            .map(SystemTime::from);
+        entries.push(StorageEntry { key, size, last_modified });
+    }
+    entries
+}
+
+impl Storage for WebdavCache {
+    fn get(&self, key: &str) -> SFuture<Cache> {
+        let path = self.entry_path(key);
+        match self.layout {
+            WebdavLayout::Flat => self.fetch(&path),
+            WebdavLayout::Bazel => {
+                let cache = self.clone();
+                Box::new(self.execute(Method::GET, &path, None).and_then(move |res| -> SFuture<Cache> {
+                    if res.status() == StatusCode::NOT_FOUND {
+                        return f_ok(Cache::Miss);
+                    }
+                    Box::new(read_body(res).and_then(move |body| -> SFuture<Cache> {
+                        match ftry!(parse_action_result(&body)) {
+                            Some(hash) => cache.fetch(&format!("cas/{}", hash)),
+                            None => f_ok(Cache::Miss),
+                        }
+                    }))
+                }))
+            }
+        }
+    }
+
+    fn put(&self, key: &str, entry: CacheWrite) -> SFuture<Duration> {
+        let start = Instant::now();
+        let data = ftry!(entry.finish());
+        let path = self.entry_path(key);
+        let stored: SFuture<()> = match self.layout {
+            WebdavLayout::Flat => self.store(&path, data),
+            WebdavLayout::Bazel => {
+                // Store the entry before the action result referring to it.
+                let hash = sha256_hex(&data);
+                let result = action_result(&hash, data.len() as u64);
+                let cache = self.clone();
+                Box::new(self.store(&format!("cas/{}", hash), data).and_then(move |()| {
+                    cache.store(&path, result)
+                }))
+            }
+        };
+        Box::new(stored.map(move |()| start.elapsed()))
+    }
+
+    fn location(&self) -> String {
+        match self.layout {
+            WebdavLayout::Flat => format!("WebDAV, endpoint: {}", self.endpoint),
+            WebdavLayout::Bazel => format!("WebDAV, endpoint: {}, Bazel layout", self.endpoint),
+        }
+    }
+
+    fn current_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+    fn max_size(&self) -> SFuture<Option<u64>> { f_ok(None) }
+
+    /// Remove the entry for `key`. Bazel remote caches evict entries
+    /// themselves, and don't support this.
+    fn delete(&self, key: &str) -> SFuture<()> {
+        if self.layout == WebdavLayout::Bazel {
+            return f_err(ErrorKind::UnsupportedStorageOperation("delete", self.location()));
+        }
+        Box::new(self.execute(Method::DELETE, &self.entry_path(key), None).and_then(|res| {
+            if res.status().is_success() || res.status() == StatusCode::NOT_FOUND {
+                Ok(())
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }))
+    }
+
+    fn exists(&self, key: &str) -> SFuture<bool> {
+        Box::new(self.execute(Method::HEAD, &self.entry_path(key), None).and_then(|res| {
+            if res.status().is_success() {
+                Ok(true)
+            } else if res.status() == StatusCode::NOT_FOUND {
+                Ok(false)
+            } else {
+                Err(ErrorKind::BadHTTPStatus(res.status().clone()).into())
+            }
+        }))
+    }
+
+    /// List the entries in the collection holding `key_prefix` with a
+    /// `PROPFIND` request, which the server must support. Every page lists
+    /// the whole collection, and returns the entries after `continuation`
+    /// in order. Bazel remote caches don't support listing.
+    fn list(&self, continuation: Option<String>, max_keys: usize) -> SFuture<StorageListing> {
+        if self.layout == WebdavLayout::Bazel {
+            return f_err(ErrorKind::UnsupportedStorageOperation("list", self.location()));
+        }
+        let (dir, name_prefix) = match self.key_prefix.rfind('/') {
+            Some(i) => (self.key_prefix[..=i].to_owned(), self.key_prefix[i + 1..].to_owned()),
+            None => (String::new(), self.key_prefix.clone()),
+        };
+        let propfind = Method::from_bytes(b"PROPFIND").unwrap();
+        let mut request = ftry!(self.request(propfind, &dir, Some(PROPFIND_BODY.as_bytes().to_vec())));
+        request.headers_mut().insert("depth", HeaderValue::from_static("1"));
+        request.headers_mut().insert("content-type", HeaderValue::from_static("application/xml"));
+        Box::new(self.send(request).and_then(read_body).and_then(move |body| -> Result<StorageListing> {
+            let body = String::from_utf8(body).chain_err(|| "invalid PROPFIND response")?;
+            let mut entries = parse_propfind(&body).into_iter()
+                .filter(|entry| entry.key.starts_with(&name_prefix))
+                .map(|mut entry| {
+                    entry.key = entry.key[name_prefix.len()..].to_owned();
+                    entry
+                })
+                .filter(|entry| continuation.as_ref().map_or(true, |c| entry.key > *c))
+                .collect::<Vec<_>>();
+            entries.sort_by(|a, b| a.key.cmp(&b.key));
+            let next = if entries.len() > max_keys {
+                entries.truncate(max_keys);
+                entries.last().map(|entry| entry.key.clone())
+            } else {
+                None
+            };
+            Ok(StorageListing { entries, next })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::serve_http;
+    use hyper::header::{HeaderValue, AUTHORIZATION};
+    use hyper::Body;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::UNIX_EPOCH;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Start an HTTP server that stores whatever is `PUT` to it in memory, and
+    /// return its address.
+    fn run_server(runtime: &mut Runtime, token: &'static str) -> String {
+        let objects = Arc::new(Mutex::new(HashMap::new()));
+        let url = serve_http(runtime, move |parts, body| {
+            let mut objects = objects.lock().unwrap();
+            let mut res = hyper::Response::new(Body::empty());
+            let expected = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+            let path = parts.uri.path().to_owned();
+            *res.status_mut() = if parts.headers.get(AUTHORIZATION) != Some(&expected) {
+                StatusCode::UNAUTHORIZED
+            } else if parts.method == Method::GET || parts.method == Method::HEAD {
+                match objects.get(&path) {
+                    Some(data) => {
+                        if parts.method == Method::GET {
+                            *res.body_mut() = Body::from(data.clone());
+                        }
+                        StatusCode::OK
+                    }
+                    None => StatusCode::NOT_FOUND,
+                }
+            } else if parts.method == Method::PUT {
+                objects.insert(path, body);
+                StatusCode::CREATED
+            } else if parts.method == Method::DELETE {
+                objects.remove(&path);
+                StatusCode::NO_CONTENT
+            } else if parts.method.as_str() == "PROPFIND" {
+                let mut body = r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">"#.to_owned();
+                body.push_str(&format!("<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+                                        <D:resourcetype><D:collection/></D:resourcetype>\
+                                        </D:prop></D:propstat></D:response>", path));
+                for (name, data) in objects.iter() {
+                    if name.starts_with(&path) && !name[path.len()..].contains('/') {
+                        body.push_str(&format!("<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+                                                <D:resourcetype/>\
+                                                <D:getcontentlength>{}</D:getcontentlength>\
+                                                </D:prop></D:propstat></D:response>",
+                                               name, data.len()));
+                    }
+                }
+                body.push_str("</D:multistatus>");
+                *res.body_mut() = Body::from(body);
+                StatusCode::MULTI_STATUS
+            } else {
+                StatusCode::METHOD_NOT_ALLOWED
+            };
+            res
+        });
+        format!("{}/cache/", url)
+    }
+
+    fn config(endpoint: String, token: &str) -> WebdavCacheConfig {
+        WebdavCacheConfig {
+            endpoint,
+            layout: WebdavLayout::Flat,
+            username: None,
+            password: None,
+            token: Some(token.to_owned()),
+            ca_cert: None,
+            key_prefix: None,
+            read_only: false,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_webdav_cache() {
+        let mut runtime = Runtime::new().unwrap();
+        let endpoint = run_server(&mut runtime, "secret");
+        let cache = WebdavCache::new(&config(endpoint.clone(), "secret")).unwrap();
+        match runtime.block_on(cache.get("abcdef")).unwrap() {
+            Cache::Miss => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"hello"[..], None).unwrap();
+        runtime.block_on(cache.put("abcdef", entry)).unwrap();
+        assert!(runtime.block_on(cache.exists("abcdef")).unwrap());
+        match runtime.block_on(cache.get("abcdef")).unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut v = vec![];
+                entry.get_object("obj", &mut v).unwrap();
+                assert_eq!(v, b"hello");
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"world"[..], None).unwrap();
+        runtime.block_on(cache.put("012345", entry)).unwrap();
+        let listing = runtime.block_on(cache.list(None, 1)).unwrap();
+        assert_eq!(listing.entries.iter().map(|e| &e.key[..]).collect::<Vec<_>>(), vec!["012345"]);
+        assert!(listing.entries[0].size > 0);
+        let listing = runtime.block_on(cache.list(listing.next, 1)).unwrap();
+        assert_eq!(listing.entries.iter().map(|e| &e.key[..]).collect::<Vec<_>>(), vec!["abcdef"]);
+        assert_eq!(listing.next, None);
+        runtime.block_on(cache.delete("abcdef")).unwrap();
+        assert!(!runtime.block_on(cache.exists("abcdef")).unwrap());
+
+        // Requests with the wrong credentials should fail rather than miss.
+        let cache = WebdavCache::new(&config(endpoint, "wrong")).unwrap();
+        match runtime.block_on(cache.get("abcdef")) {
+            Err(Error(ErrorKind::BadHTTPStatus(status), _))
+                if status == StatusCode::UNAUTHORIZED => {}
+            r => panic!("Unexpected cache result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_webdav_cache_bazel_layout() {
+        let mut runtime = Runtime::new().unwrap();
+        let endpoint = run_server(&mut runtime, "secret");
+        let mut config = config(endpoint, "secret");
+        config.layout = WebdavLayout::Bazel;
+        let cache = WebdavCache::new(&config).unwrap();
+        let mut entry = CacheWrite::new();
+        entry.put_object("obj", &mut &b"hello"[..], None).unwrap();
+        let data = entry.finish().unwrap();
+        runtime.block_on(cache.put("abcdef", CacheWrite::from_bytes(data.clone()))).unwrap();
+        assert!(runtime.block_on(cache.exists("abcdef")).unwrap());
+        assert!(!runtime.block_on(cache.exists("012345")).unwrap());
+        match runtime.block_on(cache.get("abcdef")).unwrap() {
+            Cache::Hit(mut entry) => {
+                let mut v = vec![];
+                entry.get_object("obj", &mut v).unwrap();
+                assert_eq!(v, b"hello");
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        // The entry is in the CAS under its digest.
+        match runtime.block_on(cache.fetch(&format!("cas/{}", sha256_hex(&data)))).unwrap() {
+            Cache::Hit(_) => {}
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
+        match runtime.block_on(cache.list(None, 10)) {
+            Err(Error(ErrorKind::UnsupportedStorageOperation(..), _)) => {}
+            r => panic!("Unexpected list result: {:?}", r.map(|l| l.entries)),
+        }
+    }
+
+    #[test]
+    fn test_action_result() {
+        let hash = sha256_hex(b"entry");
+        let result = action_result(&hash, 5);
+        assert_eq!(parse_action_result(&result).unwrap(), Some(hash));
+        assert_eq!(parse_action_result(&[]).unwrap(), None);
+        assert!(parse_action_result(&result[..result.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_propfind() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response>
+    <D:href>/cache/</D:href>
+    <D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>/cache/team%20a-abcdef</D:href>
+    <D:propstat>
+      <D:prop>
+        <D:resourcetype/>
+        <D:getcontentlength>1234</D:getcontentlength>
+        <D:getlastmodified>Fri, 01 Mar 2019 12:00:00 GMT</D:getlastmodified>
+      </D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+  </D:response>
+  <response xmlns="DAV:">
+    <href>http://example.com/cache/sub/</href>
+    <propstat><prop><resourcetype/></prop></propstat>
+  </response>
+</D:multistatus>"#;
+        let entries = parse_propfind(body);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "team a-abcdef");
+        assert_eq!(entries[0].size, 1234);
+        assert_eq!(entries[0].last_modified, Some(UNIX_EPOCH + Duration::from_secs(1551441600)));
+    }
+}
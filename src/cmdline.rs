@@ -20,7 +20,7 @@ use clap::{
 use crate::errors::*;
 use std::env;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use which::which_in;
 
 arg_enum!{
@@ -44,6 +44,14 @@ pub enum Command {
     StopServer,
     /// Zero cache statistics and exit.
     ZeroStats,
+    /// Remove the cache entry with the given key.
+    EvictKey(String),
+    /// Remove every entry from the cache.
+    ClearCache,
+    /// Remove unreferenced content-addressed blobs from the cache.
+    CollectGarbage,
+    /// Describe a cache entry, given its key or the path to an entry file.
+    InspectEntry(String),
     /// Show the status of the distributed client.
     DistStatus,
     /// Perform a login to authenticate for distributed compilation.
@@ -81,9 +89,15 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
              --start-server   'start background server'
              --stop-server    'stop background server'
              -z, --zero-stats 'zero statistics counters'
+             --clear-cache    'remove all entries from the cache'
+             --gc-cache       'remove content-addressed blobs no cache entry uses'
              --dist-auth      'authenticate for distributed compilation'
              --dist-status    'show status of the distributed client'"
                 )
+        .arg(Arg::from_usage("--evict-key <key> 'remove the cache entry with the given hash key'")
+             .required(false))
+        .arg(Arg::from_usage("--inspect-entry <entry> 'show the metadata and objects of a cache entry, given its hash key or file path'")
+             .required(false))
         .arg(Arg::from_usage("--package-toolchain <executable> <out> 'package toolchain for distributed compilation'")
              .required(false))
         .arg(Arg::from_usage("--stats-format  'set output format of statistics'")
@@ -96,6 +110,12 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                 )
 }
 
+/// Check that `key` looks like a cache key.
+fn is_valid_key(key: &str) -> bool {
+    // Storage backends shard entries by the leading characters of the key.
+    key.len() >= 3 && key.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 /// Parse the commandline into a `Command` to execute.
 pub fn parse() -> Result<Command> {
     trace!("parse");
@@ -141,6 +161,10 @@ pub fn parse() -> Result<Command> {
     let start_server = matches.is_present("start-server");
     let stop_server = matches.is_present("stop-server");
     let zero_stats = matches.is_present("zero-stats");
+    let evict_key = matches.value_of("evict-key");
+    let clear_cache = matches.is_present("clear-cache");
+    let gc_cache = matches.is_present("gc-cache");
+    let inspect_entry = matches.value_of("inspect-entry");
     let dist_auth = matches.is_present("dist-auth");
     let dist_status = matches.is_present("dist-status");
     let package_toolchain = matches.is_present("package-toolchain");
@@ -155,6 +179,10 @@ pub fn parse() -> Result<Command> {
         start_server,
         stop_server,
         zero_stats,
+        is_some(&evict_key),
+        clear_cache,
+        gc_cache,
+        is_some(&inspect_entry),
         package_toolchain,
         is_some(&cmd),
             ].iter()
@@ -173,6 +201,20 @@ pub fn parse() -> Result<Command> {
         Ok(Command::StopServer)
     } else if zero_stats {
         Ok(Command::ZeroStats)
+    } else if let Some(key) = evict_key {
+        if !is_valid_key(key) {
+            bail!("Invalid cache key: {}", key);
+        }
+        Ok(Command::EvictKey(key.to_owned()))
+    } else if clear_cache {
+        Ok(Command::ClearCache)
+    } else if gc_cache {
+        Ok(Command::CollectGarbage)
+    } else if let Some(entry) = inspect_entry {
+        if !is_valid_key(entry) && !Path::new(entry).is_file() {
+            bail!("Not a cache key or cache entry file: {}", entry);
+        }
+        Ok(Command::InspectEntry(entry.to_owned()))
     } else if dist_auth {
         Ok(Command::DistAuth)
     } else if dist_status {
@@ -14,6 +14,7 @@
 
 use atty::Stream;
 use byteorder::{BigEndian, ByteOrder};
+use crate::cache::{CacheEntryInfo, CacheRead};
 use crate::client::{connect_to_server, connect_with_retry, ServerConnection};
 use crate::cmdline::{Command, StatsFormat};
 use crate::compiler::ColorMode;
@@ -37,7 +38,7 @@ use tokio::runtime::current_thread::Runtime;
 use tokio_io::io::read_exact;
 use tokio_io::AsyncRead;
 use tokio_timer::Timeout;
-use crate::util::daemonize;
+use crate::util::{daemonize, fmt_duration_as_secs};
 use which::which_in;
 
 use crate::errors::*;
@@ -287,6 +288,82 @@ pub fn request_zero_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send an `EvictKey` request to the server, and return whether the entry was in the cache.
+pub fn request_evict_key(mut conn: ServerConnection, key: &str) -> Result<bool> {
+    debug!("request_evict_key");
+    let response = conn
+        .request(Request::EvictKey(key.to_owned()))
+        .chain_err(|| "Failed to send data to or receive data from server")?;
+    match response {
+        Response::KeyEvicted(existed) => Ok(existed),
+        Response::Unsupported(why) => bail!("{}", why),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+/// Send a `ClearCache` request to the server, and return the number of entries removed.
+pub fn request_clear_cache(mut conn: ServerConnection) -> Result<u64> {
+    debug!("request_clear_cache");
+    let response = conn
+        .request(Request::ClearCache)
+        .chain_err(|| "Failed to send data to or receive data from server")?;
+    match response {
+        Response::CacheCleared(removed) => Ok(removed),
+        Response::Unsupported(why) => bail!("{}", why),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+/// Send a `CollectGarbage` request to the server, and return the number of items removed.
+pub fn request_collect_garbage(mut conn: ServerConnection) -> Result<u64> {
+    debug!("request_collect_garbage");
+    let response = conn
+        .request(Request::CollectGarbage)
+        .chain_err(|| "Failed to send data to or receive data from server")?;
+    match response {
+        Response::GarbageCollected(removed) => Ok(removed),
+        Response::Unsupported(why) => bail!("{}", why),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
+/// Send an `InspectEntry` request to the server, and return a description of the entry, if found.
+pub fn request_inspect_entry(mut conn: ServerConnection, key: &str) -> Result<Option<CacheEntryInfo>> {
+    debug!("request_inspect_entry");
+    let response = conn
+        .request(Request::InspectEntry(key.to_owned()))
+        .chain_err(|| "Failed to send data to or receive data from server")?;
+    if let Response::EntryInspected(info) = response {
+        Ok(info)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
+/// Print `info`, which describes the cache entry `entry`.
+fn print_entry_info(entry: &str, info: &CacheEntryInfo) {
+    println!("{:<20} {}", "Cache entry", entry);
+    match info.metadata {
+        Some(ref m) => {
+            println!("{:<20} {} (unix time)", "Created", m.created);
+            println!("{:<20} {}", "sccache version", m.sccache_version);
+            println!("{:<20} {}", "Host", m.host);
+            println!("{:<20} {} ({})", "Compiler", m.compiler_kind, m.compiler_path);
+            println!("{:<20} {}", "Input", m.input);
+            println!("{:<20} {}", "Compile time", fmt_duration_as_secs(&m.compile_duration));
+            for (name, path) in &m.outputs {
+                println!("{:<20} {}", format!("Output `{}`", name), path);
+            }
+        }
+        None => println!("No metadata recorded"),
+    }
+    println!("Objects:");
+    for object in &info.objects {
+        let mode = object.mode.map(|m| format!("{:o}", m)).unwrap_or_else(|| "-".to_owned());
+        println!("  {:<18} {:>6} {:>12} bytes", object.name, mode, object.size);
+    }
+}
+
 /// Send a `GetStats` request to the server, and return the `ServerInfo` request if successful.
 pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_stats");
@@ -586,6 +663,44 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             let stats = request_zero_stats(conn).chain_err(|| "couldn't zero stats on server")?;
             stats.print();
         }
+        Command::EvictKey(key) => {
+            trace!("Command::EvictKey({})", key);
+            let conn = connect_or_start_server(get_port())?;
+            if request_evict_key(conn, &key).chain_err(|| "couldn't evict cache entry")? {
+                println!("Removed cache entry {}", key);
+            } else {
+                println!("No cache entry for {}", key);
+            }
+        }
+        Command::ClearCache => {
+            trace!("Command::ClearCache");
+            let conn = connect_or_start_server(get_port())?;
+            let removed = request_clear_cache(conn).chain_err(|| "couldn't clear cache")?;
+            println!("Removed {} cache entries", removed);
+        }
+        Command::CollectGarbage => {
+            trace!("Command::CollectGarbage");
+            let conn = connect_or_start_server(get_port())?;
+            let removed = request_collect_garbage(conn).chain_err(|| "couldn't collect cache garbage")?;
+            println!("Removed {} unreferenced cache blobs", removed);
+        }
+        Command::InspectEntry(entry) => {
+            trace!("Command::InspectEntry({})", entry);
+            // Entry files can be read directly, anything else is a key to
+            // look up in the server's cache.
+            let info = if Path::new(&entry).is_file() {
+                let f = File::open(&entry).chain_err(|| format!("couldn't open {}", entry))?;
+                let info = CacheRead::from(f).and_then(|mut e| e.info());
+                Some(info.chain_err(|| format!("couldn't read cache entry {}", entry))?)
+            } else {
+                let conn = connect_or_start_server(get_port())?;
+                request_inspect_entry(conn, &entry).chain_err(|| "couldn't inspect cache entry")?
+            };
+            match info {
+                Some(info) => print_entry_info(&entry, &info),
+                None => println!("No cache entry for {}", entry),
+            }
+        }
         #[cfg(feature = "dist-client")]
         Command::DistAuth => {
             use crate::config;
@@ -301,11 +301,20 @@ impl<T, I> CompilerHasher<T> for CCompilerHasher<I>
         ColorMode::Auto
     }
 
+    fn kind(&self) -> CompilerKind { CompilerKind::C(self.compiler.kind()) }
+
+    fn executable(&self) -> &Path { &self.executable }
+
     fn output_pretty(&self) -> Cow<'_, str>
     {
         self.parsed_args.output_pretty()
     }
 
+    fn input_pretty(&self) -> Cow<'_, str>
+    {
+        self.parsed_args.input.to_string_lossy()
+    }
+
     fn box_clone(&self) -> Box<dyn CompilerHasher<T>>
     {
         Box::new((*self).clone())
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheWrite, Storage};
+use crate::cache::{Cache, CacheEntryMetadata, CacheRead, CacheWrite, Storage};
 use crate::compiler::c::{CCompiler, CCompilerKind};
 use crate::compiler::clang::Clang;
 use crate::compiler::diab::Diab;
@@ -38,11 +38,10 @@ use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
 use std::str;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempdir::TempDir;
 use tempfile::NamedTempFile;
-use tokio_timer::Timeout;
-use crate::util::{fmt_duration_as_secs, ref_env, run_input_output};
+use crate::util::{fmt_duration_as_secs, hostname, ref_env, run_input_output};
 
 use crate::errors::*;
 
@@ -132,6 +131,12 @@ where
     /// Return the state of any `--color` option passed to the compiler.
     fn color_mode(&self) -> ColorMode;
 
+    /// Return the kind of compiler.
+    fn kind(&self) -> CompilerKind;
+
+    /// Return the path to the compiler executable.
+    fn executable(&self) -> &Path;
+
     /// Look up a cached compile result in `storage`. If not found, run the
     /// compile and store the result.
     fn get_cached_or_compile(
@@ -147,6 +152,17 @@ where
     ) -> SFuture<(CompileResult, process::Output)> {
         let out_pretty = self.output_pretty().into_owned();
         debug!("[{}]: get_cached_or_compile: {:?}", out_pretty, arguments);
+        let mut metadata = CacheEntryMetadata {
+            sccache_version: env!("CARGO_PKG_VERSION").to_owned(),
+            compiler_kind: match self.kind() {
+                CompilerKind::C(kind) => format!("{:?}", kind),
+                CompilerKind::Rust => "Rust".to_owned(),
+            },
+            compiler_path: self.executable().display().to_string(),
+            input: self.input_pretty().into_owned(),
+            host: hostname().unwrap_or_default(),
+            ..Default::default()
+        };
         let start = Instant::now();
         let may_dist = match dist_client {
             Ok(Some(_)) => true,
@@ -185,11 +201,6 @@ where
                 storage.get(&key)
             };
 
-            // Set a maximum time limit for the cache to respond before we forge
-            // ahead ourselves with a compilation.
-            let timeout = Duration::new(60, 0);
-            let cache_status = Timeout::new(cache_status, timeout);
-
             // Check the result of the cache lookup.
             Box::new(cache_status.then(move |result| {
                 let duration = start.elapsed();
@@ -198,18 +209,17 @@ where
                     .map(|(key, path)| (key.to_string(), cwd.join(path)))
                     .collect::<HashMap<_, _>>();
 
-                let miss_type = match result {
+                let lookup: SFuture<CacheLookup> = match result {
                     Ok(Cache::Hit(mut entry)) => {
                         debug!(
                             "[{}]: Cache hit in {}",
                             out_pretty,
                             fmt_duration_as_secs(&duration)
                         );
-                        let mut stdout = Vec::new();
-                        let mut stderr = Vec::new();
-                        drop(entry.get_object("stdout", &mut stdout));
-                        drop(entry.get_object("stderr", &mut stderr));
-                        let write = pool.spawn_fn(move || {
+                        let outputs = outputs.clone();
+                        let write = pool.spawn_fn(move || -> Result<_> {
+                            let stdout = get_optional_object(&mut entry, "stdout")?;
+                            let stderr = get_optional_object(&mut entry, "stderr")?;
                             for (key, path) in &outputs {
                                 let dir = match path.parent() {
                                     Some(d) => d,
@@ -225,15 +235,31 @@ where
                                     set_file_mode(&path, mode)?;
                                 }
                             }
-                            Ok(())
+                            Ok(process::Output {
+                                status: exit_status(0),
+                                stdout: stdout,
+                                stderr: stderr,
+                            })
                         });
-                        let output = process::Output {
-                            status: exit_status(0),
-                            stdout: stdout,
-                            stderr: stderr,
-                        };
-                        let result = CompileResult::CacheHit(duration);
-                        return Box::new(write.map(|_| (result, output))) as SFuture<_>;
+                        let storage = storage.clone();
+                        let key = key.clone();
+                        let out_pretty = out_pretty.clone();
+                        Box::new(write.then(move |res| -> SFuture<_> {
+                            match res {
+                                Ok(output) => f_ok(CacheLookup::Hit(output)),
+                                Err(Error(ErrorKind::CorruptCacheEntry(why), _)) => {
+                                    error!("[{}]: Corrupt cache entry: {}", out_pretty, why);
+                                    evict_corrupt_entry(&storage, &key, out_pretty)
+                                }
+                                // Another compile replaced the entry while it
+                                // was being read.
+                                Err(Error(ErrorKind::CacheEntryChanged(_), _)) => {
+                                    debug!("[{}]: Cache entry changed while being read", out_pretty);
+                                    f_ok(CacheLookup::Miss(MissType::Normal))
+                                }
+                                Err(e) => f_err(e),
+                            }
+                        }))
                     }
                     Ok(Cache::Miss) => {
                         debug!(
@@ -241,7 +267,7 @@ where
                             out_pretty,
                             fmt_duration_as_secs(&duration)
                         );
-                        MissType::Normal
+                        f_ok(CacheLookup::Miss(MissType::Normal))
                     }
                     Ok(Cache::Recache) => {
                         debug!(
@@ -249,115 +275,136 @@ where
                             out_pretty,
                             fmt_duration_as_secs(&duration)
                         );
-                        MissType::ForcedRecache
+                        f_ok(CacheLookup::Miss(MissType::ForcedRecache))
+                    }
+                    Err(Error(ErrorKind::CacheTimedOut(..), _)) => {
+                        debug!(
+                            "[{}]: Cache timed out {}",
+                            out_pretty,
+                            fmt_duration_as_secs(&duration)
+                        );
+                        f_ok(CacheLookup::Miss(MissType::TimedOut))
                     }
                     Err(err) => {
-                        if err.is_elapsed() {
-                            debug!(
-                                "[{}]: Cache timed out {}",
-                                out_pretty,
-                                fmt_duration_as_secs(&duration)
-                            );
-                            MissType::TimedOut
-                        } else {
-                            error!("[{}]: Cache read error: {}", out_pretty, err);
-                            if err.is_inner() {
-                                let err = err.into_inner().unwrap();
+                        error!("[{}]: Cache read error: {}", out_pretty, err);
+                        match err {
+                            Error(ErrorKind::CorruptCacheEntry(_), _) => {
+                                evict_corrupt_entry(&storage, &key, out_pretty.clone())
+                            }
+                            err => {
                                 for e in err.iter().skip(1) {
                                     error!("[{}] \t{}", out_pretty, e);
                                 }
+                                f_ok(CacheLookup::Miss(MissType::CacheReadError))
                             }
-                            MissType::CacheReadError
                         }
                     }
                 };
 
-                // Cache miss, so compile it.
-                let start = Instant::now();
-                let compile = dist_or_local_compile(
-                    dist_client,
-                    creator,
-                    cwd,
-                    compilation,
-                    weak_toolchain_key,
-                    out_pretty.clone(),
-                );
+                Box::new(lookup.and_then(move |lookup| -> SFuture<_> {
+                    let miss_type = match lookup {
+                        CacheLookup::Hit(output) => {
+                            return f_ok((CompileResult::CacheHit(duration), output));
+                        }
+                        CacheLookup::Miss(miss_type) => miss_type,
+                    };
 
-                Box::new(
-                    compile.and_then(move |(cacheable, dist_type, compiler_result)| {
-                        let duration = start.elapsed();
-                        if !compiler_result.status.success() {
+                    // Cache miss, so compile it.
+                    let start = Instant::now();
+                    let compile = dist_or_local_compile(
+                        dist_client,
+                        creator,
+                        cwd,
+                        compilation,
+                        weak_toolchain_key,
+                        out_pretty.clone(),
+                    );
+
+                    Box::new(
+                        compile.and_then(move |(cacheable, dist_type, compiler_result)| {
+                            let duration = start.elapsed();
+                            if !compiler_result.status.success() {
+                                debug!(
+                                    "[{}]: Compiled but failed, not storing in cache",
+                                    out_pretty
+                                );
+                                return f_ok((CompileResult::CompileFailed, compiler_result))
+                                    as SFuture<_>;
+                            }
+                            if cacheable != Cacheable::Yes {
+                                // Not cacheable
+                                debug!("[{}]: Compiled but not cacheable", out_pretty);
+                                return f_ok((CompileResult::NotCacheable, compiler_result));
+                            }
                             debug!(
-                                "[{}]: Compiled but failed, not storing in cache",
-                                out_pretty
+                                "[{}]: Compiled in {}, storing in cache",
+                                out_pretty,
+                                fmt_duration_as_secs(&duration)
                             );
-                            return f_ok((CompileResult::CompileFailed, compiler_result))
-                                as SFuture<_>;
-                        }
-                        if cacheable != Cacheable::Yes {
-                            // Not cacheable
-                            debug!("[{}]: Compiled but not cacheable", out_pretty);
-                            return f_ok((CompileResult::NotCacheable, compiler_result));
-                        }
-                        debug!(
-                            "[{}]: Compiled in {}, storing in cache",
-                            out_pretty,
-                            fmt_duration_as_secs(&duration)
-                        );
-                        let write = pool.spawn_fn(move || -> Result<_> {
-                            let mut entry = CacheWrite::new();
-                            for (key, path) in &outputs {
-                                let mut f = File::open(&path)?;
-                                let mode = get_file_mode(&f)?;
-                                entry.put_object(key, &mut f, mode).chain_err(|| {
-                                    format!("failed to put object `{:?}` in zip", path)
-                                })?;
-                            }
-                            Ok(entry)
-                        });
-                        let write = write.chain_err(|| "failed to zip up compiler outputs");
-                        let o = out_pretty.clone();
-                        Box::new(
-                            write
-                                .and_then(move |mut entry| {
-                                    if !compiler_result.stdout.is_empty() {
-                                        let mut stdout = &compiler_result.stdout[..];
-                                        entry.put_object("stdout", &mut stdout, None)?;
-                                    }
-                                    if !compiler_result.stderr.is_empty() {
-                                        let mut stderr = &compiler_result.stderr[..];
-                                        entry.put_object("stderr", &mut stderr, None)?;
-                                    }
+                            metadata.created = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .map(|d| d.as_secs())
+                                .unwrap_or(0);
+                            metadata.outputs = outputs.iter()
+                                .map(|(key, path)| (key.clone(), path.display().to_string()))
+                                .collect();
+                            metadata.compile_duration = duration;
+                            let write = pool.spawn_fn(move || -> Result<_> {
+                                let mut entry = CacheWrite::new();
+                                entry.set_metadata(metadata)?;
+                                for (key, path) in &outputs {
+                                    let mut f = File::open(&path)?;
+                                    let mode = get_file_mode(&f)?;
+                                    entry.put_object(key, &mut f, mode).chain_err(|| {
+                                        format!("failed to put object `{:?}` in zip", path)
+                                    })?;
+                                }
+                                Ok(entry)
+                            });
+                            let write = write.chain_err(|| "failed to zip up compiler outputs");
+                            let o = out_pretty.clone();
+                            Box::new(
+                                write
+                                    .and_then(move |mut entry| {
+                                        if !compiler_result.stdout.is_empty() {
+                                            let mut stdout = &compiler_result.stdout[..];
+                                            entry.put_object("stdout", &mut stdout, None)?;
+                                        }
+                                        if !compiler_result.stderr.is_empty() {
+                                            let mut stderr = &compiler_result.stderr[..];
+                                            entry.put_object("stderr", &mut stderr, None)?;
+                                        }
 
-                                    // Try to finish storing the newly-written cache
-                                    // entry. We'll get the result back elsewhere.
-                                    let future = storage.put(&key, entry).then(move |res| {
-                                        match res {
-                                            Ok(_) => debug!(
-                                                "[{}]: Stored in cache successfully!",
-                                                out_pretty
-                                            ),
-                                            Err(ref e) => debug!(
-                                                "[{}]: Cache write error: {:?}",
-                                                out_pretty, e
+                                        // Try to finish storing the newly-written cache
+                                        // entry. We'll get the result back elsewhere.
+                                        let future = storage.put(&key, entry).then(move |res| {
+                                            match res {
+                                                Ok(_) => debug!(
+                                                    "[{}]: Stored in cache successfully!",
+                                                    out_pretty
+                                                ),
+                                                Err(ref e) => debug!(
+                                                    "[{}]: Cache write error: {:?}",
+                                                    out_pretty, e
+                                                ),
+                                            }
+                                            res.map(|duration| CacheWriteInfo {
+                                                object_file_pretty: out_pretty,
+                                                duration: duration,
+                                            })
+                                        });
+                                        let future = Box::new(future);
+                                        Ok((
+                                            CompileResult::CacheMiss(
+                                                miss_type, dist_type, duration, future,
                                             ),
-                                        }
-                                        res.map(|duration| CacheWriteInfo {
-                                            object_file_pretty: out_pretty,
-                                            duration: duration,
-                                        })
-                                    });
-                                    let future = Box::new(future);
-                                    Ok((
-                                        CompileResult::CacheMiss(
-                                            miss_type, dist_type, duration, future,
-                                        ),
-                                        compiler_result,
-                                    ))
-                                }).chain_err(move || format!("failed to store `{}` to cache", o)),
-                        )
-                    }),
-                )
+                                            compiler_result,
+                                        ))
+                                    }).chain_err(move || format!("failed to store `{}` to cache", o)),
+                            )
+                        }),
+                    )
+                }))
             }))
         }))
     }
@@ -368,9 +415,41 @@ where
     /// artifact generation.
     fn output_pretty(&self) -> Cow<'_, str>;
 
+    /// A descriptive string about the input file being compiled, recorded in
+    /// cache entry metadata.
+    fn input_pretty(&self) -> Cow<'_, str>;
+
     fn box_clone(&self) -> Box<dyn CompilerHasher<T>>;
 }
 
+/// The outcome of looking up a compilation in the cache.
+enum CacheLookup {
+    /// The compiler outputs were restored from the cache.
+    Hit(process::Output),
+    /// The compilation needs to be run.
+    Miss(MissType),
+}
+
+/// Read the object `name` from `entry`, or nothing if it isn't present.
+fn get_optional_object(entry: &mut CacheRead, name: &str) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    if entry.object_names()?.iter().any(|n| n == name) {
+        entry.get_object(name, &mut data)?;
+    }
+    Ok(data)
+}
+
+/// Remove the corrupt cache entry at `key` from `storage`, and treat the
+/// lookup as a miss.
+fn evict_corrupt_entry(storage: &Arc<dyn Storage>, key: &str, out_pretty: String) -> SFuture<CacheLookup> {
+    Box::new(storage.delete(key).then(move |res| {
+        if let Err(e) = res {
+            warn!("[{}]: Failed to evict corrupt cache entry: {}", out_pretty, e);
+        }
+        Ok(CacheLookup::Miss(MissType::CorruptEntry))
+    }))
+}
+
 #[cfg(not(feature = "dist-client"))]
 fn dist_or_local_compile<T>(
     _dist_client: Result<Option<Arc<dyn dist::Client>>>,
@@ -685,6 +764,8 @@ pub enum MissType {
     TimedOut,
     /// Error reading from cache
     CacheReadError,
+    /// The cache entry failed its integrity check and was treated as a miss.
+    CorruptEntry,
 }
 
 /// Information about a successful cache write.
@@ -1240,6 +1321,17 @@ LLVM version: 6.0",
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+        // The cache entry should record how it was created.
+        let key = storage.list(None, 10).wait().unwrap().entries[0].key.clone();
+        match storage.peek(&key).wait().unwrap() {
+            Cache::Hit(entry) => {
+                let metadata = entry.metadata().unwrap();
+                assert_eq!(metadata.compiler_kind, "GCC");
+                assert_eq!(metadata.input, "foo.c");
+                assert_eq!(metadata.outputs.get("obj"), Some(&obj.display().to_string()));
+            }
+            c => panic!("Unexpected cache result: {:?}", c),
+        }
         // Now compile again, which should be a cache hit.
         fs::remove_file(&obj).unwrap();
         // The preprocessor invocation.
@@ -1548,6 +1640,108 @@ LLVM version: 6.0",
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_compiler_get_cached_or_compile_corrupt_entry() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = CpuPool::new(1);
+        let mut runtime = Runtime::new().unwrap();
+        let cache_dir = f.tempdir.path().join("cache");
+        let storage = DiskCache::new(&cache_dir, u64::MAX, &pool);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(&creator, &f.bins[0], &[], &pool)
+            .wait()
+            .unwrap();
+        const COMPILER_STDOUT: &'static [u8] = b"compiler stdout";
+        const COMPILER_STDERR: &'static [u8] = b"compiler stderr";
+        // The compiler should be invoked twice, since the cache entry will
+        // be corrupt the second time.
+        let obj = f.tempdir.path().join("foo.o");
+        for _ in 0..2 {
+            // The preprocessor invocation.
+            next_command(
+                &creator,
+                Ok(MockChild::new(exit_status(0), "preprocessor output", "")),
+            );
+            // The compiler invocation.
+            let o = obj.clone();
+            next_command_calls(&creator, move |_| {
+                // Pretend to compile something.
+                let mut f = File::create(&o)?;
+                f.write_all(b"file contents")?;
+                Ok(MockChild::new(
+                    exit_status(0),
+                    COMPILER_STDOUT,
+                    COMPILER_STDERR,
+                ))
+            });
+        }
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.c", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o @ _ => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, _) = runtime
+            .block_on(future::lazy(|| {
+                hasher.get_cached_or_compile(
+                    Ok(None),
+                    creator.clone(),
+                    storage.clone(),
+                    arguments.clone(),
+                    cwd.to_path_buf(),
+                    vec![],
+                    CacheControl::Default,
+                    pool.clone(),
+                )
+            })).unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        // Flip a bit in the stored object.
+        let key = storage.list(None, 10).wait().unwrap().entries[0].key.clone();
+        let entry_path = cache_dir.join(&key[0..1]).join(&key[1..2]).join(&key);
+        let mut data = fs::read(&entry_path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        fs::write(&entry_path, &data).unwrap();
+        // Now compile again, which should find the corrupt entry and recompile.
+        fs::remove_file(&obj).unwrap();
+        let (cached, res) = hasher2
+            .get_cached_or_compile(
+                Ok(None),
+                creator,
+                storage.clone(),
+                arguments,
+                cwd.to_path_buf(),
+                vec![],
+                CacheControl::Default,
+                pool,
+            ).wait()
+            .unwrap();
+        assert_eq!(fs::read(&obj).unwrap(), b"file contents");
+        match cached {
+            CompileResult::CacheMiss(MissType::CorruptEntry, DistType::NoDist, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => assert!(false, "Unexpected compile result: {:?}", cached),
+        }
+        assert_eq!(exit_status(0), res.status);
+        assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
+        assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+        // The corrupt entry was replaced by the new compile.
+        assert_ne!(fs::read(&entry_path).unwrap(), data);
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile_preprocessor_error() {
         drop(env_logger::try_init());
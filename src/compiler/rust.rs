@@ -116,6 +116,8 @@ pub struct RustHasher {
 pub struct ParsedArguments {
     /// The full commandline, with all parsed aguments
     arguments: Vec<Argument<ArgData>>,
+    /// The input file.
+    input: PathBuf,
     /// The location of compiler outputs.
     output_dir: PathBuf,
     /// Paths to extern crates used in the compile.
@@ -860,9 +862,7 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
             };
         }
     };
-    // We don't actually save the input value, but there needs to be one.
     req!(input);
-    drop(input);
     req!(output_dir);
     req!(emit);
     req!(crate_name);
@@ -916,6 +916,7 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     externs.sort();
     CompilerArguments::Ok(ParsedArguments {
         arguments: args,
+        input: input.into(),
         output_dir: output_dir.into(),
         crate_types,
         externs: externs,
@@ -950,6 +951,7 @@ impl<T> CompilerHasher<T> for RustHasher
             parsed_args:
                 ParsedArguments {
                     arguments,
+                    input: _,
                     output_dir,
                     externs,
                     crate_link_paths,
@@ -1146,10 +1148,18 @@ impl<T> CompilerHasher<T> for RustHasher
         self.parsed_args.color_mode
     }
 
+    fn kind(&self) -> CompilerKind { CompilerKind::Rust }
+
+    fn executable(&self) -> &Path { &self.executable }
+
     fn output_pretty(&self) -> Cow<'_, str> {
         Cow::Borrowed(&self.parsed_args.crate_name)
     }
 
+    fn input_pretty(&self) -> Cow<'_, str> {
+        self.parsed_args.input.to_string_lossy()
+    }
+
     fn box_clone(&self) -> Box<dyn CompilerHasher<T>> {
         Box::new((*self).clone())
     }
@@ -2083,6 +2093,7 @@ c:/foo/bar.rs:
                                         ArgData::PassThrough("abc".into()),
                                         ArgDisposition::Separated),
                 ],
+                input: "a".into(),
                 output_dir: "foo/".into(),
                 externs: vec!["bar.rlib".into()],
                 crate_link_paths: vec![],
@@ -26,6 +26,7 @@ use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::str::FromStr;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use crate::errors::*;
 
@@ -39,6 +40,23 @@ const APP_NAME: &str = "sccache";
 const DIST_APP_NAME: &str = "sccache-dist-client";
 const TEN_GIGS: u64 = 10 * 1024 * 1024 * 1024;
 
+/// How long to wait for a cache lookup by default, in seconds.
+const DEFAULT_CACHE_READ_TIMEOUT: u64 = 30;
+/// How long to wait for a cache write by default, in seconds.
+const DEFAULT_CACHE_WRITE_TIMEOUT: u64 = 120;
+/// How old an unreferenced content-addressed blob must be for garbage
+/// collection to remove it by default, in seconds.
+const DEFAULT_CAS_GC_GRACE_PERIOD: u64 = 60 * 60;
+/// The zstd compression level for new cache entries, by default.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+/// How many operations on a remote cache must fail in a row before it is
+/// skipped, by default: 0, so that failover is off unless it is configured.
+const DEFAULT_FAILOVER_THRESHOLD: u32 = 0;
+/// How long to skip a failing remote cache for at first, in seconds.
+const DEFAULT_FAILOVER_RETRY_INTERVAL: u64 = 30;
+/// The longest time to skip a failing remote cache for, in seconds.
+const DEFAULT_FAILOVER_MAX_RETRY_INTERVAL: u64 = 600;
+
 const MOZILLA_OAUTH_PKCE_CLIENT_ID: &str = "F1VVD6nRTckSVrviMRaOdLBWIk1AvHYo";
 // The sccache audience is an API set up in auth0 for sccache to allow 7 day expiry,
 // the openid scope allows us to query the auth0 /userinfo endpoint which contains
@@ -65,6 +83,15 @@ pub fn default_dist_cache_dir() -> PathBuf {
 fn default_disk_cache_size() -> u64 { TEN_GIGS }
 fn default_toolchain_cache_size() -> u64 { TEN_GIGS }
 
+/// Turn a configured timeout in seconds into a `Duration`, falling back to
+/// `default` if it is not set. A timeout of zero means no timeout.
+fn timeout_duration(secs: Option<u64>, default: u64) -> Option<Duration> {
+    match secs.unwrap_or(default) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
 pub fn parse_size(val: &str) -> Option<u64> {
     let re = Regex::new(r"^(\d+)([KMGT])$").expect("Fixed regex parse failure");
     re.captures(val)
@@ -129,7 +156,28 @@ impl HTTPUrl {
 #[derive(Debug, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct AzureCacheConfig;
+pub struct AzureCacheConfig {
+    /// A connection string giving the account, blob endpoint and credentials to use.
+    pub connection_string: Option<String>,
+    /// The storage account, when there is no connection string.
+    pub account: Option<String>,
+    /// The blob service endpoint, when there is no connection string. Defaults to
+    /// `https://<account>.blob.core.windows.net/`.
+    pub endpoint: Option<String>,
+    /// The container to store entries in. If not set, the endpoint must include it.
+    pub container: Option<String>,
+    /// A shared access signature to authorize requests with instead of an account key.
+    pub sas_token: Option<String>,
+    /// A prefix to add to the name of every entry stored in this cache.
+    pub key_prefix: Option<String>,
+    /// Read from this cache but never write to it.
+    #[serde(default)]
+    pub read_only: bool,
+    /// How long to wait for a lookup, in seconds.
+    pub read_timeout: Option<u64>,
+    /// How long to wait for a write, in seconds.
+    pub write_timeout: Option<u64>,
+}
 
 #[derive(Debug, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
@@ -139,6 +187,25 @@ pub struct DiskCacheConfig {
     pub dir: PathBuf,
     // TODO: use deserialize_with to allow human-readable sizes in toml
     pub size: u64,
+    /// Keep this cache in front of the configured remote cache, rather than
+    /// only using it when no remote cache is available.
+    pub tiered: bool,
+    /// Read from this cache but never write to it.
+    pub read_only: bool,
+    /// How long to wait for a lookup, in seconds.
+    pub read_timeout: Option<u64>,
+    /// How long to wait for a write, in seconds.
+    pub write_timeout: Option<u64>,
+    /// How to choose the entries to evict when the cache is full.
+    pub eviction_policy: DiskEvictionPolicy,
+    /// Start evicting entries when adding one would take the cache above
+    /// this percentage of `size`, or of `max_entries`.
+    pub high_watermark: u8,
+    /// Once eviction starts, evict entries until the cache is within this
+    /// percentage of `size` and of `max_entries`.
+    pub low_watermark: u8,
+    /// The maximum number of entries in the cache, if it is limited.
+    pub max_entries: Option<u64>,
 }
 
 impl Default for DiskCacheConfig {
@@ -146,10 +213,42 @@ impl Default for DiskCacheConfig {
         DiskCacheConfig {
             dir: default_disk_cache_dir(),
             size: default_disk_cache_size(),
+            tiered: false,
+            read_only: false,
+            read_timeout: None,
+            write_timeout: None,
+            eviction_policy: DiskEvictionPolicy::Lru,
+            high_watermark: 100,
+            low_watermark: 100,
+            max_entries: None,
         }
     }
 }
 
+impl DiskCacheConfig {
+    /// The lookup and write timeouts for this cache.
+    pub fn timeouts(&self) -> (Option<Duration>, Option<Duration>) {
+        (timeout_duration(self.read_timeout, DEFAULT_CACHE_READ_TIMEOUT),
+         timeout_duration(self.write_timeout, DEFAULT_CACHE_WRITE_TIMEOUT))
+    }
+}
+
+/// How the local disk cache chooses the entries to evict when it is full.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum DiskEvictionPolicy {
+    /// Evict the least-recently-used entries first.
+    #[serde(rename = "lru")]
+    Lru,
+    /// Evict the least-frequently-used entries first.
+    #[serde(rename = "lfu")]
+    Lfu,
+    /// Evict the largest entries first.
+    #[serde(rename = "size")]
+    Size,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -168,6 +267,15 @@ pub struct GCSCacheConfig {
     pub cred_path: Option<PathBuf>,
     pub url: Option<String>,
     pub rw_mode: GCSCacheRWMode,
+    /// A prefix to add to the name of every entry stored in this cache.
+    pub key_prefix: Option<String>,
+    /// Read from this cache but never write to it.
+    #[serde(default)]
+    pub read_only: bool,
+    /// How long to wait for a lookup, in seconds.
+    pub read_timeout: Option<u64>,
+    /// How long to wait for a write, in seconds.
+    pub write_timeout: Option<u64>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -175,13 +283,55 @@ pub struct GCSCacheConfig {
 #[serde(deny_unknown_fields)]
 pub struct MemcachedCacheConfig {
     pub url: String,
+    /// How long Memcached should keep entries, in seconds. Entries are kept
+    /// until Memcached evicts them if this is unset or 0.
+    pub ttl: Option<u64>,
+    /// A prefix to add to the name of every entry stored in this cache.
+    pub key_prefix: Option<String>,
+    /// Read from this cache but never write to it.
+    #[serde(default)]
+    pub read_only: bool,
+    /// How long to wait for a lookup, in seconds.
+    pub read_timeout: Option<u64>,
+    /// How long to wait for a write, in seconds.
+    pub write_timeout: Option<u64>,
+}
+
+/// Deserialize the TTL of a Redis cache, which must not be 0.
+fn deserialize_redis_ttl<'a, D>(deserializer: D) -> StdResult<Option<u64>, D::Error>
+    where D: Deserializer<'a>
+{
+    use serde::de::Error;
+    match Deserialize::deserialize(deserializer)? {
+        Some(0) => Err(D::Error::custom("a Redis ttl must be at least 1 second; \
+                                         leave it unset to keep entries until Redis evicts them")),
+        ttl => Ok(ttl),
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct RedisCacheConfig {
+    /// Either a `redis://` URL of a single server, a
+    /// `redis+sentinel://[:<passwd>@]<sentinel>[,<sentinel>...]/<master>[/<db>]` URL of the
+    /// sentinels monitoring a master, or a
+    /// `redis+cluster://[:<passwd>@]<node>[,<node>...]` URL of some of the nodes of a cluster.
     pub url: String,
+    /// How long entries are kept after they were last written or read, in seconds. Entries are
+    /// kept until Redis evicts them if this is not set. Redis can't expire keys after 0
+    /// seconds, so 0 is rejected.
+    #[serde(default, deserialize_with = "deserialize_redis_ttl")]
+    pub ttl: Option<u64>,
+    /// A prefix to add to the name of every entry stored in this cache.
+    pub key_prefix: Option<String>,
+    /// Read from this cache but never write to it.
+    #[serde(default)]
+    pub read_only: bool,
+    /// How long to wait for a lookup, in seconds.
+    pub read_timeout: Option<u64>,
+    /// How long to wait for a write, in seconds.
+    pub write_timeout: Option<u64>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -189,7 +339,205 @@ pub struct RedisCacheConfig {
 #[serde(deny_unknown_fields)]
 pub struct S3CacheConfig {
     pub bucket: String,
+    /// The host name, and optionally port, of the S3 service. Defaults to the
+    /// AWS endpoint for `region`. Endpoints that include the bucket name, as
+    /// `<bucket>.<host>` or `<host>/<bucket>`, are still accepted.
+    pub endpoint: Option<String>,
+    /// The region the bucket is in. Defaults to the region of an AWS
+    /// `endpoint`, or `us-east-1`.
+    pub region: Option<String>,
+    /// Address the bucket as `<endpoint>/<bucket>` rather than as
+    /// `<bucket>.<endpoint>`. Defaults to path-style when `endpoint` is set.
+    pub use_path_style: Option<bool>,
+    /// Connect with HTTPS rather than HTTP. Defaults to HTTPS for AWS and
+    /// to HTTP for other endpoints, unless `endpoint` starts with `https://`
+    /// or `http://`.
+    pub use_ssl: Option<bool>,
+    /// The server-side encryption to write objects with: `AES256` or
+    /// `aws:kms`.
+    pub server_side_encryption: Option<String>,
+    /// The KMS key to encrypt objects with, when using `aws:kms`.
+    pub sse_kms_key_id: Option<String>,
+    /// The storage class to write objects with, such as `STANDARD_IA`.
+    pub storage_class: Option<String>,
+    /// The canned ACL to write objects with, such as
+    /// `bucket-owner-full-control`.
+    pub acl: Option<String>,
+    /// A prefix to add to the name of every entry stored in this cache.
+    pub key_prefix: Option<String>,
+    /// Read from this cache but never write to it.
+    #[serde(default)]
+    pub read_only: bool,
+    /// How long to wait for a lookup, in seconds.
+    pub read_timeout: Option<u64>,
+    /// How long to wait for a write, in seconds.
+    pub write_timeout: Option<u64>,
+}
+
+/// Where and how to address an S3 bucket, as resolved by
+/// `S3CacheConfig::addressing`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct S3Addressing {
+    /// The host name, and optionally port, of the S3 service.
     pub endpoint: String,
+    pub region: String,
+    pub use_path_style: bool,
+    pub use_ssl: bool,
+}
+
+impl S3CacheConfig {
+    /// Resolve the endpoint, region and addressing style of the bucket,
+    /// filling in defaults.
+    ///
+    /// Older versions took an endpoint that already included the bucket
+    /// name, as `<bucket>.<host>` or `<host>/<bucket>`; those are split
+    /// back into the host and the addressing style they imply.
+    pub fn addressing(&self) -> S3Addressing {
+        let mut ssl = self.use_ssl;
+        let (endpoint, path_style) = match self.endpoint {
+            Some(ref endpoint) => {
+                let mut endpoint = endpoint.trim_end_matches('/');
+                for &(scheme, scheme_ssl) in &[("https://", true), ("http://", false)] {
+                    if endpoint.starts_with(scheme) {
+                        endpoint = &endpoint[scheme.len()..];
+                        ssl = Some(scheme_ssl);
+                    }
+                }
+                let path_suffix = format!("/{}", self.bucket);
+                let host_prefix = format!("{}.", self.bucket);
+                if endpoint.ends_with(&path_suffix) {
+                    (Some(&endpoint[..endpoint.len() - path_suffix.len()]), true)
+                } else if endpoint.starts_with(&host_prefix) {
+                    (Some(&endpoint[host_prefix.len()..]), false)
+                } else {
+                    // Custom endpoints have always been addressed path-style.
+                    (Some(endpoint), true)
+                }
+            }
+            None => (None, false),
+        };
+        let aws = endpoint.map_or(true, |endpoint| endpoint.ends_with(".amazonaws.com"));
+        let region = self.region.clone()
+            .or_else(|| endpoint.and_then(aws_region).map(|r| r.to_owned()))
+            .unwrap_or_else(|| "us-east-1".to_owned());
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.to_owned(),
+            None if region == "us-east-1" => "s3.amazonaws.com".to_owned(),
+            None => format!("s3.{}.amazonaws.com", region),
+        };
+        S3Addressing {
+            endpoint,
+            region,
+            use_path_style: self.use_path_style.unwrap_or(path_style),
+            use_ssl: ssl.unwrap_or(aws),
+        }
+    }
+}
+
+/// The region of the AWS S3 endpoint `host`, such as `us-west-2` for
+/// `s3-us-west-2.amazonaws.com` or `s3.us-west-2.amazonaws.com`.
+fn aws_region(host: &str) -> Option<&str> {
+    const SUFFIX: &str = ".amazonaws.com";
+    if !host.ends_with(SUFFIX) {
+        return None;
+    }
+    let service = &host[..host.len() - SUFFIX.len()];
+    if service.starts_with("s3-") || service.starts_with("s3.") {
+        Some(&service[3..])
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebdavCacheConfig {
+    /// The URL entries are stored under.
+    pub endpoint: String,
+    /// How entries are laid out under `endpoint`.
+    #[serde(default)]
+    pub layout: WebdavLayout,
+    /// The username to authenticate with, using HTTP basic auth.
+    pub username: Option<String>,
+    /// The password to authenticate with, using HTTP basic auth.
+    pub password: Option<String>,
+    /// The token to authenticate with, as an HTTP bearer token.
+    pub token: Option<String>,
+    /// A PEM file of extra CA certificates to trust when using TLS.
+    pub ca_cert: Option<PathBuf>,
+    /// A prefix to add to the name of every entry stored in this cache.
+    pub key_prefix: Option<String>,
+    /// Read from this cache but never write to it.
+    #[serde(default)]
+    pub read_only: bool,
+    /// How long to wait for a lookup, in seconds.
+    pub read_timeout: Option<u64>,
+    /// How long to wait for a write, in seconds.
+    pub write_timeout: Option<u64>,
+}
+
+/// How a WebDAV cache lays out the entries it stores.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum WebdavLayout {
+    /// Store each entry at `<endpoint>/<key prefix><key>`.
+    #[serde(rename = "flat")]
+    Flat,
+    /// Store entries the way Bazel's HTTP remote cache protocol does: the
+    /// entry in `<endpoint>/cas/` under its SHA-256 digest, and an action
+    /// result referring to it in `<endpoint>/ac/` under the SHA-256 digest of
+    /// `<key prefix><key>`.
+    #[serde(rename = "bazel")]
+    Bazel,
+}
+
+impl Default for WebdavLayout {
+    fn default() -> Self {
+        WebdavLayout::Flat
+    }
+}
+
+/// The kind of a remote cache, named as its section of the config file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum CacheName {
+    #[serde(rename = "azure")]
+    Azure,
+    #[serde(rename = "gcs")]
+    GCS,
+    #[serde(rename = "memcached")]
+    Memcached,
+    #[serde(rename = "redis")]
+    Redis,
+    #[serde(rename = "s3")]
+    S3,
+    #[serde(rename = "webdav")]
+    Webdav,
+}
+
+impl CacheName {
+    /// The name of the section of the config file for this kind of cache.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CacheName::Azure => "azure",
+            CacheName::GCS => "gcs",
+            CacheName::Memcached => "memcached",
+            CacheName::Redis => "redis",
+            CacheName::S3 => "s3",
+            CacheName::Webdav => "webdav",
+        }
+    }
+
+    fn named(name: &str) -> Option<CacheName> {
+        [CacheName::Azure, CacheName::GCS, CacheName::Memcached,
+         CacheName::Redis, CacheName::S3, CacheName::Webdav]
+            .iter()
+            .cloned()
+            .find(|n| n.as_str() == name)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -199,6 +547,101 @@ pub enum CacheType {
     Memcached(MemcachedCacheConfig),
     Redis(RedisCacheConfig),
     S3(S3CacheConfig),
+    Webdav(WebdavCacheConfig),
+}
+
+impl CacheType {
+    /// The kind of this cache, which also names it in statistics.
+    pub fn name(&self) -> CacheName {
+        match *self {
+            CacheType::Azure(_) => CacheName::Azure,
+            CacheType::GCS(_) => CacheName::GCS,
+            CacheType::Memcached(_) => CacheName::Memcached,
+            CacheType::Redis(_) => CacheName::Redis,
+            CacheType::S3(_) => CacheName::S3,
+            CacheType::Webdav(_) => CacheName::Webdav,
+        }
+    }
+
+    /// Whether this cache should only be read from, never written to.
+    pub fn read_only(&self) -> bool {
+        match *self {
+            CacheType::Azure(ref c) => c.read_only,
+            CacheType::GCS(ref c) => c.read_only || c.rw_mode == GCSCacheRWMode::ReadOnly,
+            CacheType::Memcached(ref c) => c.read_only,
+            CacheType::Redis(ref c) => c.read_only,
+            CacheType::S3(ref c) => c.read_only,
+            CacheType::Webdav(ref c) => c.read_only,
+        }
+    }
+
+    /// The prefix to add to the name of every entry stored in this cache.
+    pub fn key_prefix(&self) -> &str {
+        let key_prefix = match *self {
+            CacheType::Azure(ref c) => &c.key_prefix,
+            CacheType::GCS(ref c) => &c.key_prefix,
+            CacheType::Memcached(ref c) => &c.key_prefix,
+            CacheType::Redis(ref c) => &c.key_prefix,
+            CacheType::S3(ref c) => &c.key_prefix,
+            CacheType::Webdav(ref c) => &c.key_prefix,
+        };
+        key_prefix.as_ref().map(String::as_str).unwrap_or("")
+    }
+
+    /// The lookup and write timeouts for this cache.
+    pub fn timeouts(&self) -> (Option<Duration>, Option<Duration>) {
+        let (read, write) = match *self {
+            CacheType::Azure(ref c) => (c.read_timeout, c.write_timeout),
+            CacheType::GCS(ref c) => (c.read_timeout, c.write_timeout),
+            CacheType::Memcached(ref c) => (c.read_timeout, c.write_timeout),
+            CacheType::Redis(ref c) => (c.read_timeout, c.write_timeout),
+            CacheType::S3(ref c) => (c.read_timeout, c.write_timeout),
+            CacheType::Webdav(ref c) => (c.read_timeout, c.write_timeout),
+        };
+        (timeout_duration(read, DEFAULT_CACHE_READ_TIMEOUT),
+         timeout_duration(write, DEFAULT_CACHE_WRITE_TIMEOUT))
+    }
+}
+
+/// How to handle remote caches that keep failing.
+#[derive(Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct FailoverConfig {
+    /// Skip a remote cache, using the next configured cache or the local
+    /// disk cache instead, after this many operations on it fail in a row.
+    /// 0, the default, disables failover, so that only the first remote
+    /// cache is used.
+    pub failure_threshold: u32,
+    /// How long to skip a failing remote cache for before trying it again,
+    /// in seconds.
+    pub retry_interval: u64,
+    /// The longest time to skip a remote cache for, in seconds. The time is
+    /// doubled each time trying it again fails.
+    pub max_retry_interval: u64,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        FailoverConfig {
+            failure_threshold: DEFAULT_FAILOVER_THRESHOLD,
+            retry_interval: DEFAULT_FAILOVER_RETRY_INTERVAL,
+            max_retry_interval: DEFAULT_FAILOVER_MAX_RETRY_INTERVAL,
+        }
+    }
+}
+
+/// Which remote caches to write to in mirror mode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MirrorConfig {
+    /// Whether to write to every configured remote cache, looking entries
+    /// up in them in the default order.
+    All(bool),
+    /// Write to the named caches, looking entries up in them in this order.
+    Caches(Vec<CacheName>),
 }
 
 #[derive(Debug, Default)]
@@ -211,6 +654,19 @@ pub struct CacheConfigs {
     pub memcached: Option<MemcachedCacheConfig>,
     pub redis: Option<RedisCacheConfig>,
     pub s3: Option<S3CacheConfig>,
+    pub webdav: Option<WebdavCacheConfig>,
+    /// Store each distinct cache entry object only once, by content digest.
+    pub cas: Option<bool>,
+    /// How old an unreferenced content-addressed blob must be, in seconds,
+    /// for garbage collection to remove it.
+    pub cas_gc_grace_period: Option<u64>,
+    /// Skip remote caches that keep failing.
+    pub failover: Option<FailoverConfig>,
+    /// Write to every configured remote cache, or to the listed ones,
+    /// rather than only the first one that could be set up.
+    pub mirror: Option<MirrorConfig>,
+    /// The zstd compression level for new cache entries, from 1 to 22.
+    pub zstd_level: Option<i32>,
 }
 
 impl CacheConfigs {
@@ -218,7 +674,7 @@ impl CacheConfigs {
     /// consistent ordering
     fn into_vec_and_fallback(self) -> (Vec<CacheType>, DiskCacheConfig) {
         let CacheConfigs {
-            azure, disk, gcs, memcached, redis, s3
+            azure, disk, gcs, memcached, redis, s3, webdav, ..
         } = self;
 
         let caches = s3.map(CacheType::S3).into_iter()
@@ -226,6 +682,7 @@ impl CacheConfigs {
             .chain(memcached.map(CacheType::Memcached))
             .chain(gcs.map(CacheType::GCS))
             .chain(azure.map(CacheType::Azure))
+            .chain(webdav.map(CacheType::Webdav))
             .collect();
         let fallback = disk.unwrap_or_else(Default::default);
 
@@ -235,7 +692,8 @@ impl CacheConfigs {
     /// Override self with any existing fields from other
     fn merge(&mut self, other: Self) {
         let CacheConfigs {
-            azure, disk, gcs, memcached, redis, s3
+            azure, disk, gcs, memcached, redis, s3, webdav,
+            cas, cas_gc_grace_period, failover, mirror, zstd_level
         } = other;
 
         if azure.is_some()     { self.azure = azure }
@@ -244,6 +702,12 @@ impl CacheConfigs {
         if memcached.is_some() { self.memcached = memcached }
         if redis.is_some()     { self.redis = redis }
         if s3.is_some()        { self.s3 = s3 }
+        if webdav.is_some()    { self.webdav = webdav }
+        if cas.is_some()       { self.cas = cas }
+        if cas_gc_grace_period.is_some() { self.cas_gc_grace_period = cas_gc_grace_period }
+        if failover.is_some()  { self.failover = failover }
+        if mirror.is_some()    { self.mirror = mirror }
+        if zstd_level.is_some() { self.zstd_level = zstd_level }
     }
 }
 
@@ -388,30 +852,177 @@ pub fn try_read_config_file<T: DeserializeOwned>(path: &Path) -> Result<Option<T
     Ok(Some(res))
 }
 
+/// Parse a boolean flag from the environment variable `var`, if it is set.
+fn bool_from_env(var: &str) -> Option<bool> {
+    env::var(var).ok().map(|v| match v.as_str() {
+        "1" | "true" | "TRUE" => true,
+        "0" | "false" | "FALSE" | "" => false,
+        _ => {
+            warn!("Invalid value for {}: `{}`, treating it as false", var, v);
+            false
+        }
+    })
+}
+
+/// Read a cache's lookup and write timeouts from the
+/// `SCCACHE_<PREFIX>_READ_TIMEOUT` and `SCCACHE_<PREFIX>_WRITE_TIMEOUT`
+/// environment variables.
+fn timeouts_from_env(prefix: &str) -> (Option<u64>, Option<u64>) {
+    let timeout = |op| number_from_env(&format!("SCCACHE_{}_{}_TIMEOUT", prefix, op));
+    (timeout("READ"), timeout("WRITE"))
+}
+
+/// Parse the TTL in seconds in the environment variable `var`, if it is set.
+/// A TTL of 0 means entries don't expire, the same as not setting it.
+fn ttl_from_env(var: &str) -> Option<u64> {
+    number_from_env(var).and_then(|ttl| if ttl == 0 { None } else { Some(ttl) })
+}
+
+/// Parse the number in the environment variable `var`, if it is set.
+fn number_from_env<T: FromStr>(var: &str) -> Option<T> {
+    env::var(var).ok().and_then(|v| match v.parse() {
+        Ok(n) => Some(n),
+        Err(_) => {
+            warn!("Invalid value for {}: `{}`, using the default", var, v);
+            None
+        }
+    })
+}
+
+/// Read the local disk cache's eviction policy from
+/// `SCCACHE_DIR_EVICTION_POLICY`.
+fn eviction_policy_from_env() -> Option<DiskEvictionPolicy> {
+    let var = "SCCACHE_DIR_EVICTION_POLICY";
+    env::var(var).ok().and_then(|v| match v.as_str() {
+        "lru" => Some(DiskEvictionPolicy::Lru),
+        "lfu" => Some(DiskEvictionPolicy::Lfu),
+        "size" => Some(DiskEvictionPolicy::Size),
+        _ => {
+            warn!("Invalid value for {}: `{}`, using the default", var, v);
+            None
+        }
+    })
+}
+
+/// Read the caches to write to in mirror mode from `SCCACHE_MIRROR`, which
+/// is either a boolean or a comma-separated list of cache names.
+fn mirror_from_env() -> Option<MirrorConfig> {
+    let var = "SCCACHE_MIRROR";
+    let v = env::var(var).ok()?;
+    match v.as_str() {
+        "1" | "true" | "TRUE" | "0" | "false" | "FALSE" | "" => {
+            return bool_from_env(var).map(MirrorConfig::All)
+        }
+        _ => {}
+    }
+    let names = v.split(',')
+        .map(str::trim)
+        .filter_map(|name| {
+            let cache = CacheName::named(name);
+            if cache.is_none() {
+                warn!("Invalid cache name in {}: `{}`, ignoring it", var, name);
+            }
+            cache
+        })
+        .collect();
+    Some(MirrorConfig::Caches(names))
+}
+
+/// Order `caches` as listed in `names` for mirror mode, leaving out the
+/// caches that aren't listed.
+fn mirror_order(mut caches: Vec<CacheType>, names: &[CacheName]) -> Vec<CacheType> {
+    let mut ordered = vec![];
+    for name in names {
+        match caches.iter().position(|cache| cache.name() == *name) {
+            Some(i) => ordered.push(caches.remove(i)),
+            None => warn!("Cache {} is listed for mirror mode, but isn't configured", name.as_str()),
+        }
+    }
+    for cache in caches {
+        info!("Cache {} isn't listed for mirror mode, so it isn't used", cache.name().as_str());
+    }
+    ordered
+}
+
+/// Read the WebDAV cache layout from `SCCACHE_WEBDAV_LAYOUT`.
+fn webdav_layout_from_env() -> Option<WebdavLayout> {
+    let var = "SCCACHE_WEBDAV_LAYOUT";
+    env::var(var).ok().and_then(|v| match v.as_str() {
+        "flat" => Some(WebdavLayout::Flat),
+        "bazel" => Some(WebdavLayout::Bazel),
+        _ => {
+            warn!("Invalid value for {}: `{}`, using the default", var, v);
+            None
+        }
+    })
+}
+
 #[derive(Debug)]
 pub struct EnvConfig {
     cache: CacheConfigs,
 }
 
 fn config_from_env() -> EnvConfig {
+    let key_prefix = env::var("SCCACHE_KEY_PREFIX").ok();
+
     let s3 = env::var("SCCACHE_BUCKET").ok()
         .map(|bucket| {
-            let endpoint = match env::var("SCCACHE_ENDPOINT") {
-                Ok(endpoint) => format!("{}/{}", endpoint, bucket),
-                _ => match env::var("SCCACHE_REGION") {
-                    Ok(ref region) if region != "us-east-1" =>
-                        format!("{}.s3-{}.amazonaws.com", bucket, region),
-                    _ => format!("{}.s3.amazonaws.com", bucket),
-                },
-            };
-            S3CacheConfig { bucket, endpoint }
+            let endpoint = env::var("SCCACHE_ENDPOINT").ok();
+            let region = env::var("SCCACHE_REGION").ok();
+            let use_path_style = bool_from_env("SCCACHE_S3_USE_PATH_STYLE");
+            let use_ssl = bool_from_env("SCCACHE_S3_USE_SSL");
+            let server_side_encryption = env::var("SCCACHE_S3_SERVER_SIDE_ENCRYPTION").ok();
+            let sse_kms_key_id = env::var("SCCACHE_S3_SSE_KMS_KEY_ID").ok();
+            let storage_class = env::var("SCCACHE_S3_STORAGE_CLASS").ok();
+            let acl = env::var("SCCACHE_S3_ACL").ok();
+            let read_only = bool_from_env("SCCACHE_S3_READ_ONLY").unwrap_or(false);
+            let (read_timeout, write_timeout) = timeouts_from_env("S3");
+            S3CacheConfig {
+                bucket,
+                endpoint,
+                region,
+                use_path_style,
+                use_ssl,
+                server_side_encryption,
+                sse_kms_key_id,
+                storage_class,
+                acl,
+                key_prefix: key_prefix.clone(),
+                read_only,
+                read_timeout,
+                write_timeout,
+            }
         });
 
     let redis = env::var("SCCACHE_REDIS").ok()
-        .map(|url| RedisCacheConfig { url });
+        .map(|url| {
+            let read_only = bool_from_env("SCCACHE_REDIS_READ_ONLY").unwrap_or(false);
+            let (read_timeout, write_timeout) = timeouts_from_env("REDIS");
+            let ttl = ttl_from_env("SCCACHE_REDIS_TTL");
+            RedisCacheConfig {
+                url,
+                ttl,
+                key_prefix: key_prefix.clone(),
+                read_only,
+                read_timeout,
+                write_timeout,
+            }
+        });
 
     let memcached = env::var("SCCACHE_MEMCACHED").ok()
-        .map(|url| MemcachedCacheConfig { url });
+        .map(|url| {
+            let read_only = bool_from_env("SCCACHE_MEMCACHED_READ_ONLY").unwrap_or(false);
+            let (read_timeout, write_timeout) = timeouts_from_env("MEMCACHED");
+            let ttl = ttl_from_env("SCCACHE_MEMCACHED_TTL");
+            MemcachedCacheConfig {
+                url,
+                ttl,
+                key_prefix: key_prefix.clone(),
+                read_only,
+                read_timeout,
+                write_timeout,
+            }
+        });
 
     let gcs = env::var("SCCACHE_GCS_BUCKET").ok()
         .map(|bucket| {
@@ -440,28 +1051,119 @@ fn config_from_env() -> EnvConfig {
                     GCSCacheRWMode::ReadOnly
                 }
             };
-            GCSCacheConfig { bucket, cred_path, url, rw_mode }
+            let read_only = bool_from_env("SCCACHE_GCS_READ_ONLY").unwrap_or(false);
+            let (read_timeout, write_timeout) = timeouts_from_env("GCS");
+            GCSCacheConfig {
+                bucket,
+                cred_path,
+                url,
+                rw_mode,
+                key_prefix: key_prefix.clone(),
+                read_only,
+                read_timeout,
+                write_timeout,
+            }
         });
 
 
-    let azure = env::var("SCCACHE_AZURE_CONNECTION_STRING").ok()
-        .map(|_| AzureCacheConfig);
+    let azure_connection_string = env::var("SCCACHE_AZURE_CONNECTION_STRING").ok();
+    let azure_account = env::var("SCCACHE_AZURE_ACCOUNT").ok();
+    let azure = if azure_connection_string.is_some() || azure_account.is_some() {
+        let read_only = bool_from_env("SCCACHE_AZURE_READ_ONLY").unwrap_or(false);
+        let (read_timeout, write_timeout) = timeouts_from_env("AZURE");
+        Some(AzureCacheConfig {
+            connection_string: azure_connection_string,
+            account: azure_account,
+            endpoint: env::var("SCCACHE_AZURE_ENDPOINT").ok(),
+            container: env::var("SCCACHE_AZURE_BLOB_CONTAINER").ok().filter(|c| !c.is_empty()),
+            sas_token: env::var("SCCACHE_AZURE_SAS_TOKEN").ok().filter(|t| !t.is_empty()),
+            key_prefix: key_prefix.clone(),
+            read_only,
+            read_timeout,
+            write_timeout,
+        })
+    } else {
+        None
+    };
+
+    let webdav = env::var("SCCACHE_WEBDAV_ENDPOINT").ok()
+        .map(|endpoint| {
+            let read_only = bool_from_env("SCCACHE_WEBDAV_READ_ONLY").unwrap_or(false);
+            let (read_timeout, write_timeout) = timeouts_from_env("WEBDAV");
+            WebdavCacheConfig {
+                endpoint,
+                layout: webdav_layout_from_env().unwrap_or_default(),
+                username: env::var("SCCACHE_WEBDAV_USERNAME").ok(),
+                password: env::var("SCCACHE_WEBDAV_PASSWORD").ok(),
+                token: env::var("SCCACHE_WEBDAV_TOKEN").ok(),
+                ca_cert: env::var_os("SCCACHE_WEBDAV_CA_CERT").map(PathBuf::from),
+                key_prefix: key_prefix.clone(),
+                read_only,
+                read_timeout,
+                write_timeout,
+            }
+        });
 
     let disk_dir = env::var_os("SCCACHE_DIR")
         .map(PathBuf::from);
     let disk_sz = env::var("SCCACHE_CACHE_SIZE")
         .ok()
         .and_then(|v| parse_size(&v));
-
-    let disk = if disk_dir.is_some() || disk_sz.is_some() {
+    let disk_tiered = bool_from_env("SCCACHE_TIERED");
+    let disk_read_only = bool_from_env("SCCACHE_DIR_READ_ONLY");
+    let (disk_read_timeout, disk_write_timeout) = timeouts_from_env("DIR");
+    let disk_eviction_policy = eviction_policy_from_env();
+    let disk_high_watermark = number_from_env("SCCACHE_DIR_HIGH_WATERMARK");
+    let disk_low_watermark = number_from_env("SCCACHE_DIR_LOW_WATERMARK");
+    let disk_max_entries = number_from_env("SCCACHE_DIR_MAX_ENTRIES");
+
+    let disk = if disk_dir.is_some() || disk_sz.is_some() || disk_tiered.is_some()
+        || disk_read_only.is_some() || disk_read_timeout.is_some()
+        || disk_write_timeout.is_some() || disk_eviction_policy.is_some()
+        || disk_high_watermark.is_some() || disk_low_watermark.is_some()
+        || disk_max_entries.is_some()
+    {
+        let defaults = DiskCacheConfig::default();
         Some(DiskCacheConfig {
             dir: disk_dir.unwrap_or_else(default_disk_cache_dir),
             size: disk_sz.unwrap_or_else(default_disk_cache_size),
+            tiered: disk_tiered.unwrap_or(false),
+            read_only: disk_read_only.unwrap_or(false),
+            read_timeout: disk_read_timeout,
+            write_timeout: disk_write_timeout,
+            eviction_policy: disk_eviction_policy.unwrap_or(defaults.eviction_policy),
+            high_watermark: disk_high_watermark.unwrap_or(defaults.high_watermark),
+            low_watermark: disk_low_watermark.unwrap_or(defaults.low_watermark),
+            max_entries: disk_max_entries,
         })
     } else {
         None
     };
 
+    let cas = bool_from_env("SCCACHE_CAS");
+    let cas_gc_grace_period = number_from_env("SCCACHE_CAS_GC_GRACE_PERIOD");
+
+    let failover_threshold = number_from_env("SCCACHE_FAILOVER_THRESHOLD");
+    let failover_retry_interval = number_from_env("SCCACHE_FAILOVER_RETRY_INTERVAL");
+    let failover_max_retry_interval = number_from_env("SCCACHE_FAILOVER_MAX_RETRY_INTERVAL");
+    let failover = if failover_threshold.is_some() || failover_retry_interval.is_some()
+        || failover_max_retry_interval.is_some()
+    {
+        let defaults = FailoverConfig::default();
+        Some(FailoverConfig {
+            failure_threshold: failover_threshold.unwrap_or(defaults.failure_threshold),
+            retry_interval: failover_retry_interval.unwrap_or(defaults.retry_interval),
+            max_retry_interval: failover_max_retry_interval
+                .unwrap_or(defaults.max_retry_interval),
+        })
+    } else {
+        None
+    };
+
+    let mirror = mirror_from_env();
+
+    let zstd_level = number_from_env("SCCACHE_CACHE_ZSTD_LEVEL");
+
     let cache = CacheConfigs {
         azure,
         disk,
@@ -469,6 +1171,12 @@ fn config_from_env() -> EnvConfig {
         memcached,
         redis,
         s3,
+        webdav,
+        cas,
+        cas_gc_grace_period,
+        failover,
+        mirror,
+        zstd_level,
     };
 
     EnvConfig { cache }
@@ -478,6 +1186,17 @@ fn config_from_env() -> EnvConfig {
 pub struct Config {
     pub caches: Vec<CacheType>,
     pub fallback_cache: DiskCacheConfig,
+    /// Store cache entries in content-addressed form.
+    pub cas: bool,
+    /// How old an unreferenced content-addressed blob must be, in seconds,
+    /// for garbage collection to remove it.
+    pub cas_gc_grace_period: u64,
+    /// How to handle remote caches that keep failing.
+    pub failover: FailoverConfig,
+    /// Write to every cache in `caches`.
+    pub mirror: bool,
+    /// The zstd compression level for new cache entries, if set.
+    pub zstd_level: Option<i32>,
     pub dist: DistConfig,
 }
 
@@ -508,8 +1227,36 @@ impl Config {
         let EnvConfig { cache } = env_conf;
         conf_caches.merge(cache);
 
-        let (caches, fallback_cache) = conf_caches.into_vec_and_fallback();
-        Config { caches, fallback_cache, dist }
+        let cas = conf_caches.cas.unwrap_or(false);
+        let cas_gc_grace_period = conf_caches.cas_gc_grace_period
+            .unwrap_or(DEFAULT_CAS_GC_GRACE_PERIOD);
+        let failover = conf_caches.failover.take().unwrap_or_default();
+        let mirror = conf_caches.mirror.take();
+        let zstd_level = conf_caches.zstd_level;
+        let (mut caches, fallback_cache) = conf_caches.into_vec_and_fallback();
+        let mirror = match mirror {
+            Some(MirrorConfig::All(mirror)) => mirror,
+            Some(MirrorConfig::Caches(names)) => {
+                caches = mirror_order(caches, &names);
+                true
+            }
+            None => false,
+        };
+        Config {
+            caches, fallback_cache, cas, cas_gc_grace_period, failover, mirror, zstd_level, dist
+        }
+    }
+
+    /// The zstd compression level for new cache entries.
+    pub fn zstd_level(&self) -> i32 {
+        match self.zstd_level {
+            Some(level) if level >= 1 && level <= 22 => level,
+            Some(level) => {
+                warn!("Invalid zstd compression level {}, using {}", level, DEFAULT_ZSTD_LEVEL);
+                DEFAULT_ZSTD_LEVEL
+            }
+            None => DEFAULT_ZSTD_LEVEL,
+        }
     }
 }
 
@@ -715,14 +1462,38 @@ fn test_parse_size() {
 fn config_overrides() {
     let env_conf = EnvConfig {
         cache: CacheConfigs {
-            azure: Some(AzureCacheConfig),
+            azure: Some(AzureCacheConfig {
+                connection_string: None,
+                account: None,
+                endpoint: None,
+                container: None,
+                sas_token: None,
+                key_prefix: None,
+                read_only: false,
+                read_timeout: None,
+                write_timeout: None,
+            }),
             disk: Some(DiskCacheConfig {
                 dir: "/env-cache".into(),
                 size: 5,
+                tiered: false,
+                read_only: false,
+                read_timeout: None,
+                write_timeout: None,
+                eviction_policy: DiskEvictionPolicy::Lru,
+                high_watermark: 100,
+                low_watermark: 100,
+                max_entries: None,
             }),
             redis: Some(RedisCacheConfig {
                 url: "myotherredisurl".to_owned(),
+                ttl: None,
+                key_prefix: Some("team-a/".to_owned()),
+                read_only: true,
+                read_timeout: Some(5),
+                write_timeout: None,
             }),
+            zstd_level: Some(5),
             ..Default::default()
         },
     };
@@ -732,13 +1503,39 @@ fn config_overrides() {
             disk: Some(DiskCacheConfig {
                 dir: "/file-cache".into(),
                 size: 15,
+                tiered: true,
+                read_only: false,
+                read_timeout: None,
+                write_timeout: None,
+                eviction_policy: DiskEvictionPolicy::Lru,
+                high_watermark: 100,
+                low_watermark: 100,
+                max_entries: None,
             }),
             memcached: Some(MemcachedCacheConfig {
                 url: "memurl".to_owned(),
+                ttl: None,
+                key_prefix: None,
+                read_only: false,
+                read_timeout: None,
+                write_timeout: None,
             }),
             redis: Some(RedisCacheConfig {
                 url: "myredisurl".to_owned(),
+                ttl: None,
+                key_prefix: None,
+                read_only: false,
+                read_timeout: None,
+                write_timeout: None,
+            }),
+            cas: Some(true),
+            cas_gc_grace_period: Some(600),
+            failover: Some(FailoverConfig {
+                failure_threshold: 3,
+                ..Default::default()
             }),
+            mirror: Some(MirrorConfig::All(true)),
+            zstd_level: Some(19),
             ..Default::default()
         },
         dist: Default::default(),
@@ -748,19 +1545,197 @@ fn config_overrides() {
         Config::from_env_and_file_configs(env_conf, file_conf),
         Config {
             caches: vec![
-                CacheType::Redis(RedisCacheConfig { url: "myotherredisurl".to_owned() }),
-                CacheType::Memcached(MemcachedCacheConfig { url: "memurl".to_owned() }),
-                CacheType::Azure(AzureCacheConfig),
+                CacheType::Redis(RedisCacheConfig {
+                    url: "myotherredisurl".to_owned(),
+                    ttl: None,
+                    key_prefix: Some("team-a/".to_owned()),
+                    read_only: true,
+                    read_timeout: Some(5),
+                    write_timeout: None,
+                }),
+                CacheType::Memcached(MemcachedCacheConfig {
+                    url: "memurl".to_owned(),
+                    ttl: None,
+                    key_prefix: None,
+                    read_only: false,
+                    read_timeout: None,
+                    write_timeout: None,
+                }),
+                CacheType::Azure(AzureCacheConfig {
+                    connection_string: None,
+                    account: None,
+                    endpoint: None,
+                    container: None,
+                    sas_token: None,
+                    key_prefix: None,
+                    read_only: false,
+                    read_timeout: None,
+                    write_timeout: None,
+                }),
             ],
             fallback_cache: DiskCacheConfig {
                 dir: "/env-cache".into(),
                 size: 5,
+                tiered: false,
+                read_only: false,
+                read_timeout: None,
+                write_timeout: None,
+                eviction_policy: DiskEvictionPolicy::Lru,
+                high_watermark: 100,
+                low_watermark: 100,
+                max_entries: None,
             },
+            cas: true,
+            cas_gc_grace_period: 600,
+            failover: FailoverConfig {
+                failure_threshold: 3,
+                retry_interval: DEFAULT_FAILOVER_RETRY_INTERVAL,
+                max_retry_interval: DEFAULT_FAILOVER_MAX_RETRY_INTERVAL,
+            },
+            mirror: true,
+            zstd_level: Some(5),
             dist: Default::default(),
         }
     );
 }
 
+#[test]
+fn test_cache_ttls() {
+    let redis_ttl = |file: &str| -> StdResult<Option<u64>, toml::de::Error> {
+        let config: FileConfig = toml::from_str(file)?;
+        Ok(config.cache.redis.unwrap().ttl)
+    };
+    assert_eq!(redis_ttl("[cache.redis]\nurl = \"redis://localhost\"\nttl = 60\n").unwrap(), Some(60));
+    assert_eq!(redis_ttl("[cache.redis]\nurl = \"redis://localhost\"\n").unwrap(), None);
+    assert!(redis_ttl("[cache.redis]\nurl = \"redis://localhost\"\nttl = 0\n").is_err());
+
+    env::set_var("SCCACHE_TEST_TTL", "60");
+    assert_eq!(ttl_from_env("SCCACHE_TEST_TTL"), Some(60));
+    env::set_var("SCCACHE_TEST_TTL", "0");
+    assert_eq!(ttl_from_env("SCCACHE_TEST_TTL"), None);
+    env::set_var("SCCACHE_TEST_TTL", "soon");
+    assert_eq!(ttl_from_env("SCCACHE_TEST_TTL"), None);
+    env::remove_var("SCCACHE_TEST_TTL");
+}
+
+#[test]
+fn test_mirror_order() {
+    let caches = |mirror: &str| -> StdResult<(Vec<CacheName>, bool), toml::de::Error> {
+        let file = format!("[cache]\nmirror = {}\n[cache.redis]\nurl = \"redis://localhost\"\n\
+                            [cache.memcached]\nurl = \"tcp://localhost:11211\"\n", mirror);
+        let file_conf: FileConfig = toml::from_str(&file)?;
+        let env_conf = EnvConfig { cache: Default::default() };
+        let config = Config::from_env_and_file_configs(env_conf, file_conf);
+        Ok((config.caches.iter().map(CacheType::name).collect(), config.mirror))
+    };
+    assert_eq!(caches("false").unwrap(), (vec![CacheName::Redis, CacheName::Memcached], false));
+    assert_eq!(caches("true").unwrap(), (vec![CacheName::Redis, CacheName::Memcached], true));
+    assert_eq!(caches("[\"memcached\", \"redis\"]").unwrap(),
+               (vec![CacheName::Memcached, CacheName::Redis], true));
+    // Caches that aren't listed, or aren't configured, are left out.
+    assert_eq!(caches("[\"gcs\", \"memcached\"]").unwrap(), (vec![CacheName::Memcached], true));
+    assert!(caches("[\"floppy\"]").is_err());
+
+    env::set_var("SCCACHE_MIRROR", "1");
+    assert_eq!(mirror_from_env(), Some(MirrorConfig::All(true)));
+    env::set_var("SCCACHE_MIRROR", "gcs, redis");
+    assert_eq!(mirror_from_env(), Some(MirrorConfig::Caches(vec![CacheName::GCS, CacheName::Redis])));
+    env::remove_var("SCCACHE_MIRROR");
+}
+
+#[test]
+fn test_s3_old_style_endpoints() {
+    let addressing = |file: &str| -> S3Addressing {
+        let config: FileConfig = toml::from_str(file).unwrap();
+        match config.cache.s3 {
+            Some(ref s3) => s3.addressing(),
+            None => panic!("no S3 cache configured"),
+        }
+    };
+    assert_eq!(addressing(r#"
+[cache.s3]
+bucket = "my-bucket"
+endpoint = "my-bucket.s3-us-west-2.amazonaws.com"
+"#), S3Addressing {
+        endpoint: "s3-us-west-2.amazonaws.com".to_owned(),
+        region: "us-west-2".to_owned(),
+        use_path_style: false,
+        use_ssl: true,
+    });
+    assert_eq!(addressing(r#"
+[cache.s3]
+bucket = "my-bucket"
+endpoint = "my-bucket.s3.amazonaws.com"
+"#), S3Addressing {
+        endpoint: "s3.amazonaws.com".to_owned(),
+        region: "us-east-1".to_owned(),
+        use_path_style: false,
+        use_ssl: true,
+    });
+    assert_eq!(addressing(r#"
+[cache.s3]
+bucket = "my-bucket"
+endpoint = "minio:9000/my-bucket"
+"#), S3Addressing {
+        endpoint: "minio:9000".to_owned(),
+        region: "us-east-1".to_owned(),
+        use_path_style: true,
+        use_ssl: false,
+    });
+    // New-style configuration.
+    assert_eq!(addressing(r#"
+[cache.s3]
+bucket = "my-bucket"
+endpoint = "minio:9000"
+"#), S3Addressing {
+        endpoint: "minio:9000".to_owned(),
+        region: "us-east-1".to_owned(),
+        use_path_style: true,
+        use_ssl: false,
+    });
+    assert_eq!(addressing(r#"
+[cache.s3]
+bucket = "my-bucket"
+region = "eu-west-1"
+"#), S3Addressing {
+        endpoint: "s3.eu-west-1.amazonaws.com".to_owned(),
+        region: "eu-west-1".to_owned(),
+        use_path_style: false,
+        use_ssl: true,
+    });
+    assert_eq!(addressing(r#"
+[cache.s3]
+bucket = "my-bucket"
+endpoint = "storage.example.com"
+use_path_style = false
+"#).use_path_style, false);
+    assert_eq!(addressing(r#"
+[cache.s3]
+bucket = "my-bucket"
+endpoint = "https://minio:9000/"
+"#), S3Addressing {
+        endpoint: "minio:9000".to_owned(),
+        region: "us-east-1".to_owned(),
+        use_path_style: true,
+        use_ssl: true,
+    });
+    assert_eq!(addressing(r#"
+[cache.s3]
+bucket = "my-bucket"
+use_ssl = false
+"#).use_ssl, false);
+}
+
+#[test]
+fn test_cache_timeouts() {
+    let mut disk = DiskCacheConfig::default();
+    assert_eq!(disk.timeouts(), (Some(Duration::from_secs(DEFAULT_CACHE_READ_TIMEOUT)),
+                                 Some(Duration::from_secs(DEFAULT_CACHE_WRITE_TIMEOUT))));
+    disk.read_timeout = Some(5);
+    disk.write_timeout = Some(0);
+    assert_eq!(disk.timeouts(), (Some(Duration::from_secs(5)), None));
+}
+
 #[test]
 fn test_gcs_credentials_url() {
     env::set_var("SCCACHE_GCS_BUCKET", "my-bucket");
@@ -769,13 +1744,14 @@ fn test_gcs_credentials_url() {
 
     let env_cfg = config_from_env();
     match env_cfg.cache.gcs {
-        Some(GCSCacheConfig{ref bucket, cred_path: _, ref url, rw_mode}) => {
+        Some(GCSCacheConfig{ref bucket, cred_path: _, ref url, rw_mode, read_only, ..}) => {
             assert_eq!(bucket, "my-bucket");
             match url {
                 Some(ref url) => assert_eq!(url, "http://localhost/"),
                 None => panic!("URL can't be none"),
             };
             assert_eq!(rw_mode, GCSCacheRWMode::ReadWrite);
+            assert!(!read_only);
         },
         None => assert!(false),
     };
@@ -17,6 +17,7 @@
 use std::boxed::Box;
 use std::convert;
 use std::error;
+use std::fmt;
 use std::io;
 use std::process;
 
@@ -50,16 +51,58 @@ error_chain! {
             description("failed to get a successful HTTP status")
             display("didn't get a successful HTTP status, got `{}`", status)
         }
+        CacheEntryChanged(key: String) {
+            description("cache entry changed while being read")
+            display("cache entry changed while being read: {}", key)
+        }
+        CacheTimedOut(operation: &'static str, location: String) {
+            description("cache operation timed out")
+            display("cache `{}` timed out: {}", operation, location)
+        }
+        CacheUnavailable(operation: &'static str, location: String) {
+            description("cache unavailable after repeated failures")
+            display("cache `{}` skipped after repeated failures: {}", operation, location)
+        }
+        CacheWriteSkipped(location: String) {
+            description("cache write skipped")
+            display("not writing to read-only cache storage: {}", location)
+        }
+        CorruptCacheEntry(why: String) {
+            description("corrupt cache entry")
+            display("corrupt cache entry: {}", why)
+        }
         HttpClientError(msg: String) {
             display("didn't get a successful HTTP status, got `{}`", msg)
         }
         ProcessError(output: process::Output)
+        UnsupportedStorageOperation(operation: &'static str, location: String) {
+            description("operation not supported by cache storage")
+            display("`{}` is not supported by cache storage: {}", operation, location)
+        }
         Which(err: which::Error) {
             display("{}", err)
         }
     }
 }
 
+/// The error inside the `io::Error` returned by a reader for a cache entry
+/// that was replaced while it was being read, which reading objects from the
+/// entry turns back into `ErrorKind::CacheEntryChanged`.
+#[derive(Debug)]
+pub struct EntryChanged(pub String);
+
+impl fmt::Display for EntryChanged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cache entry changed while being read: {}", self.0)
+    }
+}
+
+impl error::Error for EntryChanged {
+    fn description(&self) -> &str {
+        "cache entry changed while being read"
+    }
+}
+
 impl From<which::Error> for Error {
     fn from(err: which::Error) -> Self {
         Error::from(ErrorKind::Which(err))
@@ -1,3 +1,4 @@
+use crate::cache::CacheEntryInfo;
 use crate::compiler::ColorMode;
 use std::ffi::OsString;
 use crate::server::{ServerInfo,DistInfo};
@@ -15,6 +16,16 @@ pub enum Request {
     Shutdown,
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    // New requests are added at the end, so that the requests of older
+    // clients keep their meaning.
+    /// Remove the cache entry with the given key.
+    EvictKey(String),
+    /// Remove every entry from the cache.
+    ClearCache,
+    /// Remove data that no cache entry refers to.
+    CollectGarbage,
+    /// Describe the cache entry with the given key.
+    InspectEntry(String),
 }
 
 /// A server response.
@@ -30,6 +41,18 @@ pub enum Response {
     ShuttingDown(ServerInfo),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    // New responses are added at the end, so that older clients can still
+    // read the responses they know.
+    /// Response for `Request::EvictKey`, containing whether the entry was in the cache.
+    KeyEvicted(bool),
+    /// Response for `Request::ClearCache`, containing the number of entries removed.
+    CacheCleared(u64),
+    /// Response for `Request::CollectGarbage`, containing the number of items removed.
+    GarbageCollected(u64),
+    /// Response for `Request::InspectEntry`, describing the entry if it was found.
+    EntryInspected(Option<CacheEntryInfo>),
+    /// Response for a request that the cache storage doesn't support, saying why.
+    Unsupported(String),
 }
 
 /// Possible responses from the server for a `Compile` request.
@@ -15,7 +15,7 @@
 // For tokio_io::codec::length_delimited::Framed;
 #![allow(deprecated)]
 
-use crate::cache::{storage_from_config, Storage};
+use crate::cache::{clear_storage, storage_from_config, Cache, Storage};
 use crate::compiler::{
     get_compiler_info, CacheControl, CompileResult, Compiler, CompilerKind,
     CompilerArguments, CompilerHasher, DistType, MissType,
@@ -697,6 +697,33 @@ where
                 self.zero_stats();
                 Box::new(self.get_info().map(Response::Stats))
             }
+            Request::EvictKey(key) => {
+                debug!("handle_client: evict_key({})", key);
+                let storage = self.storage.clone();
+                or_unsupported(self.storage.exists(&key).and_then(move |existed| {
+                    storage.delete(&key).map(move |()| Response::KeyEvicted(existed))
+                }))
+            }
+            Request::ClearCache => {
+                debug!("handle_client: clear_cache");
+                or_unsupported(
+                    clear_storage(self.storage.clone()).map(Response::CacheCleared))
+            }
+            Request::CollectGarbage => {
+                debug!("handle_client: collect_garbage");
+                or_unsupported(
+                    self.storage.collect_garbage().map(Response::GarbageCollected))
+            }
+            Request::InspectEntry(key) => {
+                debug!("handle_client: inspect_entry({})", key);
+                Box::new(self.storage.peek(&key).and_then(|result| -> Result<_> {
+                    let info = match result {
+                        Cache::Hit(mut entry) => Some(entry.info()?),
+                        _ => None,
+                    };
+                    Ok(Response::EntryInspected(info))
+                }))
+            }
             Request::Shutdown => {
                 debug!("handle_client: shutdown");
                 let future = self
@@ -717,6 +744,17 @@ where
     }
 }
 
+/// Answer a request that the cache storage doesn't support with
+/// `Response::Unsupported`, so that the client can say why it failed.
+fn or_unsupported(future: SFuture<Response>) -> SFuture<Response> {
+    Box::new(future.or_else(|e| match e {
+        Error(ErrorKind::UnsupportedStorageOperation(..), _) => {
+            Ok(Response::Unsupported(e.to_string()))
+        }
+        e => Err(e),
+    }))
+}
+
 impl<C> SccacheService<C>
 where
     C: CommandCreatorSync,
@@ -790,8 +828,15 @@ where
 
     /// Get info and stats about the cache.
     fn get_info(&self) -> SFuture<ServerInfo> {
-        let stats = self.stats.borrow().clone();
+        let evictions = self.storage.take_eviction_stats();
+        let stats = {
+            let mut stats = self.stats.borrow_mut();
+            stats.cache_evictions += evictions.entries;
+            stats.cache_evicted_bytes += evictions.bytes;
+            stats.clone()
+        };
         let cache_location = self.storage.location();
+        let cache_stats = self.storage.stats();
         Box::new(
             self.storage
                 .current_size()
@@ -801,12 +846,15 @@ where
                     cache_location,
                     cache_size,
                     max_cache_size,
+                    cache_stats,
                 }),
         )
     }
 
     /// Zero stats about the cache.
     fn zero_stats(&self) {
+        // Drop the evictions the storage counted, which are part of the stats.
+        self.storage.take_eviction_stats();
         *self.stats.borrow_mut() = ServerStats::default();
     }
 
@@ -993,6 +1041,11 @@ where
                                 MissType::CacheReadError => {
                                     stats.cache_errors.increment(&kind);
                                 }
+                                MissType::CorruptEntry => {
+                                    // Also a cache read error.
+                                    stats.cache_errors.increment(&kind);
+                                    stats.cache_corrupt_entries += 1;
+                                }
                             }
                             stats.cache_misses.increment(&kind);
                             stats.cache_read_miss_duration += duration;
@@ -1058,6 +1111,9 @@ where
             let me = me.clone();
             let cache_write = cache_write.then(move |result| {
                 match result {
+                    Err(Error(ErrorKind::CacheWriteSkipped(_), _)) => {
+                        me.stats.borrow_mut().cache_writes_skipped += 1;
+                    }
                     Err(e) => {
                         debug!("Error executing cache write: {}", e);
                         me.stats.borrow_mut().cache_write_errors += 1;
@@ -1140,6 +1196,8 @@ pub struct ServerStats {
     pub cache_timeouts: u64,
     /// The count of errors reading cache entries.
     pub cache_read_errors: u64,
+    /// The count of cache entries that failed integrity checks and were evicted.
+    pub cache_corrupt_entries: u64,
     /// The count of compilations which were successful but couldn't be cached.
     pub non_cacheable_compilations: u64,
     /// The count of compilations which forcibly ignored the cache.
@@ -1148,6 +1206,12 @@ pub struct ServerStats {
     pub cache_write_errors: u64,
     /// The number of successful cache writes.
     pub cache_writes: u64,
+    /// The count of cache writes skipped because the cache is read-only.
+    pub cache_writes_skipped: u64,
+    /// The count of cache entries evicted to keep the cache within its limits.
+    pub cache_evictions: u64,
+    /// The total size of the cache entries evicted, in bytes.
+    pub cache_evicted_bytes: u64,
     /// The total time spent writing cache entries.
     pub cache_write_duration: Duration,
     /// The total time spent reading cache hits.
@@ -1171,6 +1235,8 @@ pub struct ServerInfo {
     pub cache_location: String,
     pub cache_size: Option<u64>,
     pub max_cache_size: Option<u64>,
+    /// Statistics specific to the cache storage, as (name, value) pairs.
+    pub cache_stats: Vec<(String, String)>,
 }
 
 /// Status of the dist client.
@@ -1196,10 +1262,14 @@ impl Default for ServerStats {
             cache_misses: PerLanguageCount::new(),
             cache_timeouts: u64::default(),
             cache_read_errors: u64::default(),
+            cache_corrupt_entries: u64::default(),
             non_cacheable_compilations: u64::default(),
             forced_recaches: u64::default(),
             cache_write_errors: u64::default(),
             cache_writes: u64::default(),
+            cache_writes_skipped: u64::default(),
+            cache_evictions: u64::default(),
+            cache_evicted_bytes: u64::default(),
             cache_write_duration: Duration::new(0, 0),
             cache_read_hit_duration: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
@@ -1258,8 +1328,12 @@ impl ServerStats {
         set_lang_stat!(stats_vec, self.cache_misses, "Cache misses");
         set_stat!(stats_vec, self.cache_timeouts, "Cache timeouts");
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
+        set_stat!(stats_vec, self.cache_corrupt_entries, "Corrupt cache entries");
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
         set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
+        set_stat!(stats_vec, self.cache_writes_skipped, "Cache writes skipped (read-only)");
+        set_stat!(stats_vec, self.cache_evictions, "Cache evictions");
+        set_stat!(stats_vec, self.cache_evicted_bytes, "Cache bytes evicted");
         set_stat!(stats_vec, self.compile_fails, "Compilation failures");
         set_lang_stat!(stats_vec, self.cache_errors, "Cache errors");
         set_stat!(
@@ -1377,6 +1451,15 @@ impl ServerInfo {
                 );
             }
         }
+        for &(ref name, ref val) in &self.cache_stats {
+            println!(
+                "{:<name_width$} {:>stat_width$}",
+                name,
+                val,
+                name_width = name_width,
+                stat_width = stat_width
+            );
+        }
     }
 }
 
@@ -6,11 +6,12 @@ use chrono::{offset, DateTime, Duration};
 use directories::UserDirs;
 use futures::future::{self, Shared};
 use futures::{Async, Future, Stream};
+use futures_cpupool::CpuPool;
 use hyper::client::HttpConnector;
 use hyper::{Client, Request};
 use hyperx::header::Connection;
 use regex::Regex;
-use serde_json::{from_str, Value};
+use reqwest::Url;
 #[allow(unused_imports, deprecated)]
 use std::ascii::AsciiExt;
 use std::cell::RefCell;
@@ -20,6 +21,7 @@ use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::time::Duration as StdDuration;
 use tokio_timer::Timeout;
 
@@ -131,12 +133,88 @@ fn credentials_from_environment() -> Result<AwsCredentials> {
     ))
 }
 
-/// Provides AWS credentials from a profile in a credentials file.
+/// Credentials in the JSON format returned by the EC2 and ECS metadata
+/// services and printed by `credential_process` programs.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CredentialsJson {
+    /// The version of the format, which is only set by `credential_process`
+    /// programs.
+    version: Option<u32>,
+    access_key_id: String,
+    secret_access_key: String,
+    /// The session token, as the metadata services name it.
+    token: Option<String>,
+    /// The session token, as `credential_process` programs name it.
+    session_token: Option<String>,
+    expiration: Option<String>,
+}
+
+fn credentials_from_json(body: &[u8]) -> Result<AwsCredentials> {
+    let json: CredentialsJson = serde_json::from_slice(body)
+        .chain_err(|| "Couldn't parse credentials")?;
+    let expiration = match json.expiration {
+        Some(expiration) => expiration.parse().chain_err(|| "failed to parse expiration time")?,
+        None => in_ten_minutes(),
+    };
+    Ok(AwsCredentials::new(
+        json.access_key_id,
+        json.secret_access_key,
+        json.token.or(json.session_token),
+        expiration,
+    ))
+}
+
+/// Read the whole body of `response`.
+fn read_body(response: hyper::Response<hyper::Body>) -> SFuture<Vec<u8>> {
+    Box::new(response.into_body().fold(Vec::new(), |mut body, chunk| {
+        body.extend_from_slice(&chunk);
+        Ok::<_, hyper::Error>(body)
+    }).chain_err(|| "failed to read http body"))
+}
+
+/// Run `command` with the shell and parse the credentials it prints.
+///
+/// https://docs.aws.amazon.com/cli/latest/topic/config-vars.html#sourcing-credentials-from-external-processes
+fn credentials_from_process(command: &str) -> Result<AwsCredentials> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(command).output()
+    } else {
+        Command::new("sh").arg("-c").arg(command).output()
+    };
+    let output = output.chain_err(|| format!("failed to run credential_process `{}`", command))?;
+    if !output.status.success() {
+        bail!("credential_process `{}` failed: {}", command, String::from_utf8_lossy(&output.stderr));
+    }
+    let version = serde_json::from_slice::<CredentialsJson>(&output.stdout)
+        .map(|json| json.version)
+        .unwrap_or(None);
+    if version != Some(1) {
+        bail!("Unsupported output from credential_process `{}`", command);
+    }
+    credentials_from_json(&output.stdout)
+}
+
+/// The credentials a profile in a credentials file describes.
 #[derive(Clone, Debug)]
+enum ProfileCredentials {
+    /// An access key.
+    Static(AwsCredentials),
+    /// A command that prints credentials, from `credential_process`.
+    Process(String),
+}
+
+/// Provides AWS credentials from a profile in a credentials file.
+///
+/// Profiles can either contain an access key or a `credential_process`
+/// command that prints credentials.
+#[derive(Clone)]
 pub struct ProfileProvider {
     credentials: Option<AwsCredentials>,
     file_path: PathBuf,
     profile: String,
+    /// The pool to run `credential_process` commands on.
+    pool: Option<CpuPool>,
 }
 
 impl ProfileProvider {
@@ -153,6 +231,7 @@ impl ProfileProvider {
             credentials: None,
             file_path: profile_location,
             profile: "default".to_owned(),
+            pool: None,
         })
     }
 
@@ -167,6 +246,7 @@ impl ProfileProvider {
             credentials: None,
             file_path: file_path.into(),
             profile: profile.into(),
+            pool: None,
         }
     }
 
@@ -195,21 +275,41 @@ impl ProfileProvider {
     {
         self.profile = profile.into();
     }
+
+    /// Run `credential_process` commands on `pool`. Without a pool they
+    /// block the thread that polls the credentials future.
+    pub fn set_pool(&mut self, pool: CpuPool) {
+        self.pool = Some(pool);
+    }
 }
 
 impl ProvideAwsCredentials for ProfileProvider {
     fn credentials(&self) -> SFuture<AwsCredentials> {
-        let result = parse_credentials_file(self.file_path());
-        let result = result.and_then(|mut profiles| {
-            profiles
-                .remove(self.profile())
-                .ok_or("profile not found".into())
-        });
-        Box::new(future::result(result))
+        let mut profiles = ftry!(parse_credentials_file(self.file_path()));
+        match profiles.remove(self.profile()) {
+            Some(ProfileCredentials::Static(creds)) => f_ok(creds),
+            Some(ProfileCredentials::Process(command)) => match self.pool {
+                Some(ref pool) => Box::new(pool.spawn_fn(move || credentials_from_process(&command))),
+                None => Box::new(future::result(credentials_from_process(&command))),
+            },
+            None => f_err("profile not found"),
+        }
+    }
+}
+
+/// The credentials described by the settings of a profile, if any.
+fn profile_credentials(access_key: Option<String>,
+                       secret_key: Option<String>,
+                       process: Option<String>) -> Option<ProfileCredentials> {
+    match (access_key, secret_key, process) {
+        (Some(access_key), Some(secret_key), _) => Some(ProfileCredentials::Static(
+            AwsCredentials::new(access_key, secret_key, None, in_ten_minutes()))),
+        (_, _, Some(process)) => Some(ProfileCredentials::Process(process)),
+        _ => None,
     }
 }
 
-fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredentials>> {
+fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, ProfileCredentials>> {
     let metadata = fs::metadata(file_path).chain_err(|| "couldn't stat credentials file")?;
     if !metadata.is_file() {
         bail!("Couldn't open file.");
@@ -218,9 +318,10 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
     let file = File::open(file_path)?;
 
     let profile_regex = Regex::new(r"^\[([^\]]+)\]$").unwrap();
-    let mut profiles: HashMap<String, AwsCredentials> = HashMap::new();
+    let mut profiles: HashMap<String, ProfileCredentials> = HashMap::new();
     let mut access_key: Option<String> = None;
     let mut secret_key: Option<String> = None;
+    let mut process: Option<String> = None;
     let mut profile_name: Option<String> = None;
 
     let file_lines = BufReader::new(&file);
@@ -234,18 +335,15 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
 
         // handle the opening of named profile blocks
         if profile_regex.is_match(&unwrapped_line) {
-            if profile_name.is_some() && access_key.is_some() && secret_key.is_some() {
-                let creds = AwsCredentials::new(
-                    access_key.unwrap(),
-                    secret_key.unwrap(),
-                    None,
-                    in_ten_minutes(),
-                );
-                profiles.insert(profile_name.unwrap(), creds);
+            if let Some(name) = profile_name {
+                if let Some(creds) = profile_credentials(access_key, secret_key, process) {
+                    profiles.insert(name, creds);
+                }
             }
 
             access_key = None;
             secret_key = None;
+            process = None;
 
             let caps = profile_regex.captures(&unwrapped_line).unwrap();
             profile_name = Some(caps.get(1).unwrap().as_str().to_string());
@@ -265,19 +363,20 @@ fn parse_credentials_file(file_path: &Path) -> Result<HashMap<String, AwsCredent
             if !v.is_empty() {
                 secret_key = Some(v[1].trim_matches(' ').to_string());
             }
+        } else if lower_case_line.trim_start().starts_with("credential_process") && process.is_none() {
+            // The command may itself contain `=`.
+            if let Some(command) = unwrapped_line.splitn(2, '=').nth(1) {
+                process = Some(command.trim().to_string());
+            }
         }
 
         // we could potentially explode here to indicate that the file is invalid
     }
 
-    if profile_name.is_some() && access_key.is_some() && secret_key.is_some() {
-        let creds = AwsCredentials::new(
-            access_key.unwrap(),
-            secret_key.unwrap(),
-            None,
-            in_ten_minutes(),
-        );
-        profiles.insert(profile_name.unwrap(), creds);
+    if let Some(name) = profile_name {
+        if let Some(creds) = profile_credentials(access_key, secret_key, process) {
+            profiles.insert(name, creds);
+        }
     }
 
     if profiles.is_empty() {
@@ -358,78 +457,9 @@ impl ProvideAwsCredentials for IamProvider {
                     Ok::<_, hyper::Error>(body)
                 }).chain_err(|| "failed to read http body")
         });
-        let body = body
+        let creds = body
             .map_err(|_e| "Didn't get a parseable response body from instance role details".into())
-            .and_then(|body| {
-                String::from_utf8(body).chain_err(|| "failed to read iam role response")
-            });
-
-        let creds = body.and_then(|body| {
-            let json_object: Value;
-            match from_str(&body) {
-                Err(_) => bail!("Couldn't parse metadata response body."),
-                Ok(val) => json_object = val,
-            };
-
-            let access_key;
-            match json_object.get("AccessKeyId") {
-                None => bail!("Couldn't find AccessKeyId in response."),
-                Some(val) => {
-                    access_key = val
-                        .as_str()
-                        .expect("AccessKeyId value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
-
-            let secret_key;
-            match json_object.get("SecretAccessKey") {
-                None => bail!("Couldn't find SecretAccessKey in response."),
-                Some(val) => {
-                    secret_key = val
-                        .as_str()
-                        .expect("SecretAccessKey value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
-
-            let expiration;
-            match json_object.get("Expiration") {
-                None => bail!("Couldn't find Expiration in response."),
-                Some(val) => {
-                    expiration = val
-                        .as_str()
-                        .expect("Expiration value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
-
-            let expiration_time = expiration
-                .parse()
-                .chain_err(|| "failed to parse expiration time")?;
-
-            let token_from_response;
-            match json_object.get("Token") {
-                None => bail!("Couldn't find Token in response."),
-                Some(val) => {
-                    token_from_response = val
-                        .as_str()
-                        .expect("Token value was not a string")
-                        .to_owned()
-                        .replace("\"", "")
-                }
-            };
-
-            Ok(AwsCredentials::new(
-                access_key,
-                secret_key,
-                Some(token_from_response),
-                expiration_time,
-            ))
-        });
+            .and_then(|body| credentials_from_json(&body));
 
         //XXX: this is crappy, but this blocks on non-EC2 machines like
         // our mac builders.
@@ -448,6 +478,160 @@ impl ProvideAwsCredentials for IamProvider {
     }
 }
 
+/// The address of the ECS container credentials endpoint, which
+/// `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` is relative to.
+const CONTAINER_CREDENTIALS_ADDRESS: &str = "http://169.254.170.2";
+
+/// Provides AWS credentials from the ECS container credentials endpoint.
+pub struct ContainerProvider {
+    client: Client<HttpConnector>,
+    /// The URL to fetch credentials from.
+    url: String,
+    /// The value of the `Authorization` header to send, if any.
+    authorization: Option<String>,
+}
+
+impl ContainerProvider {
+    /// Create a new `ContainerProvider` that fetches credentials from `url`.
+    pub fn new(url: String, authorization: Option<String>) -> ContainerProvider {
+        ContainerProvider {
+            client: Client::new(),
+            url: url,
+            authorization: authorization,
+        }
+    }
+
+    /// Create a new `ContainerProvider` from `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI`,
+    /// or from `AWS_CONTAINER_CREDENTIALS_FULL_URI` and
+    /// `AWS_CONTAINER_AUTHORIZATION_TOKEN`, if they are set.
+    pub fn from_env() -> Option<ContainerProvider> {
+        if let Ok(uri) = var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI") {
+            let url = format!("{}{}", CONTAINER_CREDENTIALS_ADDRESS, uri);
+            return Some(ContainerProvider::new(url, None));
+        }
+        var("AWS_CONTAINER_CREDENTIALS_FULL_URI").ok().map(|url| {
+            ContainerProvider::new(url, var("AWS_CONTAINER_AUTHORIZATION_TOKEN").ok())
+        })
+    }
+}
+
+impl ProvideAwsCredentials for ContainerProvider {
+    fn credentials(&self) -> SFuture<AwsCredentials> {
+        let uri = ftry!(self.url.parse::<hyper::Uri>()
+                        .chain_err(|| format!("failed to parse `{}` as url", self.url)));
+        debug!("Attempting to fetch credentials from {}", uri);
+        let mut builder = Request::get(uri).set_header(Connection::close());
+        if let Some(ref authorization) = self.authorization {
+            builder.header("Authorization", authorization.as_str());
+        }
+        let req = builder.body("".into()).unwrap();
+        Box::new(self.client.request(req)
+            .chain_err(|| "failed to send http request")
+            .and_then(|response| {
+                if !response.status().is_success() {
+                    bail!("Container credentials endpoint returned {}", response.status());
+                }
+                Ok(response)
+            })
+            .and_then(read_body)
+            .and_then(|body| credentials_from_json(&body)))
+    }
+}
+
+/// Find the credentials in an AssumeRoleWithWebIdentity response.
+///
+/// https://docs.aws.amazon.com/STS/latest/APIReference/API_AssumeRoleWithWebIdentity.html
+fn parse_assume_role_response(body: &str) -> Result<AwsCredentials> {
+    let field = |name| -> Result<String> {
+        let regex = Regex::new(&format!(r"(?s)<Credentials>.*<{0}>(.*?)</{0}>", name)).unwrap();
+        match regex.captures(body) {
+            Some(caps) => Ok(caps[1].to_owned()),
+            None => bail!("Couldn't find {} in AssumeRoleWithWebIdentity response.", name),
+        }
+    };
+    let expiration = field("Expiration")?.parse().chain_err(|| "failed to parse expiration time")?;
+    Ok(AwsCredentials::new(
+        field("AccessKeyId")?,
+        field("SecretAccessKey")?,
+        Some(field("SessionToken")?),
+        expiration,
+    ))
+}
+
+/// Provides AWS credentials for a role, by exchanging a web identity token for
+/// them with STS, as on Kubernetes with IAM roles for service accounts.
+pub struct WebIdentityProvider {
+    client: reqwest::r#async::Client,
+    /// The file the web identity token is in.
+    token_file: PathBuf,
+    /// The ARN of the role to assume.
+    role_arn: String,
+    session_name: String,
+    /// The URL of the STS service.
+    endpoint: String,
+}
+
+impl WebIdentityProvider {
+    /// Create a new `WebIdentityProvider` that assumes `role_arn` with the
+    /// token in `token_file`, using the STS service at `endpoint`.
+    pub fn new(token_file: PathBuf, role_arn: String, session_name: String, endpoint: String) -> WebIdentityProvider {
+        WebIdentityProvider {
+            client: reqwest::r#async::Client::new(),
+            token_file: token_file,
+            role_arn: role_arn,
+            session_name: session_name,
+            endpoint: endpoint,
+        }
+    }
+
+    /// Create a new `WebIdentityProvider` from `AWS_WEB_IDENTITY_TOKEN_FILE`,
+    /// `AWS_ROLE_ARN` and optionally `AWS_ROLE_SESSION_NAME`, if they are set.
+    ///
+    /// The regional STS endpoint is used if `AWS_REGION` is set.
+    pub fn from_env() -> Option<WebIdentityProvider> {
+        let token_file = var_os("AWS_WEB_IDENTITY_TOKEN_FILE")?;
+        let role_arn = var("AWS_ROLE_ARN").ok()?;
+        let session_name = var("AWS_ROLE_SESSION_NAME").unwrap_or_else(|_| "sccache".to_owned());
+        let endpoint = match var("AWS_REGION") {
+            Ok(region) => format!("https://sts.{}.amazonaws.com/", region),
+            Err(_) => "https://sts.amazonaws.com/".to_owned(),
+        };
+        Some(WebIdentityProvider::new(token_file.into(), role_arn, session_name, endpoint))
+    }
+}
+
+impl ProvideAwsCredentials for WebIdentityProvider {
+    fn credentials(&self) -> SFuture<AwsCredentials> {
+        let token = ftry!(fs::read_to_string(&self.token_file).chain_err(|| {
+            format!("failed to read web identity token from {}", self.token_file.display())
+        }));
+        let mut url = ftry!(Url::parse(&self.endpoint)
+                            .chain_err(|| format!("failed to parse `{}` as url", self.endpoint)));
+        url.query_pairs_mut()
+            .append_pair("Action", "AssumeRoleWithWebIdentity")
+            .append_pair("Version", "2011-06-15")
+            .append_pair("RoleArn", &self.role_arn)
+            .append_pair("RoleSessionName", &self.session_name)
+            .append_pair("WebIdentityToken", token.trim());
+        debug!("Attempting to assume {} with a web identity token", self.role_arn);
+        Box::new(self.client.get(url).send().chain_err(|| {
+            "failed to send http request"
+        }).and_then(|response| {
+            let status = response.status();
+            response.into_body().fold(Vec::new(), |mut body, chunk| {
+                body.extend_from_slice(&chunk);
+                Ok::<_, reqwest::Error>(body)
+            }).chain_err(|| "failed to read http body").map(move |body| (status, body))
+        }).and_then(|(status, body)| {
+            let body = String::from_utf8_lossy(&body);
+            if !status.is_success() {
+                bail!("AssumeRoleWithWebIdentity failed with {}: {}", status, body);
+            }
+            parse_assume_role_response(&body)
+        }))
+    }
+}
+
 /// Wrapper for ProvideAwsCredentials that caches the credentials returned by the
 /// wrapped provider.  Each time the credentials are accessed, they are checked to see if
 /// they have expired, in which case they are retrieved from the wrapped provider again.
@@ -485,8 +669,10 @@ impl<P: ProvideAwsCredentials> ProvideAwsCredentials for AutoRefreshingProvider<
 /// The following sources are checked in order for credentials when calling `credentials`:
 ///
 /// 1. Environment variables: `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
-/// 2. AWS credentials file. Usually located at `~/.aws/credentials`.
-/// 3. IAM instance profile. Will only work if running on an EC2 instance with an instance profile/role.
+/// 2. A web identity token: `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN`
+/// 3. AWS credentials file. Usually located at `~/.aws/credentials`.
+/// 4. ECS container credentials: `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI`
+/// 5. IAM instance profile. Will only work if running on an EC2 instance with an instance profile/role.
 ///
 /// If the sources are exhausted without finding credentials, an error is returned.
 #[derive(Clone)]
@@ -501,9 +687,31 @@ impl ProvideAwsCredentials for ChainProvider {
             c
         });
         let mut creds = Box::new(creds) as SFuture<_>;
+        if let Some(provider) = WebIdentityProvider::from_env() {
+            creds = Box::new(creds.or_else(move |_| {
+                provider.credentials().map(|c| {
+                    debug!("Using AWS credentials from web identity");
+                    c
+                }).map_err(|e| {
+                    warn!("Failed to assume role with web identity: {}", e);
+                    e
+                })
+            }));
+        }
         for provider in self.profile_providers.iter() {
-            let alternate = provider.credentials();
-            creds = Box::new(creds.or_else(|_| alternate));
+            let provider = provider.clone();
+            creds = Box::new(creds.or_else(move |_| provider.credentials()));
+        }
+        if let Some(provider) = ContainerProvider::from_env() {
+            creds = Box::new(creds.or_else(move |_| {
+                provider.credentials().map(|c| {
+                    debug!("Using AWS credentials from the ECS container endpoint");
+                    c
+                }).map_err(|e| {
+                    warn!("Failed to fetch ECS container credentials: {}", e);
+                    e
+                })
+            }));
         }
         Box::new(
             creds
@@ -513,7 +721,8 @@ impl ProvideAwsCredentials for ChainProvider {
                         c
                     })
                 }).map_err(|_| {
-                    "Couldn't find AWS credentials in environment, credentials file, or IAM role."
+                    "Couldn't find AWS credentials in environment, web identity, credentials file, \
+                     ECS container endpoint, or IAM role."
                         .into()
                 }),
         )
@@ -528,10 +737,14 @@ impl ChainProvider {
         }
     }
 
-    /// Create a new `ChainProvider` using the provided `ProfileProvider`s.
-    pub fn with_profile_providers(profile_providers: Vec<ProfileProvider>) -> ChainProvider {
+    /// Create a new `ChainProvider` using the provided `ProfileProvider`s,
+    /// which run `credential_process` commands on `pool`.
+    pub fn with_profile_providers(profile_providers: Vec<ProfileProvider>, pool: &CpuPool) -> ChainProvider {
         ChainProvider {
-            profile_providers: profile_providers,
+            profile_providers: profile_providers.into_iter().map(|mut provider| {
+                provider.set_pool(pool.clone());
+                provider
+            }).collect(),
         }
     }
 }
@@ -539,3 +752,89 @@ impl ChainProvider {
 fn in_ten_minutes() -> DateTime<offset::Utc> {
     offset::Utc::now() + Duration::seconds(600)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::serve_http;
+    use hyper::{Body, Response};
+    use tempdir::TempDir;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn check_credentials(creds: &AwsCredentials) {
+        assert_eq!(creds.aws_access_key_id(), "AKID");
+        assert_eq!(creds.aws_secret_access_key(), "SECRET");
+        assert_eq!(creds.token().as_ref().map(|s| s.as_str()), Some("TOKEN"));
+    }
+
+    #[test]
+    fn test_container_provider() {
+        let mut runtime = Runtime::new().unwrap();
+        let url = serve_http(&mut runtime, |req, _| {
+            if req.uri.path() != "/v2/credentials"
+                || req.headers.get("Authorization").map(|v| v.as_bytes()) != Some(b"secret") {
+                return Response::builder().status(404).body(Body::empty()).unwrap();
+            }
+            Response::new(Body::from(r#"{
+                "AccessKeyId": "AKID",
+                "SecretAccessKey": "SECRET",
+                "Token": "TOKEN",
+                "Expiration": "2099-01-01T00:00:00Z",
+                "RoleArn": "arn:aws:iam::123456789012:role/sccache"
+            }"#))
+        });
+        let provider = ContainerProvider::new(format!("{}/v2/credentials", url),
+                                              Some("secret".to_owned()));
+        check_credentials(&runtime.block_on(provider.credentials()).unwrap());
+        let provider = ContainerProvider::new(format!("{}/v2/credentials", url), None);
+        assert!(runtime.block_on(provider.credentials()).is_err());
+    }
+
+    #[test]
+    fn test_web_identity_provider() {
+        let mut runtime = Runtime::new().unwrap();
+        let url = serve_http(&mut runtime, |req, _| {
+            let query = req.uri.query().unwrap_or("");
+            if !query.contains("Action=AssumeRoleWithWebIdentity")
+                || !query.contains("WebIdentityToken=web-identity-token") {
+                return Response::builder().status(400).body(Body::from("<Error/>")).unwrap();
+            }
+            Response::new(Body::from(r#"<AssumeRoleWithWebIdentityResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleWithWebIdentityResult>
+    <Credentials>
+      <SessionToken>TOKEN</SessionToken>
+      <SecretAccessKey>SECRET</SecretAccessKey>
+      <Expiration>2099-01-01T00:00:00Z</Expiration>
+      <AccessKeyId>AKID</AccessKeyId>
+    </Credentials>
+  </AssumeRoleWithWebIdentityResult>
+</AssumeRoleWithWebIdentityResponse>"#))
+        });
+        let tempdir = TempDir::new("sccache_test_web_identity").unwrap();
+        let token_file = tempdir.path().join("token");
+        fs::write(&token_file, "web-identity-token\n").unwrap();
+        let provider = WebIdentityProvider::new(token_file,
+                                                "arn:aws:iam::123456789012:role/sccache".to_owned(),
+                                                "sccache".to_owned(),
+                                                format!("{}/", url));
+        check_credentials(&runtime.block_on(provider.credentials()).unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_credential_process() {
+        let tempdir = TempDir::new("sccache_test_credential_process").unwrap();
+        let path = tempdir.path().join("credentials");
+        fs::write(&path, r#"[default]
+credential_process = echo '{"Version": 1, "AccessKeyId": "AKID", "SecretAccessKey": "SECRET", "SessionToken": "TOKEN"}'
+
+[other]
+aws_access_key_id = OTHER
+aws_secret_access_key = OTHERSECRET
+"#).unwrap();
+        let provider = ProfileProvider::with_configuration(&path, "default");
+        check_credentials(&provider.credentials().wait().unwrap());
+        let provider = ProfileProvider::with_configuration(&path, "other");
+        assert_eq!(provider.credentials().wait().unwrap().aws_access_key_id(), "OTHER");
+    }
+}
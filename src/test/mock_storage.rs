@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheWrite, Storage};
+use crate::cache::{Cache, CacheWrite, Storage, StorageListing};
 use crate::errors::*;
 use futures::future;
 use std::cell::RefCell;
@@ -49,4 +49,9 @@ impl Storage for MockStorage {
     fn location(&self) -> String { "Mock Storage".to_string() }
     fn current_size(&self) -> SFuture<Option<u64>> { Box::new(future::ok(None)) }
     fn max_size(&self) -> SFuture<Option<u64>> { Box::new(future::ok(None)) }
+    fn delete(&self, _key: &str) -> SFuture<()> { f_ok(()) }
+    fn exists(&self, _key: &str) -> SFuture<bool> { f_ok(false) }
+    fn list(&self, _continuation: Option<String>, _max_keys: usize) -> SFuture<StorageListing> {
+        f_ok(StorageListing::default())
+    }
 }
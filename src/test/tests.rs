@@ -13,14 +13,16 @@
 // limitations under the License.
 
 use crate::cache::disk::DiskCache;
+use crate::cache::readonly::ReadOnlyStorage;
+use crate::cache::Storage;
 use crate::client::connect_to_server;
-use crate::commands::{do_compile, request_shutdown, request_stats};
+use crate::commands::{do_compile, request_clear_cache, request_evict_key, request_shutdown, request_stats};
 use futures::sync::oneshot::{self, Sender};
 use futures_cpupool::CpuPool;
 use crate::jobserver::Client;
 use crate::mock_command::*;
 use crate::server::{DistClientContainer, SccacheServer, ServerMessage};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Cursor, Write};
 #[cfg(not(target_os = "macos"))]
 use std::net::TcpListener;
@@ -41,6 +43,8 @@ struct ServerOptions {
     idle_timeout: Option<u64>,
     /// The maximum size of the disk cache.
     cache_size: Option<u64>,
+    /// Make the disk cache read-only.
+    read_only: bool,
 }
 
 /// Run a server on a background thread, and return a tuple of useful things.
@@ -71,13 +75,19 @@ where
         .and_then(|o| o.cache_size.as_ref())
         .map(|s| *s)
         .unwrap_or(u64::MAX);
+    let read_only = options.as_ref().map_or(false, |o| o.read_only);
     // Create a server on a background thread, get some useful bits from it.
     let (tx, rx) = mpsc::channel();
     let (shutdown_tx, shutdown_rx) = oneshot::channel();
     let handle = thread::spawn(move || {
         let pool = CpuPool::new(1);
         let dist_client = DistClientContainer::new_disabled();
-        let storage = Arc::new(DiskCache::new(&cache_dir, cache_size, &pool));
+        let storage: Arc<dyn Storage> = Arc::new(DiskCache::new(&cache_dir, cache_size, &pool));
+        let storage: Arc<dyn Storage> = if read_only {
+            Arc::new(ReadOnlyStorage::new(storage))
+        } else {
+            storage
+        };
 
         let runtime = Runtime::new().unwrap();
         let client = unsafe { Client::new() };
@@ -161,6 +171,53 @@ fn test_server_stats() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_evict_key_and_clear_cache() {
+    let f = TestFixture::new();
+    // Put some entries in the disk cache before the server starts.
+    for key in &["abcdef", "012345", "fedcba"] {
+        let dir = f.tempdir.path().join(&key[0..1]).join(&key[1..2]);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(key), b"entry").unwrap();
+    }
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), None);
+    let conn = connect_to_server(port).unwrap();
+    assert!(request_evict_key(conn, "abcdef").unwrap());
+    assert!(!f.tempdir.path().join("a/b/abcdef").exists());
+    let conn = connect_to_server(port).unwrap();
+    assert!(!request_evict_key(conn, "abcdef").unwrap());
+    let conn = connect_to_server(port).unwrap();
+    assert_eq!(request_clear_cache(conn).unwrap(), 2);
+    assert!(!f.tempdir.path().join("0/1/012345").exists());
+    assert!(!f.tempdir.path().join("f/e/fedcba").exists());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_clear_unsupported_cache() {
+    let f = TestFixture::new();
+    let dir = f.tempdir.path().join("a/b");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("abcdef"), b"entry").unwrap();
+    let options = ServerOptions { read_only: true, ..Default::default() };
+    let (port, sender, _storage, child) = run_server_thread(&f.tempdir.path(), options);
+    // Read-only caches can't remove entries, which the server says rather
+    // than dropping the connection.
+    let conn = connect_to_server(port).unwrap();
+    let err = request_clear_cache(conn).unwrap_err();
+    assert!(err.to_string().contains("is not supported by cache storage"), "{}", err);
+    let conn = connect_to_server(port).unwrap();
+    let err = request_evict_key(conn, "abcdef").unwrap_err();
+    assert!(err.to_string().contains("is not supported by cache storage"), "{}", err);
+    assert!(dir.join("abcdef").exists());
+    // The server is still running.
+    let conn = connect_to_server(port).unwrap();
+    request_stats(conn).unwrap();
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
@@ -192,6 +192,27 @@ impl TestFixture {
     }
 }
 
+/// Start an HTTP server on `runtime` that answers every request with `respond`, which is
+/// given the head and body of the request, and return its URL.
+#[cfg(feature = "hyper")]
+pub fn serve_http<F>(runtime: &mut tokio::runtime::current_thread::Runtime, respond: F) -> String
+    where F: Fn(http::request::Parts, Vec<u8>) -> hyper::Response<hyper::Body> + Clone + Send + 'static
+{
+    use futures::{Future, Stream};
+
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(move || {
+        let respond = respond.clone();
+        hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+            let respond = respond.clone();
+            let (parts, body) = req.into_parts();
+            body.concat2().map(move |body| respond(parts, body.to_vec()))
+        })
+    });
+    let url = format!("http://{}", server.local_addr());
+    runtime.spawn(server.map_err(|e| panic!("server error: {}", e)));
+    url
+}
+
 
 #[test]
 fn test_map_contains_ok() {
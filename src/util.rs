@@ -16,7 +16,7 @@ use byteorder::{BigEndian, ByteOrder};
 use futures::{future, Future};
 use futures_cpupool::CpuPool;
 use crate::mock_command::{CommandChild, RunCommand};
-use ring::digest::{Context, SHA512};
+use ring::digest::{self as ring_digest, Context, SHA256, SHA512};
 use serde::Serialize;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
@@ -93,6 +93,11 @@ pub fn hex(bytes: &[u8]) -> String {
     }
 }
 
+/// Calculate the SHA-256 digest of `data`, in hex.
+pub fn sha256_hex(data: &[u8]) -> String {
+    ring_digest::digest(&SHA256, data).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 /// Calculate the SHA-1 digest of each file in `files` on background threads
 /// in `pool`.
 pub fn hash_all(files: &[PathBuf], pool: &CpuPool) -> SFuture<Vec<String>> {
@@ -125,6 +130,34 @@ pub fn fmt_duration_as_secs(duration: &Duration) -> String {
     )
 }
 
+/// Replace the predefined XML entities in `s`, as found in the text of the
+/// XML responses of storage services, with the characters they stand for.
+pub fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Return the name of this host, if it can be determined.
+#[cfg(unix)]
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// Return the name of this host, if it can be determined.
+#[cfg(windows)]
+pub fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
 /// If `input`, write it to `child`'s stdin while also reading `child`'s stdout and stderr, then wait on `child` and return its status and output.
 ///
 /// This was lifted from `std::process::Child::wait_with_output` and modified
@@ -517,7 +550,7 @@ pub fn daemonize() -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-    use super::OsStrExt;
+    use super::{sha256_hex, xml_unescape, OsStrExt};
     use std::ffi::{OsStr, OsString};
 
     #[test]
@@ -546,4 +579,14 @@ mod tests {
         assert_eq!(a.split_prefix("foo2"), None);
         assert_eq!(a.split_prefix("b"), None);
     }
+
+    #[test]
+    fn test_xml_unescape() {
+        assert_eq!(xml_unescape("a&lt;b&gt; &quot;c&apos; &amp;lt;"), "a<b> \"c' &lt;");
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
 }